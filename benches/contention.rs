@@ -0,0 +1,118 @@
+//! Throughput of one shared policy hammered by 1, 8 and 64 threads.
+//!
+//! Reported times are wall time per decision with all threads together, so a policy that scales
+//! shows lower times as threads are added, up to the number of cores.
+//!
+//! `contention/gcra_mutex`, with the `bench-internals` feature, is the same GCRA step with the
+//! TAT behind a mutex, the baseline for the single compare-and-swap of `Gcra`;
+//! `contention/token_bucket` locks its state too.
+
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ratelimit::{
+    Limiter, Policy, Quota, SystemClock, TokenBucket, VirtualScheduling, VirtualSchedulingBuilder,
+};
+
+const THREADS: [usize; 3] = [1, 8, 64];
+
+fn always_admits() -> VirtualSchedulingBuilder<SystemClock> {
+    VirtualScheduling::builder()
+        .gap(Duration::from_millis(1))
+        .tolerance(Duration::from_secs(u32::MAX as u64))
+}
+
+/// Run `iters` calls of `f` split over `threads` threads, timed from a common start.
+fn hammer(threads: usize, iters: u64, f: impl Fn() + Sync) -> Duration {
+    let per_thread = iters.div_ceil(threads as u64);
+    let barrier = Barrier::new(threads + 1);
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                s.spawn(|| {
+                    barrier.wait();
+                    for _ in 0..per_thread {
+                        f();
+                    }
+                })
+            })
+            .collect();
+        barrier.wait();
+        let start = Instant::now();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+fn gcra(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention/gcra");
+    for threads in THREADS {
+        let vs = always_admits().build();
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter_custom(|iters| {
+                hammer(n, iters, || {
+                    let _ = black_box(vs.check());
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(feature = "bench-internals")]
+fn gcra_mutex(c: &mut Criterion) {
+    use ratelimit::internals::LockedGcra;
+
+    let mut group = c.benchmark_group("contention/gcra_mutex");
+    for threads in THREADS {
+        let locked = LockedGcra::new(always_admits());
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter_custom(|iters| {
+                hammer(n, iters, || {
+                    let _ = black_box(locked.check());
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+#[cfg(not(feature = "bench-internals"))]
+fn gcra_mutex(_: &mut Criterion) {}
+
+fn token_bucket(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention/token_bucket");
+    for threads in THREADS {
+        let bucket = TokenBucket::builder(Quota::per_second(u32::MAX as u64)).build();
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter_custom(|iters| {
+                hammer(n, iters, || {
+                    let _ = black_box(bucket.check());
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+fn limiter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("contention/limiter");
+    for threads in THREADS {
+        let limiter = Limiter::new(always_admits().build());
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            b.iter_custom(|iters| {
+                hammer(n, iters, || {
+                    let _ = black_box(limiter.check());
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, gcra, gcra_mutex, token_bucket, limiter);
+criterion_main!(benches);
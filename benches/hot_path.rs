@@ -0,0 +1,40 @@
+//! Cost of a single uncontended decision, see the hot path guarantee on `Gcra`.
+
+use std::time::Duration;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ratelimit::{Limiter, Policy, Sampled, VirtualScheduling};
+
+// Admits every request of a run while still moving the TAT on each one.
+fn always_admits() -> VirtualScheduling {
+    VirtualScheduling::builder()
+        .gap(Duration::from_millis(1))
+        .tolerance(Duration::from_secs(u32::MAX as u64))
+        .build()
+}
+
+fn admitted(c: &mut Criterion) {
+    let vs = always_admits();
+    c.bench_function("gcra/check", |b| b.iter(|| black_box(vs.check())));
+    c.bench_function("gcra/check_n", |b| b.iter(|| black_box(vs.check_n(4))));
+    let costs = [1; 64];
+    c.bench_function("gcra/check_many_64", |b| {
+        b.iter(|| black_box(vs.check_many(&costs)))
+    });
+    let sampled = Sampled::new(&vs, 64);
+    c.bench_function("gcra/sampled_64", |b| b.iter(|| black_box(sampled.check())));
+}
+
+fn denied(c: &mut Criterion) {
+    let vs = VirtualScheduling::builder().rate(1).build();
+    let _ = vs.check();
+    c.bench_function("gcra/denied", |b| b.iter(|| black_box(vs.check())));
+}
+
+fn limiter(c: &mut Criterion) {
+    let limiter = Limiter::new(always_admits());
+    c.bench_function("limiter/check", |b| b.iter(|| black_box(limiter.check())));
+}
+
+criterion_group!(benches, admitted, denied, limiter);
+criterion_main!(benches);
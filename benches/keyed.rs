@@ -0,0 +1,58 @@
+//! Per-key decisions on a map holding a million keys.
+
+use std::hash::{BuildHasher, RandomState};
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use ratelimit::{KeyedLimiter, Quota};
+
+const KEYS: u64 = 1_000_000;
+
+fn keyed(c: &mut Criterion) {
+    let limiter: KeyedLimiter<u64> = KeyedLimiter::builder(Quota::per_second(1_000_000)).build();
+    for key in 0..KEYS {
+        let _ = limiter.check(&key);
+    }
+    // spread lookups over the whole map rather than walking it in order
+    let hasher = RandomState::new();
+    let mut i = 0u64;
+    c.bench_function("keyed/1M/existing", |b| {
+        b.iter(|| {
+            i += 1;
+            black_box(limiter.check(&(hasher.hash_one(i) % KEYS)))
+        })
+    });
+
+    let capped: KeyedLimiter<u64> = KeyedLimiter::builder(Quota::per_second(1))
+        .max_keys(KEYS as usize)
+        .build();
+    for key in 0..KEYS {
+        let _ = capped.check(&key);
+    }
+    let mut fresh = KEYS;
+    c.bench_function("keyed/1M/full_evict", |b| {
+        b.iter(|| {
+            fresh += 1;
+            black_box(capped.check(&fresh))
+        })
+    });
+}
+
+#[cfg(feature = "bench-internals")]
+fn sketch(c: &mut Criterion) {
+    use ratelimit::internals::CountMin;
+
+    let mut sketch = CountMin::new(1 << 20, 60_000);
+    let mut key = 0u64;
+    c.bench_function("keyed/prefilter_sketch", |b| {
+        b.iter(|| {
+            key += 1;
+            black_box(sketch.increment(&key, 0))
+        })
+    });
+}
+
+#[cfg(not(feature = "bench-internals"))]
+fn sketch(_: &mut Criterion) {}
+
+criterion_group!(benches, keyed, sketch);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Cost of the async waiter queue with a growing number of parked waiters.
+
+use std::task::Waker;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use ratelimit::internals::WaitQueue;
+
+fn queue(c: &mut Criterion) {
+    let mut group = c.benchmark_group("waiters/join_leave");
+    for parked in [0, 100, 1000] {
+        let queue = WaitQueue::new(None);
+        let tickets: Vec<_> = (0..parked).map(|i| queue.join(i % 4, 0)).collect();
+        group.bench_with_input(BenchmarkId::from_parameter(parked), &queue, |b, queue| {
+            b.iter(|| {
+                let ticket = queue.join(8, 0);
+                black_box(ticket.is_head(Waker::noop()))
+            })
+        });
+        drop(tickets);
+    }
+    group.finish();
+}
+
+#[cfg(feature = "tokio")]
+fn until_ready(c: &mut Criterion) {
+    use std::time::Duration;
+
+    use ratelimit::{Limiter, VirtualScheduling};
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+    let limiter = Limiter::new(
+        VirtualScheduling::builder()
+            .gap(Duration::from_millis(1))
+            .tolerance(Duration::from_secs(u32::MAX as u64))
+            .build(),
+    );
+    c.bench_function("waiters/until_ready", |b| {
+        b.iter(|| rt.block_on(limiter.until_ready()).unwrap())
+    });
+}
+
+#[cfg(not(feature = "tokio"))]
+fn until_ready(_: &mut Criterion) {}
+
+criterion_group!(benches, queue, until_ready);
+criterion_main!(benches);
@@ -0,0 +1,266 @@
+//! Standard scenarios timed on this machine, reported as JSON, to compare algorithms and
+//! wrappers before picking one. Unlike the criterion benches, this needs nothing but the crate:
+//!
+//! ```text
+//! cargo run --release --example ratelimit-bench -- [--duration-ms 1000] [--threads 1,8,64] [FILTER]
+//! ```
+//!
+//! Every scenario whose name contains `FILTER` runs for the duration at each thread count, all
+//! threads deciding on one shared policy. The report holds, per run, decisions per second over
+//! all threads, the share admitted, the p50 and p99 latency of one decision in ns, and the
+//! throughput relative to the single-thread run of the scenario, which shows how it scales
+//! under contention. Latencies are of a sample of decisions timed one by one, so they include
+//! the cost of reading the clock, some tens of ns.
+
+use std::hash::{BuildHasher, RandomState};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Barrier;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratelimit::{KeyedLimiter, Limiter, LimiterConfig, Policy, Quota, VirtualScheduling};
+use serde_json::{json, Value};
+
+// one decision in this many is timed on its own
+const SAMPLE_EVERY: u64 = 16;
+const KEYS: u64 = 100_000;
+
+/// One decision, given the index of the call on its thread. Returns whether it was admitted.
+type Decide = dyn Fn(u64) -> bool + Sync;
+
+struct Options {
+    duration: Duration,
+    threads: Vec<usize>,
+    filter: String,
+}
+
+fn options() -> Options {
+    let mut options = Options {
+        duration: Duration::from_secs(1),
+        threads: vec![1, 8, 64],
+        filter: String::new(),
+    };
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .unwrap_or_else(|| usage(&format!("{name} needs a value")))
+        };
+        match arg.as_str() {
+            "--duration-ms" => {
+                let ms = value("--duration-ms")
+                    .parse()
+                    .unwrap_or_else(|_| usage("invalid --duration-ms"));
+                options.duration = Duration::from_millis(ms);
+            }
+            "--threads" => {
+                options.threads = value("--threads")
+                    .split(',')
+                    .map(|n| n.trim().parse().ok().filter(|&n| n > 0))
+                    .collect::<Option<_>>()
+                    .unwrap_or_else(|| usage("invalid --threads"));
+            }
+            "-h" | "--help" => usage(""),
+            _ if arg.starts_with('-') => usage(&format!("unknown option {arg}")),
+            _ => options.filter = arg,
+        }
+    }
+    options
+}
+
+fn usage(error: &str) -> ! {
+    if !error.is_empty() {
+        eprintln!("error: {error}");
+    }
+    eprintln!("usage: ratelimit-bench [--duration-ms MS] [--threads N,N,..] [FILTER]");
+    std::process::exit(if error.is_empty() { 0 } else { 2 })
+}
+
+// Admits every request of a run while still moving the TAT on each one.
+fn always_admits() -> VirtualScheduling {
+    VirtualScheduling::builder()
+        .gap(Duration::from_nanos(1))
+        .tolerance(Duration::from_secs(u32::MAX as u64))
+        .build()
+}
+
+fn scenarios() -> Vec<(&'static str, Box<Decide>)> {
+    let gcra = always_admits();
+    let denied = VirtualScheduling::builder().rate(1).build();
+    let _ = denied.check();
+    let token_bucket = LimiterConfig::TokenBucket {
+        rate: 1_000_000_000,
+        burst: u32::MAX as u64,
+        refill_interval_ms: None,
+    }
+    .build()
+    .unwrap();
+    let fixed_window = LimiterConfig::FixedWindow {
+        limit: u32::MAX as u64,
+        window_ms: 1000,
+    }
+    .build()
+    .unwrap();
+    let limiter = Limiter::new(always_admits());
+    let keyed: KeyedLimiter<u64> = KeyedLimiter::builder(Quota::per_second(1_000_000_000)).build();
+    let hasher = RandomState::new();
+    vec![
+        ("gcra", Box::new(move |_| gcra.check().is_ok())),
+        ("gcra/denied", Box::new(move |_| denied.check().is_ok())),
+        (
+            "token_bucket",
+            Box::new(move |_| token_bucket.check().is_ok()),
+        ),
+        (
+            "fixed_window",
+            Box::new(move |_| fixed_window.check().is_ok()),
+        ),
+        ("limiter", Box::new(move |_| limiter.check().is_ok())),
+        (
+            "keyed/100k",
+            Box::new(move |i| keyed.check(&(hasher.hash_one(i) % KEYS)).is_ok()),
+        ),
+    ]
+}
+
+struct Run {
+    ops: u64,
+    admitted: u64,
+    elapsed: Duration,
+    // latencies of the sampled decisions, in ns
+    latencies: Vec<u64>,
+}
+
+/// Call `decide` on `threads` threads until `duration` is over, from a common start.
+fn run(threads: usize, duration: Duration, decide: &Decide) -> Run {
+    let barrier = Barrier::new(threads + 1);
+    let stop = AtomicBool::new(false);
+    thread::scope(|s| {
+        let handles: Vec<_> = (0..threads)
+            .map(|t| {
+                let (barrier, stop) = (&barrier, &stop);
+                s.spawn(move || {
+                    // threads start at different points, e.g. on different keys
+                    let mut i = (t as u64) << 40;
+                    let (mut ops, mut admitted, mut latencies) = (0, 0, Vec::new());
+                    barrier.wait();
+                    while !stop.load(Ordering::Relaxed) {
+                        for _ in 0..SAMPLE_EVERY - 1 {
+                            admitted += decide(i) as u64;
+                            i += 1;
+                        }
+                        let start = Instant::now();
+                        admitted += decide(i) as u64;
+                        latencies.push(start.elapsed().as_nanos() as u64);
+                        i += 1;
+                        ops += SAMPLE_EVERY;
+                    }
+                    (ops, admitted, latencies)
+                })
+            })
+            .collect();
+        barrier.wait();
+        let start = Instant::now();
+        thread::sleep(duration);
+        stop.store(true, Ordering::Relaxed);
+        let mut total = Run {
+            ops: 0,
+            admitted: 0,
+            elapsed: Duration::ZERO,
+            latencies: Vec::new(),
+        };
+        for handle in handles {
+            let (ops, admitted, latencies) = handle.join().unwrap();
+            total.ops += ops;
+            total.admitted += admitted;
+            total.latencies.extend(latencies);
+        }
+        total.elapsed = start.elapsed();
+        total
+    })
+}
+
+fn quantile(sorted: &[u64], q: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((q * sorted.len() as f64).ceil() as usize).clamp(1, sorted.len());
+    sorted[rank - 1]
+}
+
+fn main() {
+    let options = options();
+    let mut results = Vec::new();
+    for (name, decide) in scenarios() {
+        if !name.contains(&options.filter) {
+            continue;
+        }
+        let mut single = None;
+        for &threads in &options.threads {
+            let mut run = run(threads, options.duration, &*decide);
+            run.latencies.sort_unstable();
+            let ops_per_sec = run.ops as f64 / run.elapsed.as_secs_f64();
+            if threads == 1 {
+                single = Some(ops_per_sec);
+            }
+            eprintln!("{name} x{threads}: {ops_per_sec:.0} ops/s");
+            results.push(json!({
+                "scenario": name,
+                "threads": threads,
+                "ops_per_sec": ops_per_sec.round(),
+                "admitted": run.admitted as f64 / run.ops.max(1) as f64,
+                "p50_ns": quantile(&run.latencies, 0.5),
+                "p99_ns": quantile(&run.latencies, 0.99),
+                "scaling": single.map_or(Value::Null, |single| json!(ops_per_sec / single)),
+            }));
+        }
+    }
+    let report = json!({
+        "crate_version": env!("CARGO_PKG_VERSION"),
+        "os": std::env::consts::OS,
+        "arch": std::env::consts::ARCH,
+        "cpus": thread::available_parallelism().map_or(0, |n| n.get()),
+        "duration_ms": options.duration.as_millis() as u64,
+        "results": results,
+    });
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantile() {
+        assert_eq!(quantile(&[], 0.5), 0);
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(quantile(&sorted, 0.5), 50);
+        assert_eq!(quantile(&sorted, 0.99), 99);
+        assert_eq!(quantile(&sorted, 0.0), 1);
+        assert_eq!(quantile(&sorted, 1.0), 100);
+        assert_eq!(quantile(&[7], 0.99), 7);
+    }
+
+    #[test]
+    fn test_scenarios() {
+        // every scenario builds, and all but the denied one admit at once
+        for (name, decide) in scenarios() {
+            assert_eq!(decide(0), name != "gcra/denied", "{name}");
+        }
+    }
+
+    #[test]
+    fn test_run() {
+        let scenarios = scenarios();
+        let decide = |name| &*scenarios.iter().find(|(n, _)| *n == name).unwrap().1;
+        for (name, admitted) in [("gcra", true), ("gcra/denied", false)] {
+            let run = run(2, Duration::from_millis(20), decide(name));
+            assert!(run.ops > 0);
+            assert_eq!(run.ops % SAMPLE_EVERY, 0);
+            // one sample per batch of decisions
+            assert_eq!(run.latencies.len() as u64, run.ops / SAMPLE_EVERY);
+            assert_eq!(run.admitted, if admitted { run.ops } else { 0 });
+            assert!(run.elapsed >= Duration::from_millis(20));
+        }
+    }
+}
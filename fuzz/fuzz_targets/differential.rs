@@ -0,0 +1,45 @@
+#![no_main]
+
+use std::time::Duration;
+
+use libfuzzer_sys::fuzz_target;
+use ratelimit::testing::{differential, Op, Reference, SingleKey};
+use ratelimit::{Gcra, KeyedLimiter, MockClock, Quota};
+
+fuzz_target!(|data: &[u8]| {
+    let [gap, tolerance, ops @ ..] = data else {
+        return;
+    };
+    let ops = Op::decode(ops);
+
+    let gap_ms = *gap as u64;
+    let gap = Duration::from_millis(gap_ms);
+    let tolerance = Duration::from_millis(*tolerance as u64);
+    let clock = MockClock::new(0);
+    let reference = Reference::new(&clock, gap, tolerance);
+    let policy = Gcra::builder()
+        .clock(&clock)
+        .gap(gap)
+        .tolerance(tolerance)
+        .build();
+    if let Err(divergence) = differential(&reference, &policy, &clock, &ops) {
+        panic!("gcra: {divergence:?}");
+    }
+
+    // keyed limiters take a window, which is a tolerance of whole gaps
+    let gap_ms = gap_ms.max(1);
+    let limit = tolerance.as_millis() as u64 / gap_ms + 1;
+    let clock = MockClock::new(0);
+    let reference = Reference::new(
+        &clock,
+        Duration::from_millis(gap_ms),
+        Duration::from_millis(gap_ms * (limit - 1)),
+    );
+    let keyed: KeyedLimiter<u8, _> = KeyedLimiter::builder(Quota::per_second(1))
+        .window(limit, Duration::from_millis(gap_ms * limit))
+        .clock(&clock)
+        .build();
+    if let Err(divergence) = differential(&reference, &SingleKey::new(&keyed, 0), &clock, &ops) {
+        panic!("keyed: {divergence:?}");
+    }
+});
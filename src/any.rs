@@ -0,0 +1,86 @@
+//! A policy whose algorithm is picked at run time, e.g. from configuration.
+
+use crate::clock::{Clock, SystemClock};
+use crate::describe::{Describe, Description};
+use crate::gcra::{Denied, Gcra, Headroom, Policy};
+use crate::token_bucket::TokenBucket;
+
+/// One of the policies of this crate, usually built from a
+/// [`LimiterConfig`](crate::LimiterConfig). A fixed window is a [`TokenBucket`] refilled on
+/// aligned ticks, see [`Refill::AlignedTick`](crate::Refill::AlignedTick).
+///
+/// There is no sliding window variant, as the crate has no sliding window algorithm. Where one
+/// would be picked to avoid the bursts at the edges of fixed windows, a GCRA does the same: its
+/// rate holds over any stretch of time rather than aligned windows, with bursts of at most its
+/// capacity.
+pub enum AnyLimiter<C = SystemClock> {
+    Gcra(Gcra<C>),
+    TokenBucket(TokenBucket<C>),
+}
+
+impl<C> Policy for AnyLimiter<C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.check(),
+            AnyLimiter::TokenBucket(bucket) => bucket.check(),
+        }
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        AnyLimiter::check_n(self, n)
+    }
+
+    fn refund(&self) {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.refund(),
+            AnyLimiter::TokenBucket(bucket) => bucket.refund(),
+        }
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.headroom(),
+            AnyLimiter::TokenBucket(bucket) => bucket.headroom(),
+        }
+    }
+}
+
+impl<C> Describe for AnyLimiter<C>
+where
+    C: Clock,
+{
+    fn describe(&self) -> Description {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.describe(),
+            AnyLimiter::TokenBucket(bucket) => bucket.describe(),
+        }
+    }
+}
+
+impl<C> AnyLimiter<C>
+where
+    C: Clock,
+{
+    /// Decide on a request worth `n` cells at once, all or nothing.
+    pub fn check_n(&self, n: u64) -> Result<(), Denied> {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.check_n(n),
+            AnyLimiter::TokenBucket(bucket) => bucket.check_n(n),
+        }
+    }
+}
+
+impl<C> From<Gcra<C>> for AnyLimiter<C> {
+    fn from(gcra: Gcra<C>) -> Self {
+        AnyLimiter::Gcra(gcra)
+    }
+}
+
+impl<C> From<TokenBucket<C>> for AnyLimiter<C> {
+    fn from(bucket: TokenBucket<C>) -> Self {
+        AnyLimiter::TokenBucket(bucket)
+    }
+}
@@ -0,0 +1,237 @@
+//! Autoscaling hints derived from the demand a limiter sees.
+//!
+//! A limiter that keeps denying requests protects the instance, but the demand is still there;
+//! adding capacity is the platform's job. [`ScaleAdvisor`] compares a [`Limiter`]'s offered rate
+//! with the rate its policy refills at and, once demand stayed far above or below it for a while,
+//! hands a [`ScaleHint`] to a [`Deliver`] sink, e.g. a closure that feeds an autoscaler.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp};
+use crate::gcra::Policy;
+use crate::limiter::Limiter;
+use crate::retry::Deliver;
+
+/// Which way a [`ScaleHint`] suggests to scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ScaleDirection {
+    Up,
+    Down,
+}
+
+/// Demand stayed at `demand_ratio` times the configured rate for `sustained`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScaleHint {
+    pub limiter: String,
+    pub direction: ScaleDirection,
+    /// Offered rate divided by the configured rate, as of the hint.
+    pub demand_ratio: f64,
+    /// Requests per second.
+    pub configured_rate: f64,
+    pub sustained: Duration,
+}
+
+impl fmt::Display for ScaleHint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.sustained.as_secs();
+        write!(
+            f,
+            "{}: sustained demand {:.1}x configured rate for ",
+            self.limiter, self.demand_ratio
+        )?;
+        match (secs / 60, secs % 60) {
+            (0, s) => write!(f, "{s}s"),
+            (m, 0) => write!(f, "{m}m"),
+            (m, s) => write!(f, "{m}m{s}s"),
+        }
+    }
+}
+
+/// Watches a [`Limiter`] and emits a [`ScaleHint`] when demand stays out of line with its
+/// configured rate.
+///
+/// Call [`observe`](Self::observe) periodically, e.g. every few seconds. Demand is the
+/// limiter's [offered rate](Limiter::observed_rate), so it is smoothed over the limiter's
+/// observe window; the configured rate is what its policy refills at, see
+/// [`Headroom`](crate::Headroom). Policies that do not report their headroom give no hints.
+///
+/// A hint is emitted once demand stayed above [`scale_up_above`](Self::scale_up_above) (or below
+/// [`scale_down_below`](Self::scale_down_below)) times the configured rate for
+/// [`sustain`](Self::sustain), and again after every further `sustain` it stays there.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use ratelimit::{Limiter, Quota, ScaleAdvisor, VirtualScheduling};
+///
+/// let limiter = Limiter::new(VirtualScheduling::builder().quota(Quota::per_second(100)).build());
+/// let mut advisor = ScaleAdvisor::new(|hint| println!("{hint}")).sustain(Duration::from_secs(300));
+/// advisor.observe(&limiter);
+/// ```
+pub struct ScaleAdvisor<D, C = SystemClock> {
+    clock: C,
+    deliver: D,
+    up: f64,
+    down: f64,
+    // ms
+    sustain: u64,
+    episode: Option<Episode>,
+}
+
+struct Episode {
+    direction: ScaleDirection,
+    since: Timestamp,
+    last_hint: Timestamp,
+}
+
+impl<D> ScaleAdvisor<D, SystemClock> {
+    pub fn new(deliver: D) -> Self {
+        ScaleAdvisor {
+            clock: SystemClock,
+            deliver,
+            up: 1.2,
+            down: 0.5,
+            sustain: 5 * 60 * 1000,
+            episode: None,
+        }
+    }
+}
+
+impl<D, C> ScaleAdvisor<D, C> {
+    pub fn clock<NC>(self, clock: NC) -> ScaleAdvisor<D, NC> {
+        ScaleAdvisor {
+            clock,
+            deliver: self.deliver,
+            up: self.up,
+            down: self.down,
+            sustain: self.sustain,
+            episode: None,
+        }
+    }
+
+    /// Suggest scaling up while demand is above `ratio` times the configured rate. 1.2 by
+    /// default.
+    pub fn scale_up_above(mut self, ratio: f64) -> Self {
+        self.up = ratio;
+        self
+    }
+
+    /// Suggest scaling down while demand is below `ratio` times the configured rate. 0.5 by
+    /// default; 0 never suggests scaling down.
+    pub fn scale_down_below(mut self, ratio: f64) -> Self {
+        self.down = ratio;
+        self
+    }
+
+    /// How long demand has to stay out of line before a hint. 5 minutes by default.
+    pub fn sustain(mut self, sustain: Duration) -> Self {
+        self.sustain = sustain.as_millis() as u64;
+        self
+    }
+}
+
+impl<D, C> ScaleAdvisor<D, C>
+where
+    D: Deliver<ScaleHint>,
+    C: Clock,
+{
+    /// Look at the limiter's current demand, and emit a hint if it has been out of line long
+    /// enough.
+    pub fn observe<P, LC>(&mut self, limiter: &Limiter<P, LC>)
+    where
+        P: Policy,
+        LC: Clock,
+    {
+        let now = now_millis(&self.clock);
+        let Some(configured_rate) = limiter
+            .headroom()
+            .map(|headroom| headroom.refill_rate)
+            .filter(|&rate| rate > 0.0 && rate.is_finite())
+        else {
+            self.episode = None;
+            return;
+        };
+        let demand_ratio = limiter.observed_rate().offered / configured_rate;
+        let direction = if demand_ratio > self.up {
+            ScaleDirection::Up
+        } else if demand_ratio < self.down {
+            ScaleDirection::Down
+        } else {
+            self.episode = None;
+            return;
+        };
+
+        let episode = match &mut self.episode {
+            Some(episode) if episode.direction == direction => episode,
+            episode => episode.insert(Episode {
+                direction,
+                since: now,
+                last_hint: now,
+            }),
+        };
+        if now.saturating_sub(episode.last_hint) < self.sustain {
+            return;
+        }
+        episode.last_hint = now;
+        let sustained = Duration::from_millis(now - episode.since);
+        self.deliver.deliver(ScaleHint {
+            limiter: limiter.name().to_owned(),
+            direction,
+            demand_ratio,
+            configured_rate,
+            sustained,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::GcraBuilder;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_scale_advisor_hints() {
+        let clock = MockClock::new(1_000_000);
+        let limiter = Limiter::new(
+            GcraBuilder::new()
+                .clock(&clock)
+                .quota(Quota::per_second(10))
+                .build(),
+        )
+        .clock(&clock)
+        .named("api")
+        .observe_window(Duration::from_secs(10));
+        let mut hints = Vec::new();
+        let mut advisor = ScaleAdvisor::new(|hint: ScaleHint| hints.push(hint.to_string()))
+            .clock(&clock)
+            .sustain(Duration::from_secs(60));
+
+        // 20 requests per second for three minutes
+        for second in 0..180 {
+            for _ in 0..20 {
+                limiter.pass();
+                clock.forward(Duration::from_millis(50));
+            }
+            if second % 5 == 0 {
+                advisor.observe(&limiter);
+            }
+        }
+        assert_eq!(
+            hints,
+            [
+                "api: sustained demand 2.0x configured rate for 1m",
+                "api: sustained demand 2.0x configured rate for 2m",
+            ]
+        );
+    }
+}
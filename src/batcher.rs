@@ -0,0 +1,223 @@
+//! The protocol of [`Prefetch`](crate::Prefetch) without the I/O: which batches of tokens to ask
+//! a backend for, and what to make of its answers.
+//!
+//! A [`Batcher`] is a plain state machine. It never calls a backend and never spawns anything:
+//! the driver around it asks the backend for every [`Fetch`] it is handed, over whatever
+//! transport and on whatever runtime, and reports the answer with
+//! [`on_response`](Batcher::on_response). `Prefetch` is the driver for blocking
+//! [`Backend`](crate::Backend)s, fetching in the background on a thread, and `AsyncPrefetch`,
+//! with the `tokio` or `async-std` feature, the driver for async ones.
+//!
+//! ```
+//! use ratelimit::{Batcher, Next};
+//!
+//! let mut batcher = Batcher::new(10);
+//! // out of tokens, the first request waits for a batch
+//! let Next::Fetch(fetch) = batcher.take() else { unreachable!() };
+//! assert_eq!(fetch.n(), 10);
+//! assert_eq!(batcher.on_response(fetch, Ok(())), Some(Next::Admit));
+//! assert_eq!(batcher.tokens(), 9);
+//! assert_eq!(batcher.take(), Next::Admit);
+//! ```
+
+use crate::gcra::Denied;
+
+/// Tokens fetched from a backend in batches and handed out one per request.
+///
+/// Tokens are charged to the backend when fetched. Once a quarter of a batch is left, by
+/// default, the next one is due in the background, see [`poll_action`](Self::poll_action). Once
+/// the backend denies a batch, no more are fetched in the background: the tokens held are handed
+/// out, then every request asks for a batch or else a single token, until a batch is granted.
+#[derive(Debug)]
+pub struct Batcher {
+    batch: u64,
+    low_water: u64,
+    tokens: u64,
+    // a background fetch is due, set when a request took the tokens down to the low water mark
+    due: bool,
+    fetching: bool,
+    // the backend denied or failed the last background fetch
+    backoff: bool,
+}
+
+/// A request to the backend for `n` tokens, all or nothing, to be answered with
+/// [`Batcher::on_response`] or [`Batcher::on_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fetch {
+    n: u64,
+    // made for a request waiting on it, rather than in the background
+    request: bool,
+}
+
+/// What becomes of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    Admit,
+    Deny(Denied),
+    /// The request waits for the backend's answer to this fetch.
+    Fetch(Fetch),
+}
+
+impl Fetch {
+    pub fn n(&self) -> u64 {
+        self.n
+    }
+
+    /// Whether the fetch refills tokens ahead of time, with no request waiting on it.
+    pub fn is_background(&self) -> bool {
+        !self.request
+    }
+}
+
+impl Batcher {
+    /// Fetch `batch` tokens at a time, the next batch once a quarter of one is left.
+    ///
+    /// # Panics
+    /// Panics if `batch` is zero.
+    pub fn new(batch: u64) -> Self {
+        assert!(batch > 0, "batch must be positive");
+        Batcher {
+            batch,
+            low_water: batch / 4,
+            tokens: 0,
+            due: false,
+            fetching: false,
+            backoff: false,
+        }
+    }
+
+    /// Fetch the next batch once `tokens` are left.
+    pub fn low_water(mut self, tokens: u64) -> Self {
+        self.set_low_water(tokens);
+        self
+    }
+
+    pub(crate) fn set_low_water(&mut self, tokens: u64) {
+        self.low_water = tokens;
+    }
+
+    /// Tokens held locally.
+    pub fn tokens(&self) -> u64 {
+        self.tokens
+    }
+
+    /// Whether a background fetch is out.
+    pub fn is_fetching(&self) -> bool {
+        self.fetching
+    }
+
+    /// Decide on one request: admitted with a local token, or else it waits for a batch.
+    pub fn take(&mut self) -> Next {
+        let Some(left) = self.tokens.checked_sub(1) else {
+            return Next::Fetch(Fetch {
+                n: self.batch,
+                request: true,
+            });
+        };
+        self.tokens = left;
+        self.due |= left <= self.low_water;
+        Next::Admit
+    }
+
+    /// The background fetch to start, if one is due. At most one is out at a time.
+    pub fn poll_action(&mut self) -> Option<Fetch> {
+        if !self.due || self.fetching || self.backoff {
+            return None;
+        }
+        self.due = false;
+        self.fetching = true;
+        Some(Fetch {
+            n: self.batch,
+            request: false,
+        })
+    }
+
+    /// The backend granted or denied `fetch`. Returns what becomes of the request waiting on it,
+    /// or `None` for a background fetch.
+    pub fn on_response(&mut self, fetch: Fetch, answer: Result<(), Denied>) -> Option<Next> {
+        if !fetch.request {
+            self.fetching = false;
+            match answer {
+                Ok(()) => {
+                    self.tokens += fetch.n;
+                    self.backoff = false;
+                }
+                Err(_) => self.backoff = true,
+            }
+            return None;
+        }
+        Some(match answer {
+            Ok(()) => {
+                // the request takes one of the tokens
+                self.tokens += fetch.n - 1;
+                self.backoff = false;
+                Next::Admit
+            }
+            Err(_) if fetch.n > 1 => Next::Fetch(Fetch {
+                n: 1,
+                request: true,
+            }),
+            Err(denied) => Next::Deny(denied),
+        })
+    }
+
+    /// The backend failed to answer `fetch`. A request waiting on it is up to the driver, e.g. to
+    /// report the error.
+    pub fn on_error(&mut self, fetch: Fetch) {
+        if !fetch.request {
+            self.fetching = false;
+            self.backoff = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_batcher() {
+        let mut batcher = Batcher::new(4).low_water(1);
+        let mut left = 10;
+        let mut answer = |fetch: Fetch| {
+            if left < fetch.n() {
+                return Err(Denied::new(Duration::from_secs(1)));
+            }
+            left -= fetch.n();
+            Ok(())
+        };
+
+        let Next::Fetch(fetch) = batcher.take() else {
+            panic!("no tokens yet")
+        };
+        assert_eq!(batcher.on_response(fetch, answer(fetch)), Some(Next::Admit));
+        assert_eq!((batcher.take(), batcher.take()), (Next::Admit, Next::Admit));
+        // one token left, the next batch is due in the background
+        let fetch = batcher.poll_action().unwrap();
+        assert!(fetch.is_background());
+        assert_eq!(batcher.poll_action(), None);
+        assert_eq!(batcher.on_response(fetch, answer(fetch)), None);
+        assert_eq!(batcher.tokens(), 5);
+
+        // 2 left in the backend: the background batch is denied, then requests ask for single
+        // tokens once the local ones are gone
+        for _ in 0..4 {
+            assert_eq!(batcher.take(), Next::Admit);
+        }
+        let fetch = batcher.poll_action().unwrap();
+        assert_eq!(batcher.on_response(fetch, answer(fetch)), None);
+        assert_eq!(batcher.take(), Next::Admit);
+        assert_eq!(batcher.poll_action(), None);
+        let mut admitted = 0;
+        for _ in 0..3 {
+            let mut next = batcher.take();
+            while let Next::Fetch(fetch) = next {
+                next = batcher.on_response(fetch, answer(fetch)).unwrap();
+            }
+            admitted += (next == Next::Admit) as u32;
+        }
+        assert_eq!(admitted, 2);
+    }
+}
@@ -0,0 +1,264 @@
+//! Protecting logins against password guessing.
+//!
+//! Guessing attacks come in two shapes: many passwords against one account, and one or a few
+//! passwords against many accounts from the same addresses. [`BruteForceGuard`] counts failed
+//! logins per account and per IP, and locks either out for progressively longer once it fails
+//! too often, using an [`Escalation`] for each.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::escalation::Escalation;
+use crate::gcra::Denied;
+use crate::keyed::{KeyedLimiter, WhenFull};
+use crate::listener::Listener;
+use crate::quota::Quota;
+
+/// Locks out accounts and IPs after repeated failed logins.
+///
+/// Ask [`check`](Self::check) before verifying credentials, and report wrong ones with
+/// [`failed`](Self::failed). Successful logins are not counted. Lockouts are reported to the
+/// listener as [`Event::Escalated`](crate::Event::Escalated) from policies named
+/// `login.account` and `login.ip`, which makes a natural audit trail.
+///
+/// The defaults follow common guidance: an account is locked after more than 5 failures in 15
+/// minutes, for 1 minute, then 5, 30 and 120 minutes. An IP may fail 100 times in 15 minutes,
+/// since many users can share one address, and is locked for 5 minutes, then an hour, then a
+/// day. Lockout levels decay by one per day without new lockouts.
+///
+/// # Example
+/// ```
+/// use std::net::IpAddr;
+/// use ratelimit::BruteForceGuard;
+///
+/// let guard = BruteForceGuard::builder().build();
+/// let ip: IpAddr = "203.0.113.9".parse().unwrap();
+/// for _ in 0..5 {
+///     assert!(guard.check("alice", ip).is_ok());
+///     assert!(guard.failed("alice", ip).is_ok());
+/// }
+/// // the sixth wrong password locks the account
+/// assert!(guard.failed("alice", ip).is_err());
+/// assert!(guard.check("alice", ip).is_err());
+/// assert!(guard.check("bob", ip).is_ok());
+/// ```
+pub struct BruteForceGuard<C = SystemClock> {
+    accounts: Escalation<String, C>,
+    ips: Escalation<IpAddr, C>,
+}
+
+pub struct BruteForceGuardBuilder {
+    account_failures: (u64, Duration),
+    ip_failures: (u64, Duration),
+    account_ladder: Vec<Duration>,
+    ip_ladder: Vec<Duration>,
+    decay: Duration,
+    max_keys: usize,
+    listener: Option<Arc<dyn Listener>>,
+}
+
+impl BruteForceGuard<SystemClock> {
+    pub fn builder() -> BruteForceGuardBuilder {
+        BruteForceGuardBuilder {
+            account_failures: (5, Duration::from_secs(15 * 60)),
+            ip_failures: (100, Duration::from_secs(15 * 60)),
+            account_ladder: [1, 5, 30, 120].map(minutes).to_vec(),
+            ip_ladder: [5, 60, 24 * 60].map(minutes).to_vec(),
+            decay: minutes(24 * 60),
+            max_keys: 1 << 20,
+            listener: None,
+        }
+    }
+}
+
+fn minutes(minutes: u64) -> Duration {
+    Duration::from_secs(minutes * 60)
+}
+
+impl BruteForceGuardBuilder {
+    /// Lock an account after more than `limit` failures within `window`.
+    pub fn account_failures(mut self, limit: u64, window: Duration) -> Self {
+        self.account_failures = (limit, window);
+        self
+    }
+
+    /// Lock an IP after more than `limit` failures within `window`.
+    pub fn ip_failures(mut self, limit: u64, window: Duration) -> Self {
+        self.ip_failures = (limit, window);
+        self
+    }
+
+    /// Lockout durations of an account, see [`Escalation::ladder`].
+    pub fn account_ladder(mut self, ladder: impl IntoIterator<Item = Duration>) -> Self {
+        self.account_ladder = ladder.into_iter().collect();
+        self
+    }
+
+    /// Lockout durations of an IP, see [`Escalation::ladder`].
+    pub fn ip_ladder(mut self, ladder: impl IntoIterator<Item = Duration>) -> Self {
+        self.ip_ladder = ladder.into_iter().collect();
+        self
+    }
+
+    /// Time without lockouts after which a key drops a level, see [`Escalation::decay`].
+    pub fn decay(mut self, decay: Duration) -> Self {
+        self.decay = decay;
+        self
+    }
+
+    /// Track at most `max_keys` accounts and as many IPs, evicting the least recently limited
+    /// ones beyond. About a million by default.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    pub fn build(self) -> BruteForceGuard<SystemClock> {
+        self.build_with_clock(SystemClock)
+    }
+
+    /// Build with both escalations sharing `clock`.
+    ///
+    /// # Panics
+    /// Panics if a failure limit is zero or a ladder is empty.
+    pub fn build_with_clock<C: Clock + Clone>(self, clock: C) -> BruteForceGuard<C> {
+        let accounts = self.limiter(self.account_failures, &clock);
+        let ips = self.limiter(self.ip_failures, &clock);
+        let accounts = Escalation::new(accounts)
+            .ladder(self.account_ladder)
+            .strikes(1)
+            .decay(self.decay)
+            .named("login.account");
+        let ips = Escalation::new(ips)
+            .ladder(self.ip_ladder)
+            .strikes(1)
+            .decay(self.decay)
+            .named("login.ip");
+        match self.listener {
+            Some(listener) => BruteForceGuard {
+                accounts: accounts.listener(listener.clone()),
+                ips: ips.listener(listener),
+            },
+            None => BruteForceGuard { accounts, ips },
+        }
+    }
+}
+
+impl BruteForceGuardBuilder {
+    fn limiter<K, C: Clone>(
+        &self,
+        (limit, window): (u64, Duration),
+        clock: &C,
+    ) -> KeyedLimiter<K, C> {
+        KeyedLimiter::builder(Quota::per_second(1))
+            .window(limit, window)
+            .max_keys(self.max_keys)
+            .when_full(WhenFull::Evict)
+            .clock(clock.clone())
+            .build()
+    }
+}
+
+impl<C> BruteForceGuard<C> {
+    pub fn accounts(&self) -> &Escalation<String, C> {
+        &self.accounts
+    }
+
+    pub fn ips(&self) -> &Escalation<IpAddr, C> {
+        &self.ips
+    }
+}
+
+impl<C> BruteForceGuard<C>
+where
+    C: Clock,
+{
+    /// Whether a login to `account` from `ip` may be attempted. Denied while either is locked
+    /// out, with the longer of the remaining lockouts. Does not count as an attempt.
+    pub fn check(&self, account: &str, ip: IpAddr) -> Result<(), Denied> {
+        longer(self.accounts.banned(account), self.ips.banned(&ip))
+    }
+
+    /// Count a failed login. Denied if the account or the IP is now locked out.
+    pub fn failed(&self, account: &str, ip: IpAddr) -> Result<(), Denied> {
+        let account = self.accounts.check(account).err();
+        longer(account, self.ips.check(&ip).err())
+    }
+
+    /// Forget accounts and IPs in good standing, see [`Escalation::retain_recent`].
+    pub fn retain_recent(&self) {
+        self.accounts.retain_recent();
+        self.ips.retain_recent();
+    }
+}
+
+fn longer(a: Option<Denied>, b: Option<Denied>) -> Result<(), Denied> {
+    match a.into_iter().chain(b).max_by_key(Denied::retry_after) {
+        Some(denied) => Err(denied),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::listener::Event;
+    use crate::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_brute_force_guard() {
+        let clock = MockClock::new(1_000_000);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let guard = BruteForceGuard::builder()
+            .ip_failures(3, minutes(15))
+            .listener(move |event: &Event<'_>| sink.lock().push(format!("{event:?}")))
+            .build_with_clock(&clock);
+        let ip: IpAddr = "198.51.100.1".parse().unwrap();
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+
+        // credential stuffing: one attempt per account from the same IP
+        for account in ["a", "b", "c"] {
+            assert!(guard.check(account, ip).is_ok());
+            assert!(guard.failed(account, ip).is_ok());
+        }
+        assert_eq!(
+            guard.failed("d", ip),
+            Err(Denied::new(Duration::from_secs(300)))
+        );
+        assert!(guard.check("e", ip).is_err());
+        assert!(guard.check("e", other).is_ok());
+
+        // guessing one account from several IPs
+        for i in 0..5 {
+            let ip = IpAddr::from([203, 0, 113, i]);
+            assert!(guard.failed("victim", ip).is_ok());
+            clock.forward(Duration::from_secs(1));
+        }
+        assert_eq!(
+            guard.failed("victim", IpAddr::from([203, 0, 113, 5])),
+            Err(Denied::new(Duration::from_secs(60)))
+        );
+        clock.forward(Duration::from_secs(30));
+        assert_eq!(
+            guard.check("victim", other),
+            Err(Denied::new(Duration::from_secs(30)))
+        );
+        assert_eq!(
+            *events.lock(),
+            [
+                r#"Escalated { policy: "login.ip", key: "198.51.100.1", level: 1, ban: 300s }"#,
+                r#"Escalated { policy: "login.account", key: "victim", level: 1, ban: 60s }"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,255 @@
+//! One rate shared among named consumers.
+//!
+//! [`SharedBudget`] splits a parent quota among children by weight, e.g. 70% for interactive and
+//! 30% for batch traffic. Each child is always guaranteed its share. With borrowing enabled, a
+//! child that has used up its share may also take whatever the parent has left because its
+//! siblings are idle, as in the hierarchical token bucket model.
+//!
+//! Guaranteed requests are charged to the parent even when it is out of room, so the parent
+//! cannot be starved by borrowers for longer than it takes to pay that back. Borrowed requests
+//! only go through while the parent conforms.
+//!
+//! Children added with [`strict_child`](SharedBudgetBuilder::strict_child) never borrow, so their
+//! carve-out is a hard cap even when borrowing is enabled for the others. This is meant for
+//! tenants whose contractual limit must not be exceeded.
+
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{Denied, Gcra, GcraBuilder, Policy};
+use crate::quota::Quota;
+
+pub struct SharedBudget<C = SystemClock> {
+    clock: C,
+    parent: Gcra<()>,
+    children: Vec<ChildState>,
+    borrowing: bool,
+}
+
+struct ChildState {
+    name: String,
+    gcra: Gcra<()>,
+    strict: bool,
+}
+
+pub struct SharedBudgetBuilder<C> {
+    clock: C,
+    quota: Quota,
+    // name, weight, strict
+    children: Vec<(String, u64, bool)>,
+    borrowing: bool,
+}
+
+impl SharedBudget<SystemClock> {
+    pub fn builder(quota: Quota) -> SharedBudgetBuilder<SystemClock> {
+        SharedBudgetBuilder {
+            clock: SystemClock,
+            quota,
+            children: Vec::new(),
+            borrowing: false,
+        }
+    }
+}
+
+impl<C> SharedBudgetBuilder<C> {
+    pub fn clock<NC>(self, clock: NC) -> SharedBudgetBuilder<NC> {
+        SharedBudgetBuilder {
+            clock,
+            quota: self.quota,
+            children: self.children,
+            borrowing: self.borrowing,
+        }
+    }
+
+    /// Add a child getting `weight` parts of the parent quota.
+    pub fn child(mut self, name: impl Into<String>, weight: u64) -> Self {
+        self.children.push((name.into(), weight, false));
+        self
+    }
+
+    /// Add a child getting `weight` parts of the parent quota, and never more than that.
+    pub fn strict_child(mut self, name: impl Into<String>, weight: u64) -> Self {
+        self.children.push((name.into(), weight, true));
+        self
+    }
+
+    /// Let children use the parent's spare room once their own share is used up.
+    pub fn borrowing(mut self, borrowing: bool) -> Self {
+        self.borrowing = borrowing;
+        self
+    }
+
+    /// # Panics
+    /// Panics if there are no children, any weight is zero, or two children share a name.
+    pub fn build(self) -> SharedBudget<C> {
+        assert!(!self.children.is_empty(), "a budget needs children");
+        let total: u64 = self.children.iter().map(|(_, w, _)| w).sum();
+        let parent = GcraBuilder::new().quota(self.quota).build();
+        let (parent_gap, parent_tolerance) = parent.params();
+        // the parent's burst is split the same way as its rate, but every child can at least
+        // get one request through
+        let cells = (parent_tolerance / parent_gap.max(1) + 1) as u128;
+        let mut children: Vec<ChildState> = Vec::new();
+        for (name, weight, strict) in self.children {
+            assert!(weight > 0, "child {name:?} has zero weight");
+            assert!(
+                children.iter().all(|c| c.name != name),
+                "duplicate child {name:?}"
+            );
+            let gap = parent_gap as u128 * total as u128 / weight as u128;
+            let child_cells = std::cmp::max(1, cells * weight as u128 / total as u128);
+            let child = GcraBuilder::new()
+                .gap(Duration::from_nanos(gap as u64))
+                .tolerance(Duration::from_nanos(((child_cells - 1) * gap) as u64))
+                .build();
+            children.push(ChildState {
+                name,
+                gcra: child,
+                strict,
+            });
+        }
+        SharedBudget {
+            clock: self.clock,
+            parent,
+            children,
+            borrowing: self.borrowing,
+        }
+    }
+}
+
+impl<C> SharedBudget<C>
+where
+    C: Clock,
+{
+    /// Decide on one request of the child `name`.
+    ///
+    /// # Panics
+    /// Panics if there is no such child.
+    pub fn check(&self, name: &str) -> Result<(), Denied> {
+        let child = self
+            .find(name)
+            .unwrap_or_else(|| panic!("no child {name:?}"));
+        self.check_child(child)
+    }
+
+    /// A [`Policy`] for one child, or `None` if there is no child called `name`.
+    pub fn child(&self, name: &str) -> Option<Child<'_, C>> {
+        self.find(name).map(|child| Child {
+            budget: self,
+            child,
+        })
+    }
+
+    pub fn children(&self) -> impl Iterator<Item = &str> {
+        self.children.iter().map(|c| c.name.as_str())
+    }
+
+    /// Whether the child `name` exists and was added with
+    /// [`strict_child`](SharedBudgetBuilder::strict_child).
+    pub fn is_strict(&self, name: &str) -> bool {
+        self.find(name).is_some_and(|c| c.strict)
+    }
+
+    fn find(&self, name: &str) -> Option<&ChildState> {
+        self.children.iter().find(|c| c.name == name)
+    }
+
+    fn check_child(&self, child: &ChildState) -> Result<(), Denied> {
+        let now = self.clock.now_nanos();
+        match child.gcra.check_at(now) {
+            Ok(()) => {
+                self.parent.charge_at(now);
+                Ok(())
+            }
+            Err(own) if self.borrowing && !child.strict => match self.parent.check_at(now) {
+                Ok(()) => Ok(()),
+                Err(parent) => Err(std::cmp::min_by_key(own, parent, Denied::retry_after)),
+            },
+            Err(own) => Err(own),
+        }
+    }
+}
+
+/// One child of a [`SharedBudget`].
+pub struct Child<'a, C> {
+    budget: &'a SharedBudget<C>,
+    child: &'a ChildState,
+}
+
+impl<C> Policy for Child<'_, C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.budget.check_child(self.child)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    fn budget(clock: &MockClock, borrowing: bool) -> SharedBudget<&MockClock> {
+        SharedBudget::builder(Quota::per_second(10))
+            .child("interactive", 7)
+            .child("batch", 3)
+            .borrowing(borrowing)
+            .clock(clock)
+            .build()
+    }
+
+    #[test]
+    fn test_shared_budget_split() {
+        let clock = MockClock::new(1_000_000);
+        let budget = budget(&clock, false);
+        let interactive = budget.child("interactive").unwrap();
+        let batch = budget.child("batch").unwrap();
+        let mut admitted = (0, 0);
+        for _ in 0..=10_000 {
+            admitted.0 += interactive.pass() as u32;
+            admitted.1 += batch.pass() as u32;
+            clock.forward(Duration::from_millis(1));
+        }
+        // 10s at 7 and 3 qps, plus the initial burst
+        assert_eq!(admitted, (77, 33));
+        assert!(budget.child("other").is_none());
+    }
+
+    #[test]
+    fn test_shared_budget_borrowing() {
+        let clock = MockClock::new(1_000_000);
+        let budget = budget(&clock, true);
+        let batch = budget.child("batch").unwrap();
+        // interactive is idle, batch can take the whole parent
+        let mut admitted = 0;
+        for _ in 0..=10_000 {
+            admitted += batch.pass() as u32;
+            clock.forward(Duration::from_millis(1));
+        }
+        assert_eq!(admitted, 110);
+        assert!(budget.check("interactive").is_ok());
+    }
+
+    #[test]
+    fn test_shared_budget_strict_child() {
+        let clock = MockClock::new(1_000_000);
+        let budget = SharedBudget::builder(Quota::per_second(10))
+            .child("interactive", 7)
+            .strict_child("tenant", 3)
+            .borrowing(true)
+            .clock(&clock)
+            .build();
+        assert!(budget.is_strict("tenant"));
+        assert!(!budget.is_strict("interactive"));
+        let tenant = budget.child("tenant").unwrap();
+        let mut admitted = 0;
+        for _ in 0..=10_000 {
+            admitted += tenant.pass() as u32;
+            clock.forward(Duration::from_millis(1));
+        }
+        // interactive is idle, but the tenant still only gets its share
+        assert_eq!(admitted, 33);
+    }
+}
@@ -0,0 +1,202 @@
+//! Detecting bursts without enforcing anything.
+//!
+//! Before turning on a limit, teams usually want to know how often and how badly traffic would
+//! go over it. [`BurstDetector`] runs the same GCRA as an enforcing policy over all traffic, but
+//! never denies: it reports when the traffic went over the configured profile, for how long, and
+//! by how many requests at worst.
+
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock, Timestamp};
+use crate::gcra::{reserve_n, to_millis, to_nanos, GcraBuilder};
+use crate::quota::Quota;
+use crate::sync::Mutex;
+
+/// A stretch of time during which traffic was over a [`BurstDetector`]'s profile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Burst {
+    /// When the first request over the profile arrived, in clock time.
+    pub started_at: Timestamp,
+    /// How long traffic stayed over the profile, so far for a burst still going on.
+    pub duration: Duration,
+    /// The most requests over the profile at any point, i.e. how many a policy with this
+    /// profile would have had to deny or delay at once.
+    pub peak_excess: u64,
+    /// Requests recorded during the burst.
+    pub requests: u64,
+}
+
+/// Flags traffic that goes over a profile, using the GCRA math of an enforcing policy without
+/// ever denying.
+///
+/// Every request is charged whether it conforms or not, so a burst lasts until the traffic
+/// recorded during it has drained at the profile's rate, as the backlog of a policy that delays
+/// instead of denying would.
+///
+/// # Example
+/// ```
+/// use ratelimit::{BurstDetector, Quota};
+///
+/// let detector = BurstDetector::new(Quota::per_second(10));
+/// for _ in 0..15 {
+///     detector.record();
+/// }
+/// assert_eq!(detector.current().unwrap().peak_excess, 5);
+/// ```
+pub struct BurstDetector<C = SystemClock> {
+    clock: C,
+    // in ns, as in `Gcra`
+    gap: u64,
+    tolerance: u64,
+    state: Mutex<BurstState>,
+}
+
+#[derive(Default)]
+struct BurstState {
+    tat: u64,
+    current: Option<Burst>,
+    finished: Vec<Burst>,
+}
+
+impl BurstDetector<SystemClock> {
+    pub fn new(profile: Quota) -> Self {
+        let (gap, tolerance) = GcraBuilder::new().quota(profile).build().params();
+        BurstDetector {
+            clock: SystemClock,
+            gap,
+            tolerance,
+            state: Mutex::new(BurstState::default()),
+        }
+    }
+}
+
+impl<C> BurstDetector<C> {
+    pub fn clock<NC>(self, clock: NC) -> BurstDetector<NC> {
+        BurstDetector {
+            clock,
+            gap: self.gap,
+            tolerance: self.tolerance,
+            state: self.state,
+        }
+    }
+}
+
+impl<C> BurstDetector<C>
+where
+    C: Clock,
+{
+    pub fn record(&self) -> Option<Burst> {
+        self.record_n(1)
+    }
+
+    /// Record a request worth `n` cells, returning the burst it is part of, if any.
+    pub fn record_n(&self, n: u64) -> Option<Burst> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.settle(&mut state, now);
+        reserve_n(&mut state.tat, now, self.gap, self.tolerance, n);
+        let over = (state.tat - now).saturating_sub(self.limit());
+        if over == 0 {
+            return None;
+        }
+        let excess = over.div_ceil(self.gap.max(1));
+        let burst = state.current.get_or_insert(Burst {
+            started_at: to_millis(now),
+            duration: Duration::ZERO,
+            peak_excess: 0,
+            requests: 0,
+        });
+        burst.peak_excess = burst.peak_excess.max(excess);
+        burst.requests += n;
+        Some(Burst {
+            duration: Duration::from_nanos(now.saturating_sub(to_nanos(burst.started_at))),
+            ..*burst
+        })
+    }
+
+    /// The burst going on now, if any.
+    pub fn current(&self) -> Option<Burst> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.settle(&mut state, now);
+        let burst = state.current?;
+        Some(Burst {
+            duration: Duration::from_nanos(now.saturating_sub(to_nanos(burst.started_at))),
+            ..burst
+        })
+    }
+
+    /// Take the bursts that ended since the last call, oldest first, e.g. to raise alerts.
+    pub fn take_finished(&self) -> Vec<Burst> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.settle(&mut state, now);
+        std::mem::take(&mut state.finished)
+    }
+
+    /// How far ahead of now the TAT may be after a conforming request.
+    fn limit(&self) -> u64 {
+        self.tolerance.saturating_add(self.gap)
+    }
+
+    /// End the current burst if the traffic drained back within the profile by `now`.
+    fn settle(&self, state: &mut BurstState, now: u64) {
+        let ended_at = state.tat.saturating_sub(self.limit());
+        if ended_at > now {
+            return;
+        }
+        if let Some(mut burst) = state.current.take() {
+            burst.duration =
+                Duration::from_nanos(ended_at.saturating_sub(to_nanos(burst.started_at)));
+            state.finished.push(burst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_burst_detector() {
+        let clock = MockClock::new(1_000_000);
+        let detector = BurstDetector::new(Quota::per_second(10)).clock(&clock);
+        // the profile's own burst is not one
+        for _ in 0..10 {
+            assert_eq!(detector.record(), None);
+        }
+        clock.forward(Duration::from_secs(1));
+        for _ in 0..10 {
+            assert_eq!(detector.record(), None);
+        }
+        let burst = detector.record_n(5).unwrap();
+        assert_eq!((burst.started_at, burst.peak_excess), (1_001_000, 5));
+        clock.forward(Duration::from_millis(200));
+        assert_eq!(
+            detector.current(),
+            Some(Burst {
+                started_at: 1_001_000,
+                duration: Duration::from_millis(200),
+                peak_excess: 5,
+                requests: 5,
+            })
+        );
+        assert!(detector.take_finished().is_empty());
+
+        // over once the 5 extra requests drained at 10/s
+        clock.forward(Duration::from_millis(400));
+        assert_eq!(detector.current(), None);
+        assert_eq!(
+            detector.take_finished(),
+            [Burst {
+                started_at: 1_001_000,
+                duration: Duration::from_millis(500),
+                peak_excess: 5,
+                requests: 5,
+            }]
+        );
+        assert!(detector.take_finished().is_empty());
+    }
+}
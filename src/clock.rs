@@ -2,12 +2,98 @@
 //!
 //! This module is most craeted for testing. You can easily test rate limit algorithm with `MockClock`.
 
-use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::gcra::duration_nanos;
+use crate::sync::{AtomicU64, Ordering};
+
+/// A clock reading in milliseconds, as kept by the policies that count whole milliseconds.
 pub type Timestamp = u64;
 
+pub(crate) const NANOS_PER_MS: u64 = 1_000_000;
+
+/// A source of time. Readings are nanoseconds; the clocks of this crate count them from the
+/// unix epoch.
+///
+/// Clocks that only keep milliseconds can be read through [`MillisClock`].
 pub trait Clock {
-    fn now(&self) -> Timestamp;
+    /// The current reading, in ns.
+    fn now_nanos(&self) -> u64;
+
+    /// The current reading, in ms.
+    #[deprecated(note = "read `now_nanos`, which keeps sub-millisecond time")]
+    fn now(&self) -> Timestamp {
+        self.now_nanos() / NANOS_PER_MS
+    }
+
+    /// A reading of this clock, in ns, as wall-clock time, for times shown to users such as
+    /// [`QuotaWindow::resets_at`](crate::QuotaWindow::resets_at). By default readings are taken
+    /// to be nanoseconds since the unix epoch, as those of [`SystemClock`].
+    fn to_system_time(&self, reading: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(reading)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now_nanos(&self) -> u64 {
+        (**self).now_nanos()
+    }
+
+    fn to_system_time(&self, reading: u64) -> SystemTime {
+        (**self).to_system_time(reading)
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now_nanos(&self) -> u64 {
+        (**self).now_nanos()
+    }
+
+    fn to_system_time(&self, reading: u64) -> SystemTime {
+        (**self).to_system_time(reading)
+    }
+}
+
+/// A reading of `clock` in ms, for policies that count whole milliseconds.
+pub(crate) fn now_millis<C: Clock + ?Sized>(clock: &C) -> Timestamp {
+    clock.now_nanos() / NANOS_PER_MS
+}
+
+/// Nanoseconds since the unix epoch, 0 for times before it.
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| {
+            u64::try_from(since.as_nanos()).unwrap_or(u64::MAX)
+        })
+}
+
+/// A [`Clock`] read from a function returning milliseconds, e.g. a clock written against the
+/// millisecond API of earlier versions. Readings are those milliseconds in ns.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Clock, MillisClock};
+///
+/// let clock = MillisClock(|| 1_500);
+/// assert_eq!(clock.now_nanos(), 1_500_000_000);
+/// ```
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MillisClock<F>(pub F);
+
+impl<F> Clock for MillisClock<F>
+where
+    F: Fn() -> Timestamp,
+{
+    fn now_nanos(&self) -> u64 {
+        (self.0)().saturating_mul(NANOS_PER_MS)
+    }
+}
+
+/// Milliseconds since the unix epoch, 0 for times before it.
+pub(crate) fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
 }
 
 /// `SystemClock` use `std::time::SystemTime` to get current timestamp. Use this clock in [`LeakyBucket`](crate::gcra::LeakyBucket) means
@@ -17,51 +103,141 @@ pub trait Clock {
 /// ```no-run
 /// let policy = LeakyBucket::with_clock(SystemClock);
 /// ```
+#[derive(Debug, Clone, Copy, Default)]
 pub struct SystemClock;
 
 impl Clock for SystemClock {
-    fn now(&self) -> Timestamp {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis() as u64
+    fn now_nanos(&self) -> u64 {
+        unix_nanos(SystemTime::now())
+    }
+}
+
+/// A clock read from [`Instant`], so it never goes back when the wall clock is adjusted.
+///
+/// Readings start at the unix time of its creation. They are converted to wall-clock
+/// time by pairing the current reading with the current [`SystemTime`], so converted times
+/// follow adjustments of the wall clock made since.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    start: Instant,
+    start_nanos: u64,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock {
+            start: Instant::now(),
+            start_nanos: SystemClock.now_nanos(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now_nanos(&self) -> u64 {
+        let elapsed = u64::try_from(self.start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+        self.start_nanos.saturating_add(elapsed)
+    }
+
+    fn to_system_time(&self, reading: u64) -> SystemTime {
+        let (now, wall) = (self.now_nanos(), SystemTime::now());
+        if reading >= now {
+            wall + Duration::from_nanos(reading - now)
+        } else {
+            wall - Duration::from_nanos(now - reading)
+        }
     }
 }
 
 /// `MockClock` . Use this clock in [`LeakyBucket`](crate::gcra::LeakyBucket) means
 /// time passing is measure in a user controled time.
 ///
+/// Time can be moved through a shared reference, so one clock can drive several policies
+/// built with `.clock(&clock)`. It keeps nanoseconds, so advances shorter than a millisecond
+/// add up.
+///
 /// # Example
 /// ```no-run
 /// let policy = LeakyBucket::with_clock(MockClock::new_now());
 /// ```
-pub struct MockClock(Timestamp);
+pub struct MockClock(AtomicU64); // in ns
 
 impl MockClock {
     pub fn new_now() -> Self {
-        Self::new(
-            SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
-        )
+        Self::from_nanos(unix_nanos(SystemTime::now()))
     }
 
+    /// A clock reading `now` ms.
     pub fn new(now: Timestamp) -> Self {
-        MockClock(now)
+        Self::from_nanos(now.saturating_mul(NANOS_PER_MS))
     }
 
-    pub fn forward(&mut self, dur: Duration) {
-        self.0 += dur.as_millis() as u64;
+    /// A clock reading `now` ns.
+    pub fn from_nanos(now: u64) -> Self {
+        MockClock(AtomicU64::new(now))
     }
 
-    pub fn backward(&mut self, dur: Duration) {
-        self.0 -= dur.as_millis() as u64;
+    pub fn forward(&self, dur: Duration) {
+        self.0.fetch_add(duration_nanos(dur), Ordering::Relaxed);
+    }
+
+    pub fn backward(&self, dur: Duration) {
+        self.0.fetch_sub(duration_nanos(dur), Ordering::Relaxed);
     }
 }
 
 impl Clock for MockClock {
-    fn now(&self) -> Timestamp {
-        self.0
+    fn now_nanos(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_monotonic_clock_to_system_time() {
+        let clock = MonotonicClock::new();
+        let in_a_minute = clock.now_nanos() + 60_000 * NANOS_PER_MS;
+        let expected = unix_millis(SystemTime::now()) + 60_000;
+        let converted = unix_millis(clock.to_system_time(in_a_minute));
+        assert!(
+            converted.abs_diff(expected) < 1000,
+            "{converted} vs {expected}"
+        );
+        assert_eq!(
+            MockClock::new(1_000).to_system_time(1_500_000_001),
+            SystemTime::UNIX_EPOCH + Duration::from_nanos(1_500_000_001)
+        );
+    }
+
+    #[test]
+    fn test_mock_clock_keeps_nanos() {
+        let clock = MockClock::new(1_000);
+        for _ in 0..4 {
+            clock.forward(Duration::from_micros(250));
+        }
+        assert_eq!(clock.now_nanos(), 1_001 * NANOS_PER_MS);
+        clock.forward(Duration::from_micros(500));
+        clock.backward(Duration::from_nanos(1));
+        assert_eq!(clock.now_nanos(), 1_001_500_000 - 1);
+        assert_eq!(now_millis(&clock), 1_001);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_millis_clock() {
+        // a clock kept in ms reads through the adapter, and the ms shim still works
+        let clock = MillisClock(|| 1_500);
+        assert_eq!(clock.now_nanos(), 1_500 * NANOS_PER_MS);
+        assert_eq!(clock.now(), 1_500);
+        assert_eq!(MillisClock(|| u64::MAX).now_nanos(), u64::MAX);
+        assert_eq!(MockClock::from_nanos(2_999_999).now(), 2);
     }
 }
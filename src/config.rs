@@ -0,0 +1,603 @@
+//! Configuring limiters from data rather than code.
+//!
+//! A [`LimiterConfig`] names an algorithm and its parameters and builds the matching
+//! [`AnyLimiter`], so operators can pick the algorithm per endpoint. With the `serde` feature
+//! it can be deserialized from an internally tagged value, e.g. in JSON:
+//!
+//! ```json
+//! {"algorithm": "gcra", "rate": 100, "burst": 20}
+//! {"algorithm": "token_bucket", "rate": 100, "refill_interval_ms": 1000}
+//! {"algorithm": "fixed_window", "limit": 6000, "window_ms": 60000}
+//! ```
+//!
+//! An [`EndpointConfig`] assigns limiters to endpoints: a `default`, and `overrides` keyed by a
+//! selector naming a route, a method, a tenant or a combination of them:
+//!
+//! ```json
+//! {
+//!   "default": {"algorithm": "gcra", "rate": 100},
+//!   "overrides": {
+//!     "/upload": {"algorithm": "gcra", "rate": 10},
+//!     "POST /upload": {"algorithm": "gcra", "rate": 2},
+//!     "tenant=acme": {"algorithm": "token_bucket", "rate": 1000}
+//!   }
+//! }
+//! ```
+//!
+//! [`EndpointConfig::build`] makes the limiters, and with the `tower` feature,
+//! `RateLimitLayer::endpoints` a middleware limiting each endpoint by them.
+//!
+//! A single limiter can also be configured from environment variables, see
+//! [`LimiterConfig::from_env`], and every limiter set there at once with
+//! [`Registry::from_env`](crate::Registry::from_env).
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use crate::any::AnyLimiter;
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{Denied, GcraBuilder, Policy};
+use crate::quota::Quota;
+use crate::token_bucket::{Refill, TokenBucket};
+
+/// Algorithm and parameters of a limiter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(tag = "algorithm", rename_all = "snake_case", deny_unknown_fields)
+)]
+pub enum LimiterConfig {
+    /// [`Gcra`](crate::Gcra), also accepted as `leaky_bucket`.
+    #[cfg_attr(feature = "serde", serde(alias = "leaky_bucket"))]
+    Gcra {
+        rate: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        burst: u64,
+    },
+    /// [`TokenBucket`], refilled continuously or, given an interval, in steps.
+    TokenBucket {
+        rate: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        burst: u64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        refill_interval_ms: Option<u64>,
+    },
+    /// `limit` requests per window, with windows aligned to the epoch.
+    FixedWindow { limit: u64, window_ms: u64 },
+}
+
+/// A configuration that cannot be built, e.g. with a rate of zero.
+///
+/// The error names the offending entry, e.g. ``overrides["POST /upload"]: `rate` must be
+/// positive``.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    path: String,
+    message: String,
+}
+
+impl ConfigError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        ConfigError {
+            path: String::new(),
+            message: message.into(),
+        }
+    }
+
+    /// Prefix the path of the offending entry with the field `segment` it sits in.
+    pub(crate) fn at(mut self, segment: &str) -> Self {
+        if !self.path.is_empty() && !self.path.starts_with('[') {
+            self.path.insert(0, '.');
+        }
+        self.path.insert_str(0, segment);
+        self
+    }
+
+    /// Where in the configuration the error is, empty for the top level.
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "invalid limiter config: {}", self.message)
+        } else {
+            write!(f, "invalid limiter config: {}: {}", self.path, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn positive(value: u64, field: &str) -> Result<u64, ConfigError> {
+    if value == 0 {
+        return Err(ConfigError::new(format!("`{field}` must be positive")));
+    }
+    Ok(value)
+}
+
+impl LimiterConfig {
+    /// Sustained rate in requests per second.
+    pub(crate) fn rate(&self) -> f64 {
+        match *self {
+            LimiterConfig::Gcra { rate, .. } | LimiterConfig::TokenBucket { rate, .. } => {
+                rate as f64
+            }
+            LimiterConfig::FixedWindow { limit, window_ms } => {
+                limit as f64 * 1000.0 / window_ms as f64
+            }
+        }
+    }
+
+    /// Requests that can go through at once.
+    pub(crate) fn capacity(&self) -> u64 {
+        match *self {
+            LimiterConfig::Gcra { rate, burst }
+            | LimiterConfig::TokenBucket { rate, burst, .. } => rate.saturating_add(burst),
+            LimiterConfig::FixedWindow { limit, .. } => limit,
+        }
+    }
+
+    pub fn build(&self) -> Result<AnyLimiter, ConfigError> {
+        self.build_with_clock(SystemClock)
+    }
+
+    pub fn build_with_clock<C: Clock>(&self, clock: C) -> Result<AnyLimiter<C>, ConfigError> {
+        let limiter = match *self {
+            LimiterConfig::Gcra { rate, burst } => {
+                let quota = Quota::per_second(positive(rate, "rate")?).burst(burst);
+                GcraBuilder::new().quota(quota).clock(clock).build().into()
+            }
+            LimiterConfig::TokenBucket {
+                rate,
+                burst,
+                refill_interval_ms,
+            } => {
+                let quota = Quota::per_second(positive(rate, "rate")?).burst(burst);
+                let refill = match refill_interval_ms {
+                    Some(ms) => {
+                        Refill::Interval(Duration::from_millis(positive(ms, "refill_interval_ms")?))
+                    }
+                    None => Refill::Greedy,
+                };
+                TokenBucket::builder(quota)
+                    .clock(clock)
+                    .refill(refill)
+                    .build()
+                    .into()
+            }
+            LimiterConfig::FixedWindow { limit, window_ms } => {
+                let quota = Quota::per_second(positive(limit, "limit")?);
+                let window = Duration::from_millis(positive(window_ms, "window_ms")?);
+                TokenBucket::builder(quota)
+                    .clock(clock)
+                    .refill(Refill::AlignedTick(window))
+                    .build()
+                    .into()
+            }
+        };
+        Ok(limiter)
+    }
+}
+
+impl LimiterConfig {
+    /// Read the limiter called `name` from the environment:
+    ///
+    /// - `RATELIMIT_<NAME>_RATE`, requests per second, required,
+    /// - `RATELIMIT_<NAME>_BURST`, extra requests at once, 0 by default,
+    /// - `RATELIMIT_<NAME>_MODE`, the algorithm as in the serialized form, `gcra` by default.
+    ///   `fixed_window` allows `RATE` requests per one second window, without a burst.
+    ///
+    /// `<NAME>` is `name` in upper case, with anything but letters and digits replaced by `_`.
+    /// A variable that is set but invalid is an error naming it, so a typo in a deployment does
+    /// not silently fall back to a default.
+    ///
+    /// # Example
+    /// ```
+    /// use ratelimit::LimiterConfig;
+    ///
+    /// std::env::set_var("RATELIMIT_SEARCH_API_RATE", "50");
+    /// let config = LimiterConfig::from_env("search-api").unwrap();
+    /// assert_eq!(config, LimiterConfig::Gcra { rate: 50, burst: 0 });
+    /// ```
+    pub fn from_env(name: &str) -> Result<LimiterConfig, ConfigError> {
+        Self::from_lookup(name, |var| match std::env::var(var) {
+            Ok(value) => Ok(Some(value)),
+            Err(std::env::VarError::NotPresent) => Ok(None),
+            Err(std::env::VarError::NotUnicode(_)) => {
+                Err(ConfigError::new("not valid unicode").at(var))
+            }
+        })
+    }
+
+    /// [`from_env`](Self::from_env) with the variables looked up by `get`.
+    pub(crate) fn from_lookup(
+        name: &str,
+        get: impl Fn(&str) -> Result<Option<String>, ConfigError>,
+    ) -> Result<LimiterConfig, ConfigError> {
+        let prefix = env_prefix(name);
+        let number = |field: &str| -> Result<Option<u64>, ConfigError> {
+            let var = format!("RATELIMIT_{prefix}_{field}");
+            let Some(value) = get(&var)? else {
+                return Ok(None);
+            };
+            match value.trim().parse() {
+                Ok(number) => Ok(Some(number)),
+                Err(_) => {
+                    Err(ConfigError::new(format!("must be a whole number, got {value:?}")).at(&var))
+                }
+            }
+        };
+        let rate_var = format!("RATELIMIT_{prefix}_RATE");
+        let rate = number("RATE")?.ok_or_else(|| ConfigError::new("is not set").at(&rate_var))?;
+        let burst = number("BURST")?;
+        let mode_var = format!("RATELIMIT_{prefix}_MODE");
+        let config = match get(&mode_var)?.as_deref().map(str::trim) {
+            None | Some("gcra" | "leaky_bucket") => LimiterConfig::Gcra {
+                rate,
+                burst: burst.unwrap_or(0),
+            },
+            Some("token_bucket") => LimiterConfig::TokenBucket {
+                rate,
+                burst: burst.unwrap_or(0),
+                refill_interval_ms: None,
+            },
+            Some("fixed_window") => {
+                if burst.is_some() {
+                    return Err(ConfigError::new("a fixed window has no burst")
+                        .at(&format!("RATELIMIT_{prefix}_BURST")));
+                }
+                LimiterConfig::FixedWindow {
+                    limit: rate,
+                    window_ms: 1000,
+                }
+            }
+            Some(mode) => {
+                let expected = "gcra, leaky_bucket, token_bucket or fixed_window";
+                let message = format!("unknown mode {mode:?}, expected {expected}");
+                return Err(ConfigError::new(message).at(&mode_var));
+            }
+        };
+        // report a zero rate now, naming the variable
+        positive(rate, "rate").map_err(|err| err.at(&rate_var))?;
+        Ok(config)
+    }
+
+    /// The quota of a GCRA limiter, for the callers that only build those. `name` is the one
+    /// the config was read with, to point at its `_MODE` variable otherwise.
+    pub(crate) fn into_gcra_quota(self, name: &str) -> Result<Quota, ConfigError> {
+        match self {
+            LimiterConfig::Gcra { rate, burst } => Ok(Quota::per_second(rate).burst(burst)),
+            _ => Err(ConfigError::new("must be gcra or leaky_bucket")
+                .at(&format!("RATELIMIT_{}_MODE", env_prefix(name)))),
+        }
+    }
+}
+
+/// `name` in upper case, with anything but letters and digits replaced by `_`.
+fn env_prefix(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Limiters per endpoint: a default, and overrides for requests matching a selector.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Deserialize),
+    serde(deny_unknown_fields)
+)]
+pub struct EndpointConfig {
+    pub default: LimiterConfig,
+    /// Limiters by selector: whitespace separated parts, each a method such as `POST`, a route
+    /// starting with `/`, or `tenant=<name>`. Each part may appear once.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub overrides: BTreeMap<String, LimiterConfig>,
+}
+
+/// The request an [`EndpointLimits`] decides on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Endpoint<'a> {
+    pub method: &'a str,
+    pub route: &'a str,
+    pub tenant: Option<&'a str>,
+}
+
+/// An owned [`Endpoint`], e.g. the key a `RateLimitLayer` built with `endpoints`, with the
+/// `tower` feature, extracts from requests.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct EndpointKey {
+    pub method: String,
+    pub route: String,
+    pub tenant: Option<String>,
+}
+
+impl EndpointKey {
+    pub fn as_endpoint(&self) -> Endpoint<'_> {
+        Endpoint {
+            method: &self.method,
+            route: &self.route,
+            tenant: self.tenant.as_deref(),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Selector {
+    method: Option<String>,
+    route: Option<String>,
+    tenant: Option<String>,
+}
+
+impl Selector {
+    pub(crate) fn parse(selector: &str) -> Result<Self, ConfigError> {
+        let mut parsed = Selector::default();
+        for part in selector.split_whitespace() {
+            let (slot, value) = if let Some(tenant) = part.strip_prefix("tenant=") {
+                (&mut parsed.tenant, tenant)
+            } else if part.starts_with('/') {
+                (&mut parsed.route, part)
+            } else if part.bytes().all(|b| b.is_ascii_uppercase()) {
+                (&mut parsed.method, part)
+            } else {
+                return Err(ConfigError::new(format!(
+                    "`{part}` is neither a method, a route nor `tenant=<name>`"
+                )));
+            };
+            if slot.replace(value.to_owned()).is_some() {
+                return Err(ConfigError::new(format!("`{part}` repeats a part")));
+            }
+        }
+        if parsed == Selector::default() {
+            return Err(ConfigError::new("empty selector"));
+        }
+        Ok(parsed)
+    }
+
+    fn matches(&self, endpoint: &Endpoint<'_>) -> bool {
+        self.method.as_deref().is_none_or(|m| m == endpoint.method)
+            && self.route.as_deref().is_none_or(|r| r == endpoint.route)
+            && self
+                .tenant
+                .as_deref()
+                .is_none_or(|t| Some(t) == endpoint.tenant)
+    }
+
+    /// Whether every request this selector matches is also matched by `other`.
+    pub(crate) fn within(&self, other: &Selector) -> bool {
+        let part =
+            |mine: &Option<String>, theirs: &Option<String>| theirs.is_none() || mine == theirs;
+        part(&self.method, &other.method)
+            && part(&self.route, &other.route)
+            && part(&self.tenant, &other.tenant)
+    }
+
+    fn specificity(&self) -> usize {
+        [
+            self.method.is_some(),
+            self.route.is_some(),
+            self.tenant.is_some(),
+        ]
+        .into_iter()
+        .filter(|&part| part)
+        .count()
+    }
+}
+
+impl EndpointConfig {
+    pub fn build(&self) -> Result<EndpointLimits, ConfigError> {
+        self.build_with_clock(SystemClock)
+    }
+
+    /// Check every entry and build its limiter. All limiters share `clock`.
+    pub fn build_with_clock<C: Clock + Clone>(
+        &self,
+        clock: C,
+    ) -> Result<EndpointLimits<C>, ConfigError> {
+        let default = self
+            .default
+            .build_with_clock(clock.clone())
+            .map_err(|e| e.at("default"))?;
+        let mut overrides = self
+            .overrides
+            .iter()
+            .map(|(selector, config)| {
+                let entry = format!("[{selector:?}]");
+                let selector = Selector::parse(selector).map_err(|e| e.at(&entry))?;
+                let limiter = config
+                    .build_with_clock(clock.clone())
+                    .map_err(|e| e.at(&entry))?;
+                Ok((selector, limiter))
+            })
+            .collect::<Result<Vec<_>, ConfigError>>()
+            .map_err(|e| e.at("overrides"))?;
+        // most specific first; the sort is stable, so ties keep the order of the map
+        overrides.sort_by_key(|(selector, _)| std::cmp::Reverse(selector.specificity()));
+        Ok(EndpointLimits { default, overrides })
+    }
+}
+
+/// The limiters of an [`EndpointConfig`]. A request is limited by the most specific override
+/// matching it, or by the default if none does.
+pub struct EndpointLimits<C = SystemClock> {
+    default: AnyLimiter<C>,
+    overrides: Vec<(Selector, AnyLimiter<C>)>,
+}
+
+impl<C> EndpointLimits<C> {
+    pub fn limiter(&self, endpoint: &Endpoint<'_>) -> &AnyLimiter<C> {
+        self.overrides
+            .iter()
+            .find(|(selector, _)| selector.matches(endpoint))
+            .map_or(&self.default, |(_, limiter)| limiter)
+    }
+}
+
+impl<C: Clock> EndpointLimits<C> {
+    pub fn check(&self, endpoint: &Endpoint<'_>) -> Result<(), Denied> {
+        self.limiter(endpoint).check()
+    }
+
+    /// Decide on a request for `endpoint` worth `n` cells at once, all or nothing.
+    pub fn check_n(&self, endpoint: &Endpoint<'_>, n: u64) -> Result<(), Denied> {
+        self.limiter(endpoint).check_n(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::Policy;
+
+    use super::*;
+
+    #[test]
+    fn test_config_build() {
+        let clock = MockClock::new(1_000_000);
+        let window = LimiterConfig::FixedWindow {
+            limit: 2,
+            window_ms: 1000,
+        };
+        let limiter = window.build_with_clock(&clock).unwrap();
+        assert!(limiter.pass());
+        assert!(limiter.pass());
+        assert!(!limiter.pass());
+
+        let zero = LimiterConfig::Gcra { rate: 0, burst: 0 };
+        assert_eq!(
+            zero.build().err().unwrap().to_string(),
+            "invalid limiter config: `rate` must be positive"
+        );
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        let env = |vars: &'static [(&'static str, &'static str)]| {
+            move |var: &str| {
+                Ok(vars
+                    .iter()
+                    .find(|(name, _)| *name == var)
+                    .map(|(_, value)| value.to_string()))
+            }
+        };
+        let config = LimiterConfig::from_lookup(
+            "api.v2",
+            env(&[
+                ("RATELIMIT_API_V2_RATE", "100"),
+                ("RATELIMIT_API_V2_BURST", " 20 "),
+                ("RATELIMIT_API_V2_MODE", "token_bucket"),
+            ]),
+        );
+        assert_eq!(
+            config,
+            Ok(LimiterConfig::TokenBucket {
+                rate: 100,
+                burst: 20,
+                refill_interval_ms: None,
+            })
+        );
+
+        let err = LimiterConfig::from_lookup("api", env(&[("RATELIMIT_API_RATE", "lots")]));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            r#"invalid limiter config: RATELIMIT_API_RATE: must be a whole number, got "lots""#
+        );
+        let err = LimiterConfig::from_lookup("api", env(&[]));
+        assert_eq!(err.unwrap_err().path(), "RATELIMIT_API_RATE");
+        let err = LimiterConfig::from_lookup(
+            "api",
+            env(&[
+                ("RATELIMIT_API_RATE", "1"),
+                ("RATELIMIT_API_MODE", "sliding"),
+            ]),
+        );
+        assert_eq!(err.unwrap_err().path(), "RATELIMIT_API_MODE");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_config_deserialize() {
+        let config: LimiterConfig =
+            serde_json::from_str(r#"{"algorithm": "leaky_bucket", "rate": 10}"#).unwrap();
+        assert_eq!(config, LimiterConfig::Gcra { rate: 10, burst: 0 });
+        let config: LimiterConfig = serde_json::from_str(
+            r#"{"algorithm": "token_bucket", "rate": 10, "refill_interval_ms": 500}"#,
+        )
+        .unwrap();
+        assert!(matches!(
+            config.build().unwrap(),
+            AnyLimiter::TokenBucket(_)
+        ));
+        assert!(serde_json::from_str::<LimiterConfig>(r#"{"algorithm": "gcra"}"#).is_err());
+
+        let config: EndpointConfig = serde_json::from_str(
+            r#"{
+                "default": {"algorithm": "gcra", "rate": 100},
+                "overrides": {"POST /upload": {"algorithm": "gcra", "rate": 2}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.overrides["POST /upload"],
+            LimiterConfig::Gcra { rate: 2, burst: 0 }
+        );
+    }
+
+    #[test]
+    fn test_endpoint_config() {
+        let gcra = |rate| LimiterConfig::Gcra { rate, burst: 0 };
+        let mut config = EndpointConfig {
+            default: gcra(100),
+            overrides: BTreeMap::new(),
+        };
+        config.overrides.insert("/upload".into(), gcra(1));
+        config.overrides.insert("POST /upload".into(), gcra(2));
+        config
+            .overrides
+            .insert("tenant=acme POST /upload".into(), gcra(3));
+        let clock = MockClock::new(1_000_000);
+        let limits = config.build_with_clock(&clock).unwrap();
+
+        let post = Endpoint {
+            method: "POST",
+            route: "/upload",
+            tenant: None,
+        };
+        let acme = Endpoint {
+            tenant: Some("acme"),
+            ..post
+        };
+        let get = Endpoint {
+            method: "GET",
+            ..post
+        };
+        let other = Endpoint { route: "/", ..get };
+        // each request drains its own limiter, so the number admitted tells which one it was
+        for (endpoint, rate) in [(acme, 3), (post, 2), (get, 1), (other, 100)] {
+            let admitted = (0..200).filter(|_| limits.check(&endpoint).is_ok()).count();
+            assert_eq!(admitted, rate, "{endpoint:?}");
+        }
+
+        config.overrides.insert("get /".into(), gcra(1));
+        let err = config.build().err().unwrap();
+        assert_eq!(err.path(), r#"overrides["get /"]"#);
+        config.overrides.remove("get /");
+        config.overrides.insert("GET /".into(), gcra(0));
+        assert_eq!(
+            config.build().err().unwrap().to_string(),
+            r#"invalid limiter config: overrides["GET /"]: `rate` must be positive"#
+        );
+    }
+}
@@ -0,0 +1,234 @@
+//! Pacing a message consumer, e.g. of Kafka or NATS, enabled by the `tokio` feature.
+//!
+//! Consumer clients prefetch: they keep fetching into a local buffer for as long as the
+//! application is behind. Throttling the handler alone therefore lets the buffer grow without
+//! bound while the limiter holds messages back. [`PacedConsumer`] asks the limiter before it
+//! receives the next message, and pauses the source while the wait is long, so the client stops
+//! fetching until the limiter admits more.
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::Policy;
+use crate::limiter::{Closed, Limiter};
+
+/// An async stream of messages that can stop fetching for a while, e.g. an rdkafka
+/// `StreamConsumer` pausing its assignment, or a NATS pull consumer that stops requesting
+/// batches.
+pub trait MessageSource {
+    type Message;
+    type Error;
+
+    /// The next message, or `None` when the source is exhausted.
+    fn recv(&mut self) -> impl Future<Output = Result<Option<Self::Message>, Self::Error>> + Send;
+
+    /// Stop fetching from the broker. Messages already buffered may still be received.
+    fn pause(&mut self) -> Result<(), Self::Error>;
+
+    fn resume(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Why [`PacedConsumer::next`] failed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConsumeError<E> {
+    Source(E),
+    /// The limiter was closed, see [`Limiter::close`].
+    Closed,
+}
+
+impl<E: fmt::Display> fmt::Display for ConsumeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsumeError::Source(err) => write!(f, "message source failed: {err}"),
+            ConsumeError::Closed => fmt::Display::fmt(&Closed, f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ConsumeError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConsumeError::Source(err) => Some(err),
+            ConsumeError::Closed => None,
+        }
+    }
+}
+
+impl<E> From<Closed> for ConsumeError<E> {
+    fn from(_: Closed) -> Self {
+        ConsumeError::Closed
+    }
+}
+
+/// Receives messages from a [`MessageSource`] no faster than a [`Limiter`] admits them.
+///
+/// Each message takes one cell, charged before it is received. When the limiter makes the
+/// consumer wait at least [`pause_after`](Self::pause_after), the source is paused for the wait
+/// and resumed right before the next message is received. Shorter waits leave the source
+/// running, since pausing and resuming a consumer is not free.
+///
+/// # Example
+/// ```
+/// use ratelimit::{ConsumeError, Limiter, MessageSource, PacedConsumer, VirtualScheduling};
+///
+/// async fn consume<S: MessageSource>(source: S) -> Result<(), ConsumeError<S::Error>> {
+///     let limiter = Limiter::new(VirtualScheduling::builder().rate(500).build());
+///     let mut consumer = PacedConsumer::new(source, limiter);
+///     while let Some(message) = consumer.next().await? {
+///         // handle(message).await
+/// #       drop(message);
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct PacedConsumer<S, P, C = SystemClock> {
+    source: S,
+    limiter: Limiter<P, C>,
+    pause_after: Duration,
+    paused: bool,
+}
+
+impl<S, P, C> PacedConsumer<S, P, C> {
+    pub fn new(source: S, limiter: Limiter<P, C>) -> Self {
+        PacedConsumer {
+            source,
+            limiter,
+            pause_after: Duration::from_millis(500),
+            paused: false,
+        }
+    }
+
+    /// Pause the source for waits at least this long. 500ms by default; zero pauses for every
+    /// wait.
+    pub fn pause_after(mut self, pause_after: Duration) -> Self {
+        self.pause_after = pause_after;
+        self
+    }
+
+    pub fn limiter(&self) -> &Limiter<P, C> {
+        &self.limiter
+    }
+
+    /// Whether the source is paused, i.e. the last wait was long and no message has been
+    /// received since.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn source_mut(&mut self) -> &mut S {
+        &mut self.source
+    }
+
+    pub fn into_inner(self) -> S {
+        self.source
+    }
+}
+
+impl<S, P, C> PacedConsumer<S, P, C>
+where
+    S: MessageSource,
+    P: Policy,
+    C: Clock,
+{
+    /// Wait until the limiter admits another message, then receive it.
+    ///
+    /// Cancel safe: a cancelled call loses no message, though it may have charged the limiter.
+    pub async fn next(&mut self) -> Result<Option<S::Message>, ConsumeError<S::Error>> {
+        if let Err(denied) = self.limiter.check() {
+            if denied.retry_after() >= self.pause_after && !self.paused {
+                self.source.pause().map_err(ConsumeError::Source)?;
+                self.paused = true;
+            }
+            self.limiter.until_ready().await?;
+        }
+        if self.paused {
+            self.source.resume().map_err(ConsumeError::Source)?;
+            self.paused = false;
+        }
+        self.source.recv().await.map_err(ConsumeError::Source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+    use std::time::Instant;
+
+    use crate::gcra::{Gcra, VirtualScheduling};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Broker {
+        messages: VecDeque<u32>,
+        log: Vec<&'static str>,
+    }
+
+    impl MessageSource for Broker {
+        type Message = u32;
+        type Error = Infallible;
+
+        async fn recv(&mut self) -> Result<Option<u32>, Infallible> {
+            self.log.push("recv");
+            Ok(self.messages.pop_front())
+        }
+
+        fn pause(&mut self) -> Result<(), Infallible> {
+            self.log.push("pause");
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<(), Infallible> {
+            self.log.push("resume");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_paced_consumer() {
+        let limiter: Limiter<Gcra> = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(30))
+                .build(),
+        );
+        let broker = Broker {
+            messages: (0..3).collect(),
+            ..Broker::default()
+        };
+        let mut consumer =
+            PacedConsumer::new(broker, limiter).pause_after(Duration::from_millis(20));
+
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while let Some(message) = consumer.next().await.unwrap() {
+            received.push(message);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(85));
+        assert_eq!(received, [0, 1, 2]);
+        assert!(!consumer.is_paused());
+        assert_eq!(
+            consumer.into_inner().log,
+            [
+                "recv", "pause", "resume", "recv", "pause", "resume", "recv", "pause", "resume",
+                "recv"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_paced_consumer_closed() {
+        let limiter: Limiter<Gcra> = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(30))
+                .build(),
+        );
+        limiter.close(Duration::ZERO);
+        let mut consumer = PacedConsumer::new(Broker::default(), limiter);
+        assert_eq!(consumer.next().await, Err(ConsumeError::Closed));
+        assert!(consumer.is_paused());
+    }
+}
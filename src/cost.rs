@@ -0,0 +1,127 @@
+//! Learning request costs from observed work.
+//!
+//! Some requests are much heavier than others, and what makes them heavy is easiest to measure
+//! after the fact: latency of the downstream call, size of the response. [`CostModel`] keeps a
+//! moving average of such a measurement per request class and turns it into a cell count for
+//! [`Gcra::check_n`](crate::Gcra::check_n), so heavy classes consume more of the budget without
+//! anyone tuning per-endpoint weights.
+//!
+//! # Example
+//! ```
+//! use std::time::Duration;
+//!
+//! use ratelimit::{CostModel, LeakyBucket};
+//!
+//! // every 50ms of downstream latency costs one cell
+//! let model = CostModel::new(50.0).max_cost(20);
+//! let rl = LeakyBucket::builder().rate(100).build();
+//!
+//! if rl.check_n(model.cost("/export")).is_ok() {
+//!     // ... handle the request, then tell the model how long it took
+//!     model.record_duration("/export", Duration::from_millis(400));
+//! }
+//! assert_eq!(model.cost("/export"), 8);
+//! ```
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::sync::Mutex;
+
+pub struct CostModel<K> {
+    unit: f64,
+    alpha: f64,
+    max_cost: u64,
+    classes: Mutex<HashMap<K, f64>>,
+}
+
+impl<K> CostModel<K> {
+    /// A model where an average measurement of `unit` costs one cell.
+    ///
+    /// # Panics
+    /// Panics if `unit` is not positive.
+    pub fn new(unit: f64) -> Self {
+        assert!(unit > 0.0, "unit must be positive");
+        CostModel {
+            unit,
+            alpha: 0.2,
+            max_cost: u64::MAX,
+            classes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Weight of each new measurement in the moving average, in `(0, 1]`. 0.2 by default.
+    ///
+    /// The first measurement of a class is taken as is.
+    pub fn smoothing(mut self, alpha: f64) -> Self {
+        assert!(alpha > 0.0 && alpha <= 1.0, "alpha must be in (0, 1]");
+        self.alpha = alpha;
+        self
+    }
+
+    /// Cap costs, e.g. to the burst capacity of the policy so that every class can still get
+    /// through.
+    pub fn max_cost(mut self, max_cost: u64) -> Self {
+        self.max_cost = max_cost;
+        self
+    }
+}
+
+impl<K> CostModel<K>
+where
+    K: Hash + Eq,
+{
+    /// Record the measured work of one request of `class`, in the same unit as the model's.
+    pub fn record(&self, class: K, observed: f64) {
+        let mut classes = self.classes.lock();
+        let average = classes.entry(class).or_insert(observed);
+        *average += self.alpha * (observed - *average);
+    }
+
+    /// Record a latency measured in milliseconds.
+    pub fn record_duration(&self, class: K, observed: Duration) {
+        self.record(class, observed.as_secs_f64() * 1000.0);
+    }
+
+    /// The cost of the next request of `class`: the average measurement in units, rounded up,
+    /// and between 1 and `max_cost`. Classes without measurements cost 1.
+    pub fn cost<Q>(&self, class: &Q) -> u64
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let average = self.classes.lock().get(class).copied().unwrap_or(0.0);
+        ((average / self.unit).ceil() as u64).clamp(1, self.max_cost.max(1))
+    }
+
+    /// The current average measurement of `class`.
+    pub fn average<Q>(&self, class: &Q) -> Option<f64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.classes.lock().get(class).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_model_learns() {
+        let model: CostModel<&str> = CostModel::new(10.0).smoothing(0.5).max_cost(8);
+        assert_eq!(model.cost("a"), 1);
+        model.record("a", 40.0);
+        assert_eq!(model.cost("a"), 4);
+        model.record("a", 20.0);
+        assert_eq!(model.average("a"), Some(30.0));
+        assert_eq!(model.cost("a"), 3);
+        model.record("b", 1000.0);
+        assert_eq!(model.cost("b"), 8);
+        model.record("c", 0.0);
+        assert_eq!(model.cost("c"), 1);
+    }
+}
@@ -0,0 +1,134 @@
+//! Credit-based flow control driven by a GCRA.
+//!
+//! Protocols like HTTP/2 and QUIC do not reject a peer's data after the fact; they tell the peer
+//! up front how much it may send, with window updates. [`Credits`] turns a quota into such
+//! grants: every call to [`grant_credits`](Credits::grant_credits) hands out the capacity that
+//! accumulated since the last grant, to be sent to the peer as a window update.
+
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{conform_n, Gcra, GcraBuilder};
+use crate::quota::Quota;
+
+/// Hands out the capacity of a quota as credits.
+///
+/// Granted credits are charged right away, so the peer can spend them whenever it likes and
+/// the quota holds as long as it spends no more than it was granted. The first grant is the
+/// whole burst, later ones what refilled in between. Tiny grants only cost overhead on the
+/// wire, so grants below [`min_grant`](Self::min_grant) are held back until enough accumulated.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Credits, Quota};
+///
+/// let credits = Credits::new(Quota::per_second(1000).burst(3000)).min_grant(100);
+/// assert_eq!(credits.grant_credits(), 4000);
+/// assert_eq!(credits.grant_credits(), 0);
+/// ```
+pub struct Credits<C = SystemClock> {
+    clock: C,
+    gcra: Gcra<()>,
+    min_grant: u64,
+    max_grant: u64,
+}
+
+impl Credits<SystemClock> {
+    pub fn new(quota: Quota) -> Self {
+        Credits {
+            clock: SystemClock,
+            gcra: GcraBuilder::new().quota(quota).build(),
+            min_grant: 1,
+            max_grant: u64::MAX,
+        }
+    }
+}
+
+impl<C> Credits<C> {
+    pub fn clock<NC>(self, clock: NC) -> Credits<NC> {
+        Credits {
+            clock,
+            gcra: self.gcra,
+            min_grant: self.min_grant,
+            max_grant: self.max_grant,
+        }
+    }
+
+    /// Hold back grants smaller than `min_grant`. 1 by default.
+    ///
+    /// # Panics
+    /// Panics if `min_grant` is zero.
+    pub fn min_grant(mut self, min_grant: u64) -> Self {
+        assert!(min_grant > 0, "min_grant must be positive");
+        self.min_grant = min_grant;
+        self
+    }
+
+    /// Grant at most `max_grant` credits at once, e.g. the peer's maximum window. Unbounded by
+    /// default.
+    pub fn max_grant(mut self, max_grant: u64) -> Self {
+        self.max_grant = max_grant;
+        self
+    }
+}
+
+impl<C> Credits<C>
+where
+    C: Clock,
+{
+    /// Take the credits available now, or 0 if fewer than `min_grant` are.
+    pub fn grant_credits(&self) -> u64 {
+        let now = self.clock.now_nanos();
+        self.gcra.take_at(now, self.min_grant, self.max_grant)
+    }
+
+    /// Time until a grant of `min_grant` credits is available.
+    pub fn next_grant_in(&self) -> Duration {
+        let (mut tat, gap, tolerance) = self.gcra.state();
+        let now = self.clock.now_nanos();
+        match conform_n(&mut tat, now, gap, tolerance, self.min_grant) {
+            Ok(()) => Duration::ZERO,
+            Err(denied) => denied.retry_after(),
+        }
+    }
+
+    /// Wait for the next grant of at least `min_grant` credits. Called in a loop, this is the
+    /// stream of window updates for a peer.
+    #[cfg(feature = "async-wait")]
+    pub async fn next_grant(&self) -> u64 {
+        loop {
+            let granted = self.grant_credits();
+            if granted > 0 {
+                return granted;
+            }
+            // at least a millisecond, so rounding cannot make this spin
+            crate::timer::sleep(self.next_grant_in().max(Duration::from_millis(1))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_credits_grant_refill() {
+        let clock = MockClock::new(1_000_000);
+        let credits = Credits::new(Quota::per_second(100).burst(100))
+            .clock(&clock)
+            .min_grant(20)
+            .max_grant(150);
+        assert_eq!(credits.grant_credits(), 150);
+        assert_eq!(credits.grant_credits(), 50);
+        assert_eq!(credits.grant_credits(), 0);
+        assert_eq!(credits.next_grant_in(), Duration::from_millis(200));
+
+        clock.forward(Duration::from_millis(150));
+        assert_eq!(credits.grant_credits(), 0);
+        clock.forward(Duration::from_millis(50));
+        assert_eq!(credits.grant_credits(), 20);
+        assert_eq!(credits.next_grant_in(), Duration::from_millis(200));
+    }
+}
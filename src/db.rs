@@ -0,0 +1,190 @@
+//! Throttling database work by kind, enabled by the `tokio` feature.
+//!
+//! A shared database has a fixed amount of capacity. Background jobs that issue queries as fast as
+//! they can eat into what interactive requests need, and a connection pool does not help: it
+//! hands out connections in arrival order. [`QueryGate`] puts a [`Limiter`] in front of each kind
+//! of work, so e.g. bulk writes of a batch job can be held to a rate while reads run unthrottled.
+//!
+//! The gate wraps any future, so it works with any driver or pool without depending on them:
+//! gate the query future to throttle statements, or the pool's `get()` future to throttle
+//! connection checkouts. [`StatementKind::of`] classifies SQL text for the common case of
+//! limiting by statement kind.
+//!
+//! # Example
+//! ```
+//! use ratelimit::{Closed, Gcra, Limiter, QueryGate, StatementKind, VirtualScheduling};
+//!
+//! # async fn execute(_sql: &str) -> u64 { 1 }
+//! async fn mark_done(gate: &QueryGate<StatementKind, Gcra>) -> Result<u64, Closed> {
+//!     let sql = "UPDATE jobs SET state = 'done' WHERE id = 1";
+//!     // with sqlx, e.g. `sqlx::query(sql).execute(&pool)`
+//!     gate.run(&StatementKind::of(sql), execute(sql)).await
+//! }
+//!
+//! let gate = QueryGate::new()
+//!     .kind(StatementKind::Write, Limiter::new(VirtualScheduling::builder().rate(50).build()));
+//! # let _ = mark_done(&gate);
+//! ```
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::Policy;
+use crate::limiter::{Closed, Limiter};
+
+/// Kinds of SQL statements, by their leading keyword.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatementKind {
+    /// `SELECT`, `WITH`, `SHOW`, `EXPLAIN` and the like.
+    Read,
+    /// `INSERT`, `UPDATE`, `DELETE`, `MERGE`, `UPSERT`, `REPLACE` and `COPY`.
+    Write,
+    /// `CREATE`, `ALTER`, `DROP` and `TRUNCATE`.
+    Ddl,
+    Other,
+}
+
+impl StatementKind {
+    /// Classify `sql` by its first keyword, skipping leading whitespace, comments and
+    /// parentheses. A `WITH` query counts as a read even if it ends in a write.
+    pub fn of(sql: &str) -> Self {
+        let keyword = first_keyword(sql);
+        let is = |k: &str| keyword.eq_ignore_ascii_case(k);
+        if ["select", "with", "show", "explain", "values", "table"]
+            .into_iter()
+            .any(is)
+        {
+            StatementKind::Read
+        } else if [
+            "insert", "update", "delete", "merge", "upsert", "replace", "copy",
+        ]
+        .into_iter()
+        .any(is)
+        {
+            StatementKind::Write
+        } else if ["create", "alter", "drop", "truncate"].into_iter().any(is) {
+            StatementKind::Ddl
+        } else {
+            StatementKind::Other
+        }
+    }
+}
+
+fn first_keyword(mut sql: &str) -> &str {
+    loop {
+        sql = sql.trim_start_matches(|c: char| c.is_whitespace() || c == '(');
+        if let Some(rest) = sql.strip_prefix("--") {
+            sql = rest.split_once('\n').map_or("", |(_, rest)| rest);
+        } else if let Some(rest) = sql.strip_prefix("/*") {
+            sql = rest.split_once("*/").map_or("", |(_, rest)| rest);
+        } else {
+            break;
+        }
+    }
+    let end = sql
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(sql.len());
+    &sql[..end]
+}
+
+/// Holds back database work of each kind until its limiter admits it.
+///
+/// Kinds without a limiter pass straight through. Waiting goes through
+/// [`Limiter::until_ready`], so waiters of a kind are admitted in order, and closing a limiter
+/// fails its waiting work with [`Closed`].
+pub struct QueryGate<K, P, C = SystemClock> {
+    limiters: HashMap<K, Limiter<P, C>>,
+}
+
+impl<K, P, C> Default for QueryGate<K, P, C> {
+    fn default() -> Self {
+        QueryGate {
+            limiters: HashMap::new(),
+        }
+    }
+}
+
+impl<K, P, C> QueryGate<K, P, C>
+where
+    K: Hash + Eq,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Throttle work of `kind` with `limiter`.
+    pub fn kind(mut self, kind: K, limiter: Limiter<P, C>) -> Self {
+        self.limiters.insert(kind, limiter);
+        self
+    }
+
+    pub fn limiter(&self, kind: &K) -> Option<&Limiter<P, C>> {
+        self.limiters.get(kind)
+    }
+}
+
+impl<K, P, C> QueryGate<K, P, C>
+where
+    K: Hash + Eq,
+    P: Policy,
+    C: Clock,
+{
+    /// Wait until work of `kind` is admitted, then run `work`.
+    pub async fn run<F: Future>(&self, kind: &K, work: F) -> Result<F::Output, Closed> {
+        if let Some(limiter) = self.limiters.get(kind) {
+            limiter.until_ready().await?;
+        }
+        Ok(work.await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::gcra::{Gcra, VirtualScheduling};
+
+    use super::*;
+
+    #[test]
+    fn test_statement_kind() {
+        assert_eq!(StatementKind::of("select 1"), StatementKind::Read);
+        assert_eq!(
+            StatementKind::of("  -- refresh\n/* batch */ (WITH x AS (SELECT 1) SELECT * FROM x)"),
+            StatementKind::Read
+        );
+        assert_eq!(
+            StatementKind::of("INSERT INTO t VALUES (1)"),
+            StatementKind::Write
+        );
+        assert_eq!(StatementKind::of("drop table t"), StatementKind::Ddl);
+        assert_eq!(StatementKind::of("VACUUM"), StatementKind::Other);
+        assert_eq!(StatementKind::of(""), StatementKind::Other);
+    }
+
+    #[tokio::test]
+    async fn test_query_gate_by_kind() {
+        let gate: QueryGate<StatementKind, Gcra> = QueryGate::new().kind(
+            StatementKind::Write,
+            Limiter::new(
+                VirtualScheduling::builder()
+                    .gap(Duration::from_millis(20))
+                    .build(),
+            ),
+        );
+        let start = Instant::now();
+        for i in 0..3 {
+            assert_eq!(gate.run(&StatementKind::Write, async { i }).await, Ok(i));
+        }
+        assert!(start.elapsed() >= Duration::from_millis(35));
+
+        // reads are not held back by the writes
+        let start = Instant::now();
+        for _ in 0..100 {
+            gate.run(&StatementKind::Read, async {}).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,184 @@
+//! What a stack of policies enforces, for admin endpoints and startup logs.
+//!
+//! Once policies are wrapped in limiters, toggles and evaluations, what actually runs is hard to
+//! tell from the code that built it. [`Describe::describe`] returns the whole chain as a tree of
+//! [`Description`]s, which prints as an indented outline or as JSON:
+//!
+//! ```text
+//! limiter "api" soft_rate=8/s
+//!   toggle enabled=true
+//!     gcra rate=10/s capacity=15
+//! ```
+//!
+//! ```json
+//! {"kind":"limiter","name":"api","settings":{"soft_rate":"8/s"},"children":[...]}
+//! ```
+//!
+//! Settings are configuration, plus modes that change what is enforced, such as a frozen
+//! policy; counters and levels are in [`Snapshot`](crate::Snapshot) and the stats of each type.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::json;
+
+/// Policies that can describe what they enforce, including the policies they wrap.
+pub trait Describe {
+    fn describe(&self) -> Description;
+}
+
+impl<P: Describe + ?Sized> Describe for &P {
+    fn describe(&self) -> Description {
+        (**self).describe()
+    }
+}
+
+impl<P: Describe + ?Sized> Describe for Arc<P> {
+    fn describe(&self) -> Description {
+        (**self).describe()
+    }
+}
+
+/// One policy of a chain: what it is, its settings, and the policies it wraps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    /// What the policy is, e.g. `gcra` or `limiter`.
+    pub kind: &'static str,
+    pub name: Option<String>,
+    /// Settings in a fixed order, e.g. `("rate", "10/s")`.
+    pub settings: Vec<(&'static str, String)>,
+    /// The wrapped policies, in the order the policy asks them.
+    pub children: Vec<Description>,
+}
+
+impl Description {
+    pub fn new(kind: &'static str) -> Self {
+        Description {
+            kind,
+            name: None,
+            settings: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Name the policy, unless `name` is empty.
+    pub fn named(mut self, name: &str) -> Self {
+        if !name.is_empty() {
+            self.name = Some(name.to_owned());
+        }
+        self
+    }
+
+    pub fn setting(mut self, key: &'static str, value: impl fmt::Display) -> Self {
+        self.settings.push((key, value.to_string()));
+        self
+    }
+
+    pub fn child(mut self, child: Description) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    /// The tree as a JSON object, with settings as an object of strings.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    fn write_json(&self, out: &mut String) {
+        out.push_str(r#"{"kind":"#);
+        json::push_str(out, self.kind);
+        out.push_str(r#","name":"#);
+        match &self.name {
+            Some(name) => json::push_str(out, name),
+            None => out.push_str("null"),
+        }
+        out.push_str(r#","settings":{"#);
+        for (i, (key, value)) in self.settings.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            json::push_str(out, key);
+            out.push(':');
+            json::push_str(out, value);
+        }
+        out.push_str(r#"},"children":["#);
+        for (i, child) in self.children.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            child.write_json(out);
+        }
+        out.push_str("]}");
+    }
+
+    fn write_outline(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.kind, indent = depth * 2)?;
+        if let Some(name) = &self.name {
+            write!(f, " {name:?}")?;
+        }
+        for (key, value) in &self.settings {
+            write!(f, " {key}={value}")?;
+        }
+        for child in &self.children {
+            writeln!(f)?;
+            child.write_outline(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Description {
+    /// An outline, one line per policy, with wrapped policies indented below their wrapper.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_outline(f, 0)
+    }
+}
+
+/// The rate of one cell every `gap` ns, e.g. `10/s` or `0.5/s`.
+pub(crate) fn rate(gap: u64) -> String {
+    let per_second = 1e9 / gap.max(1) as f64;
+    let rate = format!("{per_second:.3}");
+    format!("{}/s", rate.trim_end_matches('0').trim_end_matches('.'))
+}
+
+/// The settings of a GCRA with `gap` and `tolerance` in ns: its rate and how many cells it lets
+/// through at once.
+pub(crate) fn gcra(kind: &'static str, gap: u64, tolerance: u64) -> Description {
+    Description::new(kind)
+        .setting("rate", rate(gap))
+        .setting("capacity", tolerance / gap.max(1) + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::flag::Toggle;
+    use crate::gcra::GcraBuilder;
+    use crate::limiter::Limiter;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_describe_chain() {
+        let gcra = GcraBuilder::new()
+            .clock(MockClock::new(0))
+            .quota(Quota::per_second(10).burst(5))
+            .build();
+        let limiter = Limiter::new(Toggle::new(|| true, gcra))
+            .soft_limit(Quota::per_second(8))
+            .named("api");
+        let description = limiter.describe();
+        assert_eq!(
+            description.to_string(),
+            "limiter \"api\" soft_rate=8/s\n  toggle enabled=true\n    gcra rate=10/s capacity=15"
+        );
+        assert_eq!(
+            description.to_json(),
+            r#"{"kind":"limiter","name":"api","settings":{"soft_rate":"8/s"},"children":[{"kind":"toggle","name":null,"settings":{"enabled":"true"},"children":[{"kind":"gcra","name":null,"settings":{"rate":"10/s","capacity":"15"},"children":[]}]}]}"#
+        );
+        assert_eq!(rate(3_000_000_000), "0.333/s");
+    }
+}
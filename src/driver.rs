@@ -0,0 +1,458 @@
+//! Async drivers of the [`Batcher`] and the [`Reconciler`], for backends asked over an async
+//! transport.
+//!
+//! [`Prefetch`](crate::Prefetch) and [`Optimistic`](crate::Optimistic) block on their
+//! [`Backend`](crate::Backend) and start threads for the work behind the requests.
+//! [`AsyncPrefetch`] and [`AsyncOptimistic`] are the same state machines around an
+//! [`AsyncBackend`], with the background work spawned as tasks by a [`Spawn`]: `tokio`
+//! [`TokioSpawn`] and `async-std` [`AsyncStdSpawn`], the first of these enabled by default.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::batcher::{Batcher, Fetch, Next};
+use crate::gcra::{Denied, Headroom, Policy};
+use crate::reconciler::{check_local, ReconcileStats, Reconciler, Report};
+use crate::sync::Mutex;
+
+/// A [`Backend`](crate::Backend) asked over an async transport.
+pub trait AsyncBackend: Send + Sync + 'static {
+    type Error: Send;
+
+    /// Ask for `n` cells, all or nothing.
+    fn try_check_n(
+        &self,
+        n: u64,
+    ) -> impl Future<Output = Result<Result<(), Denied>, Self::Error>> + Send;
+}
+
+/// A task spawner of an async runtime.
+pub trait Spawn: Send + Sync {
+    /// Run `task` in the background.
+    fn spawn(&self, task: Pin<Box<dyn Future<Output = ()> + Send>>);
+}
+
+/// `tokio::spawn`, which needs to be called within a tokio runtime.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioSpawn;
+
+#[cfg(feature = "tokio")]
+impl Spawn for TokioSpawn {
+    fn spawn(&self, task: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        tokio::spawn(task);
+    }
+}
+
+/// `async_std::task::spawn`.
+#[cfg(feature = "async-std")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncStdSpawn;
+
+#[cfg(feature = "async-std")]
+impl Spawn for AsyncStdSpawn {
+    fn spawn(&self, task: Pin<Box<dyn Future<Output = ()> + Send>>) {
+        async_std::task::spawn(task);
+    }
+}
+
+/// The spawner of the first runtime feature enabled.
+#[cfg(feature = "tokio")]
+fn default_spawn() -> Arc<dyn Spawn> {
+    Arc::new(TokioSpawn)
+}
+
+#[cfg(not(feature = "tokio"))]
+fn default_spawn() -> Arc<dyn Spawn> {
+    Arc::new(AsyncStdSpawn)
+}
+
+/// [`Prefetch`](crate::Prefetch) for an [`AsyncBackend`]: tokens fetched in batches of `batch`,
+/// the next batch in a background task.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "tokio")]
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// use ratelimit::{AsyncBackend, AsyncPrefetch, Denied};
+///
+/// struct Store;
+///
+/// impl AsyncBackend for Store {
+///     type Error = std::io::Error;
+///
+///     async fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, Self::Error> {
+///         // ask the shared store for `n` tokens
+///         Ok(Ok(()))
+///     }
+/// }
+///
+/// let prefetch = AsyncPrefetch::new(Store, 100);
+/// assert!(prefetch.try_check().await.unwrap().is_ok());
+/// assert_eq!(prefetch.tokens(), 99);
+/// # });
+/// ```
+pub struct AsyncPrefetch<B> {
+    shared: Arc<PrefetchShared<B>>,
+    spawn: Arc<dyn Spawn>,
+}
+
+struct PrefetchShared<B> {
+    backend: B,
+    batcher: Mutex<Batcher>,
+}
+
+impl<B> AsyncPrefetch<B> {
+    /// Fetch `batch` tokens at a time, the next batch once a quarter of one is left.
+    ///
+    /// # Panics
+    /// Panics if `batch` is zero.
+    pub fn new(backend: B, batch: u64) -> Self {
+        AsyncPrefetch {
+            shared: Arc::new(PrefetchShared {
+                backend,
+                batcher: Mutex::new(Batcher::new(batch)),
+            }),
+            spawn: default_spawn(),
+        }
+    }
+
+    /// Fetch the next batch once `tokens` are left.
+    pub fn low_water(self, tokens: u64) -> Self {
+        self.shared.batcher.lock().set_low_water(tokens);
+        self
+    }
+
+    /// Spawn the background fetches with `spawn` rather than on the default runtime.
+    pub fn spawner(mut self, spawn: impl Spawn + 'static) -> Self {
+        self.spawn = Arc::new(spawn);
+        self
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.shared.backend
+    }
+
+    /// Tokens held locally.
+    pub fn tokens(&self) -> u64 {
+        self.shared.batcher.lock().tokens()
+    }
+}
+
+impl<B> PrefetchShared<B>
+where
+    B: AsyncBackend,
+{
+    async fn refresh(&self, fetch: Fetch) {
+        let answer = self.backend.try_check_n(fetch.n()).await;
+        let mut batcher = self.batcher.lock();
+        match answer {
+            Ok(answer) => {
+                batcher.on_response(fetch, answer);
+            }
+            Err(_) => batcher.on_error(fetch),
+        }
+    }
+}
+
+impl<B> AsyncPrefetch<B>
+where
+    B: AsyncBackend,
+{
+    /// Decide on one request, waiting for the backend only when out of tokens.
+    pub async fn try_check(&self) -> Result<Result<(), Denied>, B::Error> {
+        let mut next = self.shared.batcher.lock().take();
+        // out of tokens, this request waits for the backend
+        while let Next::Fetch(fetch) = next {
+            let answer = self.shared.backend.try_check_n(fetch.n()).await;
+            let mut batcher = self.shared.batcher.lock();
+            let answer = answer.inspect_err(|_| batcher.on_error(fetch))?;
+            next = batcher
+                .on_response(fetch, answer)
+                .expect("a request waits on its fetch");
+        }
+        match next {
+            Next::Deny(denied) => Ok(Err(denied)),
+            _ => {
+                self.refresh_in_background();
+                Ok(Ok(()))
+            }
+        }
+    }
+
+    fn refresh_in_background(&self) {
+        let Some(fetch) = self.shared.batcher.lock().poll_action() else {
+            return;
+        };
+        let shared = self.shared.clone();
+        self.spawn
+            .spawn(Box::pin(async move { shared.refresh(fetch).await }));
+    }
+}
+
+/// [`Optimistic`](crate::Optimistic) for an [`AsyncBackend`]: `local` decides right away, and
+/// the admitted requests are reported to the backend in a background task.
+///
+/// With [`TokioSpawn`], [`check`](Policy::check) has to be called within a tokio runtime.
+///
+/// # Example
+/// ```
+/// # #[cfg(feature = "tokio")]
+/// # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+/// use ratelimit::{AsyncBackend, AsyncOptimistic, Denied, LeakyBucket, Policy, Quota};
+///
+/// struct Store;
+///
+/// impl AsyncBackend for Store {
+///     type Error = std::io::Error;
+///
+///     async fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, Self::Error> {
+///         // ask the shared store
+///         Ok(Ok(()))
+///     }
+/// }
+///
+/// let local = LeakyBucket::builder().quota(Quota::per_second(10)).build();
+/// let policy = AsyncOptimistic::new(local, Store);
+/// assert!(policy.check().is_ok());
+/// # });
+/// ```
+pub struct AsyncOptimistic<P, B> {
+    local: P,
+    shared: Arc<OptimisticShared<B>>,
+    spawn: Arc<dyn Spawn>,
+}
+
+struct OptimisticShared<B> {
+    backend: B,
+    reconciler: Mutex<Reconciler>,
+}
+
+impl<P, B> AsyncOptimistic<P, B> {
+    pub fn new(local: P, backend: B) -> Self {
+        AsyncOptimistic {
+            local,
+            shared: Arc::new(OptimisticShared {
+                backend,
+                reconciler: Mutex::new(Reconciler::new()),
+            }),
+            spawn: default_spawn(),
+        }
+    }
+
+    /// Spawn the reports to the backend with `spawn` rather than on the default runtime.
+    pub fn spawner(mut self, spawn: impl Spawn + 'static) -> Self {
+        self.spawn = Arc::new(spawn);
+        self
+    }
+
+    pub fn local(&self) -> &P {
+        &self.local
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.shared.backend
+    }
+
+    /// Cells the local policy still owes for requests the backend denied.
+    pub fn debt(&self) -> u64 {
+        self.shared.reconciler.lock().debt()
+    }
+
+    /// Requests admitted locally that the backend has not answered for yet.
+    pub fn pending(&self) -> u64 {
+        self.shared.reconciler.lock().pending()
+    }
+
+    pub fn stats(&self) -> ReconcileStats {
+        self.shared.reconciler.lock().stats()
+    }
+}
+
+impl<B> OptimisticShared<B>
+where
+    B: AsyncBackend,
+{
+    /// Ask the backend for `report`, then for every report still pending.
+    async fn report(&self, mut report: Report) {
+        loop {
+            let answer = self.backend.try_check_n(1).await;
+            let mut reconciler = self.reconciler.lock();
+            match answer {
+                Ok(answer) => reconciler.on_response(report, answer),
+                Err(_) => reconciler.on_error(report),
+            }
+            match reconciler.poll_action() {
+                Some(next) => report = next,
+                None => return,
+            }
+        }
+    }
+}
+
+impl<P, B> Policy for AsyncOptimistic<P, B>
+where
+    P: Policy,
+    B: AsyncBackend,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        check_local(&self.shared.reconciler, &self.local, n)?;
+        if let Some(report) = self.shared.reconciler.lock().poll_action() {
+            let shared = self.shared.clone();
+            self.spawn
+                .spawn(Box::pin(async move { shared.report(report).await }));
+        }
+        Ok(())
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        let debt = self.debt();
+        self.local.headroom().map(|headroom| Headroom {
+            remaining: headroom.remaining.saturating_sub(debt),
+            ..headroom
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::gcra::VirtualScheduling;
+    use crate::sync::{AtomicU64, Ordering};
+
+    use super::*;
+
+    struct Pool {
+        left: AtomicU64,
+        calls: AtomicU64,
+    }
+
+    impl Pool {
+        fn new(left: u64) -> Self {
+            Pool {
+                left: AtomicU64::new(left),
+                calls: AtomicU64::new(0),
+            }
+        }
+    }
+
+    impl AsyncBackend for Pool {
+        type Error = ();
+
+        async fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, ()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self
+                .left
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| {
+                    left.checked_sub(n)
+                }) {
+                Ok(_) => Ok(Ok(())),
+                Err(_) => Ok(Err(Denied::new(Duration::from_secs(1)))),
+            }
+        }
+    }
+
+    async fn prefetch_until_empty(prefetch: AsyncPrefetch<Pool>) {
+        let settle = || async {
+            while prefetch.shared.batcher.lock().is_fetching() {
+                yield_now().await;
+            }
+        };
+        let calls = || prefetch.backend().calls.load(Ordering::Relaxed);
+
+        assert_eq!(prefetch.try_check().await, Ok(Ok(())));
+        assert_eq!((prefetch.tokens(), calls()), (99, 1));
+        for _ in 0..79 {
+            assert_eq!(prefetch.try_check().await, Ok(Ok(())));
+        }
+        settle().await;
+        assert_eq!((prefetch.tokens(), calls()), (120, 2));
+
+        let mut admitted = 80;
+        for _ in 0..200 {
+            admitted += prefetch.try_check().await.unwrap().is_ok() as u64;
+            settle().await;
+        }
+        assert_eq!(admitted, 250);
+    }
+
+    async fn optimistic_owes_overadmitted(
+        policy: AsyncOptimistic<VirtualScheduling<&MockClock>, Pool>,
+    ) {
+        for _ in 0..10 {
+            assert!(policy.check().is_ok());
+        }
+        while policy.pending() > 0 {
+            yield_now().await;
+        }
+        assert_eq!(
+            policy.stats(),
+            ReconcileStats {
+                agreed: 4,
+                overadmitted: 6,
+                failed: 0,
+            }
+        );
+        assert_eq!(policy.debt(), 6);
+    }
+
+    fn local(clock: &MockClock) -> VirtualScheduling<&MockClock> {
+        VirtualScheduling::builder()
+            .clock(clock)
+            .gap(Duration::from_secs(1))
+            .tolerance(Duration::from_secs(9))
+            .build()
+    }
+
+    /// Hand the executor back once, so the spawned tasks run.
+    async fn yield_now() {
+        let mut yielded = false;
+        std::future::poll_fn(|cx| {
+            if std::mem::replace(&mut yielded, true) {
+                return std::task::Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            std::task::Poll::Pending
+        })
+        .await
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_prefetch_tokio() {
+        prefetch_until_empty(AsyncPrefetch::new(Pool::new(250), 100).low_water(20)).await;
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_async_optimistic_tokio() {
+        let clock = MockClock::new_now();
+        optimistic_owes_overadmitted(AsyncOptimistic::new(local(&clock), Pool::new(4))).await;
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_prefetch_async_std() {
+        async_std::task::block_on(prefetch_until_empty(
+            AsyncPrefetch::new(Pool::new(250), 100)
+                .low_water(20)
+                .spawner(AsyncStdSpawn),
+        ));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_async_optimistic_async_std() {
+        let clock = MockClock::new_now();
+        async_std::task::block_on(optimistic_owes_overadmitted(
+            AsyncOptimistic::new(local(&clock), Pool::new(4)).spawner(AsyncStdSpawn),
+        ));
+    }
+}
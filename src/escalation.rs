@@ -0,0 +1,380 @@
+//! Progressive penalties for keys that keep exceeding their limit.
+//!
+//! A plain [`KeyedLimiter`] lets an abusive client back in as soon as its budget refills, so it
+//! can keep hammering at exactly the limit. [`Escalation`] counts every denial of a key as a
+//! strike; enough strikes ban the key outright for the duration of the next rung of a ladder,
+//! e.g. a minute, then ten, then an hour. A key that behaves climbs back down one level per
+//! decay period after its last ban.
+//!
+//! At moderate levels a ban may be too blunt, e.g. for a user behind a shared address. Levels up
+//! to [`challenge_levels`](Escalation::challenge_levels) answer with [`Verdict::Challenge`]
+//! instead, so the application can present a CAPTCHA or ask to re-authenticate; a solved
+//! challenge lifts the ban, see [`pass_challenge`](Escalation::pass_challenge).
+
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{duration_nanos, Denied};
+use crate::keyed::{KeyedLimiter, ToKey};
+use crate::listener::{Event, Listener, Listeners};
+use crate::sync::Mutex;
+
+/// Outcome of [`Escalation::decide`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    /// The key is at a challenge level: let the client prove itself, or treat the request as
+    /// `denied`.
+    Challenge {
+        level: u32,
+        denied: Denied,
+    },
+    Deny(Denied),
+}
+
+/// Bans keys of a [`KeyedLimiter`] for progressively longer after repeated denials.
+///
+/// Every escalation emits [`Event::Escalated`] to the listeners. Keys with strikes are kept
+/// until they decay back to good standing, so call [`retain_recent`](Self::retain_recent)
+/// periodically.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use ratelimit::{Escalation, KeyedLimiter, Quota};
+///
+/// let limiter: KeyedLimiter<String> = KeyedLimiter::builder(Quota::per_second(10)).build();
+/// let escalation = Escalation::new(limiter)
+///     .ladder([Duration::from_secs(60), Duration::from_secs(3600)])
+///     .strikes(20);
+/// assert!(escalation.check("10.0.0.7").is_ok());
+/// ```
+pub struct Escalation<K, C = SystemClock> {
+    limiter: KeyedLimiter<K, C>,
+    ladder: Vec<Duration>,
+    strikes: u32,
+    challenge_levels: u32,
+    // ns, as all times here
+    decay: u64,
+    listeners: Listeners,
+    offenders: Mutex<HashMap<K, Offender>>,
+}
+
+#[derive(Default)]
+struct Offender {
+    level: u32,
+    strikes: u32,
+    banned_until: u64,
+    // last strike, or the end of the last ban
+    since: u64,
+}
+
+impl Offender {
+    fn decay(&mut self, now: u64, decay: u64) {
+        if decay == 0 || now <= self.since {
+            return;
+        }
+        let periods = (now - self.since) / decay;
+        if periods > 0 {
+            self.strikes = 0;
+            self.level = self
+                .level
+                .saturating_sub(periods.try_into().unwrap_or(u32::MAX));
+            self.since += periods * decay;
+        }
+    }
+}
+
+impl<K, C> Escalation<K, C> {
+    /// Escalate with a ladder of 1 minute, 10 minutes, 1 hour and 1 day, after 10 strikes, and
+    /// decay one level per hour.
+    pub fn new(limiter: KeyedLimiter<K, C>) -> Self {
+        Escalation {
+            limiter,
+            ladder: [60, 600, 3600, 86400].map(Duration::from_secs).to_vec(),
+            strikes: 10,
+            challenge_levels: 0,
+            decay: 3_600 * 1_000_000_000,
+            listeners: Listeners::default(),
+            offenders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Ban durations of the levels, from the first up. Keys at the top level stay there.
+    ///
+    /// # Panics
+    /// Panics if `ladder` is empty.
+    pub fn ladder(mut self, ladder: impl IntoIterator<Item = Duration>) -> Self {
+        self.ladder = ladder.into_iter().collect();
+        assert!(
+            !self.ladder.is_empty(),
+            "the ladder needs at least one level"
+        );
+        self
+    }
+
+    /// Denials that escalate a key by one level.
+    ///
+    /// # Panics
+    /// Panics if `strikes` is zero.
+    pub fn strikes(mut self, strikes: u32) -> Self {
+        assert!(strikes > 0, "strikes must be positive");
+        self.strikes = strikes;
+        self
+    }
+
+    /// Challenge keys instead of banning them at the first `levels` levels. 0 by default.
+    pub fn challenge_levels(mut self, levels: u32) -> Self {
+        self.challenge_levels = levels;
+        self
+    }
+
+    /// Time without strikes after which a key drops a level and its strikes are forgotten.
+    /// Zero never forgets.
+    pub fn decay(mut self, decay: Duration) -> Self {
+        self.decay = duration_nanos(decay);
+        self
+    }
+
+    /// Name the escalation in listener events.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.listeners.set_name(name.into());
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn limiter(&self) -> &KeyedLimiter<K, C> {
+        &self.limiter
+    }
+}
+
+impl<K, C> Escalation<K, C>
+where
+    K: Hash + Eq,
+    C: Clock,
+{
+    /// Decide on one request for `key`. A banned key is denied without asking the limiter,
+    /// with a `retry_after` of the rest of its ban. Challenges count as denials.
+    pub fn check<Q>(&self, key: &Q) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + Display + ?Sized,
+    {
+        match self.decide(key, 1) {
+            Verdict::Allow => Ok(()),
+            Verdict::Challenge { denied, .. } | Verdict::Deny(denied) => Err(denied),
+        }
+    }
+
+    /// Decide on a request for `key` worth `n` cells.
+    pub fn decide<Q>(&self, key: &Q, n: u64) -> Verdict
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + Display + ?Sized,
+    {
+        let now = self.limiter.now_nanos();
+        if let Some(offender) = self.offenders.lock().get_mut(key) {
+            offender.decay(now, self.decay);
+            if offender.banned_until > now {
+                let rest = Duration::from_nanos(offender.banned_until - now);
+                return self.verdict(offender.level, Denied::new(rest));
+            }
+        }
+        let denied = match self.limiter.check_n(key, n) {
+            Ok(()) => return Verdict::Allow,
+            Err(denied) => denied,
+        };
+
+        let mut offenders = self.offenders.lock();
+        let offender = match offenders.get_mut(key) {
+            Some(offender) => offender,
+            None => offenders.entry(key.to_key()).or_default(),
+        };
+        offender.decay(now, self.decay);
+        offender.strikes += 1;
+        offender.since = now;
+        if offender.strikes < self.strikes {
+            return Verdict::Deny(denied);
+        }
+        offender.strikes = 0;
+        offender.level = (offender.level + 1).min(self.ladder.len() as u32);
+        let level = offender.level;
+        let ban = self.ladder[level as usize - 1];
+        offender.banned_until = now.saturating_add(duration_nanos(ban));
+        offender.since = offender.banned_until;
+        drop(offenders);
+
+        let key = key.to_string();
+        self.listeners.emit(|policy| Event::Escalated {
+            policy,
+            key: &key,
+            level,
+            ban,
+        });
+        self.verdict(level, Denied::new(ban))
+    }
+
+    fn verdict(&self, level: u32, denied: Denied) -> Verdict {
+        if level <= self.challenge_levels {
+            Verdict::Challenge { level, denied }
+        } else {
+            Verdict::Deny(denied)
+        }
+    }
+
+    /// The rest of the ban of `key`, if it is banned. Unlike [`check`](Self::check), this does
+    /// not charge the limiter.
+    pub fn banned<Q>(&self, key: &Q) -> Option<Denied>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.limiter.now_nanos();
+        let offenders = self.offenders.lock();
+        let offender = offenders.get(key)?;
+        (offender.banned_until > now)
+            .then(|| Denied::new(Duration::from_nanos(offender.banned_until - now)))
+    }
+
+    /// `key` solved its challenge: lift its ban and forget its strikes. It keeps its level, so
+    /// the next escalation goes one higher.
+    pub fn pass_challenge<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.limiter.now_nanos();
+        if let Some(offender) = self.offenders.lock().get_mut(key) {
+            if offender.banned_until > now && offender.level <= self.challenge_levels {
+                offender.banned_until = now;
+                offender.since = now;
+                offender.strikes = 0;
+            }
+        }
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    pub fn pass<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + Display + ?Sized,
+    {
+        self.check(key).is_ok()
+    }
+
+    /// Current escalation level of `key`, 0 if it is in good standing.
+    pub fn level<Q>(&self, key: &Q) -> u32
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.limiter.now_nanos();
+        self.offenders.lock().get_mut(key).map_or(0, |offender| {
+            offender.decay(now, self.decay);
+            offender.level
+        })
+    }
+
+    /// Forget keys that decayed back to good standing, and drop idle entries of the limiter.
+    pub fn retain_recent(&self) {
+        let now = self.limiter.now_nanos();
+        self.offenders.lock().retain(|_, offender| {
+            offender.decay(now, self.decay);
+            offender.level > 0 || offender.strikes > 0
+        });
+        self.limiter.retain_recent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::clock::MockClock;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_escalation_ladder() {
+        let clock = MockClock::new(1_000_000);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let limiter: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .build();
+        let escalation = Escalation::new(limiter)
+            .ladder([Duration::from_secs(60), Duration::from_secs(600)])
+            .strikes(3)
+            .decay(Duration::from_secs(3600))
+            .named("login")
+            .listener(move |event: &Event<'_>| sink.lock().push(format!("{event:?}")));
+
+        assert!(escalation.pass("bot"));
+        assert!(!escalation.pass("bot"));
+        assert!(!escalation.pass("bot"));
+        assert_eq!(
+            escalation.check("bot").unwrap_err().retry_after(),
+            Duration::from_secs(60)
+        );
+        assert_eq!(escalation.level("bot"), 1);
+        // banned even though the limiter would admit it again
+        clock.forward(Duration::from_secs(30));
+        assert!(!escalation.pass("bot"));
+        assert!(escalation.pass("human"));
+
+        clock.forward(Duration::from_secs(30));
+        assert!(escalation.pass("bot"));
+        for _ in 0..3 {
+            assert!(!escalation.pass("bot"));
+        }
+        assert_eq!(escalation.level("bot"), 2);
+        assert_eq!(
+            *events.lock(),
+            [
+                r#"Escalated { policy: "login", key: "bot", level: 1, ban: 60s }"#,
+                r#"Escalated { policy: "login", key: "bot", level: 2, ban: 600s }"#,
+            ]
+        );
+
+        // one level per hour of good behaviour after the ban
+        clock.forward(Duration::from_secs(600 + 3600));
+        assert_eq!(escalation.level("bot"), 1);
+        clock.forward(Duration::from_secs(3600));
+        escalation.retain_recent();
+        assert_eq!(escalation.level("bot"), 0);
+    }
+
+    #[test]
+    fn test_escalation_challenge() {
+        let clock = MockClock::new(1_000_000);
+        let limiter: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .build();
+        let escalation = Escalation::new(limiter)
+            .ladder([Duration::from_secs(60), Duration::from_secs(600)])
+            .strikes(1)
+            .challenge_levels(1);
+
+        assert_eq!(escalation.decide("user", 1), Verdict::Allow);
+        assert!(matches!(
+            escalation.decide("user", 1),
+            Verdict::Challenge { level: 1, .. }
+        ));
+        escalation.pass_challenge("user");
+        clock.forward(Duration::from_secs(1));
+        assert_eq!(escalation.decide("user", 1), Verdict::Allow);
+        // past the challenge levels, bans are hard and cannot be solved
+        assert!(matches!(escalation.decide("user", 1), Verdict::Deny(_)));
+        escalation.pass_challenge("user");
+        assert!(!escalation.pass("user"));
+    }
+}
@@ -0,0 +1,166 @@
+//! Comparing a candidate policy against the enforced one on live traffic.
+
+use crate::describe::{Describe, Description};
+use crate::gcra::{Denied, Headroom, Policy};
+use crate::sync::{AtomicU64, Ordering};
+
+/// Enforces `current` while asking `candidate` about every request in shadow, counting where
+/// their decisions diverge.
+///
+/// The candidate sees all traffic, including what `current` denies, and its decisions are never
+/// acted on. Refunds only go to `current`, as the candidate may not have admitted the request.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Evaluate, Gcra, Policy, Quota};
+///
+/// let current = Gcra::builder().quota(Quota::per_second(10)).build();
+/// let candidate = Gcra::builder().quota(Quota::per_second(5)).build();
+/// let policy = Evaluate::new(current, candidate);
+/// for _ in 0..10 {
+///     assert!(policy.check().is_ok());
+/// }
+/// assert_eq!(policy.stats().candidate_denied, 5);
+/// ```
+pub struct Evaluate<A, B> {
+    current: A,
+    candidate: B,
+    agreed: AtomicU64,
+    current_denied: AtomicU64,
+    candidate_denied: AtomicU64,
+}
+
+/// How the decisions of the two sides of an [`Evaluate`] compared.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EvaluateStats {
+    /// Requests both admitted or both denied.
+    pub agreed: u64,
+    /// Requests denied by the current policy that the candidate would have admitted.
+    pub current_denied: u64,
+    /// Requests admitted by the current policy that the candidate would have denied.
+    pub candidate_denied: u64,
+}
+
+impl EvaluateStats {
+    /// Fraction of the requests on which the two policies disagreed, 0 if none were checked.
+    pub fn divergence(&self) -> f64 {
+        let diverged = self.current_denied + self.candidate_denied;
+        let total = self.agreed + diverged;
+        if total == 0 {
+            return 0.0;
+        }
+        diverged as f64 / total as f64
+    }
+}
+
+impl<A, B> Evaluate<A, B> {
+    pub fn new(current: A, candidate: B) -> Self {
+        Evaluate {
+            current,
+            candidate,
+            agreed: AtomicU64::new(0),
+            current_denied: AtomicU64::new(0),
+            candidate_denied: AtomicU64::new(0),
+        }
+    }
+
+    pub fn current(&self) -> &A {
+        &self.current
+    }
+
+    pub fn candidate(&self) -> &B {
+        &self.candidate
+    }
+
+    pub fn stats(&self) -> EvaluateStats {
+        EvaluateStats {
+            agreed: self.agreed.load(Ordering::Relaxed),
+            current_denied: self.current_denied.load(Ordering::Relaxed),
+            candidate_denied: self.candidate_denied.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<A, B> Policy for Evaluate<A, B>
+where
+    A: Policy,
+    B: Policy,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        let decision = self.current.check_n(n);
+        let counter = match (decision.is_ok(), self.candidate.check_n(n).is_ok()) {
+            (true, false) => &self.candidate_denied,
+            (false, true) => &self.current_denied,
+            _ => &self.agreed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        decision
+    }
+
+    fn refund(&self) {
+        self.current.refund();
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        self.current.headroom()
+    }
+}
+
+/// The current policy, then the candidate, which is only asked for its verdict.
+impl<A, B> Describe for Evaluate<A, B>
+where
+    A: Describe,
+    B: Describe,
+{
+    fn describe(&self) -> Description {
+        Description::new("evaluate")
+            .child(self.current.describe())
+            .child(self.candidate.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::gcra::GcraBuilder;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_evaluate() {
+        let clock = MockClock::new(1_000_000);
+        let current = GcraBuilder::new()
+            .clock(&clock)
+            .quota(Quota::per_second(2))
+            .build();
+        let candidate = GcraBuilder::new()
+            .clock(&clock)
+            .quota(Quota::per_second(4))
+            .build();
+        let policy = Evaluate::new(current, candidate);
+        // only the current policy's decisions are enforced
+        let passed = (0..5).filter(|_| policy.pass()).count();
+        assert_eq!(passed, 2);
+        assert_eq!(
+            policy.stats(),
+            EvaluateStats {
+                agreed: 3,
+                current_denied: 2,
+                candidate_denied: 0,
+            }
+        );
+        assert_eq!(policy.stats().divergence(), 0.4);
+
+        clock.forward(Duration::from_secs(10));
+        let policy = Evaluate::new(policy.candidate(), policy.current());
+        assert_eq!((0..3).filter(|_| policy.pass()).count(), 3);
+        assert_eq!(policy.stats().candidate_denied, 1);
+    }
+}
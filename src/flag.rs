@@ -0,0 +1,263 @@
+//! Turning limits on and off, or changing them, from a feature flag system.
+//!
+//! A flag client or config watcher is read through [`FlagSource`], which any `Fn() -> T`
+//! closure implements. [`Toggle`] enables any policy by a `bool` flag, and [`QuotaSwitch`] takes
+//! its quota from an `Option<Quota>` flag. Both read the flag once per request, so a change
+//! applies from the next request on, and every decision is made under a single value of it.
+
+use crate::clock::{Clock, SystemClock};
+use crate::describe::{self, Describe, Description};
+use crate::gcra::{available, conform_n, duration_nanos, rescale, Denied, Headroom, Policy};
+use crate::quota::Quota;
+use crate::sync::Mutex;
+
+/// The current value of a remotely controlled setting. Reading it should be cheap, e.g. a
+/// cached value the client refreshes in the background.
+pub trait FlagSource<T> {
+    fn current(&self) -> T;
+}
+
+impl<F, T> FlagSource<T> for F
+where
+    F: Fn() -> T,
+{
+    fn current(&self) -> T {
+        self()
+    }
+}
+
+/// Enforces `policy` while the flag is on, and admits everything without charging the policy
+/// while it is off.
+///
+/// # Example
+/// ```
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use ratelimit::{Gcra, Policy, Quota, Toggle};
+///
+/// let enabled = AtomicBool::new(false);
+/// let policy = Toggle::new(
+///     || enabled.load(Ordering::Relaxed),
+///     Gcra::builder().quota(Quota::per_second(1)).build(),
+/// );
+/// assert!(policy.check().is_ok() && policy.check().is_ok());
+/// enabled.store(true, Ordering::Relaxed);
+/// assert!(policy.check().is_ok());
+/// assert!(policy.check().is_err());
+/// ```
+pub struct Toggle<F, P> {
+    flag: F,
+    policy: P,
+}
+
+impl<F, P> Toggle<F, P> {
+    pub fn new(flag: F, policy: P) -> Self {
+        Toggle { flag, policy }
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+}
+
+impl<F, P> Policy for Toggle<F, P>
+where
+    F: FlagSource<bool>,
+    P: Policy,
+{
+    fn check(&self) -> Result<(), Denied> {
+        if !self.flag.current() {
+            return Ok(());
+        }
+        self.policy.check()
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        if !self.flag.current() {
+            return Ok(());
+        }
+        self.policy.check_n(n)
+    }
+
+    fn refund(&self) {
+        if self.flag.current() {
+            self.policy.refund();
+        }
+    }
+
+    /// `None` while off.
+    fn headroom(&self) -> Option<Headroom> {
+        if !self.flag.current() {
+            return None;
+        }
+        self.policy.headroom()
+    }
+}
+
+impl<F, P> Describe for Toggle<F, P>
+where
+    F: FlagSource<bool>,
+    P: Describe,
+{
+    fn describe(&self) -> Description {
+        Description::new("toggle")
+            .setting("enabled", self.flag.current())
+            .child(self.policy.describe())
+    }
+}
+
+/// A GCRA whose quota is read from a flag on every request, unlimited while the flag is `None`.
+///
+/// The state carries over a change of quota as with [`Gcra::swap_quota`](crate::Gcra::swap_quota):
+/// the share of the burst capacity in use stays the same. The state is kept while the flag is
+/// `None`, but nothing is charged to it.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Policy, Quota, QuotaSwitch};
+///
+/// let policy = QuotaSwitch::new(|| Some(Quota::per_second(1)));
+/// assert!(policy.check().is_ok());
+/// assert!(policy.check().is_err());
+/// ```
+pub struct QuotaSwitch<F, C = SystemClock> {
+    flag: F,
+    clock: C,
+    state: Mutex<SwitchState>,
+}
+
+#[derive(Default)]
+struct SwitchState {
+    // in ns
+    tat: u64,
+    // in ns, `gap` and `tolerance` the TAT was last charged under
+    params: Option<(u64, u64)>,
+}
+
+impl<F> QuotaSwitch<F, SystemClock> {
+    pub fn new(flag: F) -> Self {
+        QuotaSwitch {
+            flag,
+            clock: SystemClock,
+            state: Mutex::new(SwitchState::default()),
+        }
+    }
+}
+
+impl<F, C> QuotaSwitch<F, C> {
+    pub fn clock<NC>(self, clock: NC) -> QuotaSwitch<F, NC> {
+        QuotaSwitch {
+            flag: self.flag,
+            clock,
+            state: self.state,
+        }
+    }
+}
+
+impl SwitchState {
+    /// The TAT under `quota`, rescaled if the quota changed.
+    fn tat(&mut self, quota: Quota, now: u64) -> &mut u64 {
+        let params = (
+            duration_nanos(quota.gap()),
+            duration_nanos(quota.tolerance()),
+        );
+        if let Some(previous) = self.params.replace(params) {
+            if previous != params {
+                self.tat = rescale(self.tat, now, previous, params);
+            }
+        }
+        &mut self.tat
+    }
+}
+
+impl<F, C> Policy for QuotaSwitch<F, C>
+where
+    F: FlagSource<Option<Quota>>,
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        let Some(quota) = self.flag.current() else {
+            return Ok(());
+        };
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        conform_n(
+            state.tat(quota, now),
+            now,
+            duration_nanos(quota.gap()),
+            duration_nanos(quota.tolerance()),
+            n,
+        )
+    }
+
+    fn refund(&self) {
+        if let Some(quota) = self.flag.current() {
+            let now = self.clock.now_nanos();
+            let mut state = self.state.lock();
+            let tat = state.tat(quota, now);
+            *tat = tat.saturating_sub(duration_nanos(quota.gap()));
+        }
+    }
+
+    /// `None` while unlimited.
+    fn headroom(&self) -> Option<Headroom> {
+        let quota = self.flag.current()?;
+        let now = self.clock.now_nanos();
+        Some(Headroom {
+            remaining: available(
+                *self.state.lock().tat(quota, now),
+                now,
+                duration_nanos(quota.gap()),
+                duration_nanos(quota.tolerance()),
+            ),
+            refill_rate: quota.rate() as f64,
+        })
+    }
+}
+
+/// The quota of the flag at the time, or no settings while unlimited.
+impl<F, C> Describe for QuotaSwitch<F, C>
+where
+    F: FlagSource<Option<Quota>>,
+{
+    fn describe(&self) -> Description {
+        match self.flag.current() {
+            Some(quota) => describe::gcra(
+                "quota_switch",
+                duration_nanos(quota.gap()),
+                duration_nanos(quota.tolerance()),
+            ),
+            None => Description::new("quota_switch").setting("mode", "unlimited"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_quota_switch() {
+        let clock = MockClock::new(1_000_000);
+        let quota = Mutex::new(None);
+        let policy = QuotaSwitch::new(|| *quota.lock()).clock(&clock);
+        assert!((0..100).all(|_| policy.pass()));
+        assert_eq!(policy.headroom(), None);
+
+        *quota.lock() = Some(Quota::per_second(10));
+        assert_eq!((0..20).filter(|_| policy.pass()).count(), 10);
+        // the capacity is used up under the new quota too
+        *quota.lock() = Some(Quota::per_second(2));
+        assert!(!policy.pass());
+        clock.forward(Duration::from_millis(500));
+        assert!(policy.pass());
+        assert!(!policy.pass());
+    }
+}
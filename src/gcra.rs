@@ -1,162 +1,640 @@
 //! Implementation of generic cell rate algorithm(https://en.wikipedia.org/wiki/Generic_cell_rate_algorithm)
+//!
+//! GCRA has two classic descriptions, the continuous-state leaky bucket and virtual scheduling. They
+//! make exactly the same decisions, so [`LeakyBucket`] and [`VirtualScheduling`] are both names for
+//! [`Gcra`], and differ only in how they are usually configured: a rate and an extra burst for the
+//! former, emission interval (`gap`) and `tolerance` for the latter. [`GcraBuilder`] accepts both.
 
+use std::sync::Arc;
 use std::time::Duration;
 
-use parking_lot::Mutex;
-
-use crate::clock::{Clock, MockClock, SystemClock};
+use crate::clock::{Clock, MockClock, SystemClock, Timestamp, NANOS_PER_MS};
+use crate::config::{ConfigError, LimiterConfig};
+use crate::describe::{self, Describe, Description};
+use crate::quota::Quota;
+use crate::sync::{AtomicU64, Mutex, Ordering};
 
 pub trait Policy {
-    fn pass(&self) -> bool;
+    /// Decide on one request. A denial tells how long to wait before it would conform.
+    fn check(&self) -> Result<(), Denied>;
+
+    /// Decide on one request like [`check`](Self::check), and tell how many more would be
+    /// admitted right after an admitted one. By default, that is the [`headroom`](Self::headroom)
+    /// once admitted.
+    fn check_remaining(&self) -> Result<Allowed, Denied> {
+        self.check()?;
+        Ok(Allowed {
+            remaining: self.headroom().map(|headroom| headroom.remaining),
+        })
+    }
+
+    /// Whether [`check`](Self::check) admits the request, dropping the `retry_after` of a
+    /// denial. Hidden by the `strict-api` feature, to steer callers to `check`.
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass(&self) -> bool {
+        self.check().is_ok()
+    }
+
+    /// Decide on a request worth `n` cells at once, all or nothing. By default, more than one
+    /// cell is denied with a `retry_after` of [`Duration::MAX`], for policies that cannot weigh
+    /// requests: waiting would never help.
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        match n {
+            0 => Ok(()),
+            1 => self.check(),
+            _ => Err(Denied::new(Duration::MAX)),
+        }
+    }
+
+    /// Whether [`check_n`](Self::check_n) admits the request. Hidden by the `strict-api`
+    /// feature, as [`pass`](Self::pass).
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass_n(&self, n: u64) -> bool {
+        self.check_n(n).is_ok()
+    }
+
+    /// Give back one admitted request that was not used after all. Policies that cannot take
+    /// requests back ignore this.
+    fn refund(&self) {}
+
+    /// What the policy could admit right now, for policies that keep count.
+    fn headroom(&self) -> Option<Headroom> {
+        None
+    }
 }
 
-pub struct LeakyBucket<C = SystemClock> {
-    clock: C,
-    state: Mutex<State>,
-    burst: u64,
-    rate: u64,
+/// Capacity of a [`Policy`] at one point in time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Headroom {
+    /// Requests that would be admitted right now.
+    pub remaining: u64,
+    /// Requests per second coming back over time.
+    pub refill_rate: f64,
 }
 
-struct State {
-    level: u64,
-    lct: u64,
+impl<P: Policy + ?Sized> Policy for &P {
+    fn check(&self) -> Result<(), Denied> {
+        (**self).check()
+    }
+
+    fn check_remaining(&self) -> Result<Allowed, Denied> {
+        (**self).check_remaining()
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass(&self) -> bool {
+        (**self).pass()
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        (**self).check_n(n)
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass_n(&self, n: u64) -> bool {
+        (**self).pass_n(n)
+    }
+
+    fn refund(&self) {
+        (**self).refund()
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        (**self).headroom()
+    }
 }
 
-impl LeakyBucket<SystemClock> {
-    pub fn builder() -> LeakyBucketBuilder<SystemClock> {
-        LeakyBucketBuilder {
-            clock: SystemClock,
-            burst: 0,
-            rate: 0,
-        }
+impl<P: Policy + ?Sized> Policy for Arc<P> {
+    fn check(&self) -> Result<(), Denied> {
+        (**self).check()
+    }
+
+    fn check_remaining(&self) -> Result<Allowed, Denied> {
+        (**self).check_remaining()
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass(&self) -> bool {
+        (**self).pass()
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        (**self).check_n(n)
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    fn pass_n(&self, n: u64) -> bool {
+        (**self).pass_n(n)
+    }
+
+    fn refund(&self) {
+        (**self).refund()
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        (**self).headroom()
     }
 }
 
-pub struct LeakyBucketBuilder<C> {
-    clock: C,
-    burst: u64,
-    rate: u64,
+/// A request admitted by [`Policy::check_remaining`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allowed {
+    /// Requests that would still be admitted right after this one, `None` for policies that do
+    /// not keep count.
+    pub remaining: Option<u64>,
 }
 
-impl<C> LeakyBucketBuilder<C> {
-    pub fn clock<NC>(self, clock: NC) -> LeakyBucketBuilder<NC> {
-        LeakyBucketBuilder {
-            clock,
-            burst: self.burst,
-            rate: self.rate,
-        }
+/// A request that did not conform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Denied {
+    retry_after: Duration,
+}
+
+impl Denied {
+    pub fn new(retry_after: Duration) -> Self {
+        Denied { retry_after }
     }
 
-    pub fn burst(mut self, extra_qps: u64) -> LeakyBucketBuilder<C> {
-        self.burst = extra_qps;
-        self
+    /// How long until the same request would be admitted, assuming nothing else is admitted in
+    /// the meantime.
+    pub fn retry_after(&self) -> Duration {
+        self.retry_after
     }
+}
 
-    pub fn rate(mut self, qps: u64) -> LeakyBucketBuilder<C> {
-        self.rate = qps;
-        self
+impl std::fmt::Display for Denied {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rate limited, retry after {:?}", self.retry_after)
     }
+}
 
-    pub fn build(self) -> LeakyBucket<C> {
-        LeakyBucket {
-            clock: self.clock,
-            state: Mutex::new(State {
-                level: self.burst,
-                lct: 0,
-            }),
-            burst: self.burst,
-            rate: self.rate,
-        }
+impl std::error::Error for Denied {}
+
+// GCRA state, i.e. TATs, `gap` and `tolerance`, is kept in nanoseconds, as clocks read, so a
+// gap below a millisecond keeps its precision and rounding cannot add up over a long uptime.
+// Times kept in milliseconds, as in persisted state, are converted at the boundary.
+
+/// A time in ms in the unit of GCRA state.
+pub(crate) fn to_nanos(now: Timestamp) -> u64 {
+    now.saturating_mul(NANOS_PER_MS)
+}
+
+/// A time in the unit of GCRA state in ms, rounded up so it is never early.
+pub(crate) fn to_millis(nanos: u64) -> Timestamp {
+    nanos.div_ceil(NANOS_PER_MS)
+}
+
+/// A duration in the unit of GCRA state, saturating at `u64::MAX`, about 584 years.
+pub(crate) fn duration_nanos(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// GCRA, tracked as a theoretical arrival time (TAT).
+///
+/// A request conforms if it arrives no more than `tolerance` before the TAT. Each conforming
+/// request pushes the TAT one `gap` further.
+///
+/// # Hot path
+/// [`check`](Policy::check), [`pass`](Policy::pass), [`check_n`](Gcra::check_n) and
+/// [`pass_n`](Gcra::pass_n) never allocate and never lock. A denied request only loads its
+/// state; an admitted one moves the TAT with a single compare-and-swap, retried only if another
+/// thread moved it first. This is part of the API contract. Wrappers such as [`Limiter`](crate::Limiter) add
+/// their own bookkeeping on top. While [`swap_quota`](Gcra::swap_quota) is under way, requests
+/// wait for it to finish.
+pub struct Gcra<C = SystemClock> {
+    pub(crate) clock: C,
+    pub(crate) tat: AtomicU64, // theorical arrival time, in ns
+    // one more than the clock reading when the policy was frozen, 0 if it is not
+    frozen_at: AtomicU64,
+    // in ns, as the TAT, see `swap_quota`
+    tolerance: AtomicU64,
+    gap: AtomicU64,
+    // twice the number of swaps, plus one while a swap is under way, as in a seqlock
+    epoch: AtomicU64,
+    // held by `swap_quota` and `thaw`, which move the TAT outside of requests
+    swapping: Mutex<()>,
+}
+
+/// The configuration of a [`Gcra`] at one point, see [`Gcra::swap_quota`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcraVersion {
+    /// 0 as built, and one more after every swap.
+    pub epoch: u64,
+    pub gap: Duration,
+    pub tolerance: Duration,
+}
+
+pub type LeakyBucket<C = SystemClock> = Gcra<C>;
+
+pub type VirtualScheduling<C = SystemClock> = Gcra<C>;
+
+impl Gcra<SystemClock> {
+    pub fn builder() -> GcraBuilder<SystemClock> {
+        GcraBuilder::new().clock(SystemClock)
+    }
+
+    /// Build the limiter called `name` from `RATELIMIT_<NAME>_RATE` and `_BURST`, see
+    /// [`LimiterConfig::from_env`](crate::LimiterConfig::from_env). A `_MODE` other than `gcra`
+    /// or `leaky_bucket` is an error.
+    pub fn from_env(name: &str) -> Result<Self, ConfigError> {
+        let quota = LimiterConfig::from_env(name)?.into_gcra_quota(name)?;
+        Ok(Self::builder().quota(quota).build())
     }
 }
 
-impl<C> Policy for LeakyBucket<C>
+impl<C> Policy for Gcra<C>
 where
     C: Clock,
 {
-    fn pass(&self) -> bool {
-        let mut state = self.state.lock();
-        let now = self.clock.now();
-        let dur_in_ms = now - state.lct;
-        let leaked = dur_in_ms * self.rate / 1000;
-        let new_level = state.level as i64 - leaked as i64;
-        if new_level >= (self.rate + self.burst) as i64 {
-            false
-        } else {
-            state.level = std::cmp::max(0, new_level) as u64 + 1;
-            state.lct = now;
-            true
-        }
+    fn check(&self) -> Result<(), Denied> {
+        self.check_at(self.now())
     }
-}
 
-impl LeakyBucket<MockClock> {
-    pub fn forward(&mut self, dur: Duration) {
-        self.clock.forward(dur);
+    fn check_remaining(&self) -> Result<Allowed, Denied> {
+        let now = self.now();
+        let mut remaining = 0;
+        self.update(|tat, gap, tolerance| {
+            conform(tat, now, gap, tolerance)?;
+            remaining = available(*tat, now, gap, tolerance);
+            Ok(())
+        })?;
+        Ok(Allowed {
+            remaining: Some(remaining),
+        })
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        self.check_n_at(self.now(), n)
+    }
+
+    fn refund(&self) {
+        self.refund_n(1);
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        let now = self.now();
+        let (tat, gap, tolerance) = self.state();
+        Some(Headroom {
+            remaining: available(tat, now, gap, tolerance),
+            refill_rate: 1e9 / gap as f64,
+        })
     }
 }
 
-impl<C> LeakyBucket<C>
+impl<C> Describe for Gcra<C>
 where
     C: Clock,
 {
-    pub fn decorate<'a, Req, Resp>(
-        &'a self,
-        mut f: impl FnMut(Req) -> Resp + 'a,
-    ) -> impl FnMut(Req) -> Result<Resp, Req> + 'a {
-        move |req| {
-            if self.pass() {
-                Ok(f(req))
-            } else {
-                Err(req)
+    fn describe(&self) -> Description {
+        let (gap, tolerance) = self.params();
+        let description = describe::gcra("gcra", gap, tolerance);
+        if self.is_frozen() {
+            return description.setting("mode", "frozen");
+        }
+        description
+    }
+}
+
+// The clock is only read by `Policy::check`, so a `Gcra<()>` can serve as bare state for callers
+// that keep time themselves. Times passed in are clock readings, in ns.
+impl<C> Gcra<C> {
+    pub(crate) fn check_at(&self, now: u64) -> Result<(), Denied> {
+        self.update(|tat, gap, tolerance| conform(tat, now, gap, tolerance))
+    }
+
+    pub(crate) fn check_n_at(&self, now: u64, n: u64) -> Result<(), Denied> {
+        self.update(|tat, gap, tolerance| conform_n(tat, now, gap, tolerance, n))
+    }
+
+    /// Account for a request that was admitted regardless of this policy's decision.
+    pub(crate) fn charge_at(&self, now: u64) {
+        let _ = self.update(|tat, gap, _| {
+            *tat = std::cmp::max(*tat, now).saturating_add(gap);
+            Ok::<_, ()>(())
+        });
+    }
+
+    /// Give back `n` cells of an admitted request.
+    pub(crate) fn refund_n(&self, n: u64) {
+        let _ = self.update(|tat, gap, _| {
+            *tat = tat.saturating_sub(gap.saturating_mul(n));
+            Ok::<_, ()>(())
+        });
+    }
+
+    /// Take all cells that conform at `now`, at most `max`, or none if fewer than `min` do.
+    pub(crate) fn take_at(&self, now: u64, min: u64, max: u64) -> u64 {
+        let mut taken = 0;
+        let _ = self.update(|tat, gap, tolerance| {
+            taken = std::cmp::min(available(*tat, now, gap, tolerance), max);
+            if taken < min {
+                taken = 0;
+            }
+            conform_n(tat, now, gap, tolerance, taken)
+        });
+        taken
+    }
+
+    /// The TAT, in ns.
+    pub(crate) fn load_tat(&self) -> u64 {
+        self.tat.load(Ordering::Acquire)
+    }
+
+    /// `gap` and `tolerance`, in ns.
+    pub(crate) fn params(&self) -> (u64, u64) {
+        let (_, gap, tolerance) = self.state();
+        (gap, tolerance)
+    }
+
+    /// The TAT with the `gap` and `tolerance` it was computed with, all in ns.
+    pub(crate) fn state(&self) -> (u64, u64, u64) {
+        let (_, tat, gap, tolerance) = self.read();
+        (tat, gap, tolerance)
+    }
+
+    /// The epoch, the TAT, `gap` and `tolerance`, all from the same configuration: read again
+    /// if a swap started in the meantime, and waited out while one is under way.
+    fn read(&self) -> (u64, u64, u64, u64) {
+        loop {
+            let epoch = self.epoch.load(Ordering::Acquire);
+            if epoch & 1 == 0 {
+                let tat = self.tat.load(Ordering::Acquire);
+                let gap = self.gap.load(Ordering::Acquire);
+                let tolerance = self.tolerance.load(Ordering::Acquire);
+                if self.epoch.load(Ordering::Acquire) == epoch {
+                    return (epoch, tat, gap, tolerance);
+                }
+            }
+            crate::sync::yield_now();
+        }
+    }
+
+    /// Run `step` on a copy of the TAT and its `gap` and `tolerance`, and publish the result,
+    /// starting over if the TAT moved in the meantime. Nothing is written if `step` fails.
+    fn update<E>(
+        &self,
+        mut step: impl FnMut(&mut u64, u64, u64) -> Result<(), E>,
+    ) -> Result<(), E> {
+        loop {
+            let (_, current, gap, tolerance) = self.read();
+            let mut tat = current;
+            step(&mut tat, gap, tolerance)?;
+            if tat == current {
+                return Ok(());
+            }
+            // fails if a swap moved the TAT since, see `swap_quota`
+            if self
+                .tat
+                .compare_exchange(current, tat, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                return Ok(());
             }
         }
     }
 }
 
-pub struct VirtualScheduling<C = SystemClock> {
-    clock: C,
-    tat: Mutex<u64>, // theorical arrival time
-    tolerance: u64,
-    gap: u64,
+/// How many cells conform at `now`, i.e. the largest `n` for which `conform_n` would succeed.
+/// Unbounded, `u64::MAX`, if `gap` is zero. All times are in ns.
+pub(crate) fn available(tat: u64, now: u64, gap: u64, tolerance: u64) -> u64 {
+    if gap == 0 {
+        return u64::MAX;
+    }
+    now.saturating_add(tolerance)
+        .checked_sub(std::cmp::max(tat, now))
+        .map_or(0, |slack| slack / gap + 1)
 }
 
-impl VirtualScheduling {
-    pub fn builder() -> VirtualSchedulingBuilder<SystemClock> {
-        VirtualSchedulingBuilder {
-            clock: SystemClock,
-            tolerance: 0,
-            gap: 0,
-        }
+/// Move `tat` from `(gap, tolerance)` `from` to `to` at `now`, keeping the share of the burst
+/// capacity in use. All times are in ns.
+pub(crate) fn rescale(tat: u64, now: u64, from: (u64, u64), to: (u64, u64)) -> u64 {
+    let window = |(gap, tolerance): (u64, u64)| gap as u128 + tolerance as u128;
+    let ahead = tat.saturating_sub(now) as u128;
+    if ahead == 0 || window(from) == 0 {
+        return tat;
     }
+    let ahead = ahead * window(to) / window(from);
+    now.saturating_add(u64::try_from(ahead).unwrap_or(u64::MAX))
 }
 
-impl<C> Policy for VirtualScheduling<C>
-where
-    C: Clock,
-{
-    fn pass(&self) -> bool {
-        let now = self.clock.now();
-        let mut tat = self.tat.lock();
-        if now + self.tolerance < *tat {
-            false
-        } else {
-            *tat = std::cmp::max(*tat, now) + self.gap;
-            true
-        }
+/// The GCRA step on a bare TAT, shared by every type that keeps GCRA state.
+pub(crate) fn conform(tat: &mut u64, now: u64, gap: u64, tolerance: u64) -> Result<(), Denied> {
+    conform_n(tat, now, gap, tolerance, 1)
+}
+
+/// Charge `n` cells whether they conform or not, and return how long the request should have
+/// waited to conform. All times are in ns.
+pub(crate) fn reserve_n(tat: &mut u64, now: u64, gap: u64, tolerance: u64, n: u64) -> Duration {
+    if n == 0 {
+        return Duration::ZERO;
+    }
+    let earliest = std::cmp::max(*tat, now)
+        .saturating_add(gap.saturating_mul(n - 1))
+        .saturating_sub(tolerance);
+    *tat = std::cmp::max(*tat, now).saturating_add(gap.saturating_mul(n));
+    Duration::from_nanos(earliest.saturating_sub(now))
+}
+
+/// The GCRA step for a request worth `n` cells, which conforms if its last cell would. A request
+/// for more than `tolerance / gap + 1` cells never conforms, and is denied with a `retry_after`
+/// of [`Duration::MAX`]. All times are in ns.
+pub(crate) fn conform_n(
+    tat: &mut u64,
+    now: u64,
+    gap: u64,
+    tolerance: u64,
+    n: u64,
+) -> Result<(), Denied> {
+    if n == 0 {
+        return Ok(());
+    }
+    if gap.saturating_mul(n - 1) > tolerance {
+        trace_internals!(n, gap, tolerance, "gcra denied over capacity");
+        return Err(Denied::new(Duration::MAX));
+    }
+    // measured from now if idle, so a large `n` cannot use up credit from the idle time
+    let earliest = std::cmp::max(*tat, now)
+        .saturating_add(gap.saturating_mul(n - 1))
+        .saturating_sub(tolerance);
+    if now < earliest {
+        trace_internals!(
+            tat = *tat,
+            now,
+            n,
+            earliest,
+            wait_ns = earliest - now,
+            "gcra denied"
+        );
+        Err(Denied::new(Duration::from_nanos(earliest - now)))
+    } else {
+        *tat = std::cmp::max(*tat, now).saturating_add(gap.saturating_mul(n));
+        trace_internals!(tat = *tat, now, n, earliest, "gcra allowed");
+        Ok(())
     }
 }
 
-impl<C> VirtualScheduling<C>
+impl<C> Gcra<C>
 where
     C: Clock,
 {
+    /// Decide on a request worth `n` cells at once, all or nothing.
+    ///
+    /// A request worth more cells than the policy lets through at once, `tolerance / gap + 1`,
+    /// is never admitted.
+    pub fn check_n(&self, n: u64) -> Result<(), Denied> {
+        self.check_n_at(self.now(), n)
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    pub fn pass_n(&self, n: u64) -> bool {
+        self.check_n(n).is_ok()
+    }
+
+    /// Decide on requests worth `costs` cells each, in order, as [`check_n`](Self::check_n)
+    /// would, with a single compare-and-swap for the whole batch.
+    pub fn check_many(&self, costs: &[u64]) -> Vec<Result<(), Denied>> {
+        let now = self.now();
+        let mut decisions = Vec::with_capacity(costs.len());
+        let _ = self.update(|tat, gap, tolerance| {
+            // started over if the TAT moved
+            decisions.clear();
+            decisions.extend(
+                costs
+                    .iter()
+                    .map(|&n| conform_n(tat, now, gap, tolerance, n)),
+            );
+            Ok::<_, ()>(())
+        });
+        decisions
+    }
+
+    /// Wait until a request is admitted, e.g. to pace outbound calls.
+    ///
+    /// A denial tells when the next cell conforms, so this sleeps until then and asks again.
+    /// Waiters are not queued: whichever asks first once a cell conforms gets it. Wrap the policy
+    /// in a [`Limiter`](crate::Limiter) for waiters to be admitted in order, by priority.
+    #[cfg(feature = "async-wait")]
+    pub async fn until_ready(&self) {
+        while let Err(denied) = self.check() {
+            crate::timer::sleep(denied.retry_after()).await;
+        }
+    }
+
+    /// Use up all capacity left, so nothing passes until time refills it.
+    pub fn drain(&self) {
+        let now = self.now();
+        let _ = self.update(|tat, gap, tolerance| {
+            *tat = std::cmp::max(*tat, now.saturating_add(tolerance).saturating_add(gap));
+            Ok::<_, ()>(())
+        });
+    }
+
+    /// Stop time from refilling capacity until [`thaw`](Self::thaw). Requests are still
+    /// admitted from what is left. Together with [`drain`](Self::drain) this quiesces all
+    /// traffic without touching the configured rate.
+    pub fn freeze(&self) {
+        // stored one up, so a clock reading 0 freezes too
+        let frozen_at = self.clock.now_nanos().saturating_add(1);
+        let _ = self
+            .frozen_at
+            .compare_exchange(0, frozen_at, Ordering::AcqRel, Ordering::Acquire);
+    }
+
+    /// Let time refill capacity again. The time spent frozen does not count.
+    pub fn thaw(&self) {
+        // one thaw at a time, so the time spent frozen is added once
+        let _swapping = self.swapping.lock();
+        let Some(frozen_at) = self.frozen_at() else {
+            return;
+        };
+        let frozen_for = self.clock.now_nanos().saturating_sub(frozen_at);
+        // the TAT moves before the clock runs again, so no request sees the time spent frozen
+        // as refilled
+        let _ = self.update(|tat, _, _| {
+            *tat = tat.saturating_add(frozen_for);
+            Ok::<_, ()>(())
+        });
+        self.frozen_at.store(0, Ordering::Release);
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen_at.load(Ordering::Acquire) != 0
+    }
+
+    /// The clock reading when the policy was frozen, in ns.
+    fn frozen_at(&self) -> Option<u64> {
+        self.frozen_at.load(Ordering::Acquire).checked_sub(1)
+    }
+
+    /// The clock's time, or the time of freezing while frozen, in ns.
+    pub(crate) fn now(&self) -> u64 {
+        self.frozen_at().unwrap_or_else(|| self.clock.now_nanos())
+    }
+
+    /// Add `n` cells of capacity on top of what time gives back, e.g. when an upstream grants
+    /// more sends. Capacity never exceeds the burst, so cells that would overflow it are lost.
+    pub fn add_tokens(&self, n: u64) {
+        self.refund_n(n);
+    }
+
+    pub fn version(&self) -> GcraVersion {
+        let (epoch, _, gap, tolerance) = self.read();
+        GcraVersion {
+            epoch: epoch / 2,
+            gap: Duration::from_nanos(gap),
+            tolerance: Duration::from_nanos(tolerance),
+        }
+    }
+
+    /// Install `quota` and return the configuration it replaced, e.g. on a config reload.
+    ///
+    /// The state carries over: the share of the burst capacity in use stays the same, so a
+    /// swap neither hands out a fresh burst nor denies requests the new quota has room for.
+    /// Each request is decided entirely under one configuration or the other: requests wait
+    /// while the swap is under way, and those decided on the old configuration before it are
+    /// rescaled along with the state.
+    pub fn swap_quota(&self, quota: Quota) -> GcraVersion {
+        let _swapping = self.swapping.lock();
+        let previous = self.version();
+        let from = self.params();
+        let to = (
+            duration_nanos(quota.gap()),
+            duration_nanos(quota.tolerance()),
+        );
+        // odd, so readers wait for the new configuration
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.gap.store(to.0, Ordering::Release);
+        self.tolerance.store(to.1, Ordering::Release);
+        let now = self.now();
+        let mut current = self.load_tat();
+        loop {
+            let mut tat = rescale(current, now, from, to);
+            // move the TAT even if the scale keeps it, so a request decided on the old
+            // configuration but not published yet fails its compare-and-swap and starts over;
+            // a nanosecond is far below what the clock tells apart. It moves down, or from 0 up
+            // to `now`, which any TAT up to `now` is the same state as, so swaps back and forth
+            // never bring back a TAT such a request read
+            if tat == current {
+                tat = current.checked_sub(1).unwrap_or(std::cmp::max(now, 1));
+            }
+            match self
+                .tat
+                .compare_exchange(current, tat, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        previous
+    }
+
     pub fn decorate<'a, Req, Resp>(
         &'a self,
         mut f: impl FnMut(Req) -> Resp + 'a,
     ) -> impl FnMut(Req) -> Result<Resp, Req> + 'a {
         move |req| {
-            if self.pass() {
+            if self.check().is_ok() {
                 Ok(f(req))
             } else {
                 Err(req)
@@ -165,60 +643,181 @@ where
     }
 }
 
-impl VirtualScheduling<MockClock> {
-    pub fn forward(&mut self, dur: Duration) {
+impl Gcra<MockClock> {
+    pub fn forward(&self, dur: Duration) {
         self.clock.forward(dur);
     }
 
-    pub fn backward(&mut self, dur: Duration) {
+    pub fn backward(&self, dur: Duration) {
         self.clock.backward(dur);
     }
 }
 
-pub struct VirtualSchedulingBuilder<C> {
+pub type LeakyBucketBuilder<C> = GcraBuilder<C>;
+
+pub type VirtualSchedulingBuilder<C> = GcraBuilder<C>;
+
+/// Builder for [`Gcra`], configured either with `rate`/`burst` or with `gap`/`tolerance`.
+pub struct GcraBuilder<C> {
     clock: C,
     tolerance: u64,
     gap: u64,
+    // last values passed to `rate`/`burst`, the two are only meaningful together
+    rate: u64,
+    burst: u64,
+}
+
+impl GcraBuilder<()> {
+    /// A builder without a clock, for state driven by an outer clock.
+    pub(crate) fn new() -> Self {
+        GcraBuilder {
+            clock: (),
+            tolerance: 0,
+            gap: 0,
+            rate: 0,
+            burst: 0,
+        }
+    }
 }
 
-impl<C> VirtualSchedulingBuilder<C> {
-    pub fn clock<NC>(self, clock: NC) -> VirtualSchedulingBuilder<NC> {
-        VirtualSchedulingBuilder {
+impl<C> GcraBuilder<C> {
+    pub fn clock<NC>(self, clock: NC) -> GcraBuilder<NC> {
+        GcraBuilder {
             clock,
             tolerance: self.tolerance,
             gap: self.gap,
+            rate: self.rate,
+            burst: self.burst,
         }
     }
 
     pub fn tolerance(mut self, tolerance: Duration) -> Self {
-        self.tolerance = tolerance.as_millis() as u64;
+        self.tolerance = duration_nanos(tolerance);
         self
     }
 
+    /// Admit one request every `gap`. Kept to the nanosecond, so rates above 1000/s are exact.
     pub fn gap(mut self, gap: Duration) -> Self {
-        self.gap = gap.as_millis() as u64;
+        self.gap = duration_nanos(gap);
         self
     }
 
-    pub fn build(self) -> VirtualScheduling<C> {
-        VirtualScheduling {
+    /// Set `gap` and `tolerance` from a rate, so that `rate + burst` requests go through at once
+    /// and one more every `1s / rate` after that. See [`Quota`].
+    ///
+    /// # Panics
+    /// Panics if `qps` is zero.
+    pub fn rate(self, qps: u64) -> Self {
+        let quota = Quota::per_second(qps).burst(self.burst);
+        self.quota(quota)
+    }
+
+    /// Allow `extra_qps` requests on top of the rate to go through at once. Takes effect
+    /// together with [`rate`](Self::rate), in either order.
+    pub fn burst(mut self, extra_qps: u64) -> Self {
+        if self.rate == 0 {
+            self.burst = extra_qps;
+            return self;
+        }
+        let quota = Quota::per_second(self.rate).burst(extra_qps);
+        self.quota(quota)
+    }
+
+    /// Set `gap` and `tolerance` from a [`Quota`].
+    pub fn quota(mut self, quota: Quota) -> Self {
+        self.rate = quota.rate();
+        self.burst = quota.extra_burst();
+        self.gap(quota.gap()).tolerance(quota.tolerance())
+    }
+
+    pub fn build(self) -> Gcra<C> {
+        Gcra {
             clock: self.clock,
+            tat: AtomicU64::new(0),
+            frozen_at: AtomicU64::new(0),
+            tolerance: AtomicU64::new(self.tolerance),
+            gap: AtomicU64::new(self.gap),
+            epoch: AtomicU64::new(0),
+            swapping: Mutex::new(()),
+        }
+    }
+}
+
+/// The GCRA step of [`Gcra`] with the TAT behind a mutex rather than moved by a compare-and-swap,
+/// the baseline of the contention benches. Exposed by the `bench-internals` feature.
+#[cfg(any(feature = "bench-internals", test))]
+pub struct LockedGcra<C = SystemClock> {
+    clock: C,
+    gap: u64,
+    tolerance: u64,
+    tat: Mutex<u64>,
+}
+
+#[cfg(any(feature = "bench-internals", test))]
+impl<C> LockedGcra<C> {
+    /// Decide as the [`Gcra`] that `builder` would build.
+    pub fn new(builder: GcraBuilder<C>) -> Self {
+        LockedGcra {
+            clock: builder.clock,
+            gap: builder.gap,
+            tolerance: builder.tolerance,
             tat: Mutex::new(0),
-            tolerance: self.tolerance,
-            gap: self.gap,
         }
     }
 }
 
+#[cfg(any(feature = "bench-internals", test))]
+impl<C> Policy for LockedGcra<C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        let now = self.clock.now_nanos();
+        conform_n(&mut self.tat.lock(), now, self.gap, self.tolerance, n)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
     use crate::clock::MockClock;
 
     use super::*;
 
+    // Counts allocations per thread, so tests running in parallel do not disturb each other.
+    struct CountingAlloc;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAlloc {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
     #[test]
     fn test_leaky_bucket_steady() {
-        let mut rl = LeakyBucket::builder()
+        let rl = LeakyBucket::builder()
             .clock(MockClock::new_now())
             .burst(0)
             .rate(10)
@@ -242,7 +841,7 @@ mod tests {
 
     #[test]
     fn test_leaky_bucket_burst() {
-        let mut rl = LeakyBucket::builder()
+        let rl = LeakyBucket::builder()
             .clock(MockClock::new_now())
             .burst(10)
             .rate(10)
@@ -298,7 +897,7 @@ mod tests {
 
     #[test]
     fn test_virtual_schuduling_steady() {
-        let mut rl = VirtualScheduling::builder()
+        let rl = VirtualScheduling::builder()
             .clock(MockClock::new_now())
             .tolerance(Duration::from_secs(0))
             .gap(Duration::from_millis(100))
@@ -314,9 +913,354 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_virtual_schuduling_rate_burst() {
+        let clock = MockClock::new_now();
+        let leaky = LeakyBucket::builder()
+            .clock(&clock)
+            .burst(5)
+            .rate(10)
+            .build();
+        let vs = VirtualScheduling::builder()
+            .clock(&clock)
+            .burst(5)
+            .rate(10)
+            .build();
+        for _ in 0..5 {
+            for _ in 0..30 {
+                assert_eq!(leaky.pass(), vs.pass());
+            }
+            clock.forward(Duration::from_millis(700));
+        }
+    }
+
+    #[test]
+    fn test_leaky_bucket_uneven_rate() {
+        // 3/s leaks a third of a request every 100ms, which must add up rather than round away
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder().clock(&clock).rate(3).build();
+        let mut admitted = 0;
+        for _ in 0..1000 {
+            clock.forward(Duration::from_millis(100));
+            admitted += rl.pass() as u32;
+        }
+        // a burst of 3 from the first poll, then one every third of a second for 99.9s
+        assert_eq!(admitted, 3 + 299);
+        // the same limit described by its emission interval and tolerance
+        let gap = Duration::from_nanos(333_333_333);
+        let vs = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(gap)
+            .tolerance(2 * gap)
+            .build();
+        assert_eq!(vs.params(), rl.params());
+    }
+
+    #[test]
+    #[should_panic(expected = "rate must be positive")]
+    fn test_leaky_bucket_zero_rate() {
+        LeakyBucket::builder().rate(0).build();
+    }
+
+    #[test]
+    fn test_check_retry_after() {
+        let clock = MockClock::new_now();
+        let rl = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .tolerance(Duration::from_millis(150))
+            .build();
+        assert!(rl.check().is_ok());
+        assert!(rl.check().is_ok());
+        let denied = rl.check().unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_millis(50));
+        clock.forward(denied.retry_after());
+        assert!(rl.check().is_ok());
+    }
+
+    #[test]
+    fn test_check_remaining() {
+        let clock = MockClock::new_now();
+        let rl = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .tolerance(Duration::from_millis(150))
+            .build();
+        assert_eq!(rl.check_remaining().unwrap().remaining, Some(1));
+        assert_eq!(rl.check_remaining().unwrap().remaining, Some(0));
+        let denied = rl.check_remaining().unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_millis(50));
+        assert!(!rl.pass());
+        let bucket = crate::TokenBucket::builder(Quota::per_second(1).burst(2))
+            .clock(&clock)
+            .build();
+        assert_eq!(bucket.check_remaining().unwrap().remaining, Some(2));
+    }
+
+    #[test]
+    fn test_sub_millisecond_gap() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder()
+            .clock(&clock)
+            .quota(Quota::per_second(4000))
+            .build();
+        assert!(rl.check_n(4000).is_ok());
+        let denied = rl.check().unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_micros(250));
+        clock.forward(Duration::from_millis(1));
+        assert!(rl.check_n(4).is_ok());
+        assert!(!rl.pass());
+        // the clock keeps time below a millisecond, so every gap gives back a cell
+        for _ in 0..8 {
+            clock.forward(Duration::from_micros(250));
+            assert!(rl.pass());
+            assert!(!rl.pass());
+        }
+
+        // a third of a second does not round to 333ms, which would admit 9009 in 50 minutes
+        let rl = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_nanos(1_000_000_000 / 3))
+            .tolerance(Duration::from_secs(1))
+            .build();
+        rl.drain();
+        let mut admitted = 0;
+        for _ in 0..3000 {
+            clock.forward(Duration::from_secs(1));
+            while rl.pass() {
+                admitted += 1;
+            }
+        }
+        assert_eq!(admitted, 9000);
+    }
+
+    #[test]
+    fn test_swap_quota() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder()
+            .clock(&clock)
+            .quota(Quota::per_second(10).burst(10))
+            .build();
+        assert!(rl.check_n(10).is_ok());
+        // half the capacity is in use, and stays so under the new quota
+        let previous = rl.swap_quota(Quota::per_second(10));
+        assert_eq!(
+            previous,
+            GcraVersion {
+                epoch: 0,
+                gap: Duration::from_millis(100),
+                tolerance: Duration::from_millis(1900),
+            }
+        );
+        assert_eq!(rl.version().epoch, 1);
+        assert_eq!(rl.headroom().unwrap().remaining, 5);
+
+        rl.swap_quota(Quota::per_second(100));
+        assert_eq!(rl.headroom().unwrap().remaining, 50);
+        assert!(rl.check_n(50).is_ok());
+        assert!(!rl.pass());
+        rl.swap_quota(Quota::per_second(2));
+        assert!(!rl.pass());
+        clock.forward(Duration::from_millis(500));
+        assert!(rl.pass());
+    }
+
+    #[test]
+    fn test_swap_quota_concurrent() {
+        let clock = MockClock::new_now();
+        // the same capacity of 10 at the same time, at different rates
+        let quotas = [
+            Quota::from_gap_burst(Duration::from_millis(500), 8),
+            Quota::from_gap_burst(Duration::from_secs(1), 9),
+        ];
+        assert_eq!(quotas.map(|quota| quota.capacity()), [10, 10]);
+        let rl = LeakyBucket::builder()
+            .clock(&clock)
+            .quota(quotas[0])
+            .build();
+        let admitted = AtomicU64::new(0);
+        let done = crate::sync::AtomicBool::new(false);
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !done.load(Ordering::Acquire) {
+                        if rl.check().is_ok() {
+                            admitted.fetch_add(1, Ordering::Relaxed);
+                        }
+                        std::thread::yield_now();
+                    }
+                });
+            }
+            // swapping on while the capacity is used up, and for a while after
+            let mut swaps = 0;
+            while admitted.load(Ordering::Relaxed) < 10 || swaps < 1000 {
+                rl.swap_quota(quotas[swaps % 2]);
+                swaps += 1;
+                std::thread::yield_now();
+            }
+            done.store(true, Ordering::Release);
+        });
+        // time stood still, so a request decided on one rate but charged on the other would
+        // have let more through
+        assert_eq!(admitted.load(Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn test_swap_quota_moves_idle_tat() {
+        let clock = MockClock::new_now();
+        let quotas = [Quota::per_second(2), Quota::per_second(1)];
+        let rl = LeakyBucket::builder()
+            .clock(&clock)
+            .quota(quotas[0])
+            .build();
+        let mut tats = vec![rl.load_tat()];
+        for swap in 0..4 {
+            rl.swap_quota(quotas[swap % 2]);
+            tats.push(rl.load_tat());
+        }
+        // a request holding any of these TATs must fail its compare-and-swap after a swap
+        let mut distinct = tats.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+        assert_eq!(distinct.len(), tats.len(), "{tats:?}");
+        // still idle, with the capacity of the last quota
+        assert!(rl.check().is_ok());
+        assert!(rl.check().is_err());
+    }
+
+    #[test]
+    fn test_check_n() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        assert!(rl.check_n(4).is_ok());
+        assert!(rl.check_n(6).is_ok());
+        let denied = rl.check_n(3).unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_millis(300));
+        clock.forward(denied.retry_after());
+        assert!(rl.check_n(3).is_ok());
+        assert!(rl.check_n(0).is_ok());
+        assert!(!rl.pass());
+        // idle time does not add up beyond the burst
+        clock.forward(Duration::from_secs(60));
+        assert!(rl.check_n(11).is_err());
+        assert!(rl.check_n(10).is_ok());
+    }
+
+    #[test]
+    fn test_check_n_over_capacity() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        // a burst of 10 is all that ever conforms, however long the wait
+        assert_eq!(rl.check_n(11), Err(Denied::new(Duration::MAX)));
+        assert_eq!(rl.check_n(u64::MAX), Err(Denied::new(Duration::MAX)));
+        assert_eq!(rl.check_n(10), Ok(()));
+        assert_eq!(rl.check_n(11), Err(Denied::new(Duration::MAX)));
+        assert_eq!(
+            rl.check_n(10).unwrap_err().retry_after(),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[test]
+    fn test_add_tokens() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        assert!(rl.check_n(10).is_ok());
+        assert!(!rl.pass());
+        rl.add_tokens(3);
+        assert!(rl.check_n(3).is_ok());
+        assert!(!rl.pass());
+        // added on top of what time gave back
+        clock.forward(Duration::from_millis(200));
+        rl.add_tokens(1);
+        assert!(rl.check_n(3).is_ok());
+        assert!(!rl.pass());
+        // a full bucket does not grow
+        clock.forward(Duration::from_secs(60));
+        rl.add_tokens(5);
+        assert!(rl.check_n(11).is_err());
+        assert!(rl.check_n(10).is_ok());
+    }
+
+    #[test]
+    fn test_locked_gcra() {
+        use crate::testing::{differential, Op};
+
+        // the baseline decides exactly as the atomic GCRA it is measured against
+        let clock = MockClock::new_now();
+        let builder = || VirtualScheduling::builder().clock(&clock).rate(10).burst(5);
+        let atomic = builder().build();
+        let locked = LockedGcra::new(builder());
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        let data: Vec<u8> = (0..4096)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect();
+        assert_eq!(
+            differential(&atomic, &locked, &clock, &Op::decode(&data)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_pass_shims() {
+        // `pass` and `pass_n`, wherever they are forwarded, decide exactly as `check` and
+        // `check_n`; `strict-api` only hides them
+        let clock = MockClock::new_now();
+        let checked = LeakyBucket::builder().clock(&clock).rate(10).build();
+        let passed = Arc::new(LeakyBucket::builder().clock(&clock).rate(10).build());
+        for step in 0..40 {
+            let n = step % 4;
+            assert_eq!(passed.pass_n(n), checked.check_n(n).is_ok());
+            assert_eq!(Policy::pass_n(&&*passed, n), checked.check_n(n).is_ok());
+            assert_eq!(Policy::pass(&passed), checked.check().is_ok());
+            assert_eq!(Policy::pass(&*passed), checked.check().is_ok());
+            clock.forward(Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_default_check_n() {
+        struct Unweighted;
+
+        impl Policy for Unweighted {
+            fn check(&self) -> Result<(), Denied> {
+                Ok(())
+            }
+        }
+
+        assert_eq!(Unweighted.check_n(0), Ok(()));
+        assert_eq!(Unweighted.check_n(1), Ok(()));
+        // a retry would never be admitted either, so callers waiting for it must not spin
+        let denied = Unweighted.check_n(2).unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::MAX);
+    }
+
+    #[test]
+    fn test_check_many() {
+        let clock = MockClock::new_now();
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        // a denied request leaves the cells to those after it
+        assert_eq!(
+            rl.check_many(&[4, 7, 0, 6, 1]),
+            [
+                Ok(()),
+                Err(Denied::new(Duration::from_millis(100))),
+                Ok(()),
+                Ok(()),
+                Err(Denied::new(Duration::from_millis(100))),
+            ]
+        );
+        assert_eq!(rl.check_many(&[]), []);
+    }
+
     #[test]
     fn test_virtual_schuduling_tolerance() {
-        let mut rl = VirtualScheduling::builder()
+        let rl = VirtualScheduling::builder()
             .clock(MockClock::new_now())
             .tolerance(Duration::from_millis(500))
             .gap(Duration::from_secs(1))
@@ -332,4 +1276,214 @@ mod tests {
         rl.forward(Duration::from_secs(1));
         assert!(rl.pass());
     }
+
+    #[test]
+    fn test_hot_path_does_not_allocate() {
+        let vs = VirtualScheduling::builder()
+            .clock(MockClock::new(1_000_000))
+            .rate(10)
+            .build();
+        let before = allocations();
+        for _ in 0..100 {
+            let _ = vs.check();
+            let _ = vs.pass();
+            let _ = vs.check_n(3);
+            let _ = vs.pass_n(2);
+            vs.forward(Duration::from_millis(50));
+        }
+        assert_eq!(allocations(), before);
+    }
+
+    #[test]
+    fn test_drain_freeze() {
+        let rl = LeakyBucket::builder()
+            .clock(MockClock::new(1_000_000))
+            .rate(10)
+            .build();
+        rl.drain();
+        assert_eq!(
+            rl.check().unwrap_err().retry_after(),
+            Duration::from_millis(100)
+        );
+
+        rl.freeze();
+        assert!(rl.is_frozen());
+        rl.forward(Duration::from_secs(10));
+        assert!(!rl.pass());
+        rl.thaw();
+        // the time spent frozen did not refill anything
+        assert!(!rl.pass());
+        rl.forward(Duration::from_millis(100));
+        assert!(rl.pass());
+        assert!(!rl.pass());
+    }
+
+    #[test]
+    fn test_freeze_at_zero() {
+        let rl = LeakyBucket::builder()
+            .clock(MockClock::new(0))
+            .rate(10)
+            .build();
+        rl.drain();
+        rl.freeze();
+        assert!(rl.is_frozen());
+        rl.forward(Duration::from_secs(10));
+        assert!(!rl.pass());
+        rl.thaw();
+        assert!(!rl.is_frozen());
+        assert_eq!(
+            rl.check().unwrap_err().retry_after(),
+            Duration::from_millis(100)
+        );
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_until_ready() {
+        let gcra = Gcra::builder().gap(Duration::from_millis(20)).build();
+        let start = std::time::Instant::now();
+        for _ in 0..3 {
+            gcra.until_ready().await;
+        }
+        // the first cell conforms right away, the others one gap apart
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_update() {
+        loom::model(|| {
+            let rl = Arc::new(
+                VirtualScheduling::builder()
+                    .clock(MockClock::new(1_000))
+                    .gap(Duration::from_secs(1))
+                    .tolerance(Duration::from_secs(1))
+                    .build(),
+            );
+            let threads: Vec<_> = (0..2)
+                .map(|_| {
+                    let rl = rl.clone();
+                    loom::thread::spawn(move || rl.check().is_ok())
+                })
+                .collect();
+            let admitted = threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .filter(|&admitted| admitted)
+                .count();
+            // both fit in the burst, and neither update was lost
+            assert_eq!(admitted, 2);
+            assert!(rl.check().is_err());
+            assert_eq!(rl.load_tat(), to_nanos(1_000) + 2 * NANOS_PER_MS * 1_000);
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_thaw() {
+        loom::model(|| {
+            let rl = Arc::new(
+                LeakyBucket::builder()
+                    .clock(MockClock::new(1_000))
+                    .rate(10)
+                    .build(),
+            );
+            rl.drain();
+            rl.freeze();
+            rl.forward(Duration::from_secs(10));
+            let checking = {
+                let rl = rl.clone();
+                loom::thread::spawn(move || rl.check())
+            };
+            rl.thaw();
+            // frozen or thawed, the check never sees the time spent frozen as refilled
+            assert!(checking.join().unwrap().is_err());
+            assert!(rl.check().is_err());
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_swap_quota() {
+        loom::model(|| {
+            let rl = Arc::new(
+                LeakyBucket::builder()
+                    .clock(MockClock::new(1_000))
+                    .quota(Quota::from_gap_burst(Duration::from_millis(500), 9))
+                    .build(),
+            );
+            let from = rl.params();
+            let checking = {
+                let rl = rl.clone();
+                loom::thread::spawn(move || rl.check().unwrap())
+            };
+            rl.swap_quota(Quota::from_gap_burst(Duration::from_secs(1), 9));
+            checking.join().unwrap();
+            let to = rl.params();
+            // the request was charged before the swap and rescaled with the state, or after it
+            let now = to_nanos(1_000);
+            let before = rescale(now + from.0, now, from, to);
+            assert!([before, now + to.0].contains(&rl.load_tat()));
+        });
+    }
+
+    #[cfg(feature = "debug-internals")]
+    #[test]
+    fn test_trace_internals() {
+        use std::fmt;
+        use std::sync::{Arc, Mutex};
+
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata};
+
+        // "<target>: <fields>" of every event
+        #[derive(Clone, Default)]
+        struct Events(Arc<Mutex<Vec<String>>>);
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0 += &format!(" {}={:?}", field.name(), value);
+            }
+        }
+
+        impl tracing::Subscriber for Events {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, _: &Attributes<'_>) -> Id {
+                Id::from_u64(1)
+            }
+
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(format!("{}:", event.metadata().target()));
+                event.record(&mut fields);
+                self.0.lock().unwrap().push(fields.0);
+            }
+
+            fn enter(&self, _: &Id) {}
+
+            fn exit(&self, _: &Id) {}
+        }
+
+        let clock = MockClock::new(1000);
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        let events = Events::default();
+        tracing::subscriber::with_default(events.clone(), || {
+            assert!(rl.check_n(10).is_ok());
+            assert!(rl.check().is_err());
+        });
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].starts_with("ratelimit::internals: message=gcra allowed"));
+        assert!(events[1].starts_with("ratelimit::internals: message=gcra denied"));
+        assert!(events[1].ends_with("wait_ns=100000000"));
+    }
 }
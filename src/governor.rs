@@ -0,0 +1,173 @@
+//! Limiting the messages of a long-lived connection, e.g. a WebSocket or a streaming RPC.
+//!
+//! A [`MessageGovernor`] belongs to one connection and limits messages per second and bytes per
+//! second together. Instead of a bare yes or no it answers with a [`MessageVerdict`] telling the
+//! connection handler what to do, from asking the peer to slow down to closing the connection
+//! when the peer keeps exceeding its limits.
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{Denied, Gcra, GcraBuilder};
+use crate::quota::Quota;
+
+/// WebSocket close code for a policy violation (RFC 6455, section 7.4.1).
+pub const POLICY_VIOLATION: u16 = 1008;
+
+/// What to do with a message, see [`MessageGovernor::on_message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageVerdict {
+    /// Process the message.
+    Accept,
+    /// Drop the message and tell the peer to slow down, e.g. with an application level frame
+    /// carrying `retry_after`.
+    SlowDown(Denied),
+    /// The peer kept exceeding its limits. Close the connection with this code and reason.
+    Close { code: u16, reason: &'static str },
+}
+
+/// Per-connection limits on messages and bytes.
+///
+/// A message is accepted only if it fits both limits, and then charged against both. A message
+/// larger than the burst of the byte quota is never accepted, so size that burst to at least the
+/// largest message the protocol allows.
+///
+/// After `max_strikes` rejected messages in a row, i.e. without an accepted one in between, the
+/// verdict becomes [`MessageVerdict::Close`] with [`POLICY_VIOLATION`], and stays so.
+///
+/// # Example
+/// ```
+/// use ratelimit::{MessageGovernor, MessageVerdict, Quota};
+///
+/// let mut governor = MessageGovernor::new(
+///     Quota::per_second(50),
+///     Quota::per_second(64 * 1024).burst(64 * 1024),
+/// );
+/// assert_eq!(governor.on_message(512), MessageVerdict::Accept);
+/// ```
+pub struct MessageGovernor<C = SystemClock> {
+    clock: C,
+    messages: Gcra<()>,
+    bytes: Gcra<()>,
+    max_strikes: u32,
+    strikes: u32,
+}
+
+impl MessageGovernor<SystemClock> {
+    pub fn new(messages: Quota, bytes: Quota) -> Self {
+        MessageGovernor {
+            clock: SystemClock,
+            messages: GcraBuilder::new().quota(messages).build(),
+            bytes: GcraBuilder::new().quota(bytes).build(),
+            max_strikes: 10,
+            strikes: 0,
+        }
+    }
+}
+
+impl<C> MessageGovernor<C> {
+    pub fn clock<NC>(self, clock: NC) -> MessageGovernor<NC> {
+        MessageGovernor {
+            clock,
+            messages: self.messages,
+            bytes: self.bytes,
+            max_strikes: self.max_strikes,
+            strikes: self.strikes,
+        }
+    }
+
+    /// Close after this many rejected messages in a row. 10 by default.
+    ///
+    /// # Panics
+    /// Panics if `max_strikes` is zero.
+    pub fn max_strikes(mut self, max_strikes: u32) -> Self {
+        assert!(max_strikes > 0, "max_strikes must be positive");
+        self.max_strikes = max_strikes;
+        self
+    }
+
+    /// Rejected messages since the last accepted one.
+    pub fn strikes(&self) -> u32 {
+        self.strikes
+    }
+}
+
+impl<C> MessageGovernor<C>
+where
+    C: Clock,
+{
+    /// Decide on a message of `len` bytes.
+    pub fn on_message(&mut self, len: usize) -> MessageVerdict {
+        if self.strikes >= self.max_strikes {
+            return self.close();
+        }
+        let now = self.clock.now_nanos();
+        let decision = self.messages.check_at(now).and_then(|()| {
+            self.bytes.check_n_at(now, len as u64).inspect_err(|_| {
+                self.messages.refund_n(1);
+            })
+        });
+        match decision {
+            Ok(()) => {
+                self.strikes = 0;
+                MessageVerdict::Accept
+            }
+            Err(_) if self.strikes + 1 >= self.max_strikes => {
+                self.strikes = self.max_strikes;
+                self.close()
+            }
+            Err(denied) => {
+                self.strikes += 1;
+                MessageVerdict::SlowDown(denied)
+            }
+        }
+    }
+
+    fn close(&self) -> MessageVerdict {
+        MessageVerdict::Close {
+            code: POLICY_VIOLATION,
+            reason: "rate limit exceeded",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_governor_limits_messages_and_bytes() {
+        let clock = MockClock::new(1_000_000);
+        let mut governor = MessageGovernor::new(Quota::per_second(2), Quota::per_second(100))
+            .clock(&clock)
+            .max_strikes(3);
+
+        assert_eq!(governor.on_message(60), MessageVerdict::Accept);
+        // fits the message limit, but not the byte limit
+        assert_eq!(
+            governor.on_message(60),
+            MessageVerdict::SlowDown(Denied::new(Duration::from_millis(200)))
+        );
+        assert_eq!(governor.on_message(40), MessageVerdict::Accept);
+        assert_eq!(governor.strikes(), 0);
+
+        // out of messages: strikes pile up until the connection should be closed
+        assert!(matches!(
+            governor.on_message(1),
+            MessageVerdict::SlowDown(_)
+        ));
+        assert!(matches!(
+            governor.on_message(1),
+            MessageVerdict::SlowDown(_)
+        ));
+        let close = MessageVerdict::Close {
+            code: POLICY_VIOLATION,
+            reason: "rate limit exceeded",
+        };
+        assert_eq!(governor.on_message(1), close);
+        clock.forward(Duration::from_secs(10));
+        assert_eq!(governor.on_message(1), close);
+    }
+}
@@ -0,0 +1,149 @@
+//! GraphQL integration, enabled by the `graphql` feature.
+//!
+//! A GraphQL endpoint serves one cheap field and a query fanning out over thousands of objects
+//! through the same request, so counting requests says little. [`ComplexityLimit`] is an
+//! [async-graphql](https://docs.rs/async-graphql) extension that charges a [`KeyedLimiter`] with
+//! the complexity async-graphql computes for each query during validation instead, through
+//! [`check_n`](KeyedLimiter::check_n). A query over the limit fails before any resolver runs.
+//!
+//! # Example
+//! ```
+//! use std::sync::Arc;
+//! use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+//! use ratelimit::{ComplexityLimit, KeyedLimiter, Quota};
+//!
+//! struct Query;
+//!
+//! #[Object]
+//! impl Query {
+//!     async fn answer(&self) -> u32 {
+//!         42
+//!     }
+//! }
+//!
+//! // a thousand complexity points per second and tenant
+//! let limiter: KeyedLimiter<String> = KeyedLimiter::builder(Quota::per_second(1000)).build();
+//! let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+//!     .extension(ComplexityLimit::new(Arc::new(limiter)))
+//!     .finish();
+//! // the key is taken from the request data
+//! let request = Request::new("{ answer }").data("tenant-a".to_string());
+//! ```
+
+use std::any::Any;
+use std::hash::Hash;
+use std::sync::Arc;
+
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextValidation};
+use async_graphql::{ErrorExtensionValues, ServerError, ValidationResult};
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::Denied;
+use crate::keyed::KeyedLimiter;
+
+/// Charges each query's complexity to the limiter, keyed by the `K` in the request data.
+///
+/// Queries whose request carries no `K` are rejected, so a missing key cannot bypass the limit.
+/// Every query costs at least one cell. Rejected queries fail with an error whose extensions
+/// carry `"code": "RATE_LIMITED"` and `retryAfterMs`.
+pub struct ComplexityLimit<K, C = SystemClock> {
+    limiter: Arc<KeyedLimiter<K, C>>,
+}
+
+impl<K, C> ComplexityLimit<K, C> {
+    pub fn new(limiter: Arc<KeyedLimiter<K, C>>) -> Self {
+        ComplexityLimit { limiter }
+    }
+}
+
+impl<K, C> ExtensionFactory for ComplexityLimit<K, C>
+where
+    K: Hash + Eq + Clone + Any + Send + Sync,
+    C: Clock + Send + Sync + 'static,
+{
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(ComplexityLimitExtension {
+            limiter: self.limiter.clone(),
+        })
+    }
+}
+
+struct ComplexityLimitExtension<K, C> {
+    limiter: Arc<KeyedLimiter<K, C>>,
+}
+
+#[async_graphql::async_trait::async_trait]
+impl<K, C> Extension for ComplexityLimitExtension<K, C>
+where
+    K: Hash + Eq + Clone + Any + Send + Sync,
+    C: Clock + Send + Sync + 'static,
+{
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await?;
+        let Some(key) = ctx.data_opt::<K>() else {
+            return Err(vec![ServerError::new("missing rate limit key", None)]);
+        };
+        let cost = (result.complexity as u64).max(1);
+        match self.limiter.check_n(key, cost) {
+            Ok(()) => Ok(result),
+            Err(denied) => Err(vec![limited(denied)]),
+        }
+    }
+}
+
+fn limited(denied: Denied) -> ServerError {
+    let mut error = ServerError::new(denied.to_string(), None);
+    let mut extensions = ErrorExtensionValues::default();
+    extensions.set("code", "RATE_LIMITED");
+    extensions.set("retryAfterMs", denied.retry_after().as_millis() as u64);
+    error.extensions = Some(extensions);
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{EmptyMutation, EmptySubscription, Object, Request, Schema};
+
+    use crate::clock::MockClock;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn a(&self) -> u32 {
+            1
+        }
+
+        async fn b(&self) -> u32 {
+            2
+        }
+    }
+
+    #[tokio::test]
+    async fn test_complexity_limit() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limiter: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1).burst(2))
+            .clock(clock)
+            .build();
+        let schema = Schema::build(Query, EmptyMutation, EmptySubscription)
+            .extension(ComplexityLimit::new(Arc::new(limiter)))
+            .finish();
+        let query = |tenant: &str| Request::new("{ a b }").data(tenant.to_string());
+
+        assert!(schema.execute(query("a")).await.is_ok());
+        // two fields cost two cells, only one is left
+        let response = schema.execute(query("a")).await;
+        let extensions = response.errors[0].extensions.as_ref().unwrap();
+        assert_eq!(extensions.get("code"), Some(&"RATE_LIMITED".into()));
+        assert_eq!(extensions.get("retryAfterMs"), Some(&1000u64.into()));
+        assert!(schema.execute(query("b")).await.is_ok());
+        assert!(schema.execute(Request::new("{ a }")).await.is_err());
+    }
+}
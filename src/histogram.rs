@@ -0,0 +1,103 @@
+//! Distributions of values seen by a limiter, e.g. how long requests waited.
+
+use crate::sync::{AtomicU64, Ordering};
+
+const BUCKETS: usize = 32;
+
+/// Counts of values in power-of-two buckets: bucket 0 counts zeros, bucket `i` counts values in
+/// `2^(i-1)..2^i`, and the last one also everything above.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Histogram {
+    pub buckets: [u64; BUCKETS],
+    pub count: u64,
+    pub sum: u64,
+}
+
+impl Histogram {
+    /// The values bucket `i` counts are below this, `u64::MAX` for the last bucket.
+    pub fn bucket_limit(i: usize) -> u64 {
+        if i + 1 >= BUCKETS {
+            return u64::MAX;
+        }
+        1 << i
+    }
+
+    /// 0 if nothing was recorded.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// An upper bound of the `q` quantile, e.g. 0.99 for p99: the limit of the bucket it falls
+    /// in. 0 if nothing was recorded.
+    pub fn quantile(&self, q: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Self::bucket_limit(i);
+            }
+        }
+        u64::MAX
+    }
+}
+
+/// A [`Histogram`] recorded into from several threads.
+pub(crate) struct AtomicHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+}
+
+impl AtomicHistogram {
+    pub(crate) fn new() -> Self {
+        AtomicHistogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, value: u64) {
+        let bucket = ((u64::BITS - value.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// The counts so far. Taken while values are recorded, the fields may be a few values
+    /// apart.
+    pub(crate) fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram() {
+        let histogram = AtomicHistogram::new();
+        for value in [0, 1, 3, 3, 100] {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 5);
+        assert_eq!(snapshot.mean(), 21.4);
+        assert_eq!(&snapshot.buckets[..3], &[1, 1, 2]);
+        assert_eq!(snapshot.quantile(0.5), 4);
+        assert_eq!(snapshot.quantile(1.0), 128);
+        assert_eq!(Histogram::default().quantile(0.99), 0);
+    }
+}
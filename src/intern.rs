@@ -0,0 +1,81 @@
+//! Sharing one allocation per key between requests.
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::sync::Mutex;
+
+/// Hands out one `Arc<str>` per distinct key, so an extractor can return an owned key for a
+/// `KeyedLimiter<Arc<str>>` without allocating on every request.
+///
+/// At most `max_keys` keys are kept; once full, new keys get a fresh `Arc` that is not kept, so
+/// untrusted keys cannot grow the interner without bound. Call [`clear`](Self::clear) from
+/// time to time if keys churn.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use ratelimit::{KeyInterner, KeyedLimiter, Quota};
+///
+/// let interner = KeyInterner::new(10_000);
+/// let limiter = KeyedLimiter::<Arc<str>>::builder(Quota::per_second(10)).build();
+/// let tenant = interner.intern("tenant-a");
+/// assert!(limiter.check(&tenant).is_ok());
+/// // a limiter keyed by `Arc<str>` can also be checked with a `&str`
+/// assert!(limiter.check("tenant-a").is_ok());
+/// ```
+pub struct KeyInterner {
+    keys: Mutex<HashSet<Arc<str>>>,
+    max_keys: usize,
+}
+
+impl KeyInterner {
+    pub fn new(max_keys: usize) -> Self {
+        KeyInterner {
+            keys: Mutex::new(HashSet::new()),
+            max_keys,
+        }
+    }
+
+    pub fn intern(&self, key: &str) -> Arc<str> {
+        let mut keys = self.keys.lock();
+        if let Some(interned) = keys.get(key) {
+            return interned.clone();
+        }
+        let interned = Arc::<str>::from(key);
+        if keys.len() < self.max_keys {
+            keys.insert(interned.clone());
+        }
+        interned
+    }
+
+    /// Number of keys kept.
+    pub fn len(&self) -> usize {
+        self.keys.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forget all keys. Keys handed out stay valid.
+    pub fn clear(&self) {
+        self.keys.lock().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let interner = KeyInterner::new(1);
+        let a = interner.intern("a");
+        assert!(Arc::ptr_eq(&a, &interner.intern("a")));
+        // over the cap, keys are not kept
+        let b = interner.intern("b");
+        assert!(!Arc::ptr_eq(&b, &interner.intern("b")));
+        assert_eq!(interner.len(), 1);
+    }
+}
@@ -0,0 +1,6 @@
+//! Internal types exposed to the benchmarks by the `bench-internals` feature. Not part of the
+//! public API.
+
+pub use crate::gcra::LockedGcra;
+pub use crate::sketch::CountMin;
+pub use crate::waiters::{Ticket, WaitQueue};
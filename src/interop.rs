@@ -0,0 +1,155 @@
+//! Sharing clocks and quotas with the [`governor`] crate, for projects moving between the two
+//! crates or using both for a while.
+//!
+//! [`GovernorClock`] reads a `governor` clock as a [`Clock`], and [`AsGovernorClock`] does the
+//! reverse, so both crates can be driven by one fake clock in tests. Quotas convert with `From`
+//! and `TryFrom`, and [`GcraBuilder::governor_quota`] takes a `governor` quota as is.
+
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use governor::clock::Reference;
+use governor::nanos::Nanos;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::ConfigError;
+use crate::gcra::{duration_nanos, GcraBuilder};
+use crate::quota::Quota;
+
+/// A `governor` clock read as a [`Clock`].
+///
+/// `governor` instants only tell the time between each other, so readings count from the
+/// clock's reading at construction, which is taken to be the current unix time.
+#[derive(Debug, Clone)]
+pub struct GovernorClock<C: governor::clock::Clock> {
+    clock: C,
+    origin: C::Instant,
+    origin_nanos: u64,
+}
+
+impl<C> GovernorClock<C>
+where
+    C: governor::clock::Clock,
+{
+    pub fn new(clock: C) -> Self {
+        let origin = clock.now();
+        GovernorClock {
+            clock,
+            origin,
+            origin_nanos: SystemClock.now_nanos(),
+        }
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.clock
+    }
+}
+
+impl<C> Clock for GovernorClock<C>
+where
+    C: governor::clock::Clock,
+{
+    fn now_nanos(&self) -> u64 {
+        let elapsed: Duration = self.clock.now().duration_since(self.origin).into();
+        self.origin_nanos.saturating_add(duration_nanos(elapsed))
+    }
+}
+
+/// A [`Clock`] read as a `governor` clock, with instants in nanoseconds of the clock's time.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsGovernorClock<C>(pub C);
+
+impl<C> governor::clock::Clock for AsGovernorClock<C>
+where
+    C: Clock,
+{
+    type Instant = Nanos;
+
+    fn now(&self) -> Nanos {
+        Nanos::new(self.0.now_nanos())
+    }
+}
+
+// lets `governor` limiters that need a real-time clock, e.g. for async waits, use it
+impl<C> governor::clock::ReasonablyRealtime for AsGovernorClock<C> where C: Clock {}
+
+impl From<Quota> for governor::Quota {
+    /// The same emission interval and capacity. A capacity beyond `u32::MAX` is capped.
+    fn from(quota: Quota) -> Self {
+        let capacity = u32::try_from(quota.capacity()).unwrap_or(u32::MAX);
+        governor::Quota::with_period(quota.gap())
+            .expect("a quota has a positive gap")
+            .allow_burst(NonZeroU32::new(capacity).expect("a quota has a positive capacity"))
+    }
+}
+
+impl TryFrom<governor::Quota> for Quota {
+    type Error = ConfigError;
+
+    /// The rate is rounded to the nearest whole qps, see [`Quota::from_gap_tolerance`]. Rates
+    /// below one per second, such as `governor::Quota::per_minute`, cannot be expressed; use
+    /// [`GcraBuilder::governor_quota`] for those.
+    fn try_from(quota: governor::Quota) -> Result<Self, ConfigError> {
+        let gap = quota.replenish_interval();
+        if gap.is_zero() || gap > Duration::from_secs(1) {
+            return Err(ConfigError::new(format!(
+                "a replenish interval of {gap:?} is not a whole rate per second"
+            )));
+        }
+        Ok(Quota::from_gap_tolerance(gap, tolerance(quota)))
+    }
+}
+
+/// The GCRA tolerance of a `governor` quota.
+fn tolerance(quota: governor::Quota) -> Duration {
+    quota
+        .replenish_interval()
+        .saturating_mul(quota.burst_size().get() - 1)
+}
+
+impl<C> GcraBuilder<C> {
+    /// Set `gap` and `tolerance` from a `governor` quota, exactly, including rates below one
+    /// per second.
+    pub fn governor_quota(self, quota: governor::Quota) -> Self {
+        self.gap(quota.replenish_interval())
+            .tolerance(tolerance(quota))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use governor::clock::FakeRelativeClock;
+
+    use crate::clock::MockClock;
+    use crate::gcra::Policy;
+
+    use super::*;
+
+    #[test]
+    fn test_governor_interop() {
+        let fake = FakeRelativeClock::default();
+        let clock = GovernorClock::new(fake.clone());
+        let rl = GcraBuilder::new()
+            .clock(&clock)
+            .governor_quota(governor::Quota::per_minute(NonZeroU32::new(2).unwrap()))
+            .build();
+        assert!(rl.pass() && rl.pass());
+        assert!(!rl.pass());
+        fake.advance(Duration::from_secs(30));
+        assert!(rl.pass());
+
+        let quota = Quota::per_second(10).burst(5);
+        let theirs = governor::Quota::from(quota);
+        assert_eq!(theirs.replenish_interval(), Duration::from_millis(100));
+        assert_eq!(theirs.burst_size().get(), 15);
+        assert_eq!(Quota::try_from(theirs), Ok(quota));
+        assert!(Quota::try_from(governor::Quota::per_hour(NonZeroU32::new(1).unwrap())).is_err());
+
+        let mock = MockClock::new(1_000);
+        let theirs = AsGovernorClock(&mock);
+        let start = governor::clock::Clock::now(&theirs);
+        mock.forward(Duration::from_millis(250));
+        let elapsed = governor::clock::Clock::now(&theirs).duration_since(start);
+        assert_eq!(Duration::from(elapsed), Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,1228 @@
+//! Rate limiting per key, e.g. per client or per API key.
+//!
+//! [`KeyedLimiter`] keeps one GCRA state per key, created on first use. Only the TAT is stored
+//! per key; the quota and the clock are shared.
+//! For another policy per key, e.g. a [`TokenBucket`](crate::TokenBucket), see
+//! [`PerKey`](crate::PerKey).
+//!
+//! # Bounding memory
+//!
+//! Keys often come from untrusted input, so the number of entries can be capped with
+//! [`max_keys`](KeyedLimiterBuilder::max_keys). A key whose TAT has passed is indistinguishable
+//! from a fresh one, so such idle entries are always dropped first to make room. If the map is
+//! still full, [`WhenFull`] decides what happens to a new key. [`approx_bytes`](KeyedLimiter::approx_bytes)
+//! estimates the memory used by the map.
+//!
+//! When keys churn at a high rate, e.g. scanners cycling through source addresses, even a capped
+//! map is mostly filled with keys seen only once. A [`prefilter`](KeyedLimiterBuilder::prefilter)
+//! counts keys approximately in a fixed size count-min sketch and only creates state for a key
+//! once it was seen a given number of times. Requests of keys below that threshold are admitted
+//! without being charged, so combine this with a global limit if that matters.
+//!
+//! # Hot and cold keys
+//!
+//! Traffic is rarely spread evenly: a few keys often take most requests. Keys live in a cold
+//! map, sharded by hash so that requests for different keys rarely wait on each other. From time
+//! to time the busiest keys are promoted to a small [hot segment](KeyedLimiterBuilder::hot_keys)
+//! where they are decided on with a compare-and-swap, and keys that went quiet are demoted back.
+//! State moves along with a key, so this changes no decision. The hot segment is read under a
+//! read lock, taken for writing only to pick the hot keys, or without any lock with the
+//! `lock-free-hot-keys` feature.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp, NANOS_PER_MS};
+use crate::describe::{self, Describe, Description};
+use crate::gcra::{
+    available, conform_n, duration_nanos, reserve_n, to_millis, to_nanos, Denied, GcraBuilder,
+};
+use crate::quota::Quota;
+use crate::sketch::CountMin;
+use crate::sync::{ArcSwap, AtomicU64, AtomicUsize, Mutex, Ordering};
+use crate::window::QuotaStatus;
+
+/// What to do with a new key when a [`KeyedLimiter`] is at its key cap.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Evict the key closest to being idle, i.e. the one with the earliest TAT.
+    #[default]
+    Evict,
+    /// Deny new keys until an entry becomes idle.
+    Reject,
+    /// Limit all new keys together with one shared bucket of the same quota.
+    Shared,
+}
+
+/// What [`KeyedLimiter::check_existing`] found for a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyState {
+    /// The key has no state, e.g. it was never limited, or was dropped once idle.
+    Unknown,
+    /// A request would be admitted, and `remaining` of them at once.
+    Allowed {
+        remaining: u64,
+    },
+    Denied(Denied),
+}
+
+/// A borrowed form of a [`KeyedLimiter`] key `K`, turned into a `K` only when the key gets
+/// state. Besides every key for itself, `str` is one for `String`, `Arc<str>` and `Box<str>`,
+/// so a limiter keyed by any of those can be checked with a `&str` borrowed from a header or a
+/// `Cow<str>`, and `[T]` is one for `Vec<T>`.
+pub trait ToKey<K>: Hash + Eq {
+    fn to_key(&self) -> K;
+}
+
+impl<K> ToKey<K> for K
+where
+    K: Clone + Hash + Eq,
+{
+    fn to_key(&self) -> K {
+        self.clone()
+    }
+}
+
+impl ToKey<String> for str {
+    fn to_key(&self) -> String {
+        self.to_owned()
+    }
+}
+
+impl ToKey<Arc<str>> for str {
+    fn to_key(&self) -> Arc<str> {
+        Arc::from(self)
+    }
+}
+
+impl ToKey<Box<str>> for str {
+    fn to_key(&self) -> Box<str> {
+        Box::from(self)
+    }
+}
+
+impl<T> ToKey<Vec<T>> for [T]
+where
+    T: Clone + Hash + Eq,
+{
+    fn to_key(&self) -> Vec<T> {
+        self.to_vec()
+    }
+}
+
+pub struct KeyedLimiter<K, C = SystemClock> {
+    clock: C,
+    // in ns, as the TATs
+    gap: u64,
+    tolerance: u64,
+    max_keys: usize,
+    when_full: WhenFull,
+    // in ns, see `KeyedLimiterBuilder::evict_idle`
+    evict_every: Option<u64>,
+    // the busiest keys, decided on without taking a lock
+    hot: ArcSwap<HashMap<K, HotEntry>>,
+    max_hot: usize,
+    // all other keys, spread over the shards by hash
+    cold: Box<[Mutex<HashMap<K, ColdEntry>>]>,
+    cold_len: AtomicUsize,
+    hasher: RandomState,
+    // requests decided in the cold map since the hot keys were last picked
+    cold_hits: AtomicU64,
+    // held to create state for a key, and to move keys between the segments
+    admission: Mutex<Admission>,
+}
+
+struct HotEntry {
+    tat: AtomicU64,
+    hits: AtomicU64,
+}
+
+struct ColdEntry {
+    tat: u64,
+    hits: u64,
+}
+
+struct Admission {
+    // TAT of the bucket shared by keys that did not fit, see `WhenFull::Shared`
+    shared: u64,
+    prefilter: Option<Prefilter>,
+    // in ns, when a new key next drops the idle entries
+    next_eviction: u64,
+}
+
+struct Prefilter {
+    sketch: CountMin,
+    threshold: u8,
+}
+
+const DEFAULT_SHARDS: usize = 16;
+const DEFAULT_HOT_KEYS: usize = 32;
+// cold decisions between two picks of the hot keys, at least
+const REBALANCE_EVERY: u64 = 4096;
+// requests a key needs since the last pick to be picked
+const PROMOTE_MIN_HITS: u64 = 64;
+
+pub struct KeyedLimiterBuilder<K, C> {
+    clock: C,
+    quota: Quota,
+    max_keys: usize,
+    when_full: WhenFull,
+    evict_every: Option<Duration>,
+    // gap and tolerance of the window, in ns
+    window: Option<(u64, u64)>,
+    prefilter: Option<(u8, usize, Duration)>,
+    seed: Option<u64>,
+    shards: usize,
+    hot_keys: usize,
+    _key: PhantomData<fn() -> K>,
+}
+
+impl<K> KeyedLimiter<K, SystemClock> {
+    pub fn builder(quota: Quota) -> KeyedLimiterBuilder<K, SystemClock> {
+        KeyedLimiterBuilder {
+            clock: SystemClock,
+            quota,
+            max_keys: usize::MAX,
+            when_full: WhenFull::default(),
+            evict_every: None,
+            window: None,
+            prefilter: None,
+            seed: None,
+            shards: DEFAULT_SHARDS,
+            hot_keys: DEFAULT_HOT_KEYS,
+            _key: PhantomData,
+        }
+    }
+}
+
+impl<K, C> KeyedLimiterBuilder<K, C> {
+    pub fn clock<NC>(self, clock: NC) -> KeyedLimiterBuilder<K, NC> {
+        KeyedLimiterBuilder {
+            clock,
+            quota: self.quota,
+            max_keys: self.max_keys,
+            when_full: self.when_full,
+            evict_every: self.evict_every,
+            window: self.window,
+            prefilter: self.prefilter,
+            seed: self.seed,
+            shards: self.shards,
+            hot_keys: self.hot_keys,
+            _key: PhantomData,
+        }
+    }
+
+    /// Keep at most `max_keys` entries. Unbounded by default.
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    /// Instead of the quota, allow `limit` requests per `window`, all at once if need be, and
+    /// one more every `window / limit` after. For limits slower than one per second, which a
+    /// [`Quota`] cannot express, e.g. 5 login attempts per 15 minutes.
+    ///
+    /// # Panics
+    /// Panics if `limit` is zero, or more than `window` has nanoseconds.
+    pub fn window(mut self, limit: u64, window: Duration) -> Self {
+        assert!(limit > 0, "limit must be positive");
+        let window = duration_nanos(window);
+        let gap = window / limit;
+        assert!(gap > 0, "window must be at least `limit` ns");
+        self.window = Some((gap, window - gap));
+        self
+    }
+
+    pub fn when_full(mut self, when_full: WhenFull) -> Self {
+        self.when_full = when_full;
+        self
+    }
+
+    /// Drop idle entries at most once per `every`, when a new key is created, so the map does
+    /// not keep every key ever seen. Each time walks all cold entries under their shard locks.
+    /// By default idle entries are only dropped at the key cap, or by
+    /// [`retain_recent`](KeyedLimiter::retain_recent).
+    pub fn evict_idle(mut self, every: Duration) -> Self {
+        self.evict_every = Some(every);
+        self
+    }
+
+    /// Only create state for a key once it was seen `threshold` times, counted in a sketch of
+    /// `width` counters per row whose counts halve every `decay`.
+    ///
+    /// The sketch takes `4 * width` bytes. Collisions make keys reach the threshold early, so
+    /// size `width` to a few times the number of distinct keys expected within `decay`.
+    ///
+    /// # Panics
+    /// Panics if `threshold` is zero.
+    pub fn prefilter(mut self, threshold: u8, width: usize, decay: Duration) -> Self {
+        assert!(threshold > 0, "threshold must be positive");
+        self.prefilter = Some((threshold, width, decay));
+        self
+    }
+
+    /// Seed the randomness of the limiter, i.e. which keys collide in the
+    /// [`prefilter`](Self::prefilter) sketch, so tests and simulations are reproducible. Random by
+    /// default.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Spread the keys that are not hot over `shards` maps, each behind its own lock. 16 by
+    /// default.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "shards must be positive");
+        self.shards = shards;
+        self
+    }
+
+    /// Keep up to `hot_keys` of the busiest keys where they are decided on without a shard lock,
+    /// and with the `lock-free-hot-keys` feature without any lock. Keys are picked by requests
+    /// since the last pick, every few thousand requests to other keys. 32 by default, 0 turns
+    /// the hot segment off.
+    pub fn hot_keys(mut self, hot_keys: usize) -> Self {
+        self.hot_keys = hot_keys;
+        self
+    }
+
+    pub fn build(self) -> KeyedLimiter<K, C> {
+        let (gap, tolerance) = match self.window {
+            Some(window) => window,
+            None => GcraBuilder::new().quota(self.quota).build().params(),
+        };
+        KeyedLimiter {
+            clock: self.clock,
+            gap,
+            tolerance,
+            max_keys: self.max_keys,
+            when_full: self.when_full,
+            evict_every: self.evict_every.map(duration_nanos),
+            hot: ArcSwap::from_pointee(HashMap::new()),
+            max_hot: self.hot_keys,
+            cold: (0..self.shards)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            cold_len: AtomicUsize::new(0),
+            hasher: RandomState::new(),
+            cold_hits: AtomicU64::new(0),
+            admission: Mutex::new(Admission {
+                shared: 0,
+                prefilter: self.prefilter.map(|(threshold, width, decay)| Prefilter {
+                    sketch: match self.seed {
+                        Some(seed) => CountMin::with_seed(width, decay.as_millis() as u64, seed),
+                        None => CountMin::new(width, decay.as_millis() as u64),
+                    },
+                    threshold,
+                }),
+                next_eviction: 0,
+            }),
+        }
+    }
+}
+
+impl HotEntry {
+    /// Run `step` on the TAT, publishing the result with a compare-and-swap.
+    fn update(
+        &self,
+        now: u64,
+        step: &mut impl FnMut(&mut u64, u64) -> Result<(), Denied>,
+    ) -> Result<(), Denied> {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        let mut current = self.tat.load(Ordering::Acquire);
+        loop {
+            let mut tat = current;
+            step(&mut tat, now)?;
+            match self
+                .tat
+                .compare_exchange_weak(current, tat, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => return Ok(()),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+/// The quota of every key, and the key cap if there is one.
+impl<K, C> Describe for KeyedLimiter<K, C> {
+    fn describe(&self) -> Description {
+        let description = describe::gcra("keyed", self.gap, self.tolerance);
+        if self.max_keys == usize::MAX {
+            return description;
+        }
+        let when_full = match self.when_full {
+            WhenFull::Evict => "evict",
+            WhenFull::Reject => "reject",
+            WhenFull::Shared => "shared",
+        };
+        description
+            .setting("max_keys", self.max_keys)
+            .setting("when_full", when_full)
+    }
+}
+
+impl<K, C> KeyedLimiter<K, C>
+where
+    K: Hash + Eq,
+    C: Clock,
+{
+    /// Decide on one request for `key`.
+    pub fn check<Q>(&self, key: &Q) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.check_n(key, 1)
+    }
+
+    /// Decide on a request for `key` worth `n` cells, admitted as a whole or not at all.
+    pub fn check_n<Q>(&self, key: &Q, n: u64) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.check_n_with_offset(key, n, Duration::ZERO)
+    }
+
+    /// Decide on a request for `key` worth `n` cells as if it arrived `offset` later, e.g. one
+    /// proxied from a region with known added latency. Where keys compete for the same capacity,
+    /// as in the bucket of [`WhenFull::Shared`], such requests are then not beaten by local ones
+    /// sent at the same time.
+    ///
+    /// Keep the offset of a key steady; the key is idle `offset` later than it would be.
+    pub fn check_n_with_offset<Q>(&self, key: &Q, n: u64, offset: Duration) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.decide(key, offset, |tat, now| {
+            conform_n(tat, now, self.gap, self.tolerance, n)
+        })
+    }
+
+    /// Admit a request for `key` worth `n` cells whether it conforms or not, and return how long
+    /// it should have waited to conform, for callers that cannot be denied yet but can slow
+    /// down. The cells are charged either way, so the delay grows while a key keeps going over
+    /// its rate. See [`check_n_with_offset`](Self::check_n_with_offset) for `offset`.
+    pub fn pace_n_with_offset<Q>(&self, key: &Q, n: u64, offset: Duration) -> Duration
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        let mut delay = Duration::ZERO;
+        let decision = self.decide(key, offset, |tat, now| {
+            delay = reserve_n(tat, now, self.gap, self.tolerance, n);
+            Ok(())
+        });
+        match decision {
+            Ok(()) => delay,
+            // a new key over the key cap with `WhenFull::Reject`
+            Err(denied) => denied.retry_after(),
+        }
+    }
+
+    /// Find the TAT of `key`, creating it as configured, and run `step` on it at the offset
+    /// time.
+    fn decide<Q>(
+        &self,
+        key: &Q,
+        offset: Duration,
+        mut step: impl FnMut(&mut u64, u64) -> Result<(), Denied>,
+    ) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        // keys are idle by the clock, and decided on at the offset time
+        let idle_before = self.clock.now_nanos();
+        let now = idle_before.saturating_add(duration_nanos(offset));
+        if let Some(decision) = self.decide_existing(key, now, &mut step) {
+            return decision;
+        }
+        let mut admission = self.admission.lock();
+        let admission = &mut *admission;
+        // created in the meantime, or moved between the segments while we looked
+        if let Some(decision) = self.decide_existing(key, now, &mut step) {
+            return decision;
+        }
+        if let Some(prefilter) = &mut admission.prefilter {
+            if prefilter.sketch.increment(key, idle_before / NANOS_PER_MS) < prefilter.threshold {
+                return Ok(());
+            }
+        }
+        if let Some(every) = self.evict_every {
+            if idle_before >= admission.next_eviction {
+                self.retain_cold(idle_before);
+                admission.next_eviction = idle_before.saturating_add(every);
+            }
+        }
+        if self.len_locked() >= self.max_keys {
+            self.retain_cold(idle_before);
+        }
+        let full = self.len_locked() >= self.max_keys;
+        if full {
+            match self.when_full {
+                WhenFull::Evict if self.max_keys > 0 => {}
+                WhenFull::Reject => {
+                    let free_at = self.earliest_tat().unwrap_or(idle_before);
+                    return Err(Denied::new(Duration::from_nanos(
+                        free_at.saturating_sub(idle_before),
+                    )));
+                }
+                WhenFull::Evict | WhenFull::Shared => return step(&mut admission.shared, now),
+            }
+        }
+        // a rejected request creates no state, and evicts no key to make room for it
+        let mut tat = 0;
+        step(&mut tat, now)?;
+        if full && !self.evict_oldest() {
+            // every key is hot, none is up for eviction
+            return step(&mut admission.shared, now);
+        }
+        self.shard(key)
+            .lock()
+            .insert(key.to_key(), ColdEntry { tat, hits: 1 });
+        self.cold_len.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Run `step` on the TAT of `key` if it has state, in either segment.
+    fn decide_existing<Q>(
+        &self,
+        key: &Q,
+        now: u64,
+        step: &mut impl FnMut(&mut u64, u64) -> Result<(), Denied>,
+    ) -> Option<Result<(), Denied>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        if let Some(entry) = self.hot.load().get(key) {
+            return Some(entry.update(now, step));
+        }
+        let mut shard = self.shard(key).lock();
+        let entry = shard.get_mut(key)?;
+        entry.hits += 1;
+        let decision = step(&mut entry.tat, now);
+        drop(shard);
+        if self.max_hot > 0 && self.cold_hits.fetch_add(1, Ordering::Relaxed) >= REBALANCE_EVERY {
+            self.try_rebalance();
+        }
+        Some(decision)
+    }
+
+    fn shard<Q>(&self, key: &Q) -> &Mutex<HashMap<K, ColdEntry>>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        &self.cold[(hash % self.cold.len() as u64) as usize]
+    }
+
+    /// Number of keys with state, exact while the admission lock is held.
+    fn len_locked(&self) -> usize {
+        self.cold_len.load(Ordering::Relaxed) + self.hot.load().len()
+    }
+
+    /// Drop cold entries that are idle before `idle_before`.
+    fn retain_cold(&self, idle_before: u64) {
+        for shard in self.cold.iter() {
+            let mut shard = shard.lock();
+            let before = shard.len();
+            shard.retain(|_, entry| entry.tat > idle_before);
+            self.cold_len
+                .fetch_sub(before - shard.len(), Ordering::Relaxed);
+        }
+    }
+
+    /// Evict the cold key with the earliest TAT, if there is one.
+    fn evict_oldest(&self) -> bool {
+        loop {
+            let oldest = self
+                .cold
+                .iter()
+                .enumerate()
+                .filter_map(|(i, shard)| Some((shard.lock().values().map(|e| e.tat).min()?, i)))
+                .min();
+            let Some((oldest, i)) = oldest else {
+                return false;
+            };
+            let mut shard = self.cold[i].lock();
+            let before = shard.len();
+            let mut evicted = false;
+            shard.retain(|_, entry| {
+                let evict = !evicted && entry.tat == oldest;
+                evicted |= evict;
+                !evict
+            });
+            // dropped by `retain_recent` or charged again since the shard was scanned
+            if shard.len() < before {
+                self.cold_len.fetch_sub(1, Ordering::Relaxed);
+                return true;
+            }
+        }
+    }
+
+    /// The earliest TAT of any key.
+    fn earliest_tat(&self) -> Option<u64> {
+        let hot = self
+            .hot
+            .load()
+            .values()
+            .map(|entry| entry.tat.load(Ordering::Acquire))
+            .min();
+        let cold = self
+            .cold
+            .iter()
+            .filter_map(|shard| shard.lock().values().map(|entry| entry.tat).min())
+            .min();
+        hot.into_iter().chain(cold).min()
+    }
+
+    /// Pick the hot keys again, unless another thread holds the admission lock.
+    fn try_rebalance(&self) {
+        let Some(_admission) = self.admission.try_lock() else {
+            return;
+        };
+        let every = REBALANCE_EVERY.max(self.cold_len.load(Ordering::Relaxed) as u64);
+        if self.cold_hits.load(Ordering::Relaxed) < every {
+            return;
+        }
+        self.cold_hits.store(0, Ordering::Relaxed);
+        self.rebalance();
+    }
+
+    /// Move all hot keys back to the cold map and promote the keys with the most hits since the
+    /// last rebalance, at most `max_hot`. Hits are halved on the way, so a key that went quiet
+    /// is demoted after a few rounds. Runs under the admission lock.
+    fn rebalance(&self) {
+        // new readers find the key in neither segment and wait for the admission lock, those
+        // already in the old segment finish their compare-and-swap before it is taken apart
+        let mut old = self.hot.swap(Arc::new(HashMap::new()));
+        let old = loop {
+            match Arc::try_unwrap(old) {
+                Ok(segment) => break segment,
+                Err(segment) => {
+                    old = segment;
+                    crate::sync::yield_now();
+                }
+            }
+        };
+        for (key, entry) in old {
+            let entry = ColdEntry {
+                tat: entry.tat.into_inner(),
+                hits: entry.hits.into_inner(),
+            };
+            self.shard(&key).lock().insert(key, entry);
+            self.cold_len.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut hits: Vec<u64> = Vec::new();
+        for shard in self.cold.iter() {
+            let shard = shard.lock();
+            hits.extend(
+                shard
+                    .values()
+                    .map(|entry| entry.hits)
+                    .filter(|&hits| hits >= PROMOTE_MIN_HITS),
+            );
+        }
+        let threshold = match hits.len().checked_sub(self.max_hot) {
+            None | Some(0) => PROMOTE_MIN_HITS,
+            Some(_) => {
+                let (_, &mut nth, _) =
+                    hits.select_nth_unstable_by(self.max_hot - 1, |a, b| b.cmp(a));
+                nth
+            }
+        };
+
+        let mut hot = HashMap::new();
+        let mut room = self.max_hot;
+        for shard in self.cold.iter() {
+            let mut shard = shard.lock();
+            let entries: Vec<_> = shard.drain().collect();
+            for (key, mut entry) in entries {
+                let promote = entry.hits >= threshold && room > 0;
+                room -= usize::from(promote);
+                entry.hits /= 2;
+                if !promote {
+                    shard.insert(key, entry);
+                    continue;
+                }
+                hot.insert(
+                    key,
+                    HotEntry {
+                        tat: AtomicU64::new(entry.tat),
+                        hits: AtomicU64::new(entry.hits),
+                    },
+                );
+            }
+        }
+        self.cold_len.fetch_sub(hot.len(), Ordering::Relaxed);
+        self.hot.store(Arc::new(hot));
+    }
+
+    /// The TAT of `key`, if it has state.
+    fn tat<Q>(&self, key: &Q) -> Option<u64>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let find = || match self.hot.load().get(key) {
+            Some(entry) => Some(entry.tat.load(Ordering::Acquire)),
+            None => self.shard(key).lock().get(key).map(|entry| entry.tat),
+        };
+        // a key is briefly in neither segment while they are rebalanced
+        find().or_else(|| {
+            let _admission = self.admission.lock();
+            find()
+        })
+    }
+
+    /// Look up what a request for `key` would get, without charging it and without creating
+    /// state for a new key, e.g. to show a user their remaining quota. Keys limited by the
+    /// shared bucket of [`WhenFull::Shared`] or not yet past the prefilter are
+    /// [`Unknown`](KeyState::Unknown).
+    pub fn check_existing<Q>(&self, key: &Q) -> KeyState
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.clock.now_nanos();
+        let Some(mut tat) = self.tat(key) else {
+            return KeyState::Unknown;
+        };
+        let remaining = available(tat, now, self.gap, self.tolerance);
+        match conform_n(&mut tat, now, self.gap, self.tolerance, 1) {
+            Ok(()) => KeyState::Allowed { remaining },
+            Err(denied) => KeyState::Denied(denied),
+        }
+    }
+
+    /// What `key` has left, without charging it or creating state for it. A key without state
+    /// has its full limit, as do keys limited by the shared bucket of [`WhenFull::Shared`].
+    pub fn quota_status<Q>(&self, key: &Q) -> QuotaStatus
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let now = self.clock.now_nanos();
+        let tat = self.tat(key).unwrap_or(0);
+        let limit = match self.gap {
+            0 => u64::MAX,
+            gap => self.tolerance / gap + 1,
+        };
+        QuotaStatus {
+            limit,
+            remaining: available(tat, now, self.gap, self.tolerance),
+            resets_in: Duration::from_nanos(tat.saturating_sub(now)),
+        }
+    }
+
+    #[cfg(any(not(feature = "strict-api"), test))]
+    pub fn pass<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.check(key).is_ok()
+    }
+
+    /// Number of keys with state.
+    pub fn len(&self) -> usize {
+        let _admission = self.admission.lock();
+        self.len_locked()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated memory used by the key maps, in bytes.
+    ///
+    /// This counts the tables themselves, sized by their capacity, but not heap data owned by
+    /// the keys such as the contents of a `String`.
+    pub fn approx_bytes(&self) -> usize {
+        let admission = self.admission.lock();
+        // one control byte per bucket in the hashbrown layout
+        let cold_bucket = std::mem::size_of::<(K, ColdEntry)>() + 1;
+        let hot_bucket = std::mem::size_of::<(K, HotEntry)>() + 1;
+        let cold: usize = self
+            .cold
+            .iter()
+            .map(|shard| shard.lock().capacity() * cold_bucket)
+            .sum();
+        let hot = self.hot.load().capacity() * hot_bucket;
+        let sketch = admission.prefilter.as_ref().map_or(0, |p| p.sketch.bytes());
+        std::mem::size_of::<Self>() + cold + hot + sketch
+    }
+
+    /// Call `f` with every key and its TAT, in ns, under the admission lock so that no key is
+    /// missed by a rebalance.
+    fn for_each_tat(&self, mut f: impl FnMut(&K, u64)) {
+        let _admission = self.admission.lock();
+        for (key, entry) in self.hot.load().iter() {
+            f(key, entry.tat.load(Ordering::Acquire));
+        }
+        for shard in self.cold.iter() {
+            for (key, entry) in shard.lock().iter() {
+                f(key, entry.tat);
+            }
+        }
+    }
+
+    /// Up to `n` keys furthest ahead of schedule, with how long until each is idle.
+    pub(crate) fn busiest(&self, n: usize) -> Vec<(String, Duration)>
+    where
+        K: std::fmt::Display,
+    {
+        let now = self.clock.now_nanos();
+        let mut busy = Vec::new();
+        self.for_each_tat(|key, tat| {
+            if tat > now {
+                busy.push((key.to_string(), tat - now));
+            }
+        });
+        busy.sort_unstable_by_key(|&(_, ns)| std::cmp::Reverse(ns));
+        busy.truncate(n);
+        busy.into_iter()
+            .map(|(key, ns)| (key, Duration::from_nanos(ns)))
+            .collect()
+    }
+
+    /// The clock's time, in ms.
+    pub(crate) fn now(&self) -> Timestamp {
+        now_millis(&self.clock)
+    }
+
+    /// The clock's time, in ns.
+    pub(crate) fn now_nanos(&self) -> u64 {
+        self.clock.now_nanos()
+    }
+
+    /// Call `f` with every key that is not idle, and its TAT as a clock reading.
+    pub(crate) fn for_each_busy(&self, mut f: impl FnMut(&K, Timestamp)) {
+        let now = self.clock.now_nanos();
+        self.for_each_tat(|key, tat| {
+            if tat > now {
+                f(key, to_millis(tat));
+            }
+        });
+    }
+
+    /// Put back saved TATs, busiest first, without going over the key cap. Entries that are
+    /// idle by now and keys that already have state are skipped. Returns the number restored.
+    pub(crate) fn restore(&self, mut entries: Vec<(K, Timestamp)>) -> usize {
+        let now = now_millis(&self.clock);
+        entries.retain(|&(_, tat)| tat > now);
+        entries.sort_unstable_by_key(|&(_, tat)| std::cmp::Reverse(tat));
+        let _admission = self.admission.lock();
+        let mut restored = 0;
+        for (key, tat) in entries {
+            if self.len_locked() >= self.max_keys {
+                break;
+            }
+            if self.hot.load().contains_key(&key) {
+                continue;
+            }
+            let mut shard = self.shard(&key).lock();
+            if let std::collections::hash_map::Entry::Vacant(entry) = shard.entry(key) {
+                entry.insert(ColdEntry {
+                    tat: to_nanos(tat),
+                    hits: 0,
+                });
+                self.cold_len.fetch_add(1, Ordering::Relaxed);
+                restored += 1;
+            }
+        }
+        restored
+    }
+
+    /// `gap` and `tolerance`, in ns.
+    pub(crate) fn params(&self) -> (u64, u64) {
+        (self.gap, self.tolerance)
+    }
+
+    /// Drop entries that are idle, as they hold no information. Hot keys are kept until they
+    /// are demoted.
+    pub fn retain_recent(&self) {
+        self.retain_cold(self.clock.now_nanos());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    fn limiter(clock: &MockClock, when_full: WhenFull) -> KeyedLimiter<String, &MockClock> {
+        KeyedLimiter::builder(Quota::per_second(1).burst(1))
+            .clock(clock)
+            .max_keys(2)
+            .when_full(when_full)
+            .build()
+    }
+
+    #[test]
+    fn test_keyed_independent_keys() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .build();
+        assert!(rl.pass("a"));
+        assert!(!rl.pass("a"));
+        assert!(rl.pass("b"));
+        assert_eq!(rl.len(), 2);
+        clock.forward(Duration::from_secs(1));
+        assert!(rl.pass("a"));
+        assert!(rl.approx_bytes() >= 2 * std::mem::size_of::<(String, u64)>());
+    }
+
+    #[test]
+    fn test_keyed_evict_idle() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .evict_idle(Duration::from_secs(10))
+            .build();
+        assert!(rl.pass("a") && rl.pass("b"));
+        clock.forward(Duration::from_secs(5));
+        assert!(rl.pass("c"));
+        assert_eq!(rl.len(), 3);
+        // a new key after the interval drops the keys that went idle
+        clock.forward(Duration::from_secs(5));
+        assert!(rl.pass("a"));
+        assert!(rl.pass("d"));
+        assert_eq!(rl.len(), 2);
+    }
+
+    #[test]
+    fn test_keyed_window() {
+        let clock = MockClock::new(1_000_000);
+        // a limit beyond u32 used to be clamped, which stretched the gap
+        let limit = 5_000_000_000;
+        let rl: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .window(limit, Duration::from_secs(10))
+            .build();
+        assert_eq!(rl.params(), (2, 10_000_000_000 - 2));
+        assert_eq!(rl.check_n("a", limit), Ok(()));
+        assert_eq!(
+            rl.check("a").unwrap_err().retry_after(),
+            Duration::from_nanos(2)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "window must be at least `limit` ns")]
+    fn test_keyed_window_too_short() {
+        let _ = KeyedLimiter::<String>::builder(Quota::per_second(1))
+            .window(2_000, Duration::from_micros(1));
+    }
+
+    #[test]
+    fn test_keyed_borrowed_keys() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<Arc<str>, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .build();
+        let header = std::borrow::Cow::Borrowed("tenant-a");
+        assert!(rl.pass(&*header));
+        assert!(!rl.pass(&Arc::from("tenant-a")));
+        assert_eq!(rl.len(), 1);
+    }
+
+    #[test]
+    fn test_keyed_check_existing() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1).burst(2))
+            .clock(&clock)
+            .build();
+        assert_eq!(rl.check_existing("a"), KeyState::Unknown);
+        assert!(rl.is_empty());
+        assert!(rl.pass("a"));
+        assert_eq!(rl.check_existing("a"), KeyState::Allowed { remaining: 2 });
+        assert_eq!(rl.check_existing("a"), KeyState::Allowed { remaining: 2 });
+        assert!(rl.pass("a") && rl.pass("a"));
+        assert_eq!(
+            rl.check_existing("a"),
+            KeyState::Denied(Denied::new(Duration::from_secs(1)))
+        );
+    }
+
+    #[test]
+    fn test_quota_status() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1).burst(2))
+            .clock(&clock)
+            .build();
+        assert!(rl.pass("a"));
+        assert_eq!(
+            rl.quota_status("a"),
+            QuotaStatus {
+                limit: 3,
+                remaining: 2,
+                resets_in: Duration::from_secs(1)
+            }
+        );
+        assert!(rl.pass("a") && rl.pass("a"));
+        assert_eq!(
+            rl.quota_status("a"),
+            QuotaStatus {
+                limit: 3,
+                remaining: 0,
+                resets_in: Duration::from_secs(3)
+            }
+        );
+        // a key without state has its full limit, and gets none from asking
+        assert_eq!(
+            rl.quota_status("b"),
+            QuotaStatus {
+                limit: 3,
+                remaining: 3,
+                resets_in: Duration::ZERO
+            }
+        );
+        assert_eq!(rl.len(), 1);
+
+        // keys in the shared bucket have no state of their own, and report the full limit
+        let rl = limiter(&clock, WhenFull::Shared);
+        assert!(rl.pass("a") && rl.pass("b"));
+        assert!(rl.pass("c") && rl.pass("c"));
+        assert!(!rl.pass("c"));
+        assert_eq!(
+            rl.quota_status("c"),
+            QuotaStatus {
+                limit: 2,
+                remaining: 2,
+                resets_in: Duration::ZERO
+            }
+        );
+        assert_eq!(rl.quota_status("a").remaining, 1);
+    }
+
+    #[test]
+    fn test_keyed_when_full() {
+        let clock = MockClock::new(1_000_000);
+
+        let rl = limiter(&clock, WhenFull::Evict);
+        assert!(rl.pass("a"));
+        clock.forward(Duration::from_millis(10));
+        assert!(rl.pass("b"));
+        assert!(rl.pass("c"));
+        assert_eq!(rl.len(), 2);
+        // "a" was evicted and starts over
+        assert!(rl.pass("a"));
+        assert!(rl.pass("a"));
+
+        let rl = limiter(&clock, WhenFull::Reject);
+        assert!(rl.pass("a"));
+        assert!(rl.pass("b"));
+        assert_eq!(
+            rl.check("c").unwrap_err().retry_after(),
+            Duration::from_secs(1)
+        );
+        // once "a" is idle, its slot is reused
+        clock.forward(Duration::from_secs(1));
+        assert!(rl.pass("c"));
+
+        let rl = limiter(&clock, WhenFull::Shared);
+        assert!(rl.pass("a"));
+        assert!(rl.pass("b"));
+        assert!(rl.pass("c"));
+        assert!(rl.pass("d"));
+        assert!(!rl.pass("e"));
+        assert_eq!(rl.len(), 2);
+    }
+
+    #[test]
+    fn test_check_n_with_offset() {
+        let clock = MockClock::new(1_000_000);
+        let rl = limiter(&clock, WhenFull::Shared);
+        let far = Duration::from_millis(300);
+
+        // decided 300ms later, so the key's TAT is 300ms further out
+        assert!(rl.check_n_with_offset("a", 2, far).is_ok());
+        assert_eq!(
+            rl.check_n_with_offset("a", 1, far)
+                .unwrap_err()
+                .retry_after(),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            rl.check("a").unwrap_err().retry_after(),
+            Duration::from_millis(1_300)
+        );
+
+        // an offset past the TAT finds the key with all its capacity back
+        assert!(rl.check_n("b", 2).is_ok());
+        assert!(rl
+            .check_n_with_offset("b", 2, Duration::from_secs(3))
+            .is_ok());
+        assert_eq!(
+            rl.check("b").unwrap_err().retry_after(),
+            Duration::from_secs(4)
+        );
+        // still busy by the clock until its TAT, while "a" is idle by now
+        clock.forward(Duration::from_secs(4));
+        rl.retain_recent();
+        assert_eq!(rl.len(), 1);
+        assert!(rl.tat("b").is_some());
+        clock.forward(Duration::from_secs(1));
+        rl.retain_recent();
+        assert!(rl.is_empty());
+
+        // in the shared bucket, a request from far away gets the capacity freeing up by the
+        // time it was sent
+        assert!(rl.pass("c"));
+        assert!(rl.pass("d"));
+        assert!(rl.pass("e"));
+        assert!(rl.pass("f"));
+        clock.forward(Duration::from_millis(800));
+        assert_eq!(
+            rl.check("g").unwrap_err().retry_after(),
+            Duration::from_millis(200)
+        );
+        assert!(rl.check_n_with_offset("h", 1, far).is_ok());
+        assert!(rl.check_n_with_offset("i", 1, far).is_err());
+    }
+
+    #[test]
+    fn test_keyed_rejected_new_key() {
+        let clock = MockClock::new(1_000_000);
+        let rl = limiter(&clock, WhenFull::Evict);
+        // more cells than the quota lets through at once
+        assert!(rl.check_n("a", 3).is_err());
+        assert!(rl.is_empty());
+        assert!(rl.pass("a"));
+        assert!(rl.pass("b"));
+        // at the key cap, a rejected key evicts no other
+        assert!(rl.check_n("c", 3).is_err());
+        assert_eq!(rl.len(), 2);
+        assert!(rl.tat("a").is_some());
+    }
+
+    #[test]
+    fn test_keyed_hot_keys() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<u32, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .hot_keys(1)
+            .build();
+        // key 0 takes half the traffic, 100 others share the rest
+        for round in 0..REBALANCE_EVERY as u32 {
+            rl.pass(&0);
+            rl.pass(&(round % 100 + 1));
+        }
+        assert!(rl.hot.load().contains_key(&0));
+        assert_eq!(rl.len(), 101);
+        // its state moved along
+        assert!(!rl.pass(&0));
+        clock.forward(Duration::from_secs(1));
+        assert!(rl.pass(&0));
+        assert!(!rl.pass(&0));
+
+        // once key 1 takes over, key 0 is demoted with its state
+        for round in 0..2 * REBALANCE_EVERY as u32 {
+            rl.pass(&1);
+            rl.pass(&(round % 100 + 2));
+        }
+        assert!(rl.hot.load().contains_key(&1));
+        assert!(matches!(rl.check_existing(&0), KeyState::Denied(_)));
+        assert_eq!(rl.len(), 102);
+    }
+
+    #[test]
+    fn test_keyed_prefilter() {
+        let clock = MockClock::new(1_000_000);
+        let rl: KeyedLimiter<u32, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .prefilter(3, 1 << 16, Duration::from_secs(60))
+            .build();
+        // one-off keys never get state
+        for key in 0..1000 {
+            assert!(rl.pass(&key));
+        }
+        assert_eq!(rl.len(), 0);
+        // a repeat offender is limited from its third request on
+        assert!(rl.pass(&5000));
+        assert!(rl.pass(&5000));
+        assert!(rl.pass(&5000));
+        assert!(!rl.pass(&5000));
+        assert_eq!(rl.len(), 1);
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_promote() {
+        loom::model(|| {
+            let limiter: Arc<KeyedLimiter<&str, MockClock>> = Arc::new(
+                KeyedLimiter::builder(Quota::per_second(1))
+                    .clock(MockClock::new(1_000))
+                    .shards(1)
+                    .hot_keys(1)
+                    .build(),
+            );
+            let tat = to_nanos(1_000);
+            limiter.cold[0].lock().insert(
+                "a",
+                ColdEntry {
+                    tat,
+                    hits: PROMOTE_MIN_HITS,
+                },
+            );
+            limiter.cold_len.store(1, Ordering::Relaxed);
+            let promoting = {
+                let limiter = limiter.clone();
+                loom::thread::spawn(move || {
+                    let _admission = limiter.admission.lock();
+                    limiter.rebalance();
+                })
+            };
+            // decided in the cold map, while in neither segment, or in the hot segment
+            assert!(limiter.check(&"a").is_ok());
+            promoting.join().unwrap();
+            assert!(limiter.hot.load().contains_key(&"a"));
+            assert_eq!(limiter.len_locked(), 1);
+            assert_eq!(limiter.tat(&"a"), Some(tat + limiter.gap));
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_evict() {
+        loom::model(|| {
+            let limiter: Arc<KeyedLimiter<&str, MockClock>> = Arc::new(
+                KeyedLimiter::builder(Quota::per_second(1))
+                    .clock(MockClock::new(1_000))
+                    .shards(1)
+                    .build(),
+            );
+            limiter.cold[0]
+                .lock()
+                .insert("a", ColdEntry { tat: 0, hits: 1 });
+            limiter.cold_len.store(1, Ordering::Relaxed);
+            let retaining = {
+                let limiter = limiter.clone();
+                loom::thread::spawn(move || limiter.retain_recent())
+            };
+            {
+                let _admission = limiter.admission.lock();
+                limiter.evict_oldest();
+            }
+            retaining.join().unwrap();
+            // the idle key was dropped once, by either
+            assert_eq!(limiter.cold_len.load(Ordering::Relaxed), 0);
+        });
+    }
+}
@@ -1,5 +1,172 @@
+/// A `tracing` event at trace level from inside an algorithm, compiled in only with the
+/// `debug-internals` feature.
+macro_rules! trace_internals {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "debug-internals")]
+        tracing::trace!(target: "ratelimit::internals", $($arg)*);
+    };
+}
+
+mod any;
+mod autoscale;
+mod batcher;
+mod bruteforce;
+mod budget;
+mod burst;
 mod clock;
+mod config;
+#[cfg(feature = "tokio")]
+mod consumer;
+mod cost;
+mod credit;
+#[cfg(feature = "tokio")]
+mod db;
+mod describe;
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+mod driver;
+mod escalation;
+mod evaluate;
+mod flag;
 mod gcra;
+mod governor;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod histogram;
+mod intern;
+#[cfg(feature = "bench-internals")]
+#[doc(hidden)]
+pub mod internals;
+#[cfg(feature = "interop-governor")]
+mod interop;
+mod json;
+mod keyed;
+mod limiter;
+mod listener;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod observed;
+mod optimistic;
+#[cfg(feature = "otel")]
+mod otel;
+mod overflow;
+mod pacer;
+mod per_key;
+mod persist;
+mod pipeline;
+mod prefetch;
+mod quota;
+mod reconciler;
+mod registry;
+mod rejection;
+#[cfg(feature = "reload")]
+mod reload;
+mod remote;
+pub mod replay;
+mod retry;
+mod sampled;
+mod selfcheck;
+mod send;
+#[cfg(feature = "tower")]
+mod service;
+mod shaper;
+mod sketch;
+mod sleep;
+mod slo;
+pub mod snapshot;
+mod spend;
+mod sync;
+pub mod testing;
+#[cfg(feature = "async-wait")]
+mod timer;
+mod token_bucket;
+mod usage;
+#[cfg_attr(not(feature = "async-wait"), allow(dead_code))]
+mod waiters;
+mod window;
 
-pub use clock::Clock;
-pub use gcra::{LeakyBucket, Policy, VirtualScheduling};
+pub use any::AnyLimiter;
+pub use autoscale::{ScaleAdvisor, ScaleDirection, ScaleHint};
+pub use batcher::{Batcher, Fetch, Next};
+pub use bruteforce::{BruteForceGuard, BruteForceGuardBuilder};
+pub use budget::{Child, SharedBudget, SharedBudgetBuilder};
+pub use burst::{Burst, BurstDetector};
+pub use clock::{Clock, MillisClock, MockClock, MonotonicClock, SystemClock, Timestamp};
+pub use config::{
+    ConfigError, Endpoint, EndpointConfig, EndpointKey, EndpointLimits, LimiterConfig,
+};
+#[cfg(feature = "tokio")]
+pub use consumer::{ConsumeError, MessageSource, PacedConsumer};
+pub use cost::CostModel;
+pub use credit::Credits;
+#[cfg(feature = "tokio")]
+pub use db::{QueryGate, StatementKind};
+pub use describe::{Describe, Description};
+#[cfg(feature = "async-std")]
+pub use driver::AsyncStdSpawn;
+#[cfg(feature = "tokio")]
+pub use driver::TokioSpawn;
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub use driver::{AsyncBackend, AsyncOptimistic, AsyncPrefetch, Spawn};
+pub use escalation::{Escalation, Verdict};
+pub use evaluate::{Evaluate, EvaluateStats};
+pub use flag::{FlagSource, QuotaSwitch, Toggle};
+pub use gcra::{
+    Allowed, Denied, Gcra, GcraBuilder, GcraVersion, Headroom, LeakyBucket, LeakyBucketBuilder,
+    Policy, VirtualScheduling, VirtualSchedulingBuilder,
+};
+pub use governor::{MessageGovernor, MessageVerdict, POLICY_VIOLATION};
+#[cfg(feature = "graphql")]
+pub use graphql::ComplexityLimit;
+pub use histogram::Histogram;
+pub use intern::KeyInterner;
+#[cfg(feature = "interop-governor")]
+pub use interop::{AsGovernorClock, GovernorClock};
+pub use keyed::{KeyState, KeyedLimiter, KeyedLimiterBuilder, ToKey, WhenFull};
+pub use limiter::{
+    Admitted, Closed, Deadline, DecisionContext, Health, Limiter, ObservedRate, Rejected,
+    Reservation, Stats,
+};
+pub use listener::{Event, Listener, Meta};
+#[cfg(feature = "metrics")]
+pub use metrics::MetricsListener;
+pub use optimistic::Optimistic;
+#[cfg(feature = "otel")]
+pub use otel::OtelListener;
+pub use overflow::{Backlogged, Overflow};
+pub use pacer::{Pacer, PacerStats};
+pub use per_key::PerKey;
+pub use pipeline::{AdmissionMode, Global, KeyExtractor, Permit, PolicyBuilder, PolicyStack};
+pub use prefetch::Prefetch;
+pub use quota::Quota;
+pub use reconciler::{ReconcileStats, Reconciler, Report};
+pub use registry::Registry;
+pub use rejection::{JsonRejection, NoMessage, RejectionBody};
+#[cfg(feature = "reload")]
+pub use reload::{QuotaChange, QuotaReloader, ReloadError};
+pub use remote::{Backend, Cached, Degrade, FailureMode, Fallback, FallbackStats};
+pub use retry::{Deliver, RetryQueue};
+pub use sampled::Sampled;
+pub use selfcheck::{CapacityHints, Finding, Severity};
+pub use send::{SendGovernor, SendVerdict};
+#[cfg(feature = "tower")]
+pub use service::{
+    ChallengeHandler, CostExtractor, KeyedPolicy, NoChallenge, NoRejectionBody, RateLimit,
+    RateLimitError, RateLimitLayer, RejectionHandler, ResponseFuture, UnitCost, WithRejectionBody,
+};
+pub use shaper::Shaper;
+pub use slo::SloGuard;
+pub use snapshot::Snapshot;
+pub use spend::SpendCap;
+#[cfg(any(feature = "async-std", feature = "smol"))]
+pub use timer::AsyncIoTimer;
+#[cfg(feature = "futures-timer")]
+pub use timer::FuturesTimer;
+#[cfg(feature = "async-wait")]
+pub use timer::Sleep;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+pub use token_bucket::{Refill, TokenBucket, TokenBucketBuilder};
+pub use usage::{UsageAggregator, UsageRecord, UsageReport};
+#[cfg(feature = "chrono")]
+pub use window::rfc3339;
+pub use window::{QuotaStatus, QuotaWindow};
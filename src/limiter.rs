@@ -0,0 +1,1446 @@
+//! Ergonomic wrapper around any [`Policy`].
+//!
+//! A policy only has to decide on a single request. [`Limiter`] builds the rest on top of that:
+//! blocking and async waiting, decorating functions, counting decisions, and reporting them to
+//! [`Listener`]s.
+
+use std::any::Any;
+#[cfg(feature = "async-wait")]
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::clock::{now_millis, Clock, SystemClock, NANOS_PER_MS};
+use crate::describe::{self, Describe, Description};
+use crate::gcra::{Denied, Gcra, GcraBuilder, Headroom, Policy};
+use crate::histogram::{AtomicHistogram, Histogram};
+use crate::listener::{Event, Listener, Listeners, Meta};
+use crate::observed::Ewma;
+use crate::quota::Quota;
+use crate::sleep::sleep;
+use crate::sync::{AtomicBool, AtomicU64, Ordering};
+#[cfg(feature = "async-wait")]
+use crate::timer::Sleep;
+use crate::waiters::WaitQueue;
+
+/// Wraps a policy with the full set of limiter methods.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use ratelimit::{Limiter, VirtualScheduling};
+///
+/// let limiter = Limiter::new(
+///     VirtualScheduling::builder()
+///         .gap(Duration::from_millis(5))
+///         .build(),
+/// );
+/// for _ in 0..3 {
+///     limiter.acquire().unwrap();
+/// }
+/// assert_eq!(limiter.stats().allowed, 3);
+/// ```
+///
+/// The limiter keeps its own clock, [`SystemClock`] by default, to estimate the observed request
+/// rates. Give it the same clock as the policy.
+///
+/// # Guarantees
+///
+/// Budget is taken from the policy at exactly one point, when a request is admitted, and
+/// nothing between that point and handing the admission to the caller can fail. Therefore:
+///
+/// - Dropping an [`until_ready`](Self::until_ready) future at any time consumes no budget, and
+///   its place in the waiter queue is released, waking the next waiter if it was at the head.
+/// - A [`Reservation`] that is dropped without being committed, including while unwinding from
+///   a panic, gives its request back to the policy.
+/// - The decorated functions hold a reservation while calling the wrapped function, so a panic
+///   in it gives the request back.
+/// - No lock is held while user code (the wrapped function, listeners) runs, and none of the
+///   locks poison, so a panic leaves the limiter fully usable.
+pub struct Limiter<P, C = SystemClock> {
+    policy: P,
+    clock: C,
+    listeners: Listeners,
+    allowed: AtomicU64,
+    denied: AtomicU64,
+    denied_in_a_row: AtomicU64,
+    // emit `Event::DenialStreak` once this many requests in a row were denied
+    streak_alarm: u64,
+    // traced decisions are reported if the hash of their trace ID is at most this
+    trace_sampling: u64,
+    queue_depth: AtomicHistogram,
+    wait_micros: AtomicHistogram,
+    // sleep until absolute deadlines in the blocking waits
+    precise_sleep: bool,
+    offered_rate: Ewma,
+    admitted_rate: Ewma,
+    waiters: WaitQueue,
+    forecast: Option<Forecast>,
+    // admits at the soft rate, requests it denies are admitted with a warning
+    soft: Option<Gcra<()>>,
+    // admits at the peak rate, requests must conform to it as well as to the policy
+    smoothing: Option<Gcra<()>>,
+    saturation: f64,
+    closed: AtomicBool,
+    // when async waiters queued before `close` give up
+    grace_until: AtomicU64,
+    // wakes the waiter at the head of the queue early, on close and on refill
+    #[cfg(feature = "async-wait")]
+    wake_head: tokio::sync::Notify,
+    #[cfg(feature = "async-wait")]
+    timer: Box<dyn Sleep>,
+}
+
+struct Forecast {
+    threshold: Duration,
+    // whether the last forecast was within the threshold
+    warned: AtomicBool,
+}
+
+/// Whether a [`Limiter`] keeps up with its demand, for readiness probes and autoscalers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Health {
+    Healthy,
+    /// Over the observe window, the limiter denied at least the
+    /// [`saturation_threshold`](Limiter::saturation_threshold) share of requests.
+    Saturated {
+        denial_ratio: f64,
+    },
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        matches!(self, Health::Healthy)
+    }
+}
+
+/// The limiter was [closed](Limiter::close).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Closed;
+
+impl std::fmt::Display for Closed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("limiter is closed")
+    }
+}
+
+impl std::error::Error for Closed {}
+
+/// A request [`decorate_with_meta`](Limiter::decorate_with_meta) turned away, given back with its
+/// metadata.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rejected<Req, M> {
+    pub request: Req,
+    pub meta: M,
+    pub denied: Denied,
+}
+
+impl<Req, M> std::fmt::Display for Rejected<Req, M> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "rejected: {}", self.denied)
+    }
+}
+
+impl<Req: std::fmt::Debug, M: std::fmt::Debug> std::error::Error for Rejected<Req, M> {}
+
+/// Decisions made through a [`Limiter`] so far.
+///
+/// Waiting in [`Limiter::acquire`] counts a denial for every time the policy was asked too early.
+///
+/// A limiter that denies shows in `denied`; one that paces, admitting requests only after a
+/// growing wait, shows in `queue_depth` and `wait_micros`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub allowed: u64,
+    pub denied: u64,
+    /// Denials since the last admitted request.
+    pub denied_in_a_row: u64,
+    /// Async waiters queued, including itself, as seen by each waiter on joining the queue.
+    pub queue_depth: Histogram,
+    /// Time admitted requests waited in blocking and async waits, in microseconds.
+    pub wait_micros: Histogram,
+}
+
+/// Request rates seen by a [`Limiter`], in requests per second.
+///
+/// Both are exponentially weighted moving averages that mostly reflect the last
+/// [`observe_window`](Limiter::observe_window), 10 seconds by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ObservedRate {
+    /// Requests asked about, admitted or not.
+    pub offered: f64,
+    pub admitted: f64,
+}
+
+impl<P> Limiter<P> {
+    pub fn new(policy: P) -> Self {
+        Limiter {
+            policy,
+            clock: SystemClock,
+            listeners: Listeners::default(),
+            allowed: AtomicU64::new(0),
+            denied: AtomicU64::new(0),
+            denied_in_a_row: AtomicU64::new(0),
+            streak_alarm: u64::MAX,
+            trace_sampling: u64::MAX,
+            queue_depth: AtomicHistogram::new(),
+            wait_micros: AtomicHistogram::new(),
+            precise_sleep: false,
+            offered_rate: Ewma::new(DEFAULT_OBSERVE_WINDOW),
+            admitted_rate: Ewma::new(DEFAULT_OBSERVE_WINDOW),
+            waiters: WaitQueue::new(None),
+            forecast: None,
+            soft: None,
+            smoothing: None,
+            saturation: DEFAULT_SATURATION,
+            closed: AtomicBool::new(false),
+            grace_until: AtomicU64::new(u64::MAX),
+            #[cfg(feature = "async-wait")]
+            wake_head: tokio::sync::Notify::new(),
+            #[cfg(feature = "async-wait")]
+            timer: crate::timer::default_timer(),
+        }
+    }
+}
+
+const DEFAULT_OBSERVE_WINDOW: Duration = Duration::from_secs(10);
+const DEFAULT_SATURATION: f64 = 0.95;
+
+impl<P, C> Limiter<P, C> {
+    pub fn clock<NC>(self, clock: NC) -> Limiter<P, NC> {
+        Limiter {
+            policy: self.policy,
+            clock,
+            listeners: self.listeners,
+            allowed: self.allowed,
+            denied: self.denied,
+            denied_in_a_row: self.denied_in_a_row,
+            streak_alarm: self.streak_alarm,
+            trace_sampling: self.trace_sampling,
+            queue_depth: self.queue_depth,
+            wait_micros: self.wait_micros,
+            precise_sleep: self.precise_sleep,
+            offered_rate: self.offered_rate,
+            admitted_rate: self.admitted_rate,
+            waiters: self.waiters,
+            forecast: self.forecast,
+            soft: self.soft,
+            smoothing: self.smoothing,
+            saturation: self.saturation,
+            closed: self.closed,
+            grace_until: self.grace_until,
+            #[cfg(feature = "async-wait")]
+            wake_head: self.wake_head,
+            #[cfg(feature = "async-wait")]
+            timer: self.timer,
+        }
+    }
+
+    /// Let async waiters gain one priority level for every `interval` they spent waiting, so
+    /// that low priority waiters are not starved by a steady stream of higher priority ones.
+    pub fn aging(mut self, interval: Duration) -> Self {
+        self.waiters
+            .set_aging(Some(interval.as_millis().max(1) as u64));
+        self
+    }
+
+    /// Number of async waiters currently queued.
+    pub fn waiting(&self) -> usize {
+        self.waiters.len()
+    }
+
+    /// Roughly how far back the observed rates look.
+    pub fn observe_window(mut self, window: Duration) -> Self {
+        self.offered_rate = Ewma::new(window);
+        self.admitted_rate = Ewma::new(window);
+        self
+    }
+
+    /// Report [`Health::Saturated`] once at least `ratio` of the offered requests are denied.
+    /// 0.95 by default.
+    ///
+    /// # Panics
+    /// Panics if `ratio` is not within `0.0..=1.0`.
+    pub fn saturation_threshold(mut self, ratio: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&ratio),
+            "saturation threshold must be a ratio"
+        );
+        self.saturation = ratio;
+        self
+    }
+
+    /// Emit [`Event::ExhaustionForecast`] when [`time_to_exhaustion`](Self::time_to_exhaustion)
+    /// drops to `threshold` or below. It fires once per crossing, not on every request.
+    pub fn forecast_threshold(mut self, threshold: Duration) -> Self {
+        self.forecast = Some(Forecast {
+            threshold,
+            warned: AtomicBool::new(false),
+        });
+        self
+    }
+
+    /// Emit [`Event::DenialStreak`] once `denials` requests in a row were denied, e.g. to catch
+    /// a misconfigured limit that black-holes a client. It fires once per streak.
+    ///
+    /// # Panics
+    /// Panics if `denials` is zero.
+    pub fn denial_alarm(mut self, denials: u64) -> Self {
+        assert!(denials > 0, "denial alarm must be positive");
+        self.streak_alarm = denials;
+        self
+    }
+
+    /// Report only a `rate` share of the decisions made with [`check_with`](Self::check_with),
+    /// all of them by default. Which ones is decided by a stable hash of the trace ID, so a
+    /// request traced through several limiters, or services, is reported by all or none.
+    ///
+    /// # Panics
+    /// Panics if `rate` is not within `0.0..=1.0`.
+    pub fn trace_sample_rate(mut self, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate), "sample rate must be a ratio");
+        self.trace_sampling = (rate * u64::MAX as f64) as u64;
+        self
+    }
+
+    /// Sleep in [`acquire`](Self::acquire) and [`try_acquire_for`](Self::try_acquire_for) with
+    /// `clock_nanosleep(TIMER_ABSTIME)` on `CLOCK_MONOTONIC`, waking at the deadline fixed when
+    /// the request was denied. Tight pacing loops then do not accumulate the drift of relative
+    /// sleeps. Other platforms keep using [`std::thread::sleep`].
+    #[cfg(feature = "precise-sleep")]
+    pub fn precise_sleep(mut self) -> Self {
+        self.precise_sleep = true;
+        self
+    }
+
+    /// Admit requests beyond `soft` as usual, but with a warning: they are reported as
+    /// [`Event::Allowed`] with `warning: true`, and tagged by
+    /// [`check_with_warning`](Self::check_with_warning). Set it below the policy's limits, e.g.
+    /// at 80% of the rate, to nudge clients before they are denied.
+    pub fn soft_limit(mut self, soft: Quota) -> Self {
+        self.soft = Some(GcraBuilder::new().quota(soft).build());
+        self
+    }
+
+    /// Release the burst of `quota`, the policy's quota, over `window` instead of all at once:
+    /// requests are also paced at a peak rate that lets the burst through in `window` on top of
+    /// the sustained rate, `(window + tolerance) / window` times that rate. For downstreams that
+    /// take elevated load but not the whole burst in one instant.
+    ///
+    /// A request the peak rate denies is not charged to the policy.
+    ///
+    /// # Panics
+    /// Panics if `window` is zero.
+    pub fn smooth_burst(mut self, quota: Quota, window: Duration) -> Self {
+        assert!(!window.is_zero(), "smoothing window must be positive");
+        let window_nanos = window.as_nanos();
+        let peak_gap =
+            quota.gap().as_nanos() * window_nanos / (window_nanos + quota.tolerance().as_nanos());
+        self.smoothing = Some(
+            GcraBuilder::new()
+                .gap(Duration::from_nanos(peak_gap.max(1) as u64))
+                // clocks read whole milliseconds, let a millisecond's worth through together
+                .tolerance(Duration::from_millis(1))
+                .build(),
+        );
+        self
+    }
+
+    /// Sleep on `timer` in the async waits, instead of the timer of the first runtime feature
+    /// enabled, see [`Sleep`].
+    #[cfg(feature = "async-wait")]
+    pub fn timer(mut self, timer: impl Sleep + 'static) -> Self {
+        self.timer = Box::new(timer);
+        self
+    }
+
+    /// Name the limiter in listener events.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.listeners.set_name(name.into());
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        self.listeners.name()
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    pub fn into_inner(self) -> P {
+        self.policy
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            allowed: self.allowed.load(Ordering::Relaxed),
+            denied: self.denied.load(Ordering::Relaxed),
+            denied_in_a_row: self.denied_in_a_row.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.snapshot(),
+            wait_micros: self.wait_micros.snapshot(),
+        }
+    }
+}
+
+impl<P, C> Limiter<P, C>
+where
+    C: Clock,
+{
+    /// Stop admitting new requests, e.g. when the service shuts down.
+    ///
+    /// From now on every check is denied with a `retry_after` of [`Duration::MAX`], and new
+    /// waits fail with [`Closed`]. Async waiters already queued keep being admitted as budget
+    /// allows for `grace`, and fail with [`Closed`] once it is over; a zero `grace` fails them
+    /// right away. Closing again can only shorten the grace period. Blocking waits do not get a
+    /// grace period, they fail the next time they ask the policy.
+    pub fn close(&self, grace: Duration) {
+        let grace = u64::try_from(grace.as_millis()).unwrap_or(u64::MAX);
+        let until = now_millis(&self.clock).saturating_add(grace);
+        self.grace_until.fetch_min(until, Ordering::SeqCst);
+        self.closed.store(true, Ordering::SeqCst);
+        self.waiters.wake_all();
+        #[cfg(feature = "async-wait")]
+        self.wake_head.notify_waiters();
+    }
+
+    /// Tell async waiters that budget came back early, e.g. after
+    /// [`add_tokens`](crate::Gcra::add_tokens) on the policy or a quota swap, rather than
+    /// letting them sleep until the time their last denial gave.
+    ///
+    /// Only the waiter at the head of the queue is woken. Each waiter admitted hands over to the
+    /// next, so the wakeups stop at the first waiter the budget cannot serve, however many are
+    /// queued. Blocking waits are not woken.
+    pub fn refilled(&self) {
+        #[cfg(feature = "async-wait")]
+        self.wake_head.notify_waiters();
+    }
+
+    /// Time left for queued waiters, or `None` if the grace period is over.
+    #[cfg_attr(not(feature = "async-wait"), allow(dead_code))]
+    fn grace_left(&self) -> Option<Duration> {
+        if !self.is_closed() {
+            return Some(Duration::MAX);
+        }
+        let left = self
+            .grace_until
+            .load(Ordering::SeqCst)
+            .checked_sub(now_millis(&self.clock))
+            .filter(|&left| left > 0)?;
+        Some(Duration::from_millis(left))
+    }
+
+    pub fn observed_rate(&self) -> ObservedRate {
+        let now = now_millis(&self.clock);
+        ObservedRate {
+            offered: self.offered_rate.rate(now),
+            admitted: self.admitted_rate.rate(now),
+        }
+    }
+
+    /// Whether the limiter has been denying most requests over the
+    /// [`observe_window`](Self::observe_window), meaning this instance is persistently over its
+    /// limits rather than seeing a short burst. Set the window to a minute or so for probes.
+    pub fn health(&self) -> Health {
+        let rate = self.observed_rate();
+        if rate.offered <= 0.0 {
+            return Health::Healthy;
+        }
+        let denial_ratio = (1.0 - rate.admitted / rate.offered).max(0.0);
+        if denial_ratio >= self.saturation {
+            Health::Saturated { denial_ratio }
+        } else {
+            Health::Healthy
+        }
+    }
+}
+
+impl<P, C> Limiter<P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    /// When the policy would run out of capacity if requests kept being admitted at the
+    /// observed rate, or `None` if it would not, or the policy does not report its
+    /// [`Headroom`]. A rough forecast: the observed rate lags behind changes of the pace.
+    pub fn time_to_exhaustion(&self) -> Option<Duration> {
+        let Headroom {
+            remaining,
+            refill_rate,
+        } = self.policy.headroom()?;
+        let drain = self.admitted_rate.rate(now_millis(&self.clock)) - refill_rate;
+        if drain <= 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64(remaining as f64 / drain))
+    }
+
+    fn forecast(&self) {
+        let Some(forecast) = &self.forecast else {
+            return;
+        };
+        let tte = self
+            .time_to_exhaustion()
+            .filter(|&tte| tte <= forecast.threshold);
+        let was_warned = forecast.warned.swap(tte.is_some(), Ordering::Relaxed);
+        if let (Some(time_to_exhaustion), false) = (tte, was_warned) {
+            self.listeners.emit(|policy| Event::ExhaustionForecast {
+                policy,
+                time_to_exhaustion,
+            });
+        }
+    }
+}
+
+impl<P, C> Policy for Limiter<P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    fn refund(&self) {
+        self.policy.refund();
+        if let Some(smoothing) = &self.smoothing {
+            smoothing.refund_n(1);
+        }
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        self.policy.headroom()
+    }
+
+    fn check(&self) -> Result<(), Denied> {
+        self.check_with_warning().map(|_| ())
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        if self.is_closed() {
+            return self.decide(Err(Denied::new(Duration::MAX)), n).map(|_| ());
+        }
+        self.decide(self.ask(n), n).map(|_| ())
+    }
+}
+
+impl<P, C> Describe for Limiter<P, C>
+where
+    P: Describe,
+{
+    fn describe(&self) -> Description {
+        let mut description = Description::new("limiter").named(self.name());
+        if let Some(soft) = &self.soft {
+            description = description.setting("soft_rate", describe::rate(soft.params().0));
+        }
+        if let Some(smoothing) = &self.smoothing {
+            description = description.setting("peak_rate", describe::rate(smoothing.params().0));
+        }
+        if self.is_closed() {
+            description = description.setting("mode", "closed");
+        }
+        description.child(self.policy.describe())
+    }
+}
+
+/// A request admitted by [`Limiter::check_with_warning`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Admitted {
+    /// The request is over the [`soft_limit`](Limiter::soft_limit).
+    pub warning: bool,
+}
+
+/// What the caller knows about a request, attached to its decision by
+/// [`Limiter::check_with`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecisionContext<'a> {
+    trace_id: &'a str,
+    always_trace: bool,
+}
+
+impl<'a> DecisionContext<'a> {
+    /// A request known by `trace_id`, e.g. the request ID of a web request or the trace ID of
+    /// its span.
+    pub fn new(trace_id: &'a str) -> Self {
+        DecisionContext {
+            trace_id,
+            always_trace: false,
+        }
+    }
+
+    /// Report the decision whatever the [`trace_sample_rate`](Limiter::trace_sample_rate), e.g.
+    /// for the requests of a user being debugged.
+    pub fn always_trace(mut self) -> Self {
+        self.always_trace = true;
+        self
+    }
+
+    pub fn trace_id(&self) -> &'a str {
+        self.trace_id
+    }
+
+    fn sampled(&self, sampling: u64) -> bool {
+        self.always_trace || sampling == u64::MAX || fnv1a(self.trace_id.as_bytes()) <= sampling
+    }
+}
+
+/// 64-bit FNV-1a, stable across processes and versions, unlike the std hashers.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl<P, C> Limiter<P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    /// Decide on one request like [`check`](Policy::check), and tell whether an admitted one is
+    /// over the [`soft_limit`](Self::soft_limit), e.g. to add a warning header to its response.
+    pub fn check_with_warning(&self) -> Result<Admitted, Denied> {
+        if self.is_closed() {
+            return self.decide(Err(Denied::new(Duration::MAX)), 1);
+        }
+        self.decide(self.ask(1), 1)
+    }
+
+    /// Decide on one request like [`check_with_warning`](Self::check_with_warning), and report
+    /// the decision with the request's trace ID as [`Event::Traced`], subject to the
+    /// [`trace_sample_rate`](Self::trace_sample_rate). A complaint about a specific request can
+    /// then be matched to the decision made on it.
+    pub fn check_with(&self, ctx: &DecisionContext<'_>) -> Result<Admitted, Denied> {
+        let decision = self.check_with_warning();
+        if ctx.sampled(self.trace_sampling) {
+            self.listeners.emit(|policy| Event::Traced {
+                policy,
+                trace_id: ctx.trace_id,
+                decision: decision.map(|_| ()),
+            });
+        }
+        decision
+    }
+
+    /// Ask the peak rate of [`smooth_burst`](Self::smooth_burst), if any, and the policy for
+    /// `n` cells.
+    fn ask(&self, n: u64) -> Result<(), Denied> {
+        let Some(smoothing) = &self.smoothing else {
+            return self.policy.check_n(n);
+        };
+        smoothing.check_n_at(self.clock.now_nanos(), n)?;
+        self.policy
+            .check_n(n)
+            .inspect_err(|_| smoothing.refund_n(n))
+    }
+
+    /// Count and report the decision on a request worth `n` cells.
+    fn decide(&self, decision: Result<(), Denied>, n: u64) -> Result<Admitted, Denied> {
+        let nanos = self.clock.now_nanos();
+        let now = nanos / NANOS_PER_MS;
+        self.offered_rate.record(now, n);
+        match decision {
+            Ok(()) => {
+                let warning = self
+                    .soft
+                    .as_ref()
+                    .is_some_and(|soft| soft.check_n_at(nanos, n).is_err());
+                self.admitted_rate.record(now, n);
+                self.allowed.fetch_add(1, Ordering::Relaxed);
+                self.denied_in_a_row.store(0, Ordering::Relaxed);
+                self.listeners
+                    .emit(|policy| Event::Allowed { policy, warning });
+                self.forecast();
+                Ok(Admitted { warning })
+            }
+            Err(denied) => {
+                self.denied.fetch_add(1, Ordering::Relaxed);
+                self.listeners.emit(|policy| Event::Denied {
+                    policy,
+                    retry_after: denied.retry_after(),
+                });
+                let streak = self.denied_in_a_row.fetch_add(1, Ordering::Relaxed) + 1;
+                if streak == self.streak_alarm {
+                    self.listeners.emit(|policy| Event::DenialStreak {
+                        policy,
+                        denials: streak,
+                    });
+                }
+                Err(denied)
+            }
+        }
+    }
+
+    /// Block the current thread until a request is admitted.
+    pub fn acquire(&self) -> Result<(), Closed> {
+        let start = Instant::now();
+        while let Err(denied) = self.check() {
+            if self.is_closed() {
+                return Err(Closed);
+            }
+            sleep(denied.retry_after(), self.precise_sleep);
+        }
+        self.waited(start);
+        Ok(())
+    }
+
+    /// Block the current thread until a request is admitted, or give up if that would take
+    /// longer than `timeout`.
+    pub fn try_acquire_for(&self, timeout: Duration) -> Result<(), Denied> {
+        let start = Instant::now();
+        let mut left = timeout;
+        loop {
+            match self.check() {
+                Ok(()) => {
+                    self.waited(start);
+                    return Ok(());
+                }
+                Err(denied) if denied.retry_after() > left => return Err(denied),
+                Err(denied) => {
+                    sleep(denied.retry_after(), self.precise_sleep);
+                    left -= denied.retry_after();
+                }
+            }
+        }
+    }
+
+    /// Wait until a request is admitted.
+    ///
+    /// Waiters queue up and are admitted one at a time, see
+    /// [`until_ready_with_priority`](Self::until_ready_with_priority).
+    #[cfg(feature = "async-wait")]
+    pub async fn until_ready(&self) -> Result<(), Closed> {
+        self.until_ready_with_priority(0).await
+    }
+
+    /// Wait until a request is admitted, ahead of all waiters with a lower `priority`.
+    ///
+    /// Only the waiter at the head of the queue asks the policy, so budget freed while several
+    /// tasks wait goes to the highest priority first and to the longest waiting among equals.
+    /// See [`aging`](Self::aging) to keep low priorities from starving.
+    #[cfg(feature = "async-wait")]
+    pub async fn until_ready_with_priority(&self, priority: u32) -> Result<(), Closed> {
+        let start = Instant::now();
+        if self.is_closed() {
+            return Err(Closed);
+        }
+        let ticket = self.waiters.join(priority, now_millis(&self.clock));
+        let depth = self.waiters.len();
+        self.queue_depth.record(depth as u64);
+        self.listeners
+            .emit(|policy| Event::Queued { policy, depth });
+        loop {
+            let grace_left = std::future::poll_fn(|cx| {
+                // register the waker before looking at the grace period, so a concurrent
+                // `close` cannot slip in between unnoticed
+                let is_head = ticket.is_head(cx.waker());
+                match self.grace_left() {
+                    None => std::task::Poll::Ready(Err(Closed)),
+                    Some(left) if is_head => std::task::Poll::Ready(Ok(left)),
+                    Some(_) => std::task::Poll::Pending,
+                }
+            })
+            .await?;
+            let mut woken = std::pin::pin!(self.wake_head.notified());
+            woken.as_mut().enable();
+            // queued waiters bypass the closed check until the grace period is over
+            match self.decide(self.ask(1), 1) {
+                Ok(_) => break,
+                Err(denied) => {
+                    let wait = denied.retry_after().min(grace_left);
+                    trace_internals!(priority, wait = ?wait, grace_left = ?grace_left, "waiter sleeping");
+                    let mut sleep = self.timer.sleep(wait);
+                    std::future::poll_fn(|cx| {
+                        if woken.as_mut().poll(cx).is_ready() || sleep.as_mut().poll(cx).is_ready()
+                        {
+                            return std::task::Poll::Ready(());
+                        }
+                        std::task::Poll::Pending
+                    })
+                    .await;
+                }
+            }
+        }
+        drop(ticket);
+        self.waited(start);
+        Ok(())
+    }
+
+    fn waited(&self, start: Instant) {
+        let waited = start.elapsed();
+        self.wait_micros
+            .record(u64::try_from(waited.as_micros()).unwrap_or(u64::MAX));
+        self.listeners
+            .emit(|policy| Event::Waited { policy, waited });
+    }
+
+    /// Call `f` for admitted requests, hand rejected ones back.
+    pub fn decorate<'a, Req, Resp>(
+        &'a self,
+        mut f: impl FnMut(Req) -> Resp + 'a,
+    ) -> impl FnMut(Req) -> Result<Resp, Req> + 'a {
+        move |req| match self.reserve() {
+            Ok(reservation) => {
+                let resp = f(req);
+                reservation.commit();
+                Ok(resp)
+            }
+            Err(_) => Err(req),
+        }
+    }
+
+    /// Like [`decorate`](Self::decorate), with a metadata value passed alongside each request,
+    /// such as its trace context or routing info. `f` sees the metadata of admitted requests;
+    /// rejected ones are handed back with theirs in a [`Rejected`], and reported as
+    /// [`Event::Rejected`].
+    pub fn decorate_with_meta<'a, Req, M, Resp>(
+        &'a self,
+        mut f: impl FnMut(Req, &M) -> Resp + 'a,
+    ) -> impl FnMut(Req, M) -> Result<Resp, Rejected<Req, M>> + 'a
+    where
+        M: Any,
+    {
+        move |request, meta| match self.reserve() {
+            Ok(reservation) => {
+                let resp = f(request, &meta);
+                reservation.commit();
+                Ok(resp)
+            }
+            Err(denied) => {
+                self.listeners.emit(|policy| Event::Rejected {
+                    policy,
+                    retry_after: denied.retry_after(),
+                    meta: Meta::new(&meta),
+                });
+                Err(Rejected {
+                    request,
+                    meta,
+                    denied,
+                })
+            }
+        }
+    }
+
+    /// Take one request from the policy, to be given back unless it is
+    /// [committed](Reservation::commit).
+    pub fn reserve(&self) -> Result<Reservation<'_, P, C>, Denied> {
+        self.check().map(|()| Reservation { limiter: self })
+    }
+
+    /// Like [`decorate`](Self::decorate), but a request waits for admission as long as it can
+    /// still make its [`Deadline`]. A request whose wait would outlast its deadline is handed
+    /// back right away instead of waiting in vain.
+    pub fn decorate_with_deadline<'a, Req, Resp>(
+        &'a self,
+        mut f: impl FnMut(Req) -> Resp + 'a,
+    ) -> impl FnMut(Req) -> Result<Resp, Req> + 'a
+    where
+        Req: Deadline,
+    {
+        move |req| {
+            let admitted = match req.remaining() {
+                Some(left) => self.try_acquire_for(left).is_ok(),
+                None => self.acquire().is_ok(),
+            };
+            if !admitted {
+                return Err(req);
+            }
+            let reservation = Reservation { limiter: self };
+            let resp = f(req);
+            reservation.commit();
+            Ok(resp)
+        }
+    }
+}
+
+/// An admitted request, given back to the policy when dropped unless committed.
+#[must_use = "dropping a reservation gives the request back"]
+pub struct Reservation<'a, P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    limiter: &'a Limiter<P, C>,
+}
+
+impl<P, C> Reservation<'_, P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    /// Keep the request.
+    pub fn commit(self) {
+        std::mem::forget(self);
+    }
+}
+
+impl<P, C> Drop for Reservation<'_, P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    fn drop(&mut self) {
+        self.limiter.refund();
+    }
+}
+
+/// Requests that carry a deadline, such as an RPC with a timeout propagated from its caller.
+pub trait Deadline {
+    /// Time left until the deadline, or `None` if there is none.
+    fn remaining(&self) -> Option<Duration>;
+}
+
+impl Deadline for Instant {
+    fn remaining(&self) -> Option<Duration> {
+        Some(self.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    use crate::clock::MockClock;
+    use crate::gcra::VirtualScheduling;
+
+    use super::*;
+
+    #[test]
+    fn test_limiter_stats() {
+        let clock = MockClock::new_now();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_millis(100))
+                .build(),
+        );
+        let mut f = limiter.decorate(|x: u32| x * 2);
+        assert_eq!(f(1), Ok(2));
+        assert_eq!(f(2), Err(2));
+        drop(f);
+        clock.forward(Duration::from_millis(100));
+        assert!(limiter.pass());
+        assert_eq!(
+            limiter.stats(),
+            Stats {
+                allowed: 2,
+                denied: 1,
+                denied_in_a_row: 0,
+                ..Stats::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_limiter_check_n() {
+        struct Unweighted;
+
+        impl Policy for Unweighted {
+            fn check(&self) -> Result<(), Denied> {
+                Ok(())
+            }
+        }
+
+        let clock = MockClock::new_now();
+        let limiter =
+            Limiter::new(VirtualScheduling::builder().clock(&clock).rate(10).build()).clock(&clock);
+        // weighted through the limiter, as through the GCRA
+        assert!(limiter.pass_n(4));
+        assert_eq!(
+            limiter.check_n(7),
+            Err(Denied::new(Duration::from_millis(100)))
+        );
+        assert!(limiter.pass_n(6));
+        assert_eq!((limiter.stats().allowed, limiter.stats().denied), (2, 1));
+        // policies that cannot weigh requests deny more than one cell
+        let limiter = Limiter::new(Unweighted);
+        assert!(limiter.pass_n(1) && limiter.pass_n(0));
+        assert_eq!(limiter.check_n(2), Err(Denied::new(Duration::MAX)));
+    }
+
+    #[test]
+    fn test_limiter_listener() {
+        let clock = MockClock::new_now();
+        let events = Arc::new(crate::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_millis(100))
+                .build(),
+        )
+        .named("api")
+        .listener(move |event: &Event<'_>| sink.lock().push(format!("{event:?}")));
+        assert!(limiter.pass());
+        assert!(!limiter.pass());
+        assert_eq!(
+            *events.lock(),
+            [
+                r#"Allowed { policy: "api", warning: false }"#,
+                r#"Denied { policy: "api", retry_after: 100ms }"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_limiter_check_with() {
+        let clock = MockClock::new_now();
+        let traced = Arc::new(crate::sync::Mutex::new(Vec::new()));
+        let sink = traced.clone();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_millis(100))
+                .build(),
+        )
+        .clock(&clock)
+        .trace_sample_rate(0.0)
+        .listener(move |event: &Event<'_>| {
+            if let Event::Traced {
+                trace_id, decision, ..
+            } = *event
+            {
+                sink.lock().push((trace_id.to_string(), decision.is_ok()));
+            }
+        });
+        assert!(limiter.check_with(&DecisionContext::new("req-1")).is_ok());
+        let ctx = DecisionContext::new("req-2").always_trace();
+        assert!(limiter.check_with(&ctx).is_err());
+        // only the decision traced regardless of sampling was reported
+        assert_eq!(*traced.lock(), vec![("req-2".to_string(), false)]);
+    }
+
+    #[test]
+    fn test_limiter_denial_alarm() {
+        let clock = MockClock::new_now();
+        let streaks = Arc::new(AtomicU64::new(0));
+        let sink = streaks.clone();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_secs(1))
+                .build(),
+        )
+        .denial_alarm(3)
+        .listener(move |event: &Event<'_>| {
+            if let Event::DenialStreak { denials, .. } = event {
+                assert_eq!(*denials, 3);
+                sink.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        assert!(limiter.pass());
+        for _ in 0..5 {
+            assert!(!limiter.pass());
+        }
+        assert_eq!(limiter.stats().denied_in_a_row, 5);
+        assert_eq!(streaks.load(Ordering::Relaxed), 1);
+        // an admitted request ends the streak
+        clock.forward(Duration::from_secs(1));
+        assert!(limiter.pass());
+        assert_eq!(limiter.stats().denied_in_a_row, 0);
+        for _ in 0..3 {
+            assert!(!limiter.pass());
+        }
+        assert_eq!(streaks.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_limiter_exhaustion_forecast() {
+        let clock = MockClock::new(1_000_000);
+        let forecasts = Arc::new(crate::sync::Mutex::new(0));
+        let sink = forecasts.clone();
+        let limiter = Limiter::new(
+            crate::GcraBuilder::new()
+                .clock(&clock)
+                .quota(crate::Quota::per_second(1).burst(99))
+                .build(),
+        )
+        .clock(&clock)
+        .observe_window(Duration::from_secs(1))
+        .forecast_threshold(Duration::from_secs(1))
+        .listener(move |event: &Event<'_>| {
+            if let Event::ExhaustionForecast { .. } = event {
+                *sink.lock() += 1;
+            }
+        });
+        assert_eq!(limiter.time_to_exhaustion(), None);
+
+        // 100 requests per second against a refill of one
+        for _ in 0..90 {
+            assert!(limiter.pass());
+            clock.forward(Duration::from_millis(10));
+        }
+        let tte = limiter.time_to_exhaustion().unwrap();
+        assert!(tte < Duration::from_millis(500), "{tte:?}");
+        assert_eq!(*forecasts.lock(), 1);
+
+        clock.forward(Duration::from_secs(60));
+        assert_eq!(limiter.time_to_exhaustion(), None);
+    }
+
+    #[test]
+    fn test_limiter_smooth_burst() {
+        let clock = MockClock::new(1_000_000);
+        let quota = crate::Quota::per_second(10).burst(10);
+        let limiter = Limiter::new(crate::GcraBuilder::new().clock(&clock).quota(quota).build())
+            .smooth_burst(quota, Duration::from_millis(1900))
+            .clock(&clock);
+        // the burst of 20 is paced at twice the rate instead
+        assert_eq!((0..20).filter(|_| limiter.pass()).count(), 1);
+        let mut admitted = 1;
+        for _ in 0..100 {
+            clock.forward(Duration::from_millis(10));
+            admitted += (0..5).filter(|_| limiter.pass()).count();
+        }
+        assert_eq!(admitted, 21);
+        // requests denied by the peak rate were not charged to the policy
+        assert_eq!(limiter.headroom().unwrap().remaining, 9);
+    }
+
+    #[test]
+    fn test_limiter_soft_limit() {
+        let clock = MockClock::new(1_000_000);
+        let warnings = Arc::new(crate::sync::Mutex::new(0));
+        let sink = warnings.clone();
+        let limiter = Limiter::new(
+            crate::GcraBuilder::new()
+                .clock(&clock)
+                .quota(crate::Quota::per_second(10))
+                .build(),
+        )
+        .soft_limit(crate::Quota::per_second(8))
+        .clock(&clock)
+        .listener(move |event: &Event<'_>| {
+            if let Event::Allowed { warning: true, .. } = event {
+                *sink.lock() += 1;
+            }
+        });
+        for _ in 0..8 {
+            assert_eq!(
+                limiter.check_with_warning(),
+                Ok(Admitted { warning: false })
+            );
+        }
+        for _ in 0..2 {
+            assert_eq!(limiter.check_with_warning(), Ok(Admitted { warning: true }));
+        }
+        assert!(limiter.check_with_warning().is_err());
+        assert_eq!(*warnings.lock(), 2);
+        clock.forward(Duration::from_secs(1));
+        assert_eq!(
+            limiter.check_with_warning(),
+            Ok(Admitted { warning: false })
+        );
+    }
+
+    #[test]
+    fn test_limiter_health() {
+        let clock = MockClock::new(1_000_000);
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_secs(1))
+                .build(),
+        )
+        .clock(&clock)
+        .observe_window(Duration::from_secs(60))
+        .saturation_threshold(0.9);
+        assert_eq!(limiter.health(), Health::Healthy);
+
+        // 20 requests per second against a rate of one
+        for _ in 0..1200 {
+            limiter.pass();
+            clock.forward(Duration::from_millis(50));
+        }
+        match limiter.health() {
+            Health::Saturated { denial_ratio } => assert!(denial_ratio > 0.94, "{denial_ratio}"),
+            Health::Healthy => panic!("not saturated"),
+        }
+
+        // back under the rate, the ratio recovers over the window
+        for _ in 0..120 {
+            limiter.pass();
+            clock.forward(Duration::from_secs(1));
+        }
+        assert!(limiter.health().is_healthy());
+    }
+
+    #[test]
+    fn test_limiter_acquire() {
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(20))
+                .build(),
+        );
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire().unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(55));
+        assert_eq!(limiter.stats().allowed, 4);
+        assert!(limiter.try_acquire_for(Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn test_limiter_decorate_with_deadline() {
+        struct Call(u32, Duration);
+
+        impl Deadline for Call {
+            fn remaining(&self) -> Option<Duration> {
+                Some(self.1)
+            }
+        }
+
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(30))
+                .build(),
+        );
+        let mut f = limiter.decorate_with_deadline(|call: Call| call.0);
+        assert_eq!(f(Call(1, Duration::ZERO)).ok(), Some(1));
+        // the next slot is up to 30ms away
+        assert!(f(Call(2, Duration::from_millis(1))).is_err());
+        let start = Instant::now();
+        assert_eq!(f(Call(3, Duration::from_millis(200))).ok(), Some(3));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn test_limiter_decorate_with_meta() {
+        let clock = MockClock::new_now();
+        let rejected = Arc::new(crate::sync::Mutex::new(Vec::new()));
+        let sink = rejected.clone();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_secs(1))
+                .build(),
+        )
+        .clock(&clock)
+        .listener(move |event: &Event<'_>| {
+            if let Event::Rejected { meta, .. } = *event {
+                sink.lock().push(*meta.downcast_ref::<&str>().unwrap());
+            }
+        });
+        let mut f = limiter.decorate_with_meta(|req: u32, route: &&str| format!("{route}:{req}"));
+        assert_eq!(f(1, "eu").unwrap(), "eu:1");
+        let err = f(2, "us").unwrap_err();
+        assert_eq!((err.request, err.meta), (2, "us"));
+        assert_eq!(err.denied.retry_after(), Duration::from_secs(1));
+        assert_eq!(*rejected.lock(), vec!["us"]);
+    }
+
+    #[test]
+    fn test_reservation_refund() {
+        let clock = MockClock::new_now();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_millis(100))
+                .build(),
+        );
+        let reservation = limiter.reserve().unwrap();
+        assert!(limiter.reserve().is_err());
+        drop(reservation);
+        limiter.reserve().unwrap().commit();
+        assert!(limiter.reserve().is_err());
+    }
+
+    #[test]
+    fn test_decorate_panic_refunds() {
+        let clock = MockClock::new_now();
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(Duration::from_millis(100))
+                .build(),
+        );
+        let mut f = limiter.decorate(|fail: bool| assert!(!fail));
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(true)));
+        assert!(panicked.is_err());
+        // the panicking call did not use up the budget
+        assert_eq!(f(false), Ok(()));
+        assert_eq!(f(false), Err(false));
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_until_ready_cancellation() {
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(50))
+                .build(),
+        );
+        limiter.acquire().unwrap();
+        // give up on a wait while at the head of the queue, and while parked behind it
+        let head = tokio::time::timeout(Duration::from_millis(5), limiter.until_ready());
+        let behind = tokio::time::timeout(Duration::from_millis(5), limiter.until_ready());
+        let (head, behind) = tokio::join!(head, behind);
+        assert!(head.is_err() && behind.is_err());
+        assert_eq!(limiter.waiting(), 0);
+        assert_eq!(limiter.stats().allowed, 1);
+        // nothing was consumed by the cancelled waits
+        let start = Instant::now();
+        limiter.until_ready().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_limiter_refilled() {
+        use std::sync::atomic::AtomicUsize;
+
+        let limiter = Arc::new(Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_secs(10))
+                .tolerance(Duration::from_secs(20))
+                .build(),
+        ));
+        limiter.policy().drain();
+        let admitted = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..5)
+            .map(|_| {
+                let (limiter, admitted) = (limiter.clone(), admitted.clone());
+                tokio::spawn(async move {
+                    limiter.until_ready().await.unwrap();
+                    admitted.fetch_add(1, Ordering::Relaxed);
+                })
+            })
+            .collect();
+        while limiter.waiting() < 5 {
+            tokio::task::yield_now().await;
+        }
+        // two cells serve the first two waiters long before the gap is over, the rest sleep on
+        limiter.policy().add_tokens(2);
+        limiter.refilled();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while admitted.load(Ordering::Relaxed) < 2 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(admitted.load(Ordering::Relaxed), 2);
+        assert_eq!(limiter.waiting(), 3);
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_limiter_until_ready() {
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(20))
+                .build(),
+        );
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.until_ready().await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(55));
+        let stats = limiter.stats();
+        assert_eq!(stats.wait_micros.count, 4);
+        assert!(stats.wait_micros.quantile(1.0) >= 10_000);
+
+        // three waiters at once queue up behind each other
+        let (a, b, c) = tokio::join!(
+            limiter.until_ready(),
+            limiter.until_ready(),
+            limiter.until_ready()
+        );
+        assert!(a.is_ok() && b.is_ok() && c.is_ok());
+        let depth = limiter.stats().queue_depth;
+        assert_eq!(depth.count, 7);
+        assert_eq!(depth.sum, 4 + 1 + 2 + 3);
+    }
+
+    #[test]
+    fn test_limiter_close_long_grace() {
+        let clock = MockClock::new(1_000);
+        let limiter =
+            Limiter::new(VirtualScheduling::builder().clock(&clock).rate(10).build()).clock(&clock);
+        // more milliseconds than a u64 holds, which must not wrap to a short grace period
+        limiter.close(Duration::from_secs(u64::MAX / 1_000 + 1));
+        assert!(limiter.is_closed());
+        assert_eq!(
+            limiter.grace_left(),
+            Some(Duration::from_millis(u64::MAX - 1_000))
+        );
+        limiter.close(Duration::from_secs(1));
+        assert_eq!(limiter.grace_left(), Some(Duration::from_secs(1)));
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_limiter_close() {
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(50))
+                .build(),
+        );
+        limiter.acquire().unwrap();
+        // a queued waiter is still admitted within the grace period
+        let (waited, ()) = tokio::join!(limiter.until_ready(), async {
+            tokio::task::yield_now().await;
+            limiter.close(Duration::from_secs(1));
+        });
+        assert_eq!(waited, Ok(()));
+        assert!(!limiter.pass());
+        assert_eq!(limiter.until_ready().await, Err(Closed));
+        assert_eq!(limiter.acquire(), Err(Closed));
+
+        // closing again without grace fails the queued waiters right away
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_secs(10))
+                .build(),
+        );
+        limiter.acquire().unwrap();
+        let start = Instant::now();
+        let (head, behind, ()) =
+            tokio::join!(limiter.until_ready(), limiter.until_ready(), async {
+                tokio::task::yield_now().await;
+                limiter.close(Duration::from_secs(60));
+                limiter.close(Duration::ZERO);
+            });
+        assert_eq!((head, behind), (Err(Closed), Err(Closed)));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(limiter.waiting(), 0);
+    }
+
+    #[cfg(feature = "async-wait")]
+    #[tokio::test]
+    async fn test_limiter_until_ready_with_priority() {
+        let limiter = Arc::new(Limiter::new(
+            VirtualScheduling::builder()
+                .gap(Duration::from_millis(20))
+                .build(),
+        ));
+        let order = Arc::new(crate::sync::Mutex::new(Vec::new()));
+        limiter.acquire().unwrap();
+        let mut tasks = Vec::new();
+        for (i, priority) in [0, 0, 5, 1, 5].into_iter().enumerate() {
+            let limiter = limiter.clone();
+            let order = order.clone();
+            tasks.push(tokio::spawn(async move {
+                limiter.until_ready_with_priority(priority).await.unwrap();
+                order.lock().push(i);
+            }));
+            tokio::task::yield_now().await;
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // higher priorities overtake waiters that were already queued
+        assert_eq!(*order.lock(), [2, 4, 3, 0, 1]);
+        assert_eq!(limiter.waiting(), 0);
+    }
+}
@@ -0,0 +1,143 @@
+//! Observing limiter decisions.
+//!
+//! A [`Listener`] attached to a [`Limiter`](crate::Limiter) is told about every decision as an
+//! [`Event`]. Metrics and tracing integrations are listeners.
+
+use std::any::Any;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::gcra::Denied;
+
+/// Something that happened in a named limiter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Event<'a> {
+    /// A request was admitted; `warning` if it is over a soft limit, see
+    /// [`Limiter::soft_limit`](crate::Limiter::soft_limit).
+    Allowed { policy: &'a str, warning: bool },
+    Denied {
+        policy: &'a str,
+        retry_after: Duration,
+    },
+    /// A decision made with [`Limiter::check_with`](crate::Limiter::check_with), for the request
+    /// known by `trace_id`. Reported in addition to `Allowed` or `Denied`.
+    Traced {
+        policy: &'a str,
+        trace_id: &'a str,
+        decision: Result<(), Denied>,
+    },
+    /// A request decorated with [`Limiter::decorate_with_meta`](crate::Limiter::decorate_with_meta)
+    /// was rejected, carrying `meta`. Reported in addition to `Denied`.
+    Rejected {
+        policy: &'a str,
+        retry_after: Duration,
+        meta: Meta<'a>,
+    },
+    /// An async waiter joined the queue, making it `depth` waiters long.
+    Queued { policy: &'a str, depth: usize },
+    /// A blocking or async wait finished with the request admitted.
+    Waited { policy: &'a str, waited: Duration },
+    /// A remote backend failed, decisions are degraded until it recovers.
+    BackendDown { policy: &'a str },
+    /// A remote backend answered again after being down for `downtime`.
+    BackendUp { policy: &'a str, downtime: Duration },
+    /// A key of an [`Escalation`](crate::Escalation) reached `level` and is banned for `ban`.
+    Escalated {
+        policy: &'a str,
+        key: &'a str,
+        level: u32,
+        ban: Duration,
+    },
+    /// `denials` requests in a row were denied, see
+    /// [`denial_alarm`](crate::Limiter::denial_alarm).
+    DenialStreak { policy: &'a str, denials: u64 },
+    /// At the pace of the observed admitted rate, the policy runs out of capacity within the
+    /// configured [`forecast_threshold`](crate::Limiter::forecast_threshold).
+    ExhaustionForecast {
+        policy: &'a str,
+        time_to_exhaustion: Duration,
+    },
+}
+
+/// The metadata of a [rejected](Event::Rejected) request, of whatever type it was given as.
+#[derive(Clone, Copy)]
+pub struct Meta<'a>(&'a dyn Any);
+
+impl<'a> Meta<'a> {
+    pub(crate) fn new(meta: &'a dyn Any) -> Self {
+        Meta(meta)
+    }
+
+    /// The metadata, if it is a `T`.
+    pub fn downcast_ref<T: Any>(&self) -> Option<&'a T> {
+        self.0.downcast_ref()
+    }
+}
+
+impl fmt::Debug for Meta<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Meta(..)")
+    }
+}
+
+/// The same metadata value, not merely an equal one.
+impl PartialEq for Meta<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self.0, other.0)
+    }
+}
+
+impl Eq for Meta<'_> {}
+
+pub trait Listener: Send + Sync {
+    fn on_event(&self, event: &Event<'_>);
+}
+
+impl<F> Listener for F
+where
+    F: Fn(&Event<'_>) + Send + Sync,
+{
+    fn on_event(&self, event: &Event<'_>) {
+        self(event)
+    }
+}
+
+impl<L: Listener + ?Sized> Listener for Arc<L> {
+    fn on_event(&self, event: &Event<'_>) {
+        (**self).on_event(event)
+    }
+}
+
+/// The listeners of one named component.
+#[derive(Default)]
+pub(crate) struct Listeners {
+    name: String,
+    listeners: Vec<Arc<dyn Listener>>,
+}
+
+impl Listeners {
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn set_name(&mut self, name: String) {
+        self.name = name;
+    }
+
+    pub(crate) fn push(&mut self, listener: impl Listener + 'static) {
+        self.listeners.push(Arc::new(listener));
+    }
+
+    /// Build an event for the component's name and hand it to every listener.
+    pub(crate) fn emit<'a>(&'a self, event: impl FnOnce(&'a str) -> Event<'a>) {
+        if self.listeners.is_empty() {
+            return;
+        }
+        let event = event(&self.name);
+        for listener in &self.listeners {
+            listener.on_event(&event);
+        }
+    }
+}
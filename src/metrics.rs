@@ -0,0 +1,210 @@
+//! [metrics](https://docs.rs/metrics) facade instrumentation, enabled by the `metrics` feature.
+
+use metrics::{counter, histogram, Label};
+
+use crate::listener::{Event, Listener};
+
+/// Records decisions of a [`Limiter`](crate::Limiter) through the `metrics` facade, with whatever
+/// recorder the application installed.
+///
+/// - `<prefix>.allowed` and `<prefix>.denied` count decisions,
+/// - `<prefix>.wait` is a histogram of the time spent waiting for admission, in seconds,
+/// - `<prefix>.queue_depth` is a histogram of the async wait queue's length as seen by each
+///   waiter joining it,
+///
+/// labelled with the limiter's name as `policy` plus any extra labels. The prefix defaults to
+/// `ratelimit`.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Limiter, MetricsListener, VirtualScheduling};
+///
+/// let limiter = Limiter::new(VirtualScheduling::builder().rate(100).build())
+///     .named("outbound")
+///     .listener(MetricsListener::new().prefix("gateway").label("region", "eu"));
+/// ```
+pub struct MetricsListener {
+    allowed: String,
+    denied: String,
+    wait: String,
+    queue_depth: String,
+    labels: Vec<Label>,
+}
+
+impl MetricsListener {
+    pub fn new() -> Self {
+        MetricsListener {
+            allowed: String::new(),
+            denied: String::new(),
+            wait: String::new(),
+            queue_depth: String::new(),
+            labels: Vec::new(),
+        }
+        .prefix("ratelimit")
+    }
+
+    pub fn prefix(mut self, prefix: &str) -> Self {
+        self.allowed = format!("{prefix}.allowed");
+        self.denied = format!("{prefix}.denied");
+        self.wait = format!("{prefix}.wait");
+        self.queue_depth = format!("{prefix}.queue_depth");
+        self
+    }
+
+    /// Add a label to every metric.
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels
+            .push(Label::new(key.to_string(), value.to_string()));
+        self
+    }
+
+    fn labels(&self, policy: &str) -> Vec<Label> {
+        let mut labels = self.labels.clone();
+        labels.push(Label::new("policy", policy.to_string()));
+        labels
+    }
+}
+
+impl Default for MetricsListener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Listener for MetricsListener {
+    fn on_event(&self, event: &Event<'_>) {
+        match *event {
+            Event::Allowed { policy, .. } => {
+                counter!(self.allowed.clone(), self.labels(policy)).increment(1);
+            }
+            Event::Denied { policy, .. } => {
+                counter!(self.denied.clone(), self.labels(policy)).increment(1);
+            }
+            Event::Waited { policy, waited } => {
+                histogram!(self.wait.clone(), self.labels(policy)).record(waited.as_secs_f64());
+            }
+            Event::Queued { policy, depth } => {
+                histogram!(self.queue_depth.clone(), self.labels(policy)).record(depth as f64);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use metrics::{
+        Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+        SharedString, Unit,
+    };
+
+    use super::*;
+
+    // (name, labels, value) of every increment and record
+    type Log = Arc<Mutex<Vec<(String, Vec<String>, f64)>>>;
+
+    struct Entry {
+        key: Key,
+        log: Log,
+    }
+
+    impl Entry {
+        fn push(&self, value: f64) {
+            let labels = self
+                .key
+                .labels()
+                .map(|label| format!("{}={}", label.key(), label.value()))
+                .collect();
+            self.log
+                .lock()
+                .unwrap()
+                .push((self.key.name().to_string(), labels, value));
+        }
+    }
+
+    impl CounterFn for Entry {
+        fn increment(&self, value: u64) {
+            self.push(value as f64);
+        }
+
+        fn absolute(&self, _: u64) {}
+    }
+
+    impl HistogramFn for Entry {
+        fn record(&self, value: f64) {
+            self.push(value);
+        }
+    }
+
+    #[derive(Default)]
+    struct TestRecorder {
+        log: Log,
+    }
+
+    impl Recorder for TestRecorder {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(Arc::new(Entry {
+                key: key.clone(),
+                log: self.log.clone(),
+            }))
+        }
+
+        fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::noop()
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(Arc::new(Entry {
+                key: key.clone(),
+                log: self.log.clone(),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_metrics_listener() {
+        let recorder = TestRecorder::default();
+        let listener = MetricsListener::new()
+            .prefix("gateway")
+            .label("region", "eu");
+        metrics::with_local_recorder(&recorder, || {
+            listener.on_event(&Event::Allowed {
+                policy: "outbound",
+                warning: false,
+            });
+            listener.on_event(&Event::Denied {
+                policy: "outbound",
+                retry_after: Duration::from_secs(1),
+            });
+            listener.on_event(&Event::Waited {
+                policy: "outbound",
+                waited: Duration::from_millis(250),
+            });
+            listener.on_event(&Event::Queued {
+                policy: "outbound",
+                depth: 3,
+            });
+            // not a metric
+            listener.on_event(&Event::BackendDown { policy: "outbound" });
+        });
+
+        let labels = vec!["region=eu".to_string(), "policy=outbound".to_string()];
+        let log = recorder.log.lock().unwrap();
+        assert_eq!(
+            *log,
+            [
+                ("gateway.allowed".to_string(), labels.clone(), 1.0),
+                ("gateway.denied".to_string(), labels.clone(), 1.0),
+                ("gateway.wait".to_string(), labels.clone(), 0.25),
+                ("gateway.queue_depth".to_string(), labels, 3.0),
+            ]
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Observed request rates.
+
+use std::time::Duration;
+
+use crate::clock::Timestamp;
+use crate::sync::Mutex;
+
+/// Exponentially decaying request counter, read as a rate.
+///
+/// Every recorded request adds one to a counter that decays with time constant `tau`. For a steady
+/// stream of `r` requests per second the counter settles at `r * tau`, so dividing by `tau` gives
+/// an estimate of the rate that mostly reflects the last `tau` or so.
+pub(crate) struct Ewma {
+    tau: f64, // in ms
+    state: Mutex<EwmaState>,
+}
+
+struct EwmaState {
+    count: f64,
+    at: Timestamp,
+}
+
+impl Ewma {
+    pub(crate) fn new(tau: Duration) -> Self {
+        Ewma {
+            tau: tau.as_millis().max(1) as f64,
+            state: Mutex::new(EwmaState { count: 0.0, at: 0 }),
+        }
+    }
+
+    /// The time constant the counter decays with.
+    pub(crate) fn window(&self) -> Duration {
+        Duration::from_millis(self.tau as u64)
+    }
+
+    pub(crate) fn record(&self, now: Timestamp, n: u64) {
+        let mut state = self.state.lock();
+        state.count = self.decayed(&state, now) + n as f64;
+        state.at = std::cmp::max(state.at, now);
+    }
+
+    /// Requests per second.
+    pub(crate) fn rate(&self, now: Timestamp) -> f64 {
+        let state = self.state.lock();
+        self.decayed(&state, now) / self.tau * 1000.0
+    }
+
+    /// Record `n` if the counter stays within `limit`, or return the current count.
+    pub(crate) fn record_within(&self, now: Timestamp, n: u64, limit: f64) -> Result<(), f64> {
+        let mut state = self.state.lock();
+        let count = self.decayed(&state, now);
+        if count + n as f64 > limit {
+            return Err(count);
+        }
+        state.count = count + n as f64;
+        state.at = std::cmp::max(state.at, now);
+        Ok(())
+    }
+
+    /// Take back `n` recorded requests.
+    pub(crate) fn unrecord(&self, now: Timestamp, n: u64) {
+        let mut state = self.state.lock();
+        state.count = (self.decayed(&state, now) - n as f64).max(0.0);
+        state.at = std::cmp::max(state.at, now);
+    }
+
+    pub(crate) fn count(&self, now: Timestamp) -> f64 {
+        self.decayed(&self.state.lock(), now)
+    }
+
+    /// How long until the counter decays to `target`.
+    pub(crate) fn decays_to(&self, count: f64, target: f64) -> Duration {
+        if count <= target {
+            return Duration::ZERO;
+        }
+        if target <= 0.0 {
+            return Duration::MAX;
+        }
+        Duration::from_millis((self.tau * (count / target).ln()).ceil() as u64)
+    }
+
+    fn decayed(&self, state: &EwmaState, now: Timestamp) -> f64 {
+        let elapsed = now.saturating_sub(state.at) as f64;
+        state.count * (-elapsed / self.tau).exp()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ewma_converges() {
+        let ewma = Ewma::new(Duration::from_secs(1));
+        for t in 0..10_000 {
+            if t % 10 == 0 {
+                ewma.record(t, 1);
+            }
+        }
+        let rate = ewma.rate(10_000);
+        assert!((rate - 100.0).abs() < 1.0, "{rate}");
+        // idle for a long time, the estimate fades out
+        assert!(ewma.rate(30_000) < 0.01);
+    }
+}
@@ -0,0 +1,226 @@
+//! Serving a remote limit at local latency, with the backend catching up behind.
+//!
+//! [`Prefetch`](crate::Prefetch) and [`Cached`](crate::Cached) still wait for the backend now
+//! and then. [`Optimistic`] never does: a local policy decides every request at once, and the
+//! backend, the authority on the limit, is told about the admitted ones afterwards, in the
+//! background. Where the two disagree, the local policy pays the difference out of its future
+//! budget.
+
+use std::sync::Arc;
+
+use crate::gcra::{Denied, Headroom, Policy};
+use crate::reconciler::{check_local, ReconcileStats, Reconciler, Report};
+use crate::remote::Backend;
+use crate::sync::Mutex;
+
+/// A [`Policy`] deciding with `local` right away and reconciling with a [`Backend`] later.
+///
+/// Every request admitted locally is then asked of the backend on a background thread, a cell
+/// at a time for one worth several. A
+/// request the backend denies was admitted over the global limit: it becomes a debt, which the
+/// next requests pay with local cells before any is admitted, so the local policy admits that
+/// much less. Requests denied locally are never asked of the backend, so the backend is only
+/// charged for what was admitted. Backend errors leave the local decisions standing, the way
+/// [`FailureMode::Local`](crate::FailureMode::Local) would. The bookkeeping is a
+/// [`Reconciler`], which drivers for other transports can share.
+///
+/// The global limit can be overshot by what is admitted while the backend's answers are on
+/// their way, plus whatever is admitted before the debt is paid. Give `local` a share of the
+/// global quota, as for `FailureMode::Local`.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Backend, Denied, LeakyBucket, Optimistic, Policy, Quota};
+///
+/// struct Store;
+///
+/// impl Backend for Store {
+///     type Error = std::io::Error;
+///
+///     fn try_check(&self) -> Result<Result<(), Denied>, Self::Error> {
+///         // ask the shared store
+///         Ok(Ok(()))
+///     }
+/// }
+///
+/// let local = LeakyBucket::builder().quota(Quota::per_second(10)).build();
+/// let policy = Optimistic::new(local, Store);
+/// assert!(policy.check().is_ok());
+/// ```
+pub struct Optimistic<P, B> {
+    local: P,
+    shared: Arc<Shared<B>>,
+}
+
+struct Shared<B> {
+    backend: B,
+    reconciler: Mutex<Reconciler>,
+}
+
+impl<P, B> Optimistic<P, B> {
+    pub fn new(local: P, backend: B) -> Self {
+        Optimistic {
+            local,
+            shared: Arc::new(Shared {
+                backend,
+                reconciler: Mutex::new(Reconciler::new()),
+            }),
+        }
+    }
+
+    pub fn local(&self) -> &P {
+        &self.local
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.shared.backend
+    }
+
+    /// Cells the local policy still owes for requests the backend denied.
+    pub fn debt(&self) -> u64 {
+        self.shared.reconciler.lock().debt()
+    }
+
+    /// Requests admitted locally that the backend has not answered for yet.
+    pub fn pending(&self) -> u64 {
+        self.shared.reconciler.lock().pending()
+    }
+
+    pub fn stats(&self) -> ReconcileStats {
+        self.shared.reconciler.lock().stats()
+    }
+}
+
+impl<B> Shared<B>
+where
+    B: Backend,
+{
+    /// Ask the backend for `report`, then for every report still pending.
+    fn report(&self, mut report: Report) {
+        loop {
+            let answer = self.backend.try_check();
+            let mut reconciler = self.reconciler.lock();
+            match answer {
+                Ok(answer) => reconciler.on_response(report, answer),
+                Err(_) => reconciler.on_error(report),
+            }
+            match reconciler.poll_action() {
+                Some(next) => report = next,
+                None => return,
+            }
+        }
+    }
+}
+
+impl<P, B> Optimistic<P, B>
+where
+    B: Backend + Send + Sync + 'static,
+{
+    /// Start a thread asking the backend for the pending requests, unless one is running.
+    fn reconcile(&self) {
+        let Some(report) = self.shared.reconciler.lock().poll_action() else {
+            return;
+        };
+        let shared = self.shared.clone();
+        std::thread::spawn(move || shared.report(report));
+    }
+}
+
+impl<P, B> Policy for Optimistic<P, B>
+where
+    P: Policy,
+    B: Backend + Send + Sync + 'static,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        check_local(&self.shared.reconciler, &self.local, n)?;
+        self.reconcile();
+        Ok(())
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        let debt = self.debt();
+        self.local.headroom().map(|headroom| Headroom {
+            remaining: headroom.remaining.saturating_sub(debt),
+            ..headroom
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::gcra::VirtualScheduling;
+    use crate::sync::{AtomicU64, Ordering};
+
+    use super::*;
+
+    struct Pool {
+        left: AtomicU64,
+    }
+
+    impl Backend for Pool {
+        type Error = ();
+
+        fn try_check(&self) -> Result<Result<(), Denied>, ()> {
+            match self
+                .left
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| {
+                    left.checked_sub(1)
+                }) {
+                Ok(_) => Ok(Ok(())),
+                Err(_) => Ok(Err(Denied::new(Duration::from_secs(1)))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_optimistic() {
+        let clock = MockClock::new_now();
+        let local = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_secs(1))
+            .tolerance(Duration::from_secs(9))
+            .build();
+        let policy = Optimistic::new(
+            local,
+            Pool {
+                left: AtomicU64::new(4),
+            },
+        );
+        let settle = || {
+            while policy.pending() > 0 {
+                std::thread::yield_now();
+            }
+        };
+
+        // the local burst of 10 is served at once, the pool only had 4 of them
+        for _ in 0..10 {
+            assert!(policy.check().is_ok());
+        }
+        settle();
+        assert_eq!(
+            policy.stats(),
+            ReconcileStats {
+                agreed: 4,
+                overadmitted: 6,
+                failed: 0,
+            }
+        );
+        assert_eq!(policy.debt(), 6);
+
+        // 10 cells come back locally, 6 of them pay the debt
+        clock.forward(Duration::from_secs(10));
+        assert_eq!(policy.headroom().unwrap().remaining, 4);
+        let admitted = (0..10).filter(|_| policy.check().is_ok()).count();
+        assert_eq!(admitted, 4);
+        // the pool is empty, so those 4 are owed in turn
+        settle();
+        assert_eq!(policy.debt(), 4);
+    }
+}
@@ -0,0 +1,95 @@
+//! OpenTelemetry instrumentation, enabled by the `otel` feature.
+
+use opentelemetry::metrics::{Counter, Histogram, Meter};
+use opentelemetry::trace::get_active_span;
+use opentelemetry::KeyValue;
+
+use crate::listener::{Event, Listener};
+
+/// Records decisions of a [`Limiter`](crate::Limiter) as OpenTelemetry metrics.
+///
+/// - `ratelimit.allowed` and `ratelimit.denied` count decisions,
+/// - `ratelimit.wait` is a histogram of the time spent waiting for admission, in seconds,
+/// - `ratelimit.queue_depth` is a histogram of the async wait queue's length as seen by each
+///   waiter joining it,
+///
+/// all with a `policy` attribute carrying the limiter's name. Every denial also adds a
+/// `rate_limited` event with `policy` and `retry_after_ms` to the active span.
+///
+/// # Example
+/// ```no_run
+/// use ratelimit::{Limiter, OtelListener, VirtualScheduling};
+///
+/// let meter = opentelemetry::global::meter("my-service");
+/// let limiter = Limiter::new(VirtualScheduling::builder().rate(100).build())
+///     .named("outbound")
+///     .listener(OtelListener::new(&meter));
+/// ```
+pub struct OtelListener {
+    allowed: Counter<u64>,
+    denied: Counter<u64>,
+    wait: Histogram<f64>,
+    queue_depth: Histogram<u64>,
+}
+
+impl OtelListener {
+    pub fn new(meter: &Meter) -> Self {
+        OtelListener {
+            allowed: meter
+                .u64_counter("ratelimit.allowed")
+                .with_description("Requests admitted by the rate limiter")
+                .build(),
+            denied: meter
+                .u64_counter("ratelimit.denied")
+                .with_description("Requests denied by the rate limiter")
+                .build(),
+            wait: meter
+                .f64_histogram("ratelimit.wait")
+                .with_description("Time spent waiting for the rate limiter")
+                .with_unit("s")
+                .build(),
+            queue_depth: meter
+                .u64_histogram("ratelimit.queue_depth")
+                .with_description("Async waiters queued for the rate limiter")
+                .build(),
+        }
+    }
+}
+
+impl Listener for OtelListener {
+    fn on_event(&self, event: &Event<'_>) {
+        match *event {
+            Event::Allowed { policy, .. } => {
+                self.allowed
+                    .add(1, &[KeyValue::new("policy", policy.to_string())]);
+            }
+            Event::Denied {
+                policy,
+                retry_after,
+            } => {
+                self.denied
+                    .add(1, &[KeyValue::new("policy", policy.to_string())]);
+                get_active_span(|span| {
+                    span.add_event(
+                        "rate_limited",
+                        vec![
+                            KeyValue::new("policy", policy.to_string()),
+                            KeyValue::new("retry_after_ms", retry_after.as_millis() as i64),
+                        ],
+                    )
+                });
+            }
+            Event::Waited { policy, waited } => {
+                self.wait.record(
+                    waited.as_secs_f64(),
+                    &[KeyValue::new("policy", policy.to_string())],
+                );
+            }
+            Event::Queued { policy, depth } => {
+                self.queue_depth
+                    .record(depth as u64, &[KeyValue::new("policy", policy.to_string())]);
+            }
+            _ => {}
+        }
+    }
+}
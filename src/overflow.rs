@@ -0,0 +1,97 @@
+//! Caps on the queues that hold items back, such as [`Shaper`](crate::Shaper) and
+//! [`RetryQueue`](crate::RetryQueue).
+//!
+//! An item a queue holds back is throttled: it goes out later. An item that would take a queue
+//! over one of its caps is backlogged beyond policy: the queue gives it back in a
+//! [`Backlogged`] error naming the cap, for the caller to shed or send elsewhere.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::clock::Timestamp;
+
+/// The cap of a queue an item would have gone over, with its value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// The queue holds this many items already.
+    Items(usize),
+    /// The item does not fit within this many bytes, with those queued.
+    Bytes(usize),
+    /// The oldest item it would wait behind has waited longer than this already.
+    Age(Duration),
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Overflow::Items(max) => write!(f, "queue holds its maximum of {max} items"),
+            Overflow::Bytes(max) => write!(f, "queue would exceed its maximum of {max} bytes"),
+            Overflow::Age(max) => write!(f, "queue is more than {max:?} behind"),
+        }
+    }
+}
+
+/// An item a queue turned away, given back with the cap it would have gone over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backlogged<T> {
+    pub item: T,
+    pub overflow: Overflow,
+}
+
+impl<T> fmt::Display for Backlogged<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "backlogged: {}", self.overflow)
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for Backlogged<T> {}
+
+/// The caps of a queue of `T`, none by default.
+pub(crate) struct Caps<T> {
+    pub(crate) max_items: Option<usize>,
+    pub(crate) max_bytes: Option<usize>,
+    // the size of an item in bytes, 0 without a byte cap
+    pub(crate) size: fn(&T) -> usize,
+    pub(crate) max_age: Option<Duration>,
+}
+
+impl<T> Default for Caps<T> {
+    fn default() -> Self {
+        Caps {
+            max_items: None,
+            max_bytes: None,
+            size: |_| 0,
+            max_age: None,
+        }
+    }
+}
+
+impl<T> Caps<T> {
+    pub(crate) fn size(&self, item: &T) -> usize {
+        (self.size)(item)
+    }
+
+    /// Whether an item of `size` bytes may join a queue of `len` items and `bytes` bytes,
+    /// behind an oldest item queued at `oldest`.
+    pub(crate) fn check(
+        &self,
+        len: usize,
+        bytes: usize,
+        size: usize,
+        oldest: Option<Timestamp>,
+        now: Timestamp,
+    ) -> Result<(), Overflow> {
+        if let Some(max) = self.max_items.filter(|&max| len >= max) {
+            return Err(Overflow::Items(max));
+        }
+        if let Some(max) = self.max_bytes.filter(|&max| bytes + size > max) {
+            return Err(Overflow::Bytes(max));
+        }
+        if let (Some(max), Some(oldest)) = (self.max_age, oldest) {
+            if now.saturating_sub(oldest) > max.as_millis() as u64 {
+                return Err(Overflow::Age(max));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! Pacing a sender loop at rates beyond what a millisecond clock and OS timers resolve.
+
+use std::cmp;
+use std::hint;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::quota::Quota;
+
+/// Spaces sends evenly at a fixed rate, e.g. 100k packets per second.
+///
+/// Unlike the policies, a `Pacer` keeps time with [`Instant`] at nanosecond precision and does
+/// not reject: [`pace_next`](Self::pace_next) blocks until the next send is due. Long waits
+/// sleep until shortly before the deadline and spin for the rest, because sleeps overshoot by
+/// roughly the timer slack, often 50µs or more, which is several send slots at high rates.
+/// Raising [`spin`](Self::spin) buys precision with CPU time.
+///
+/// A sender that falls behind, e.g. because it was descheduled, may catch up by sending faster
+/// for at most [`max_catch_up`](Self::max_catch_up) worth of slots; anything older is dropped
+/// rather than sent in a burst. By default the schedule then restarts from the late send, so a
+/// loop that often wakes late drifts below the rate; in [`precise`](Self::precise) mode it stays
+/// on the original grid instead. [`stats`](Self::stats) tells the rate actually achieved.
+///
+/// # Example
+/// ```no_run
+/// use ratelimit::{Pacer, Quota};
+///
+/// let mut pacer = Pacer::new(Quota::per_second(100_000));
+/// # let packets: Vec<Vec<u8>> = Vec::new();
+/// for packet in packets {
+///     pacer.pace_next();
+///     // socket.send(&packet)
+/// }
+/// ```
+pub struct Pacer {
+    gap: Duration,
+    spin: Duration,
+    max_catch_up: Duration,
+    precise: bool,
+    next: Option<Instant>,
+    sent: u64,
+    missed: u64,
+    // when the first and the latest send went out
+    first: Option<Instant>,
+    last: Option<Instant>,
+}
+
+/// Sends paced by a [`Pacer`] so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PacerStats {
+    pub sent: u64,
+    /// Slots dropped because the sender was too far behind.
+    pub missed: u64,
+    /// Sends per second between the first and the latest send, 0 before the second.
+    pub achieved_rate: f64,
+}
+
+impl Pacer {
+    /// Pace at the rate of `quota`. Its burst is ignored, see
+    /// [`max_catch_up`](Self::max_catch_up) instead.
+    pub fn new(quota: Quota) -> Self {
+        Pacer {
+            gap: quota.gap(),
+            spin: Duration::from_micros(100),
+            max_catch_up: Duration::ZERO,
+            precise: false,
+            next: None,
+            sent: 0,
+            missed: 0,
+            first: None,
+            last: None,
+        }
+    }
+
+    /// Spin instead of sleeping for the last `spin` before a send. 100µs by default.
+    pub fn spin(mut self, spin: Duration) -> Self {
+        self.spin = spin;
+        self
+    }
+
+    /// How far behind schedule a sender may be and still catch up. Zero by default.
+    pub fn max_catch_up(mut self, max_catch_up: Duration) -> Self {
+        self.max_catch_up = max_catch_up;
+        self
+    }
+
+    /// Keep every send on the schedule set by the first one, `gap` apart, even after late
+    /// wakeups. A late send does not delay the following ones, so sleep overshoot does not add
+    /// up over a long loop; slots older than [`max_catch_up`](Self::max_catch_up) are still
+    /// dropped, but whole, keeping the remaining ones on the grid.
+    pub fn precise(mut self) -> Self {
+        self.precise = true;
+        self
+    }
+
+    /// Time between two sends.
+    pub fn gap(&self) -> Duration {
+        self.gap
+    }
+
+    pub fn stats(&self) -> PacerStats {
+        let achieved_rate = match (self.first, self.last) {
+            (Some(first), Some(last)) if last > first => {
+                (self.sent - 1) as f64 / (last - first).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        PacerStats {
+            sent: self.sent,
+            missed: self.missed,
+            achieved_rate,
+        }
+    }
+
+    /// Wait until the next send is due and return the time it was scheduled for.
+    pub fn pace_next(&mut self) -> Instant {
+        let now = Instant::now();
+        let slot = match self.next {
+            Some(next) if next > now => {
+                wait_until(next, self.spin);
+                next
+            }
+            Some(next) => match now.checked_sub(self.max_catch_up) {
+                Some(oldest) if oldest > next => {
+                    let missed = ((oldest - next).as_nanos() / self.gap.as_nanos().max(1)) as u64;
+                    self.missed += missed;
+                    if self.precise {
+                        next + self.gap * missed as u32
+                    } else {
+                        cmp::max(next, oldest)
+                    }
+                }
+                _ => next,
+            },
+            None => now,
+        };
+        self.next = Some(slot + self.gap);
+        let sent_at = Instant::now();
+        self.first.get_or_insert(sent_at);
+        self.last = Some(sent_at);
+        self.sent += 1;
+        slot
+    }
+}
+
+fn wait_until(deadline: Instant, spin: Duration) {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    if remaining > spin {
+        thread::sleep(remaining - spin);
+    }
+    while Instant::now() < deadline {
+        hint::spin_loop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pacer_spacing() {
+        let mut pacer = Pacer::new(Quota::per_second(100_000));
+        assert_eq!(pacer.gap(), Duration::from_micros(10));
+        let first = pacer.pace_next();
+        let mut last = first;
+        for _ in 0..1000 {
+            let slot = pacer.pace_next();
+            assert!(slot - last >= pacer.gap());
+            assert!(Instant::now() >= slot);
+            last = slot;
+        }
+        assert!(last - first >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_pacer_catch_up() {
+        let mut pacer = Pacer::new(Quota::per_second(1000)).max_catch_up(Duration::from_millis(50));
+        let first = pacer.pace_next();
+        thread::sleep(Duration::from_millis(3));
+        // the missed slots are still sent, back to back
+        assert_eq!(pacer.pace_next(), first + pacer.gap());
+        assert_eq!(pacer.pace_next(), first + 2 * pacer.gap());
+    }
+
+    #[test]
+    fn test_pacer_precise() {
+        // sleeping all the way, wakeups are late every time
+        let mut pacer = Pacer::new(Quota::per_second(1000))
+            .spin(Duration::ZERO)
+            .precise();
+        let first = pacer.pace_next();
+        for i in 1..200 {
+            let slot = pacer.pace_next();
+            let missed = pacer.stats().missed as u32;
+            assert_eq!(slot, first + pacer.gap() * (i + missed));
+        }
+        let stats = pacer.stats();
+        assert_eq!(stats.sent, 200);
+        assert!(stats.achieved_rate > 0.0 && stats.achieved_rate < 1001.0);
+    }
+}
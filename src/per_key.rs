@@ -0,0 +1,325 @@
+//! Any [`Policy`] per key.
+//!
+//! [`KeyedLimiter`](crate::KeyedLimiter) is specialised for GCRA: it stores a bare TAT per key
+//! and shares the quota. [`PerKey`] instead creates a whole policy per key from a factory, e.g. a
+//! [`TokenBucket`](crate::TokenBucket) with a refill strategy, or a [`Limiter`](crate::Limiter)
+//! with listeners, and drops the policies of keys that went unused.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{duration_nanos, Denied, Policy};
+use crate::keyed::ToKey;
+use crate::sync::{AtomicU64, Mutex, Ordering};
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// A policy per key, created by a factory on the key's first request.
+///
+/// Keys live in maps sharded by hash, so requests for different keys rarely wait on each other.
+/// The shard lock is not held while a policy decides.
+///
+/// # Idle eviction
+/// With [`evict_idle`](Self::evict_idle), the policy of a key that went unused for a while is
+/// dropped, and created afresh if the key comes back. Unlike the idle entries of a
+/// `KeyedLimiter`, a dropped policy may still have been recovering, so pick a time no shorter
+/// than the policy takes to refill from empty, e.g. the `gap + tolerance` of a GCRA.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+///
+/// use ratelimit::{PerKey, Quota, Refill, TokenBucket};
+///
+/// let limiter = PerKey::new(|_: &String| {
+///     TokenBucket::builder(Quota::per_second(2))
+///         .refill(Refill::Interval(Duration::from_secs(1)))
+///         .build()
+/// })
+/// .evict_idle(Duration::from_secs(60));
+/// assert!(limiter.check("alice").is_ok());
+/// assert!(limiter.check("alice").is_ok());
+/// assert!(limiter.check("alice").is_err());
+/// assert!(limiter.check("bob").is_ok());
+/// ```
+pub struct PerKey<K, P, C = SystemClock> {
+    factory: Box<dyn Fn(&K) -> P + Send + Sync>,
+    clock: C,
+    // in ns, as the times of the entries
+    idle: Option<u64>,
+    shards: Box<[Shard<K, P>]>,
+    hasher: RandomState,
+    // when a new key next drops the idle entries
+    next_eviction: AtomicU64,
+}
+
+type Shard<K, P> = Mutex<HashMap<K, Entry<P>>>;
+
+struct Entry<P> {
+    policy: Arc<P>,
+    last_used: u64,
+}
+
+impl<K, P> PerKey<K, P, SystemClock> {
+    pub fn new(factory: impl Fn(&K) -> P + Send + Sync + 'static) -> Self {
+        PerKey {
+            factory: Box::new(factory),
+            clock: SystemClock,
+            idle: None,
+            shards: (0..DEFAULT_SHARDS)
+                .map(|_| Mutex::new(HashMap::new()))
+                .collect(),
+            hasher: RandomState::new(),
+            next_eviction: AtomicU64::new(0),
+        }
+    }
+}
+
+impl<K, P, C> PerKey<K, P, C> {
+    /// The clock telling when keys were last used. The policies keep their own clocks.
+    pub fn clock<NC>(self, clock: NC) -> PerKey<K, P, NC> {
+        PerKey {
+            factory: self.factory,
+            clock,
+            idle: self.idle,
+            shards: self.shards,
+            hasher: self.hasher,
+            next_eviction: self.next_eviction,
+        }
+    }
+
+    /// Drop the policies of keys unused for `after`. Checked at most once per `after`, when a
+    /// new key is created, or by [`retain_recent`](Self::retain_recent). Kept forever by
+    /// default.
+    ///
+    /// # Panics
+    /// Panics if `after` is zero.
+    pub fn evict_idle(mut self, after: Duration) -> Self {
+        assert!(!after.is_zero(), "idle time must be positive");
+        self.idle = Some(duration_nanos(after));
+        self
+    }
+
+    /// Spread the keys over `shards` maps, each behind its own lock. 16 by default.
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn shards(mut self, shards: usize) -> Self {
+        assert!(shards > 0, "shards must be positive");
+        self.shards = (0..shards).map(|_| Mutex::new(HashMap::new())).collect();
+        self
+    }
+
+    /// Number of keys with a policy.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, P, C> PerKey<K, P, C>
+where
+    K: Hash + Eq,
+    C: Clock,
+{
+    fn shard<Q>(&self, key: &Q) -> &Shard<K, P>
+    where
+        Q: Hash + ?Sized,
+    {
+        let hash = self.hasher.hash_one(key);
+        &self.shards[hash as usize % self.shards.len()]
+    }
+
+    /// The policy of `key`, created if the key has none.
+    pub fn policy<Q>(&self, key: &Q) -> Arc<P>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        let now = self.clock.now_nanos();
+        let shard = self.shard(key);
+        if let Some(entry) = shard.lock().get_mut(key) {
+            entry.last_used = now;
+            return entry.policy.clone();
+        }
+        self.evict_due(now);
+        let key = key.to_key();
+        let policy = Arc::new((self.factory)(&key));
+        shard
+            .lock()
+            .entry(key)
+            .or_insert(Entry {
+                policy,
+                last_used: now,
+            })
+            .policy
+            .clone()
+    }
+
+    /// The policy of `key`, if it has one. Does not count as a use.
+    pub fn get<Q>(&self, key: &Q) -> Option<Arc<P>>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let shard = self.shard(key).lock();
+        shard.get(key).map(|entry| entry.policy.clone())
+    }
+
+    /// Drop the policies of keys unused for the time set with
+    /// [`evict_idle`](Self::evict_idle). Does nothing without it.
+    pub fn retain_recent(&self) {
+        if let Some(idle) = self.idle {
+            self.retain_used_since(self.clock.now_nanos().saturating_sub(idle));
+        }
+    }
+
+    fn evict_due(&self, now: u64) {
+        let Some(idle) = self.idle else {
+            return;
+        };
+        let due = self.next_eviction.load(Ordering::Relaxed);
+        if now < due
+            || self
+                .next_eviction
+                .compare_exchange(
+                    due,
+                    now.saturating_add(idle),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return;
+        }
+        self.retain_used_since(now.saturating_sub(idle));
+    }
+
+    fn retain_used_since(&self, since: u64) {
+        for shard in self.shards.iter() {
+            shard.lock().retain(|_, entry| entry.last_used > since);
+        }
+    }
+}
+
+impl<K, P, C> PerKey<K, P, C>
+where
+    K: Hash + Eq,
+    P: Policy,
+    C: Clock,
+{
+    /// Decide on one request for `key`.
+    pub fn check<Q>(&self, key: &Q) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.policy(key).check()
+    }
+
+    /// Decide on a request for `key` worth `n` cells, as the key's policy does.
+    pub fn check_n<Q>(&self, key: &Q, n: u64) -> Result<(), Denied>
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        self.policy(key).check_n(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::LeakyBucket;
+    use crate::quota::Quota;
+    use crate::token_bucket::{Refill, TokenBucket};
+
+    use super::*;
+
+    #[test]
+    fn test_per_key_independent_keys() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limiter = {
+            let policy_clock = clock.clone();
+            PerKey::new(move |_: &String| {
+                TokenBucket::builder(Quota::per_second(2))
+                    .clock(policy_clock.clone())
+                    .refill(Refill::Interval(Duration::from_secs(1)))
+                    .build()
+            })
+            .clock(clock.clone())
+        };
+        assert_eq!(limiter.check_n("a", 2), Ok(()));
+        assert_eq!(
+            limiter.check("a").unwrap_err().retry_after(),
+            Duration::from_secs(1)
+        );
+        assert!(limiter.check("b").is_ok());
+        assert_eq!(limiter.len(), 2);
+        clock.forward(Duration::from_secs(1));
+        assert_eq!(limiter.check_n("a", 2), Ok(()));
+        assert!(limiter.get("c").is_none());
+    }
+
+    #[test]
+    fn test_per_key_factory_sees_key() {
+        let limiter = PerKey::new(|key: &String| {
+            let rate = if key.starts_with("premium:") { 10 } else { 1 };
+            LeakyBucket::builder().rate(rate).build()
+        });
+        assert_eq!(
+            (0..20)
+                .filter(|_| limiter.check("premium:a").is_ok())
+                .count(),
+            10
+        );
+        assert_eq!(
+            (0..20).filter(|_| limiter.check("free:b").is_ok()).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_per_key_evict_idle() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let created = Arc::new(AtomicU64::new(0));
+        let limiter = {
+            let (policy_clock, created) = (clock.clone(), created.clone());
+            PerKey::new(move |_: &u32| {
+                created.fetch_add(1, Ordering::Relaxed);
+                LeakyBucket::builder()
+                    .clock(policy_clock.clone())
+                    .rate(1)
+                    .build()
+            })
+            .clock(clock.clone())
+            .evict_idle(Duration::from_secs(60))
+        };
+        assert!(limiter.check(&1).is_ok());
+        clock.forward(Duration::from_secs(30));
+        assert!(limiter.check(&2).is_ok());
+        // key 1 has been unused for 60s when key 3 is created, key 2 for 30s
+        clock.forward(Duration::from_secs(30));
+        assert!(limiter.check(&3).is_ok());
+        assert!(limiter.get(&1).is_none());
+        assert!(limiter.get(&2).is_some());
+        // a use keeps a key, and a returning key gets a fresh policy
+        assert!(limiter.check(&2).is_ok());
+        clock.forward(Duration::from_secs(59));
+        limiter.retain_recent();
+        assert_eq!(limiter.len(), 2);
+        clock.forward(Duration::from_secs(1));
+        limiter.retain_recent();
+        assert_eq!(limiter.len(), 0);
+        assert!(limiter.check(&1).is_ok());
+        assert_eq!(created.load(Ordering::Relaxed), 4);
+    }
+}
@@ -0,0 +1,150 @@
+//! Saving the hot keys of a [`KeyedLimiter`] across restarts.
+//!
+//! Restarting a process resets every key to a full burst, which lets a client that was being
+//! clamped, e.g. an abusive one, start over. Saving the keys that are still ahead of their
+//! schedule and restoring them on startup keeps those clamps in place.
+//!
+//! The file is plain text: a header line, then one line per key with its TAT in ms and the key
+//! as written by its `Display` impl:
+//!
+//! ```text
+//! # ratelimit keyed snapshot v1
+//! 1700000005400 10.0.0.7
+//! ```
+//!
+//! TATs are timestamps of the limiter's clock, so this only makes sense with a clock that keeps
+//! counting across restarts, such as [`SystemClock`](crate::SystemClock).
+
+use std::fmt::{Display, Write as _};
+use std::fs;
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::clock::Clock;
+use crate::keyed::KeyedLimiter;
+
+const HEADER: &str = "# ratelimit keyed snapshot v1";
+
+impl<K, C> KeyedLimiter<K, C>
+where
+    K: Hash + Eq,
+    C: Clock,
+{
+    /// Write the keys that are not idle to `path`, replacing it atomically. Keys whose `Display`
+    /// spans several lines are left out. Returns the number of keys written.
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: Display,
+    {
+        let path = path.as_ref();
+        let mut out = format!("{HEADER}\n");
+        let mut written = 0;
+        self.for_each_busy(|key, tat| {
+            let start = out.len();
+            let _ = writeln!(out, "{tat} {key}");
+            if out[start..].trim_end_matches('\n').contains('\n') {
+                out.truncate(start);
+            } else {
+                written += 1;
+            }
+        });
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, out)?;
+        fs::rename(&tmp, path)?;
+        Ok(written)
+    }
+
+    /// Restore the keys saved at `path`, e.g. on startup. Keys that became idle in the meantime
+    /// are skipped, as are keys that already have state, and the key cap is respected, keeping
+    /// the busiest keys. A missing file restores nothing. Returns the number of keys restored.
+    pub fn restore_snapshot(&self, path: impl AsRef<Path>) -> io::Result<usize>
+    where
+        K: FromStr,
+    {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut lines = text.lines();
+        if lines.next() != Some(HEADER) {
+            return Err(invalid("not a keyed limiter snapshot"));
+        }
+        let mut entries = Vec::new();
+        for line in lines {
+            let (tat, key) = line.split_once(' ').ok_or_else(|| invalid("missing key"))?;
+            let tat = tat.parse().map_err(|_| invalid("invalid TAT"))?;
+            let key = key.parse().map_err(|_| invalid("invalid key"))?;
+            entries.push((key, tat));
+        }
+        Ok(self.restore(entries))
+    }
+
+    /// Save a snapshot to `path` every `interval`, forever. A failed save is retried at the next
+    /// interval; the error is handed to `on_error`.
+    ///
+    /// Saving writes the file with blocking I/O, which is fine for the few keys that are hot at
+    /// any time, but keep `interval` in the order of seconds.
+    #[cfg(feature = "tokio")]
+    pub async fn save_periodically(
+        &self,
+        path: impl AsRef<Path>,
+        interval: std::time::Duration,
+        mut on_error: impl FnMut(io::Error),
+    ) where
+        K: Display,
+    {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = self.save_snapshot(path.as_ref()) {
+                on_error(err);
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_keyed_snapshot_roundtrip() {
+        let path = std::env::temp_dir().join(format!("ratelimit-{}.snapshot", std::process::id()));
+        let clock = MockClock::new(1_000_000);
+        let limiter = || -> KeyedLimiter<String, _> {
+            KeyedLimiter::builder(Quota::per_second(1).burst(2))
+                .clock(&clock)
+                .build()
+        };
+
+        let before = limiter();
+        for _ in 0..3 {
+            assert!(before.pass("attacker"));
+        }
+        assert!(before.pass("visitor"));
+        clock.forward(Duration::from_millis(1500));
+        // only the attacker is still ahead of schedule
+        assert_eq!(before.save_snapshot(&path).unwrap(), 1);
+
+        let after = limiter();
+        assert_eq!(after.restore_snapshot(&path).unwrap(), 1);
+        assert!(after.pass("attacker"));
+        assert!(!after.pass("attacker"));
+        assert!(after.pass("visitor"));
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(limiter().restore_snapshot(&path).unwrap(), 0);
+    }
+}
@@ -0,0 +1,632 @@
+//! Assembling common limiter setups from one builder.
+//!
+//! Most services want the same few layers: a rate with some burst, maybe per client, maybe a cap
+//! on requests in flight, and a shadow mode to try new limits on production traffic without
+//! rejecting anyone. [`PolicyBuilder`] puts these together into a [`PolicyStack`]:
+//!
+//! ```
+//! use ratelimit::PolicyBuilder;
+//!
+//! struct Request {
+//!     client: String,
+//! }
+//!
+//! let stack = PolicyBuilder::rate("100/s")
+//!     .burst(20)
+//!     .concurrency(50)
+//!     .per_key(|req: &Request| req.client.clone())
+//!     .shadow(false)
+//!     .build()
+//!     .unwrap();
+//!
+//! let req = Request { client: "a".into() };
+//! let permit = stack.check(&req).unwrap();
+//! // ... handle the request; dropping the permit ends it
+//! drop(permit);
+//! ```
+//!
+//! By default the concurrency cap is first come, first served, so one busy key can hold every
+//! slot; [`AdmissionMode::Fair`] shares the slots between keys instead.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock, Timestamp};
+use crate::config::ConfigError;
+use crate::describe::{Describe, Description};
+use crate::gcra::Denied;
+use crate::keyed::KeyedLimiter;
+use crate::listener::{Event, Listener, Listeners};
+use crate::quota::Quota;
+use crate::sync::{AtomicUsize, Mutex, Ordering};
+
+/// Picks the key a request is limited on. Any `Fn(&Req) -> Key` closure is a `KeyExtractor`.
+pub trait KeyExtractor<Req> {
+    type Key;
+
+    fn key(&self, req: &Req) -> Self::Key;
+
+    /// How much later than it arrived to decide on `req`, e.g. the known added latency of the
+    /// region it was proxied from. See
+    /// [`KeyedLimiter::check_n_with_offset`](crate::KeyedLimiter::check_n_with_offset). Zero by
+    /// default.
+    fn offset(&self, _req: &Req) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Whether `req` comes from a caller that must not be denied, only paced: such requests are
+    /// always admitted, charged to their key, and given a
+    /// [`suggested_delay`](Permit::suggested_delay). For callers migrating onto limits that
+    /// cannot handle rejections yet. `false` by default.
+    fn pace_only(&self, _req: &Req) -> bool {
+        false
+    }
+}
+
+impl<F, Req, Key> KeyExtractor<Req> for F
+where
+    F: Fn(&Req) -> Key,
+{
+    type Key = Key;
+
+    fn key(&self, req: &Req) -> Key {
+        self(req)
+    }
+}
+
+/// Limits all requests together, with the key `()`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Global;
+
+impl<Req> KeyExtractor<Req> for Global {
+    type Key = ();
+
+    fn key(&self, _req: &Req) {}
+}
+
+/// How a [`PolicyStack`] hands out the slots of its [concurrency](PolicyBuilder::concurrency)
+/// cap between keys.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AdmissionMode {
+    /// First come, first served: any request under the cap gets a slot, so a key sending many
+    /// requests can hold all of them while others are denied.
+    #[default]
+    Greedy,
+    /// Slots are shared evenly between the keys with demand: those with requests in flight, and
+    /// those denied a slot in the last second. While another key waits, a key holding its share
+    /// of the cap or more is denied, so the slots it frees go to the keys below their share.
+    /// With no one waiting, any key may use the free slots.
+    ///
+    /// This takes a lock on every decision, and keys are told apart by a 64-bit hash.
+    Fair,
+}
+
+/// Builds a [`PolicyStack`]. Errors in the rate are reported by [`build`](Self::build).
+pub struct PolicyBuilder<E = Global, C = SystemClock> {
+    rate: String,
+    burst: u64,
+    concurrency: Option<usize>,
+    admission: AdmissionMode,
+    shadow: bool,
+    max_keys: usize,
+    listeners: Listeners,
+    extractor: E,
+    clock: C,
+}
+
+impl PolicyBuilder<Global, SystemClock> {
+    /// Start from a rate like `100/s`: a count, a slash and a period of a unit among `ms`, `s`,
+    /// `m`, `h` and `d`, optionally with a number in front, e.g. `5/15m`.
+    pub fn rate(rate: impl Into<String>) -> Self {
+        PolicyBuilder {
+            rate: rate.into(),
+            burst: 0,
+            concurrency: None,
+            admission: AdmissionMode::Greedy,
+            shadow: false,
+            max_keys: usize::MAX,
+            listeners: Listeners::default(),
+            extractor: Global,
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<E, C> PolicyBuilder<E, C> {
+    pub fn clock<NC>(self, clock: NC) -> PolicyBuilder<E, NC> {
+        PolicyBuilder {
+            rate: self.rate,
+            burst: self.burst,
+            concurrency: self.concurrency,
+            admission: self.admission,
+            shadow: self.shadow,
+            max_keys: self.max_keys,
+            listeners: self.listeners,
+            extractor: self.extractor,
+            clock,
+        }
+    }
+
+    /// Let `extra` requests on top of the rate through at once, as with [`Quota::burst`].
+    pub fn burst(mut self, extra: u64) -> Self {
+        self.burst = extra;
+        self
+    }
+
+    /// Admit at most `max` requests in flight at once, over all keys. A request is in flight
+    /// until its [`Permit`] is dropped.
+    pub fn concurrency(mut self, max: usize) -> Self {
+        self.concurrency = Some(max);
+        self
+    }
+
+    /// How the concurrency cap is shared between keys, [`AdmissionMode::Greedy`] by default.
+    pub fn admission(mut self, admission: AdmissionMode) -> Self {
+        self.admission = admission;
+        self
+    }
+
+    /// Limit each key on its own, as picked by `extractor`.
+    pub fn per_key<NE>(self, extractor: NE) -> PolicyBuilder<NE, C> {
+        PolicyBuilder {
+            rate: self.rate,
+            burst: self.burst,
+            concurrency: self.concurrency,
+            admission: self.admission,
+            shadow: self.shadow,
+            max_keys: self.max_keys,
+            listeners: self.listeners,
+            extractor,
+            clock: self.clock,
+        }
+    }
+
+    /// Keep at most `max_keys` keys, see
+    /// [`KeyedLimiterBuilder::max_keys`](crate::KeyedLimiterBuilder::max_keys).
+    pub fn max_keys(mut self, max_keys: usize) -> Self {
+        self.max_keys = max_keys;
+        self
+    }
+
+    /// Decide as usual and report the decisions to the listeners, but admit every request.
+    pub fn shadow(mut self, shadow: bool) -> Self {
+        self.shadow = shadow;
+        self
+    }
+
+    /// Name the stack in listener events.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.listeners.set_name(name.into());
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn build<K>(self) -> Result<PolicyStack<K, E, C>, ConfigError> {
+        let (limit, period) = parse_rate(&self.rate).map_err(|e| e.at("rate"))?;
+        let cells = limit.saturating_add(self.burst);
+        // `cells` go through at once, and one more every `period / limit`
+        let window = period.mul_f64(cells as f64 / limit as f64);
+        let limiter = KeyedLimiter::builder(Quota::per_second(1))
+            .window(cells, window)
+            .max_keys(self.max_keys)
+            .clock(self.clock)
+            .build();
+        Ok(PolicyStack {
+            limiter,
+            extractor: self.extractor,
+            concurrency: self.concurrency.unwrap_or(usize::MAX),
+            in_flight: AtomicUsize::new(0),
+            fair: (self.admission == AdmissionMode::Fair).then(|| FairShare {
+                hasher: RandomState::new(),
+                state: Mutex::new(FairState {
+                    keys: HashMap::new(),
+                    waiting: 0,
+                    next_expiry: Timestamp::MAX,
+                }),
+            }),
+            shadow: self.shadow,
+            listeners: self.listeners,
+        })
+    }
+}
+
+fn parse_rate(rate: &str) -> Result<(u64, Duration), ConfigError> {
+    let invalid = || ConfigError::new(format!("expected e.g. `100/s`, got `{rate}`"));
+    let (limit, period) = rate.trim().split_once('/').ok_or_else(invalid)?;
+    let limit: u64 = limit.trim().parse().map_err(|_| invalid())?;
+    let period = period.trim();
+    let digits = period
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let count = match &period[..digits] {
+        "" => 1,
+        count => count.parse().map_err(|_| invalid())?,
+    };
+    let unit = match &period[digits..] {
+        "ms" => Duration::from_millis(1),
+        "s" => Duration::from_secs(1),
+        "m" => Duration::from_secs(60),
+        "h" => Duration::from_secs(3600),
+        "d" => Duration::from_secs(86400),
+        _ => return Err(invalid()),
+    };
+    if limit == 0 || count == 0 {
+        return Err(ConfigError::new("rate must be positive"));
+    }
+    Ok((limit, unit * count))
+}
+
+/// A rate per key, a cap on requests in flight and an optional shadow mode, built by
+/// [`PolicyBuilder`].
+pub struct PolicyStack<K, E = Global, C = SystemClock> {
+    limiter: KeyedLimiter<K, C>,
+    extractor: E,
+    concurrency: usize,
+    in_flight: AtomicUsize,
+    fair: Option<FairShare>,
+    shadow: bool,
+    listeners: Listeners,
+}
+
+/// How long a key denied a slot counts as waiting for one, in [`AdmissionMode::Fair`].
+const DEMAND_FOR: Duration = Duration::from_secs(1);
+
+/// Demand per key for [`AdmissionMode::Fair`]. Keys are kept by hash, so permits need not know
+/// the key type.
+struct FairShare {
+    hasher: RandomState,
+    state: Mutex<FairState>,
+}
+
+struct FairState {
+    // keys with requests in flight or waiting for a slot
+    keys: HashMap<u64, Demand>,
+    // keys with `waiting_until` set
+    waiting: usize,
+    // the earliest `waiting_until`, to know when to look for expired ones
+    next_expiry: Timestamp,
+}
+
+#[derive(Default)]
+struct Demand {
+    in_flight: usize,
+    // set while the key was denied a slot and has not had one since
+    waiting_until: Option<Timestamp>,
+}
+
+impl FairState {
+    fn expire(&mut self, now: Timestamp) {
+        if now < self.next_expiry {
+            return;
+        }
+        let (mut waiting, mut next_expiry) = (0, Timestamp::MAX);
+        self.keys.retain(|_, demand| {
+            match demand.waiting_until {
+                Some(until) if until <= now => demand.waiting_until = None,
+                Some(until) => {
+                    waiting += 1;
+                    next_expiry = next_expiry.min(until);
+                }
+                None => {}
+            }
+            demand.in_flight > 0 || demand.waiting_until.is_some()
+        });
+        self.waiting = waiting;
+        self.next_expiry = next_expiry;
+    }
+
+    /// Take a slot for the key hashed to `hash` if it is within its share, or note that it
+    /// waits for one. `free` is whether the cap has a slot left.
+    fn admit(&mut self, hash: u64, now: Timestamp, cap: usize, free: bool) -> bool {
+        self.expire(now);
+        let active = self.keys.len() + usize::from(!self.keys.contains_key(&hash));
+        let share = cap.div_ceil(active);
+        let waiting = self.waiting;
+        let demand = self.keys.entry(hash).or_default();
+        let others_waiting = waiting - usize::from(demand.waiting_until.is_some());
+        let admitted = (demand.in_flight < share || others_waiting == 0) && free;
+        if admitted {
+            demand.in_flight += 1;
+            if demand.waiting_until.take().is_some() {
+                self.waiting -= 1;
+            }
+        } else {
+            let until = now.saturating_add(DEMAND_FOR.as_millis() as u64);
+            if demand.waiting_until.replace(until).is_none() {
+                self.waiting += 1;
+            }
+            self.next_expiry = self.next_expiry.min(until);
+        }
+        admitted
+    }
+
+    fn release(&mut self, hash: u64) {
+        if let Some(demand) = self.keys.get_mut(&hash) {
+            demand.in_flight -= 1;
+            if demand.in_flight == 0 && demand.waiting_until.is_none() {
+                self.keys.remove(&hash);
+            }
+        }
+    }
+}
+
+/// A request admitted by a [`PolicyStack`], in flight until dropped.
+#[must_use = "the request is in flight until the permit is dropped"]
+pub struct Permit<'a> {
+    in_flight: &'a AtomicUsize,
+    // the fair share state and key hash the slot was taken for
+    fair: Option<(&'a FairShare, u64)>,
+    suggested_delay: Duration,
+}
+
+impl Permit<'_> {
+    /// How long a [pace-only](KeyExtractor::pace_only) request should wait before going on to
+    /// stay within its rate. Zero for all other requests.
+    pub fn suggested_delay(&self) -> Duration {
+        self.suggested_delay
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.in_flight.fetch_sub(1, Ordering::Release);
+        if let Some((fair, hash)) = self.fair {
+            fair.state.lock().release(hash);
+        }
+    }
+}
+
+impl<K, E, C> PolicyStack<K, E, C> {
+    pub fn limiter(&self) -> &KeyedLimiter<K, C> {
+        &self.limiter
+    }
+
+    /// Requests admitted and not yet finished.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Acquire)
+    }
+}
+
+impl<K, E, C> Describe for PolicyStack<K, E, C> {
+    fn describe(&self) -> Description {
+        let mut description = Description::new("policy_stack").named(self.listeners.name());
+        if self.concurrency != usize::MAX {
+            let admission = if self.fair.is_some() {
+                "fair"
+            } else {
+                "greedy"
+            };
+            description = description
+                .setting("concurrency", self.concurrency)
+                .setting("admission", admission);
+        }
+        if self.shadow {
+            description = description.setting("mode", "shadow");
+        }
+        description.child(self.limiter.describe())
+    }
+}
+
+impl<K, E, C> PolicyStack<K, E, C>
+where
+    K: Hash + Eq + Clone,
+    C: Clock,
+{
+    /// Decide on `req`: admitted if it is under the concurrency cap, within its share of it in
+    /// [`AdmissionMode::Fair`], and its key has rate left. A request denied a slot does not use
+    /// up rate, and is denied with a `retry_after` of zero, as it depends on other requests
+    /// finishing.
+    ///
+    /// [Pace-only](KeyExtractor::pace_only) requests are admitted regardless. Listeners see
+    /// those over their rate as denied, with the suggested delay as `retry_after`.
+    pub fn check<Req>(&self, req: &Req) -> Result<Permit<'_>, Denied>
+    where
+        E: KeyExtractor<Req, Key = K>,
+    {
+        let mut permit = Permit {
+            in_flight: &self.in_flight,
+            fair: None,
+            suggested_delay: Duration::ZERO,
+        };
+        let pace_only = self.extractor.pace_only(req);
+        let key = self.extractor.key(req);
+        let slot = match &self.fair {
+            None => self.in_flight.fetch_add(1, Ordering::AcqRel) < self.concurrency,
+            Some(fair) => {
+                let hash = fair.hasher.hash_one(&key);
+                let mut state = fair.state.lock();
+                // taken under the lock, so the count is exact among fair decisions
+                let free = self.in_flight.fetch_add(1, Ordering::AcqRel) < self.concurrency;
+                let admitted = state.admit(hash, self.limiter.now(), self.concurrency, free);
+                if admitted {
+                    permit.fair = Some((fair, hash));
+                }
+                admitted
+            }
+        };
+        let decision = if !slot {
+            Err(Denied::new(Duration::ZERO))
+        } else {
+            let offset = self.extractor.offset(req);
+            if pace_only {
+                permit.suggested_delay = self.limiter.pace_n_with_offset(&key, 1, offset);
+                match permit.suggested_delay {
+                    Duration::ZERO => Ok(()),
+                    delay => Err(Denied::new(delay)),
+                }
+            } else {
+                self.limiter.check_n_with_offset(&key, 1, offset)
+            }
+        };
+        match decision {
+            Ok(()) => self.listeners.emit(|policy| Event::Allowed {
+                policy,
+                warning: false,
+            }),
+            Err(denied) => self.listeners.emit(|policy| Event::Denied {
+                policy,
+                retry_after: denied.retry_after(),
+            }),
+        }
+        match decision {
+            Err(denied) if !self.shadow && !pace_only => Err(denied),
+            _ => Ok(permit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::clock::MockClock;
+    use crate::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("100/s"), Ok((100, Duration::from_secs(1))));
+        assert_eq!(parse_rate(" 5 / 15m "), Ok((5, Duration::from_secs(900))));
+        assert_eq!(parse_rate("1/250ms"), Ok((1, Duration::from_millis(250))));
+        assert!(parse_rate("100").is_err());
+        assert!(parse_rate("100/week").is_err());
+        let err = PolicyBuilder::rate("0/s").build::<()>().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid limiter config: rate: rate must be positive"
+        );
+    }
+
+    #[test]
+    fn test_policy_stack() {
+        let clock = MockClock::new(1_000_000);
+        let stack = PolicyBuilder::rate("2/s")
+            .burst(1)
+            .concurrency(2)
+            .per_key(|client: &&str| client.to_string())
+            .clock(&clock)
+            .build()
+            .unwrap();
+
+        let a = stack.check(&"a").unwrap();
+        let b = stack.check(&"a").unwrap();
+        // over the concurrency cap, even for another key
+        assert_eq!(stack.check(&"b").err(), Some(Denied::new(Duration::ZERO)));
+        drop((a, b));
+        assert_eq!(stack.in_flight(), 0);
+        let _c = stack.check(&"a").unwrap();
+        assert!(stack.check(&"a").is_err());
+        assert_eq!(stack.in_flight(), 1);
+        assert!(stack.check(&"b").is_ok());
+
+        // the same limits in shadow mode admit everything, and report what they would deny
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let shadow = PolicyBuilder::rate("1/s")
+            .shadow(true)
+            .named("trial")
+            .listener(move |event: &Event<'_>| sink.lock().push(format!("{event:?}")))
+            .clock(&clock)
+            .build()
+            .unwrap();
+        let permits = [shadow.check(&()), shadow.check(&())];
+        assert!(permits.iter().all(Result::is_ok));
+        assert_eq!(shadow.in_flight(), 2);
+        assert_eq!(
+            *events.lock(),
+            [
+                r#"Allowed { policy: "trial", warning: false }"#,
+                r#"Denied { policy: "trial", retry_after: 1s }"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fair_admission() {
+        let clock = MockClock::new(1_000_000);
+        let stack = |admission| {
+            PolicyBuilder::rate("1000/s")
+                .concurrency(4)
+                .admission(admission)
+                .per_key(|client: &&str| client.to_string())
+                .clock(&clock)
+                .build()
+                .unwrap()
+        };
+
+        for (admission, gets_slot) in [
+            (AdmissionMode::Greedy, "heavy"),
+            (AdmissionMode::Fair, "light"),
+        ] {
+            let stack = stack(admission);
+            let mut heavy: Vec<_> = (0..4).map(|_| stack.check(&"heavy").unwrap()).collect();
+            // the cap is full, the light key waits
+            assert!(stack.check(&"light").is_err());
+            heavy.pop();
+            // the heavy key asks first for the freed slot, but is over its share in fair mode
+            let heavy_again = stack.check(&"heavy");
+            assert_eq!(heavy_again.is_ok(), gets_slot == "heavy");
+            let light = stack.check(&"light");
+            assert_eq!(light.is_ok(), gets_slot == "light");
+        }
+
+        // a key no longer waiting does not hold the others back
+        let stack = stack(AdmissionMode::Fair);
+        let heavy: Vec<_> = (0..4).map(|_| stack.check(&"heavy").unwrap()).collect();
+        assert!(stack.check(&"light").is_err());
+        drop(heavy);
+        clock.forward(DEMAND_FOR);
+        let heavy: Vec<_> = (0..4).map(|_| stack.check(&"heavy")).collect();
+        assert!(heavy.iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn test_pace_only() {
+        struct Call {
+            client: &'static str,
+            internal: bool,
+        }
+
+        struct ByClient;
+
+        impl KeyExtractor<Call> for ByClient {
+            type Key = String;
+
+            fn key(&self, call: &Call) -> String {
+                call.client.to_string()
+            }
+
+            fn pace_only(&self, call: &Call) -> bool {
+                call.internal
+            }
+        }
+
+        let clock = MockClock::new(1_000_000);
+        let stack = PolicyBuilder::rate("2/s")
+            .per_key(ByClient)
+            .clock(&clock)
+            .build()
+            .unwrap();
+        let batch = Call {
+            client: "batch",
+            internal: true,
+        };
+        let delays: Vec<_> = (0..4)
+            .map(|_| stack.check(&batch).unwrap().suggested_delay())
+            .collect();
+        assert_eq!(delays, [0, 0, 500, 1000].map(Duration::from_millis));
+
+        let external = Call {
+            client: "web",
+            internal: false,
+        };
+        assert!(stack.check(&external).is_ok() && stack.check(&external).is_ok());
+        assert!(stack.check(&external).is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Serving a remote limit from tokens fetched in batches.
+//!
+//! Asking a shared store on every request puts a round trip on the hot path. [`Prefetch`] takes
+//! tokens from the [`Backend`] a batch at a time and hands them out locally, fetching the next
+//! batch in the background once the local tokens run low, so requests only wait for the backend
+//! when a batch could not be fetched in time.
+
+use std::sync::Arc;
+
+use crate::batcher::{Batcher, Fetch, Next};
+use crate::gcra::Denied;
+use crate::remote::Backend;
+use crate::sync::Mutex;
+
+/// A [`Backend`] serving tokens fetched from another backend in batches of `batch`.
+///
+/// Tokens are charged to the backend when fetched, so the backend's limit still holds over the
+/// fleet. Each node may hold up to a batch plus the low water mark it has not handed out yet,
+/// which other nodes cannot use, and tokens still held when it is dropped are lost. Size batches
+/// to a fraction of what the limit admits per node in a second or so.
+///
+/// The backend must implement [`try_check_n`](Backend::try_check_n). Once it denies a batch,
+/// the node hands out what it holds, then asks for a batch or else a single token on every
+/// request, so it falls back to a round trip per request as the limit is reached. Requests
+/// worth several tokens are asked of the backend directly, without the tokens held.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Backend, Degrade, Denied, FailureMode, Policy, Prefetch, Quota};
+///
+/// struct Store;
+///
+/// impl Backend for Store {
+///     type Error = std::io::Error;
+///
+///     fn try_check(&self) -> Result<Result<(), Denied>, Self::Error> {
+///         self.try_check_n(1)
+///     }
+///
+///     fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, Self::Error> {
+///         // ask the shared store for `n` tokens
+///         Ok(Ok(()))
+///     }
+/// }
+///
+/// let policy = Degrade::new(Prefetch::new(Store, 100), FailureMode::Local(Quota::per_second(10)));
+/// assert!(policy.check().is_ok());
+/// ```
+pub struct Prefetch<B> {
+    shared: Arc<Shared<B>>,
+}
+
+struct Shared<B> {
+    backend: B,
+    batcher: Mutex<Batcher>,
+}
+
+impl<B> Prefetch<B> {
+    /// Fetch `batch` tokens at a time, the next batch once a quarter of one is left.
+    ///
+    /// # Panics
+    /// Panics if `batch` is zero.
+    pub fn new(backend: B, batch: u64) -> Self {
+        Prefetch {
+            shared: Arc::new(Shared {
+                backend,
+                batcher: Mutex::new(Batcher::new(batch)),
+            }),
+        }
+    }
+
+    /// Fetch the next batch once `tokens` are left.
+    pub fn low_water(self, tokens: u64) -> Self {
+        self.shared.batcher.lock().set_low_water(tokens);
+        self
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.shared.backend
+    }
+
+    /// Tokens held locally.
+    pub fn tokens(&self) -> u64 {
+        self.shared.batcher.lock().tokens()
+    }
+
+    /// Whether a background fetch is out.
+    pub fn is_fetching(&self) -> bool {
+        self.shared.batcher.lock().is_fetching()
+    }
+}
+
+impl<B> Shared<B>
+where
+    B: Backend,
+{
+    fn refresh(&self, fetch: Fetch) {
+        let answer = self.backend.try_check_n(fetch.n());
+        let mut batcher = self.batcher.lock();
+        match answer {
+            Ok(answer) => {
+                batcher.on_response(fetch, answer);
+            }
+            Err(_) => batcher.on_error(fetch),
+        }
+    }
+}
+
+impl<B> Prefetch<B>
+where
+    B: Backend + Send + Sync + 'static,
+{
+    fn refresh_in_background(&self) {
+        let Some(fetch) = self.shared.batcher.lock().poll_action() else {
+            return;
+        };
+        let shared = self.shared.clone();
+        std::thread::spawn(move || shared.refresh(fetch));
+    }
+}
+
+impl<B> Backend for Prefetch<B>
+where
+    B: Backend + Send + Sync + 'static,
+{
+    type Error = B::Error;
+
+    fn try_check(&self) -> Result<Result<(), Denied>, B::Error> {
+        let mut next = self.shared.batcher.lock().take();
+        // out of tokens, this request waits for the backend
+        while let Next::Fetch(fetch) = next {
+            let answer = self.shared.backend.try_check_n(fetch.n());
+            let mut batcher = self.shared.batcher.lock();
+            let answer = answer.inspect_err(|_| batcher.on_error(fetch))?;
+            next = batcher
+                .on_response(fetch, answer)
+                .expect("a request waits on its fetch");
+        }
+        match next {
+            Next::Deny(denied) => Ok(Err(denied)),
+            _ => {
+                self.refresh_in_background();
+                Ok(Ok(()))
+            }
+        }
+    }
+
+    fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, B::Error> {
+        match n {
+            0 => Ok(Ok(())),
+            1 => self.try_check(),
+            _ => self.shared.backend.try_check_n(n),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::sync::{AtomicU64, Ordering};
+
+    use super::*;
+
+    struct Pool {
+        left: AtomicU64,
+        calls: AtomicU64,
+    }
+
+    impl Backend for Pool {
+        type Error = ();
+
+        fn try_check(&self) -> Result<Result<(), Denied>, ()> {
+            self.try_check_n(1)
+        }
+
+        fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, ()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            match self
+                .left
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |left| {
+                    left.checked_sub(n)
+                }) {
+                Ok(_) => Ok(Ok(())),
+                Err(_) => Ok(Err(Denied::new(Duration::from_secs(1)))),
+            }
+        }
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let pool = Pool {
+            left: AtomicU64::new(250),
+            calls: AtomicU64::new(0),
+        };
+        let prefetch = Prefetch::new(pool, 100).low_water(20);
+        let settle = || {
+            while prefetch.is_fetching() {
+                std::thread::yield_now();
+            }
+        };
+        let calls = || prefetch.backend().calls.load(Ordering::Relaxed);
+
+        assert_eq!(prefetch.try_check(), Ok(Ok(())));
+        assert_eq!((prefetch.tokens(), calls()), (99, 1));
+        // the next batch is fetched in the background at the low water mark
+        for _ in 0..79 {
+            assert_eq!(prefetch.try_check(), Ok(Ok(())));
+        }
+        settle();
+        assert_eq!((prefetch.tokens(), calls()), (120, 2));
+
+        // the pool has 50 left, which are only handed out one at a time
+        let mut admitted = 80;
+        for _ in 0..200 {
+            admitted += prefetch.try_check().unwrap().is_ok() as u64;
+            settle();
+        }
+        assert_eq!(admitted, 250);
+        assert_eq!(prefetch.tokens(), 0);
+    }
+}
@@ -0,0 +1,212 @@
+//! Rate limit quotas, and conversion between the two ways of describing them.
+//!
+//! [`LeakyBucket`](crate::LeakyBucket) is configured with a rate and an extra burst, while
+//! [`VirtualScheduling`](crate::VirtualScheduling) takes the GCRA emission interval (`gap`) and
+//! `tolerance`. Both describe the same limit: a quota of `rate` qps with `burst` extra lets
+//! `rate + burst` requests through at once, which is an emission interval of `1s / rate` and a
+//! tolerance of `rate + burst - 1` intervals.
+
+use std::time::Duration;
+
+/// A rate and an extra burst. Everything but [`scale`](Self::scale) is `const`, so limits can
+/// be declared as constants, and an invalid one fails to compile:
+///
+/// ```
+/// use ratelimit::Quota;
+///
+/// struct Search;
+///
+/// impl Search {
+///     const QUOTA: Quota = Quota::per_second(100).burst(20);
+/// }
+///
+/// assert_eq!(Search::QUOTA.capacity(), 120);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Quota {
+    rate: u64,
+    burst: u64,
+}
+
+impl Quota {
+    /// A quota of `qps` requests per second with no extra burst.
+    ///
+    /// # Panics
+    /// Panics if `qps` is zero.
+    pub const fn per_second(qps: u64) -> Self {
+        assert!(qps > 0, "rate must be positive");
+        Quota {
+            rate: qps,
+            burst: 0,
+        }
+    }
+
+    /// Allow `extra` requests on top of the rate to go through at once.
+    pub const fn burst(self, extra: u64) -> Self {
+        Quota {
+            burst: extra,
+            ..self
+        }
+    }
+
+    /// Recover a quota from GCRA parameters, rounding the rate to the nearest whole qps.
+    ///
+    /// # Panics
+    /// Panics if `gap` is zero or longer than one second.
+    pub const fn from_gap_tolerance(gap: Duration, tolerance: Duration) -> Self {
+        let gap = gap.as_nanos();
+        assert!(gap > 0 && gap <= 1_000_000_000, "gap must be in (0, 1s]");
+        let rate = (1_000_000_000 + gap / 2) / gap;
+        let cells = tolerance.as_nanos() / gap + 1;
+        Quota {
+            rate: rate as u64,
+            burst: cells.saturating_sub(rate) as u64,
+        }
+    }
+
+    /// A quota from the GCRA emission interval and an extra burst, rounding the rate to the
+    /// nearest whole qps.
+    ///
+    /// # Panics
+    /// Panics if `gap` is zero or longer than one second.
+    pub const fn from_gap_burst(gap: Duration, extra: u64) -> Self {
+        Quota::from_gap_tolerance(gap, Duration::ZERO).burst(extra)
+    }
+
+    /// Scale rate and burst by `factor`, rounding to whole requests. The rate stays at least one.
+    pub fn scale(self, factor: f64) -> Self {
+        Quota {
+            rate: ((self.rate as f64 * factor).round() as u64).max(1),
+            burst: (self.burst as f64 * factor).round() as u64,
+        }
+    }
+
+    /// One of `n` equal shares, e.g. per instance of a service. Rate and burst are rounded down,
+    /// so the shares never add up to more than the whole, except that each keeps a rate of at
+    /// least one.
+    ///
+    /// # Panics
+    /// Panics if `n` is zero.
+    pub const fn split(self, n: u64) -> Self {
+        assert!(n > 0, "cannot split into zero shares");
+        Quota {
+            rate: if n < self.rate { self.rate / n } else { 1 },
+            burst: self.burst / n,
+        }
+    }
+
+    /// The stricter of two quotas: the lower rate, and the smaller number of requests that can
+    /// go through at once.
+    pub const fn min(self, other: Quota) -> Self {
+        let rate = if self.rate < other.rate {
+            self.rate
+        } else {
+            other.rate
+        };
+        let capacity = if self.capacity() < other.capacity() {
+            self.capacity()
+        } else {
+            other.capacity()
+        };
+        Quota {
+            rate,
+            burst: capacity - rate,
+        }
+    }
+
+    pub const fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub const fn extra_burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// How many requests can go through at once, `rate + burst`, saturating at `u64::MAX`.
+    pub const fn capacity(&self) -> u64 {
+        self.rate.saturating_add(self.burst)
+    }
+
+    /// The GCRA emission interval, i.e. the time one request is worth.
+    pub const fn gap(&self) -> Duration {
+        Duration::from_nanos(1_000_000_000 / self.rate)
+    }
+
+    /// The GCRA tolerance, i.e. how far ahead of schedule a request may arrive. Saturates at
+    /// `u64::MAX` ns, over 584 years.
+    pub const fn tolerance(&self) -> Duration {
+        let cells = (self.capacity() - 1) as u128;
+        let nanos = self.gap().as_nanos() * cells;
+        if nanos > u64::MAX as u128 {
+            return Duration::from_nanos(u64::MAX);
+        }
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quota_roundtrip() {
+        let quota = Quota::per_second(10).burst(5);
+        assert_eq!(quota.gap(), Duration::from_millis(100));
+        assert_eq!(quota.tolerance(), Duration::from_millis(1400));
+        assert_eq!(
+            Quota::from_gap_tolerance(quota.gap(), quota.tolerance()),
+            quota
+        );
+        assert_eq!(
+            Quota::from_gap_tolerance(Duration::from_millis(100), Duration::ZERO),
+            Quota::per_second(10)
+        );
+    }
+
+    #[test]
+    fn test_quota_arithmetic() {
+        let quota = Quota::per_second(10).burst(5);
+        assert_eq!(quota.capacity(), 15);
+        assert_eq!(quota.split(3), Quota::per_second(3).burst(1));
+        assert_eq!(quota.split(100), Quota::per_second(1));
+        assert_eq!(quota.min(Quota::per_second(20)), quota);
+        assert_eq!(
+            quota.min(Quota::per_second(5).burst(20)),
+            Quota::per_second(5).burst(10)
+        );
+        assert_eq!(Quota::from_gap_burst(Duration::from_millis(100), 5), quota);
+    }
+
+    #[test]
+    fn test_quota_huge_burst() {
+        // 2e19ns of tolerance is more than a u64 of ns holds
+        let quota = Quota::per_second(1).burst(20_000_000_000);
+        assert_eq!(quota.capacity(), 20_000_000_001);
+        assert_eq!(quota.tolerance(), Duration::from_nanos(u64::MAX));
+        let quota = Quota::per_second(2).burst(u64::MAX);
+        assert_eq!(quota.capacity(), u64::MAX);
+        assert_eq!(quota.tolerance(), Duration::from_nanos(u64::MAX));
+        assert_eq!(quota.min(Quota::per_second(1)), Quota::per_second(1));
+    }
+
+    #[test]
+    fn test_const_quota() {
+        // evaluated at compile time, so these agree with the runtime arithmetic above
+        const QUOTA: Quota =
+            Quota::from_gap_tolerance(Duration::from_millis(100), Duration::from_millis(1400));
+        const SHARE: Quota = QUOTA.split(3).min(Quota::per_second(2));
+        const CAPACITY: u64 = QUOTA.capacity();
+        const GAP: Duration = QUOTA.gap();
+        const TOLERANCE: Duration = QUOTA.tolerance();
+        assert_eq!(QUOTA, Quota::per_second(10).burst(5));
+        assert_eq!(SHARE, Quota::per_second(2));
+        assert_eq!(CAPACITY, 15);
+        assert_eq!(GAP, Duration::from_millis(100));
+        assert_eq!(TOLERANCE, Duration::from_millis(1400));
+        // no share is ever below one request per second
+        const ONE: Quota = Quota::per_second(10).split(10);
+        const FLOOR: Quota = Quota::per_second(10).split(11);
+        assert_eq!(ONE, Quota::per_second(1));
+        assert_eq!(FLOOR, Quota::per_second(1));
+    }
+}
@@ -0,0 +1,198 @@
+//! The bookkeeping of [`Optimistic`](crate::Optimistic) without the I/O: which locally admitted
+//! requests still have to be told to a backend, and what the local policy owes for the ones it
+//! denied.
+//!
+//! Like a [`Batcher`](crate::Batcher), a [`Reconciler`] is a plain state machine. The driver
+//! around it reports each request the local policy admits with
+//! [`on_admitted`](Reconciler::on_admitted), asks the backend for every [`Report`] it is handed,
+//! and feeds the answer back with [`on_response`](Reconciler::on_response). `Optimistic` is the
+//! driver for blocking [`Backend`](crate::Backend)s, reporting on a thread, and
+//! `AsyncOptimistic`, with the `tokio` or `async-std` feature, the driver for async ones.
+//!
+//! ```
+//! use std::time::Duration;
+//!
+//! use ratelimit::{Denied, Reconciler};
+//!
+//! let mut reconciler = Reconciler::new();
+//! reconciler.on_admitted();
+//! reconciler.on_admitted();
+//! // one report is out at a time
+//! let report = reconciler.poll_action().unwrap();
+//! assert!(reconciler.poll_action().is_none());
+//! reconciler.on_response(report, Err(Denied::new(Duration::from_secs(1))));
+//! assert_eq!(reconciler.debt(), 1);
+//! let report = reconciler.poll_action().unwrap();
+//! reconciler.on_response(report, Ok(()));
+//! assert_eq!(reconciler.pending(), 0);
+//! // the next request pays the debt with a local cell first
+//! assert!(reconciler.take_debt());
+//! assert!(!reconciler.take_debt());
+//! ```
+
+use crate::gcra::{Denied, Policy};
+use crate::sync::Mutex;
+
+/// Requests admitted locally, reported to a backend one at a time, and the debt of the ones it
+/// denied.
+#[derive(Debug, Default)]
+pub struct Reconciler {
+    // admitted locally, not yet handed out as a report
+    pending: u64,
+    // a report is out
+    reporting: bool,
+    // cells admitted locally that the backend denied, not yet paid
+    debt: u64,
+    stats: ReconcileStats,
+}
+
+/// A request admitted locally, to be asked of the backend and answered with
+/// [`Reconciler::on_response`] or [`Reconciler::on_error`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Report {
+    _private: (),
+}
+
+/// How the backend's answers compared to the local decisions so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReconcileStats {
+    /// Admitted by both.
+    pub agreed: u64,
+    /// Admitted locally, denied by the backend.
+    pub overadmitted: u64,
+    /// The backend failed to answer.
+    pub failed: u64,
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Reconciler::default()
+    }
+
+    /// Cells the local policy still owes for requests the backend denied.
+    pub fn debt(&self) -> u64 {
+        self.debt
+    }
+
+    /// Requests admitted locally that the backend has not answered for yet.
+    pub fn pending(&self) -> u64 {
+        self.pending + self.reporting as u64
+    }
+
+    pub fn stats(&self) -> ReconcileStats {
+        self.stats
+    }
+
+    /// Take a cell of the debt for the local policy to pay, or `false` if nothing is owed.
+    pub fn take_debt(&mut self) -> bool {
+        let Some(debt) = self.debt.checked_sub(1) else {
+            return false;
+        };
+        self.debt = debt;
+        true
+    }
+
+    /// The local policy could not pay the cell from [`take_debt`](Self::take_debt), which is
+    /// owed again.
+    pub fn on_unpaid(&mut self) {
+        self.debt += 1;
+    }
+
+    /// The local policy admitted a request, to be reported to the backend.
+    pub fn on_admitted(&mut self) {
+        self.on_admitted_n(1);
+    }
+
+    /// The local policy admitted a request worth `n` cells, each reported to the backend.
+    pub fn on_admitted_n(&mut self, n: u64) {
+        self.pending = self.pending.saturating_add(n);
+    }
+
+    /// The report to ask the backend for, if one is pending. At most one is out at a time.
+    pub fn poll_action(&mut self) -> Option<Report> {
+        if self.reporting {
+            return None;
+        }
+        self.pending = self.pending.checked_sub(1)?;
+        self.reporting = true;
+        Some(Report { _private: () })
+    }
+
+    /// The backend admitted or denied `report`. A denial is owed by the local policy.
+    pub fn on_response(&mut self, _report: Report, answer: Result<(), Denied>) {
+        self.reporting = false;
+        match answer {
+            Ok(()) => self.stats.agreed += 1,
+            Err(_) => {
+                self.stats.overadmitted += 1;
+                self.debt += 1;
+            }
+        }
+    }
+
+    /// The backend failed to answer `report`, which leaves the local decision standing.
+    pub fn on_error(&mut self, _report: Report) {
+        self.reporting = false;
+        self.stats.failed += 1;
+    }
+}
+
+/// Decide on a request worth `n` cells with `local`, after it paid what it can of the debt, a cell
+/// at a time. An admitted request is reported to `reconciler`.
+pub(crate) fn check_local(
+    reconciler: &Mutex<Reconciler>,
+    local: &impl Policy,
+    n: u64,
+) -> Result<(), Denied> {
+    while reconciler.lock().take_debt() {
+        if let Err(denied) = local.check() {
+            reconciler.lock().on_unpaid();
+            return Err(denied);
+        }
+    }
+    local.check_n(n)?;
+    reconciler.lock().on_admitted_n(n);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_reconciler() {
+        let mut reconciler = Reconciler::new();
+        assert_eq!(reconciler.poll_action(), None);
+        reconciler.on_admitted();
+        reconciler.on_admitted_n(2);
+        let report = reconciler.poll_action().unwrap();
+        assert_eq!(reconciler.poll_action(), None);
+        assert_eq!(reconciler.pending(), 3);
+        reconciler.on_error(report);
+        let report = reconciler.poll_action().unwrap();
+        reconciler.on_response(report, Err(Denied::new(Duration::from_secs(1))));
+        let report = reconciler.poll_action().unwrap();
+        reconciler.on_response(report, Ok(()));
+        assert_eq!(reconciler.poll_action(), None);
+        assert_eq!(
+            (reconciler.pending(), reconciler.debt(), reconciler.stats()),
+            (
+                0,
+                1,
+                ReconcileStats {
+                    agreed: 1,
+                    overadmitted: 1,
+                    failed: 1,
+                }
+            )
+        );
+
+        // a cell the local policy cannot pay stays owed
+        assert!(reconciler.take_debt());
+        reconciler.on_unpaid();
+        assert!(reconciler.take_debt());
+        assert!(!reconciler.take_debt());
+    }
+}
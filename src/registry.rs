@@ -0,0 +1,217 @@
+//! Named limiters built from configuration.
+//!
+//! A [`Registry`] holds one [`Limiter`] per name, each around the [`AnyLimiter`] a
+//! [`LimiterConfig`] builds, so a service can look its limiters up by name and have them
+//! configured in one place, e.g. from the environment with [`Registry::from_env`].
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::any::AnyLimiter;
+use crate::clock::{Clock, SystemClock};
+use crate::config::{ConfigError, LimiterConfig};
+use crate::limiter::Limiter;
+
+const ENV_PREFIX: &str = "RATELIMIT_";
+const ENV_SUFFIXES: [&str; 3] = ["_RATE", "_BURST", "_MODE"];
+
+/// Limiters by name. The limiters are named after their entries, so listener events tell them
+/// apart.
+///
+/// # Example
+/// ```
+/// use ratelimit::{LimiterConfig, Policy, Registry};
+///
+/// let mut registry = Registry::new();
+/// registry
+///     .insert("search", &LimiterConfig::Gcra { rate: 1, burst: 0 })
+///     .unwrap();
+/// let search = registry.get("search").unwrap();
+/// assert!(search.check().is_ok());
+/// assert!(search.check().is_err());
+/// ```
+pub struct Registry<C = SystemClock> {
+    clock: C,
+    limiters: BTreeMap<String, Limiter<AnyLimiter<C>, C>>,
+}
+
+impl Registry<SystemClock> {
+    pub fn new() -> Self {
+        Registry::with_clock(SystemClock)
+    }
+
+    /// Build a limiter for every name configured in the environment, see
+    /// [`LimiterConfig::from_env`] for the variables. A name is configured when any of
+    /// `RATELIMIT_<NAME>_RATE`, `_BURST` or `_MODE` is set, and registered as `<NAME>` in lower
+    /// case, e.g. `RATELIMIT_SEARCH_API_RATE` makes `search_api`.
+    ///
+    /// A `_BURST` or `_MODE` without its `_RATE` is an error, as is any invalid variable, so a
+    /// typo in a deployment does not silently leave a limiter out.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut registry = Registry::new();
+        registry.insert_from_vars(std::env::vars_os().filter_map(|(var, value)| {
+            // a variable whose name is not unicode is none of ours, a value is checked when read
+            Some((var.into_string().ok()?, value.into_string().ok()))
+        }))?;
+        Ok(registry)
+    }
+}
+
+impl Default for Registry<SystemClock> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl<C> Registry<C> {
+    /// An empty registry, whose limiters all read `clock`.
+    pub fn with_clock(clock: C) -> Self {
+        Registry {
+            clock,
+            limiters: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Limiter<AnyLimiter<C>, C>> {
+        self.limiters.get(name)
+    }
+
+    /// The limiters, in order of name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Limiter<AnyLimiter<C>, C>)> {
+        self.limiters
+            .iter()
+            .map(|(name, limiter)| (name.as_str(), limiter))
+    }
+
+    pub fn len(&self) -> usize {
+        self.limiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limiters.is_empty()
+    }
+}
+
+impl<C> Registry<C>
+where
+    C: Clock + Clone,
+{
+    /// Build the limiter `config` describes under `name`, replacing any limiter of that name. An
+    /// error names the entry, e.g. ``["search"]: `rate` must be positive``.
+    pub fn insert(
+        &mut self,
+        name: impl Into<String>,
+        config: &LimiterConfig,
+    ) -> Result<(), ConfigError> {
+        let name = name.into();
+        let policy = config
+            .build_with_clock(self.clock.clone())
+            .map_err(|e| e.at(&format!("[{name:?}]")))?;
+        let limiter = Limiter::new(policy)
+            .clock(self.clock.clone())
+            .named(name.clone());
+        self.limiters.insert(name, limiter);
+        Ok(())
+    }
+
+    /// [`from_env`](Registry::from_env) over `vars`, with `None` for a value that is not
+    /// unicode.
+    pub(crate) fn insert_from_vars(
+        &mut self,
+        vars: impl IntoIterator<Item = (String, Option<String>)>,
+    ) -> Result<(), ConfigError> {
+        let vars: BTreeMap<String, Option<String>> = vars
+            .into_iter()
+            .filter(|(var, _)| var.starts_with(ENV_PREFIX))
+            .collect();
+        let names: BTreeSet<String> = vars
+            .keys()
+            .filter_map(|var| {
+                let name = var.strip_prefix(ENV_PREFIX)?;
+                let name = ENV_SUFFIXES
+                    .iter()
+                    .find_map(|suffix| name.strip_suffix(suffix))?;
+                (!name.is_empty()).then(|| name.to_ascii_lowercase())
+            })
+            .collect();
+        for name in names {
+            let config = LimiterConfig::from_lookup(&name, |var| match vars.get(var) {
+                None => Ok(None),
+                Some(Some(value)) => Ok(Some(value.clone())),
+                Some(None) => Err(ConfigError::new("not valid unicode").at(var)),
+            })?;
+            self.insert(name, &config)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::Policy;
+
+    use super::*;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, Option<String>)> {
+        vars.iter()
+            .map(|(var, value)| (var.to_string(), Some(value.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn test_registry_insert() {
+        let clock = MockClock::new(1_000_000);
+        let mut registry = Registry::with_clock(&clock);
+        registry
+            .insert("a", &LimiterConfig::Gcra { rate: 2, burst: 0 })
+            .unwrap();
+        let err = registry
+            .insert("b", &LimiterConfig::Gcra { rate: 0, burst: 0 })
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid limiter config: ["b"]: `rate` must be positive"#
+        );
+        assert_eq!(registry.len(), 1);
+        let a = registry.get("a").unwrap();
+        assert_eq!(a.name(), "a");
+        assert_eq!((0..5).filter(|_| a.check().is_ok()).count(), 2);
+    }
+
+    #[test]
+    fn test_registry_from_env() {
+        let clock = MockClock::new(1_000_000);
+        let mut registry = Registry::with_clock(&clock);
+        registry
+            .insert_from_vars(vars(&[
+                ("RATELIMIT_SEARCH_API_RATE", "1"),
+                ("RATELIMIT_SEARCH_API_BURST", "2"),
+                ("RATELIMIT_UPLOAD_RATE", "5"),
+                ("RATELIMIT_UPLOAD_MODE", "fixed_window"),
+                ("PATH", "/usr/bin"),
+                ("RATELIMIT_LOG", "debug"),
+            ]))
+            .unwrap();
+        let names: Vec<_> = registry.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["search_api", "upload"]);
+        let search = registry.get("search_api").unwrap();
+        assert_eq!((0..5).filter(|_| search.check().is_ok()).count(), 3);
+        assert!(matches!(
+            registry.get("upload").unwrap().policy(),
+            AnyLimiter::TokenBucket(_)
+        ));
+
+        // a burst without its rate is a typo, not a limiter to leave out
+        let err = Registry::with_clock(&clock)
+            .insert_from_vars(vars(&[("RATELIMIT_SEARCH_BURST", "2")]))
+            .unwrap_err();
+        assert_eq!(err.path(), "RATELIMIT_SEARCH_RATE");
+        let err = Registry::with_clock(&clock)
+            .insert_from_vars(vec![("RATELIMIT_SEARCH_RATE".to_string(), None)])
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid limiter config: RATELIMIT_SEARCH_RATE: not valid unicode"
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Building the body of a rate limited (429) response.
+//!
+//! Middlewares hand every rejection to a [`RejectionBody`] together with the parts of the request
+//! that was rejected, so the payload can be branded or localized per request without touching the
+//! middleware. Any `Fn(&Denied, &Parts) -> Body` closure is a `RejectionBody`; [`JsonRejection`] is
+//! the default.
+
+use crate::gcra::Denied;
+use crate::json;
+
+pub trait RejectionBody<Parts> {
+    type Body;
+
+    fn build(&self, denied: &Denied, parts: &Parts) -> Self::Body;
+}
+
+impl<F, Parts, Body> RejectionBody<Parts> for F
+where
+    F: Fn(&Denied, &Parts) -> Body,
+{
+    type Body = Body;
+
+    fn build(&self, denied: &Denied, parts: &Parts) -> Body {
+        self(denied, parts)
+    }
+}
+
+/// Renders `{"error":"rate_limited","retry_after_ms":1500}`.
+///
+/// A message hook can add a human readable `"message"` field, e.g. looked up from the request's
+/// `Accept-Language`:
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ratelimit::{Denied, JsonRejection, RejectionBody};
+///
+/// let body = JsonRejection::new().message(|denied: &Denied, lang: &&str| match *lang {
+///     "fr" => Some(format!("Réessayez dans {} s", denied.retry_after().as_secs())),
+///     _ => None,
+/// });
+/// let denied = Denied::new(Duration::from_secs(2));
+/// assert_eq!(
+///     body.build(&denied, &"fr"),
+///     r#"{"error":"rate_limited","retry_after_ms":2000,"message":"Réessayez dans 2 s"}"#
+/// );
+/// assert_eq!(
+///     body.build(&denied, &"en"),
+///     r#"{"error":"rate_limited","retry_after_ms":2000}"#
+/// );
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct JsonRejection<M = NoMessage> {
+    message: M,
+}
+
+/// No `"message"` field in [`JsonRejection`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMessage;
+
+impl JsonRejection<NoMessage> {
+    pub fn new() -> Self {
+        JsonRejection { message: NoMessage }
+    }
+
+    /// Add a `"message"` field when `message` returns one.
+    pub fn message<M>(self, message: M) -> JsonRejection<M> {
+        JsonRejection { message }
+    }
+}
+
+impl Default for JsonRejection<NoMessage> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Parts> RejectionBody<Parts> for JsonRejection<NoMessage> {
+    type Body = String;
+
+    fn build(&self, denied: &Denied, _parts: &Parts) -> String {
+        render(denied, None)
+    }
+}
+
+impl<M, Parts> RejectionBody<Parts> for JsonRejection<M>
+where
+    M: Fn(&Denied, &Parts) -> Option<String>,
+{
+    type Body = String;
+
+    fn build(&self, denied: &Denied, parts: &Parts) -> String {
+        render(denied, (self.message)(denied, parts).as_deref())
+    }
+}
+
+fn render(denied: &Denied, message: Option<&str>) -> String {
+    let mut out = format!(
+        r#"{{"error":"rate_limited","retry_after_ms":{}"#,
+        denied.retry_after().as_millis()
+    );
+    if let Some(message) = message {
+        out.push_str(r#","message":"#);
+        json::push_str(&mut out, message);
+    }
+    out.push('}');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_json_rejection_escapes_message() {
+        let body = JsonRejection::new().message(|_: &Denied, _: &()| Some("say \"hi\"\n".into()));
+        assert_eq!(
+            body.build(&Denied::new(Duration::from_millis(5)), &()),
+            r#"{"error":"rate_limited","retry_after_ms":5,"message":"say \"hi\"\n"}"#
+        );
+    }
+
+    #[test]
+    fn test_closure_rejection_body() {
+        let body = |denied: &Denied, path: &&str| (429u16, format!("{path}: {denied}"));
+        assert_eq!(
+            body.build(&Denied::new(Duration::from_secs(1)), &"/api"),
+            (429, "/api: rate limited, retry after 1s".to_string())
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Reloading quotas from a mounted config file, e.g. a Kubernetes ConfigMap.
+//!
+//! The file holds the variables of [`LimiterConfig::from_env`], one `KEY=VALUE` per line. Blank
+//! lines and lines starting with `#` are skipped:
+//!
+//! ```text
+//! # quotas for the edge gateway
+//! RATELIMIT_SEARCH_RATE=100
+//! RATELIMIT_SEARCH_BURST=20
+//! RATELIMIT_UPLOAD_RATE=5
+//! ```
+//!
+//! Kubernetes updates a mounted ConfigMap by swapping a symlink in its directory, which file
+//! events on the file itself do not reliably report, so the file is polled: reread and compared
+//! with what was last applied. The file is small, so this is cheap at intervals of seconds.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::clock::{Clock, SystemClock};
+use crate::config::{ConfigError, LimiterConfig};
+use crate::gcra::{Gcra, GcraVersion};
+use crate::quota::Quota;
+
+/// Applies the quotas in a config file to named [`Gcra`] limiters, with
+/// [`Gcra::swap_quota`].
+///
+/// A reload is all or nothing: every limiter's entry is validated before any quota is swapped,
+/// so a bad edit leaves all limiters as they were. In [`dry_run`](Self::dry_run) mode nothing is
+/// ever swapped, and a reload only reports what it would change, e.g. to validate a new
+/// ConfigMap against running gateways before rolling it out.
+///
+/// # Example
+/// ```no_run
+/// use std::sync::Arc;
+/// use ratelimit::{LeakyBucket, Quota, QuotaReloader};
+///
+/// let search = Arc::new(LeakyBucket::builder().quota(Quota::per_second(100)).build());
+/// let mut reloader = QuotaReloader::new("/etc/ratelimit/quotas").limiter("search", search);
+/// match reloader.poll() {
+///     Ok(Some(changes)) => println!("applied {changes:?}"),
+///     Ok(None) => {}
+///     Err(err) => eprintln!("kept the previous quotas: {err}"),
+/// }
+/// ```
+pub struct QuotaReloader<C = SystemClock> {
+    path: PathBuf,
+    limiters: Vec<(String, Arc<Gcra<C>>)>,
+    dry_run: bool,
+    // contents of the file at the last reload, applied or not
+    last: Option<String>,
+}
+
+/// A quota a reload swapped, or would swap in dry-run mode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaChange {
+    pub name: String,
+    /// The configuration before the reload.
+    pub from: GcraVersion,
+    pub to: Quota,
+}
+
+/// Why a reload was not applied.
+#[derive(Debug)]
+pub enum ReloadError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is malformed, or an entry is missing or invalid.
+    Config(ConfigError),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadError::Io(err) => write!(f, "cannot read quota file: {err}"),
+            ReloadError::Config(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReloadError::Io(err) => Some(err),
+            ReloadError::Config(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ReloadError {
+    fn from(err: io::Error) -> Self {
+        ReloadError::Io(err)
+    }
+}
+
+impl From<ConfigError> for ReloadError {
+    fn from(err: ConfigError) -> Self {
+        ReloadError::Config(err)
+    }
+}
+
+impl<C> QuotaReloader<C>
+where
+    C: Clock,
+{
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        QuotaReloader {
+            path: path.into(),
+            limiters: Vec::new(),
+            dry_run: false,
+            last: None,
+        }
+    }
+
+    /// Reload the quota of `limiter` from the entries of `name`, as in
+    /// [`LimiterConfig::from_env`]. Its `_MODE` must be `gcra` or `leaky_bucket`.
+    pub fn limiter(mut self, name: impl Into<String>, limiter: Arc<Gcra<C>>) -> Self {
+        self.limiters.push((name.into(), limiter));
+        self
+    }
+
+    /// Validate and report changes, but never swap a quota.
+    pub fn dry_run(mut self) -> Self {
+        self.dry_run = true;
+        self
+    }
+
+    /// Read the file and apply it. Returns the quotas that changed; limiters whose quota is the
+    /// same are left alone.
+    pub fn reload(&mut self) -> Result<Vec<QuotaChange>, ReloadError> {
+        let text = fs::read_to_string(&self.path)?;
+        self.load(text)
+    }
+
+    /// [`reload`](Self::reload) if the file changed since the last reload, `None` otherwise. A
+    /// file that failed to apply is reported once, not again on every poll until it changes.
+    pub fn poll(&mut self) -> Result<Option<Vec<QuotaChange>>, ReloadError> {
+        let text = fs::read_to_string(&self.path)?;
+        if self.last.as_ref() == Some(&text) {
+            return Ok(None);
+        }
+        self.load(text).map(Some)
+    }
+
+    /// [`poll`](Self::poll) every `interval`, forever, handing every reload or failure to
+    /// `on_reload`.
+    ///
+    /// The file is read with blocking I/O, which is fine for a small file, but keep `interval`
+    /// in the order of seconds.
+    #[cfg(feature = "tokio")]
+    pub async fn watch(
+        &mut self,
+        interval: std::time::Duration,
+        mut on_reload: impl FnMut(Result<Vec<QuotaChange>, ReloadError>),
+    ) {
+        loop {
+            match self.poll() {
+                Ok(None) => {}
+                Ok(Some(changes)) => on_reload(Ok(changes)),
+                Err(err) => on_reload(Err(err)),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    fn load(&mut self, text: String) -> Result<Vec<QuotaChange>, ReloadError> {
+        let vars = parse(&text);
+        self.last = Some(text);
+        let vars = vars?;
+        let mut changes = Vec::new();
+        for (name, limiter) in &self.limiters {
+            let quota = LimiterConfig::from_lookup(name, |var| Ok(vars.get(var).cloned()))?
+                .into_gcra_quota(name)?;
+            let from = limiter.version();
+            if from.gap != quota.gap() || from.tolerance != quota.tolerance() {
+                changes.push(QuotaChange {
+                    name: name.clone(),
+                    from,
+                    to: quota,
+                });
+            }
+        }
+        if !self.dry_run {
+            for change in &changes {
+                let (_, limiter) = self
+                    .limiters
+                    .iter()
+                    .find(|(name, _)| *name == change.name)
+                    .expect("changes are of registered limiters");
+                limiter.swap_quota(change.to);
+            }
+        }
+        Ok(changes)
+    }
+}
+
+fn parse(text: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let mut vars = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| ConfigError::new("expected KEY=VALUE").at(&format!("line {}", i + 1)))?;
+        vars.insert(key.trim().to_owned(), value.trim().to_owned());
+    }
+    Ok(vars)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::gcra::GcraBuilder;
+
+    use super::*;
+
+    #[test]
+    fn test_quota_reloader() {
+        let path = std::env::temp_dir().join(format!("ratelimit-{}.quotas", std::process::id()));
+        let clock = MockClock::new(1_000_000);
+        let limiter = || {
+            Arc::new(
+                GcraBuilder::new()
+                    .clock(&clock)
+                    .quota(Quota::per_second(10))
+                    .build(),
+            )
+        };
+        let (search, upload) = (limiter(), limiter());
+        let mut dry = QuotaReloader::new(&path)
+            .limiter("search", search.clone())
+            .dry_run();
+        let mut reloader = QuotaReloader::new(&path)
+            .limiter("search", search.clone())
+            .limiter("upload", upload.clone());
+
+        fs::write(
+            &path,
+            "# quotas\nRATELIMIT_SEARCH_RATE=100\nRATELIMIT_SEARCH_BURST=20\n\nRATELIMIT_UPLOAD_RATE=10\n",
+        )
+        .unwrap();
+        let changes = dry.poll().unwrap().unwrap();
+        assert_eq!(changes[0].to, Quota::per_second(100).burst(20));
+        assert_eq!(search.version().epoch, 0);
+        assert_eq!(dry.poll().unwrap(), None);
+
+        // only the search quota changed
+        let changes = reloader.poll().unwrap().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].name, "search");
+        assert_eq!(changes[0].from.gap, Duration::from_millis(100));
+        assert_eq!(search.version().gap, Duration::from_millis(10));
+        assert_eq!(upload.version().epoch, 0);
+
+        // a bad entry leaves every limiter as it was, and is reported once
+        fs::write(
+            &path,
+            "RATELIMIT_SEARCH_RATE=50\nRATELIMIT_UPLOAD_RATE=fast\n",
+        )
+        .unwrap();
+        let err = reloader.poll().unwrap_err();
+        assert!(matches!(&err, ReloadError::Config(err) if err.path() == "RATELIMIT_UPLOAD_RATE"));
+        assert_eq!(search.version().gap, Duration::from_millis(10));
+        assert_eq!(reloader.poll().unwrap(), None);
+
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(reloader.poll(), Err(ReloadError::Io(_))));
+    }
+}
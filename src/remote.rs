@@ -0,0 +1,562 @@
+//! Policies decided by a remote backend, e.g. a shared store counting for a whole fleet.
+//!
+//! A [`Backend`] is a policy that can fail to decide. [`Degrade`] turns it into a [`Policy`] by
+//! choosing what happens while the backend is unreachable, see [`FailureMode`]; [`Fallback`]
+//! asks another policy instead. [`Cached`] saves round trips to a backend during bursts.
+
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::gcra::{duration_nanos, Denied, Gcra, GcraBuilder, Policy};
+use crate::listener::{Event, Listener, Listeners};
+use crate::quota::Quota;
+use crate::sync::{AtomicU64, Mutex, Ordering};
+
+/// A policy decided somewhere that can fail to answer.
+pub trait Backend {
+    type Error;
+
+    fn try_check(&self) -> Result<Result<(), Denied>, Self::Error>;
+
+    /// Decide on `n` cells at once, all or nothing. By default, more than one cell is denied
+    /// with a `retry_after` of [`Duration::MAX`], for backends that cannot decide on batches.
+    fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, Self::Error> {
+        match n {
+            0 => Ok(Ok(())),
+            1 => self.try_check(),
+            _ => Ok(Err(Denied::new(Duration::MAX))),
+        }
+    }
+}
+
+/// How [`Degrade`] decides while its backend is down.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FailureMode {
+    /// Admit everything.
+    Open,
+    /// Deny everything.
+    Closed,
+    /// Limit locally with this quota, usually a fraction of the global one, e.g.
+    /// `FailureMode::Local(global.scale(1.0 / instances as f64))`.
+    Local(Quota),
+}
+
+/// Falls back to a [`FailureMode`] when the backend errors.
+///
+/// After a failure the backend is left alone for `probe_interval`, then the next request probes
+/// it again. The first successful answer ends the degraded period. Both transitions are reported
+/// to listeners as [`Event::BackendDown`] and [`Event::BackendUp`].
+pub struct Degrade<B, C = SystemClock> {
+    backend: B,
+    clock: C,
+    mode: FailureMode,
+    local: Option<Gcra<()>>,
+    probe_interval: u64,
+    health: Mutex<Health>,
+    listeners: Listeners,
+}
+
+struct Health {
+    // in ns, as all times here
+    down_since: Option<u64>,
+    next_probe: u64,
+}
+
+impl<B> Degrade<B, SystemClock> {
+    pub fn new(backend: B, mode: FailureMode) -> Self {
+        let local = match mode {
+            FailureMode::Local(quota) => Some(GcraBuilder::new().quota(quota).build()),
+            FailureMode::Open | FailureMode::Closed => None,
+        };
+        Degrade {
+            backend,
+            clock: SystemClock,
+            mode,
+            local,
+            probe_interval: 1_000_000_000,
+            health: Mutex::new(Health {
+                down_since: None,
+                next_probe: 0,
+            }),
+            listeners: Listeners::default(),
+        }
+    }
+}
+
+impl<B, C> Degrade<B, C> {
+    pub fn clock<NC>(self, clock: NC) -> Degrade<B, NC> {
+        Degrade {
+            backend: self.backend,
+            clock,
+            mode: self.mode,
+            local: self.local,
+            probe_interval: self.probe_interval,
+            health: self.health,
+            listeners: self.listeners,
+        }
+    }
+
+    /// How long to wait after a failure before asking the backend again. One second by default.
+    pub fn probe_interval(mut self, interval: Duration) -> Self {
+        self.probe_interval = duration_nanos(interval);
+        self
+    }
+
+    /// Name the policy in listener events.
+    pub fn named(mut self, name: impl Into<String>) -> Self {
+        self.listeners.set_name(name.into());
+        self
+    }
+
+    pub fn listener(mut self, listener: impl Listener + 'static) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn mode(&self) -> FailureMode {
+        self.mode
+    }
+
+    /// Whether the backend is currently considered down.
+    pub fn is_degraded(&self) -> bool {
+        self.health.lock().down_since.is_some()
+    }
+}
+
+impl<B, C> Degrade<B, C>
+where
+    C: Clock,
+{
+    /// Decide on `n` cells without the backend, at `now` on the clock.
+    fn degraded(&self, now: u64, next_probe: u64, n: u64) -> Result<(), Denied> {
+        match &self.local {
+            Some(local) => local.check_n_at(now, n),
+            None if self.mode == FailureMode::Open => Ok(()),
+            None => Err(Denied::new(Duration::from_nanos(
+                next_probe.saturating_sub(now),
+            ))),
+        }
+    }
+}
+
+impl<B, C> Policy for Degrade<B, C>
+where
+    B: Backend,
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        let now = self.clock.now_nanos();
+        {
+            let mut health = self.health.lock();
+            if health.down_since.is_some() {
+                if now < health.next_probe {
+                    let next_probe = health.next_probe;
+                    drop(health);
+                    return self.degraded(now, next_probe, n);
+                }
+                // this request probes, the others keep degrading meanwhile
+                health.next_probe = now.saturating_add(self.probe_interval);
+            }
+        }
+        match self.backend.try_check_n(n) {
+            Ok(decision) => {
+                let down_since = self.health.lock().down_since.take();
+                if let Some(since) = down_since {
+                    self.listeners.emit(|policy| Event::BackendUp {
+                        policy,
+                        downtime: Duration::from_nanos(now.saturating_sub(since)),
+                    });
+                }
+                decision
+            }
+            Err(_) => {
+                let mut health = self.health.lock();
+                let next_probe = now.saturating_add(self.probe_interval);
+                health.next_probe = next_probe;
+                if health.down_since.is_none() {
+                    health.down_since = Some(now);
+                    drop(health);
+                    self.listeners.emit(|policy| Event::BackendDown { policy });
+                }
+                self.degraded(now, next_probe, n)
+            }
+        }
+    }
+}
+
+/// Answers from recent decisions of a backend where it can, so a burst of requests does not pay
+/// a round trip each.
+///
+/// Two kinds of decisions are cached:
+/// - A denial is repeated until its `retry_after` is over. This never admits more than the
+///   backend would, but capacity the backend regains early, e.g. from refunds, is only seen
+///   once the denial runs out.
+/// - With [`prepay`](Self::prepay), an admission takes a batch of cells from the backend and
+///   the rest of the batch admits the next requests without asking, until it expires. Cells are
+///   charged when the batch is taken, so the backend's limit still holds over the fleet, but
+///   each instance may admit its prepaid cells up to `ttl` after they were charged, and cells
+///   still unused when the batch expires are lost.
+pub struct Cached<B, C = SystemClock> {
+    backend: B,
+    clock: C,
+    batch: u64,
+    ttl: u64,
+    state: Mutex<CacheState>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    // in ns, as all times here
+    denied_until: u64,
+    prepaid: u64,
+    prepaid_until: u64,
+}
+
+impl<B> Cached<B, SystemClock> {
+    /// Cache denials only, until configured with [`prepay`](Self::prepay).
+    pub fn new(backend: B) -> Self {
+        Cached {
+            backend,
+            clock: SystemClock,
+            batch: 1,
+            ttl: 0,
+            state: Mutex::new(CacheState::default()),
+        }
+    }
+}
+
+impl<B, C> Cached<B, C> {
+    pub fn clock<NC>(self, clock: NC) -> Cached<B, NC> {
+        Cached {
+            backend: self.backend,
+            clock,
+            batch: self.batch,
+            ttl: self.ttl,
+            state: self.state,
+        }
+    }
+
+    /// Take `batch` cells at a time from the backend, usable for `ttl`. Should the backend deny
+    /// a whole batch, a single cell is asked for instead. Keep `ttl` to a few milliseconds or
+    /// tens of them, see the bounds above.
+    ///
+    /// # Panics
+    /// Panics if `batch` is zero.
+    pub fn prepay(mut self, batch: u64, ttl: Duration) -> Self {
+        assert!(batch > 0, "batch must be positive");
+        self.batch = batch;
+        self.ttl = duration_nanos(ttl);
+        self
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B, C> Backend for Cached<B, C>
+where
+    B: Backend,
+    C: Clock,
+{
+    type Error = B::Error;
+
+    fn try_check(&self) -> Result<Result<(), Denied>, B::Error> {
+        self.try_check_n(1)
+    }
+
+    /// Decide on `n` cells, from the prepaid ones if enough are left. A cached denial denies any
+    /// number of cells, but only the denial of a single cell is cached, since fewer cells than a
+    /// denied batch may still conform.
+    fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, B::Error> {
+        if n == 0 {
+            return Ok(Ok(()));
+        }
+        let now = self.clock.now_nanos();
+        {
+            let mut state = self.state.lock();
+            if now < state.denied_until {
+                return Ok(Err(Denied::new(Duration::from_nanos(
+                    state.denied_until - now,
+                ))));
+            }
+            if state.prepaid >= n && now < state.prepaid_until {
+                state.prepaid -= n;
+                return Ok(Ok(()));
+            }
+        }
+        // no lock across the round trip; concurrent misses each ask the backend
+        let mut taken = std::cmp::max(self.batch, n);
+        let mut decision = self.backend.try_check_n(taken)?;
+        if decision.is_err() && taken > n {
+            taken = n;
+            decision = self.backend.try_check_n(n)?;
+        }
+        let mut state = self.state.lock();
+        match decision {
+            Ok(()) => {
+                state.prepaid = taken - n;
+                state.prepaid_until = now.saturating_add(self.ttl);
+            }
+            Err(denied) if n == 1 => {
+                let until = now.saturating_add(duration_nanos(denied.retry_after()));
+                state.denied_until = std::cmp::max(state.denied_until, until);
+            }
+            Err(_) => {}
+        }
+        Ok(decision)
+    }
+}
+
+/// Asks `primary`, and `secondary` whenever the primary errors, e.g. a local GCRA behind a
+/// shared store. Timeouts are up to the primary to report as errors.
+///
+/// Unlike [`Degrade`], the primary is asked on every request, and the secondary can be any
+/// policy, including another `Fallback`.
+pub struct Fallback<B, P> {
+    primary: B,
+    secondary: P,
+    by_primary: AtomicU64,
+    by_secondary: AtomicU64,
+}
+
+/// How many decisions each side of a [`Fallback`] made.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FallbackStats {
+    pub primary: u64,
+    pub secondary: u64,
+}
+
+impl<B, P> Fallback<B, P> {
+    pub fn new(primary: B, secondary: P) -> Self {
+        Fallback {
+            primary,
+            secondary,
+            by_primary: AtomicU64::new(0),
+            by_secondary: AtomicU64::new(0),
+        }
+    }
+
+    pub fn primary(&self) -> &B {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &P {
+        &self.secondary
+    }
+
+    pub fn stats(&self) -> FallbackStats {
+        FallbackStats {
+            primary: self.by_primary.load(Ordering::Relaxed),
+            secondary: self.by_secondary.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<B, P> Policy for Fallback<B, P>
+where
+    B: Backend,
+    P: Policy,
+{
+    fn check(&self) -> Result<(), Denied> {
+        match self.primary.try_check() {
+            Ok(decision) => {
+                self.by_primary.fetch_add(1, Ordering::Relaxed);
+                decision
+            }
+            Err(_) => {
+                self.by_secondary.fetch_add(1, Ordering::Relaxed);
+                self.secondary.check()
+            }
+        }
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        match self.primary.try_check_n(n) {
+            Ok(decision) => {
+                self.by_primary.fetch_add(1, Ordering::Relaxed);
+                decision
+            }
+            Err(_) => {
+                self.by_secondary.fetch_add(1, Ordering::Relaxed);
+                self.secondary.check_n(n)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicU32};
+    use std::sync::Arc;
+
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Flaky {
+        down: AtomicBool,
+        calls: AtomicU32,
+    }
+
+    impl Backend for Flaky {
+        type Error = ();
+
+        fn try_check(&self) -> Result<Result<(), Denied>, ()> {
+            self.calls.fetch_add(1, Ordering::Relaxed);
+            if self.down.load(Ordering::Relaxed) {
+                Err(())
+            } else {
+                Ok(Ok(()))
+            }
+        }
+    }
+
+    #[test]
+    fn test_default_try_check_n() {
+        let backend = Flaky::default();
+        assert_eq!(backend.try_check_n(1), Ok(Ok(())));
+        let denied = backend.try_check_n(2).unwrap().unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::MAX);
+        assert_eq!(backend.calls.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_degrade_modes() {
+        let clock = MockClock::new(1_000_000);
+        for (mode, admitted) in [
+            (FailureMode::Open, 10),
+            (FailureMode::Closed, 0),
+            (FailureMode::Local(Quota::per_second(2)), 2),
+        ] {
+            let backend = Flaky::default();
+            backend.down.store(true, Ordering::Relaxed);
+            let policy = Degrade::new(backend, mode).clock(&clock);
+            let passed = (0..10).filter(|_| policy.pass()).count();
+            assert_eq!(passed, admitted, "{mode:?}");
+            assert!(policy.is_degraded());
+        }
+    }
+
+    #[test]
+    fn test_degrade_recovery() {
+        let clock = MockClock::new(1_000_000);
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let backend = Flaky::default();
+        backend.down.store(true, Ordering::Relaxed);
+        let policy = Degrade::new(backend, FailureMode::Closed)
+            .clock(&clock)
+            .probe_interval(Duration::from_millis(500))
+            .named("redis")
+            .listener(move |event: &Event<'_>| sink.lock().push(format!("{event:?}")));
+
+        let denied = policy.check().unwrap_err();
+        assert_eq!(denied.retry_after(), Duration::from_millis(500));
+        for _ in 0..10 {
+            assert!(!policy.pass());
+        }
+        // the backend is not asked again until the probe interval passed
+        let calls = || policy.backend().calls.load(Ordering::Relaxed);
+        assert_eq!(calls(), 1);
+
+        policy.backend().down.store(false, Ordering::Relaxed);
+        clock.forward(Duration::from_millis(500));
+        assert!(policy.pass());
+        assert!(!policy.is_degraded());
+        assert_eq!(calls(), 2);
+        assert_eq!(
+            *events.lock(),
+            [
+                r#"BackendDown { policy: "redis" }"#,
+                r#"BackendUp { policy: "redis", downtime: 500ms }"#
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fallback() {
+        let clock = MockClock::new(1_000_000);
+        let local = GcraBuilder::new()
+            .clock(&clock)
+            .quota(Quota::per_second(2))
+            .build();
+        let policy = Fallback::new(Flaky::default(), local);
+        assert!(policy.pass());
+        policy.primary().down.store(true, Ordering::Relaxed);
+        let passed = (0..5).filter(|_| policy.pass()).count();
+        assert_eq!(passed, 2);
+        assert_eq!(
+            policy.stats(),
+            FallbackStats {
+                primary: 1,
+                secondary: 5
+            }
+        );
+        // the primary decides again as soon as it answers
+        policy.primary().down.store(false, Ordering::Relaxed);
+        assert!(policy.pass());
+        assert_eq!(policy.primary().calls.load(Ordering::Relaxed), 7);
+    }
+
+    #[test]
+    fn test_cached() {
+        struct Remote<'a> {
+            gcra: Gcra<&'a MockClock>,
+            calls: AtomicU32,
+        }
+
+        impl Backend for Remote<'_> {
+            type Error = ();
+
+            fn try_check(&self) -> Result<Result<(), Denied>, ()> {
+                self.try_check_n(1)
+            }
+
+            fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, ()> {
+                self.calls.fetch_add(1, Ordering::Relaxed);
+                Ok(self.gcra.check_n(n))
+            }
+        }
+
+        let clock = MockClock::new(1_000_000);
+        let remote = Remote {
+            gcra: GcraBuilder::new()
+                .clock(&clock)
+                .quota(Quota::per_second(10))
+                .build(),
+            calls: AtomicU32::new(0),
+        };
+        let cached = Cached::new(remote)
+            .prepay(4, Duration::from_millis(10))
+            .clock(&clock);
+        let calls = || cached.backend().calls.load(Ordering::Relaxed);
+        let passed = (0..20).filter(|_| cached.try_check() == Ok(Ok(()))).count();
+        // two batches of 4, and two single cells each asked for after a denied batch, then the
+        // denial is cached
+        assert_eq!(passed, 10);
+        assert_eq!(calls(), 8);
+        assert_eq!(
+            cached.try_check(),
+            Ok(Err(Denied::new(Duration::from_millis(100))))
+        );
+        assert_eq!(calls(), 8);
+
+        // unused prepaid cells expire, and the backend is asked again
+        clock.forward(Duration::from_millis(500));
+        assert_eq!(cached.try_check(), Ok(Ok(())));
+        assert_eq!(calls(), 9);
+        clock.forward(Duration::from_millis(10));
+        assert_eq!(cached.try_check(), Ok(Ok(())));
+        assert_eq!(calls(), 11);
+    }
+}
@@ -0,0 +1,158 @@
+//! Replaying recorded traffic against a candidate configuration.
+//!
+//! Choosing limits by guessing either lets abuse through or rejects legitimate bursts. With a log
+//! of real arrivals, [`replay`] shows what a [`LimiterConfig`] would have done to them: it runs
+//! the log through one limiter per key under virtual time, so a day of traffic replays in
+//! moments, and reports how many requests of each key would have been denied.
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::{now_millis, MockClock, Timestamp};
+use crate::config::{ConfigError, LimiterConfig};
+
+/// A recorded request: when it arrived, in ms of the recording's clock, on which key, and its
+/// cost in cells.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Arrival<K> {
+    pub at: Timestamp,
+    pub key: K,
+    pub cost: u64,
+}
+
+/// Requests offered and denied during a replay.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub offered: u64,
+    pub denied: u64,
+}
+
+impl ReplayStats {
+    /// Fraction of the offered requests that were denied, 0 if none were offered.
+    pub fn denial_rate(&self) -> f64 {
+        if self.offered == 0 {
+            return 0.0;
+        }
+        self.denied as f64 / self.offered as f64
+    }
+}
+
+/// Outcome of a [`replay`].
+#[derive(Debug, Clone)]
+pub struct ReplayReport<K> {
+    pub total: ReplayStats,
+    pub keys: HashMap<K, ReplayStats>,
+    /// Time covered by the log, from its first to its last arrival.
+    pub span: Duration,
+}
+
+impl<K> ReplayReport<K> {
+    /// The `n` keys with the highest denial rate, most denied first.
+    pub fn most_denied(&self, n: usize) -> Vec<(&K, ReplayStats)> {
+        let mut keys: Vec<_> = self.keys.iter().map(|(key, stats)| (key, *stats)).collect();
+        keys.sort_by(|(_, a), (_, b)| {
+            b.denial_rate()
+                .total_cmp(&a.denial_rate())
+                .then(b.denied.cmp(&a.denied))
+        });
+        keys.truncate(n);
+        keys
+    }
+}
+
+/// Run `arrivals` through a limiter built from `config` for every key, under virtual time.
+///
+/// Arrivals should be in time order; one earlier than the one before it is replayed at the time
+/// of the latter, as the virtual clock cannot go back.
+///
+/// # Example
+/// ```
+/// use ratelimit::replay::{replay, Arrival};
+/// use ratelimit::LimiterConfig;
+///
+/// // a crawler at 100/s for 10s
+/// let log = (0..1000).map(|i| Arrival { at: i * 10, key: "crawler", cost: 1 });
+/// let report = replay(&LimiterConfig::Gcra { rate: 50, burst: 0 }, log).unwrap();
+/// // gets its first 50 through at once, then 50/s, so about 450 are denied
+/// let denial_rate = report.keys["crawler"].denial_rate();
+/// assert!((0.44..0.46).contains(&denial_rate));
+/// ```
+pub fn replay<K, I>(config: &LimiterConfig, arrivals: I) -> Result<ReplayReport<K>, ConfigError>
+where
+    K: Hash + Eq + Clone,
+    I: IntoIterator<Item = Arrival<K>>,
+{
+    // fail on a bad configuration even if the log is empty
+    config.build()?;
+    let mut arrivals = arrivals.into_iter().peekable();
+    let start = arrivals.peek().map_or(0, |arrival| arrival.at);
+    let clock = MockClock::new(start);
+    let mut limiters = HashMap::new();
+    let mut report = ReplayReport {
+        total: ReplayStats::default(),
+        keys: HashMap::new(),
+        span: Duration::ZERO,
+    };
+    for arrival in arrivals {
+        clock.forward(Duration::from_millis(
+            arrival.at.saturating_sub(now_millis(&clock)),
+        ));
+        let limiter = match limiters.get(&arrival.key) {
+            Some(limiter) => limiter,
+            None => limiters
+                .entry(arrival.key.clone())
+                .or_insert(config.build_with_clock(&clock)?),
+        };
+        let denied = limiter.check_n(arrival.cost).is_err() as u64;
+        let stats = report.keys.entry(arrival.key).or_default();
+        for stats in [stats, &mut report.total] {
+            stats.offered += 1;
+            stats.denied += denied;
+        }
+    }
+    report.span = Duration::from_millis(now_millis(&clock) - start);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let config = LimiterConfig::Gcra { rate: 2, burst: 0 };
+        let mut log = Vec::new();
+        for second in 0..10 {
+            let at = 1_700_000_000_000 + second * 1000;
+            log.push(Arrival {
+                at,
+                key: "steady",
+                cost: 1,
+            });
+            for i in 0..4 {
+                log.push(Arrival {
+                    at: at + i,
+                    key: "bursty",
+                    cost: 1,
+                });
+            }
+        }
+        let report = replay(&config, log).unwrap();
+        assert_eq!(report.span, Duration::from_millis(9003));
+        assert_eq!(
+            report.keys["steady"],
+            ReplayStats {
+                offered: 10,
+                denied: 0
+            }
+        );
+        // two of every four burst requests fit
+        assert_eq!(report.keys["bursty"].denied, 20);
+        assert_eq!(report.total.offered, 50);
+        assert_eq!(report.most_denied(1)[0].0, &"bursty");
+
+        let invalid = LimiterConfig::Gcra { rate: 0, burst: 0 };
+        assert!(replay(&invalid, Vec::<Arrival<&str>>::new()).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+//! Deferred delivery of rejected work, in the style of a mail queue.
+//!
+//! A [`RetryQueue`] sits between producers and a [`Policy`]. Items the policy admits are
+//! delivered right away; rejected ones are kept until the policy said they could conform and
+//! offered again then. An item rejected again backs off exponentially, so a policy that stays
+//! saturated is not hammered by its own backlog.
+
+use std::cmp::{self, Ordering};
+use std::collections::BinaryHeap;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp};
+use crate::gcra::{duration_nanos, to_millis, Denied, Policy};
+use crate::overflow::{Backlogged, Caps};
+use crate::sync::Mutex;
+
+/// Where a [`RetryQueue`] delivers admitted items. Any `FnMut(T)` closure is a `Deliver`, and so
+/// is the sending half of a channel.
+pub trait Deliver<T> {
+    fn deliver(&mut self, item: T);
+}
+
+impl<F, T> Deliver<T> for F
+where
+    F: FnMut(T),
+{
+    fn deliver(&mut self, item: T) {
+        self(item)
+    }
+}
+
+impl<T> Deliver<T> for Sender<T> {
+    /// Items sent after the receiver hung up are dropped.
+    fn deliver(&mut self, item: T) {
+        let _ = self.send(item);
+    }
+}
+
+/// Delivers items as `policy` admits them, holding back and retrying rejected ones.
+///
+/// A rejected item is due again after the `retry_after` of its denial. If it is rejected on that
+/// attempt too, the delay doubles with every further rejection, starting at the base of
+/// [`backoff`](Self::backoff), but is never shorter than what the policy asked for. Due items are
+/// offered in order of their due time, and only when [`process_due`](Self::process_due) runs;
+/// [`next_due`](Self::next_due) tells when that is worth doing. With caps set, an item that
+/// would take the queue over one is given back as [`Backlogged`] rather than queued.
+///
+/// # Example
+/// ```
+/// use std::sync::mpsc;
+/// use ratelimit::{RetryQueue, VirtualScheduling};
+///
+/// let (tx, rx) = mpsc::channel();
+/// let queue = RetryQueue::new(VirtualScheduling::builder().rate(1).build(), tx);
+/// queue.submit("first").unwrap();
+/// queue.submit("second").unwrap();
+/// assert_eq!(rx.try_recv(), Ok("first"));
+/// assert_eq!(queue.len(), 1);
+/// ```
+pub struct RetryQueue<P, T, D, C = SystemClock> {
+    policy: P,
+    clock: C,
+    deliver: Mutex<D>,
+    // ms, see `backoff`
+    base: u64,
+    max: u64,
+    caps: Caps<T>,
+    pending: Mutex<Pending<T>>,
+    #[cfg(feature = "tokio")]
+    notify: tokio::sync::Notify,
+}
+
+struct Pending<T> {
+    next_seq: u64,
+    entries: BinaryHeap<Entry<T>>,
+    // of the entries, and of those taken out by `process_due` and not delivered yet
+    bytes: usize,
+}
+
+struct Entry<T> {
+    due: Timestamp,
+    // breaks ties between equal due times in submission order
+    seq: u64,
+    rejections: u32,
+    submitted: Timestamp,
+    // in bytes, 0 without a byte cap
+    size: usize,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.due, self.seq) == (other.due, other.seq)
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    // reversed, so the max-heap pops the earliest entry first
+    fn cmp(&self, other: &Self) -> Ordering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+impl<P, T, D> RetryQueue<P, T, D, SystemClock> {
+    pub fn new(policy: P, deliver: D) -> Self {
+        RetryQueue {
+            policy,
+            clock: SystemClock,
+            deliver: Mutex::new(deliver),
+            base: 100,
+            max: 60_000,
+            caps: Caps::default(),
+            pending: Mutex::new(Pending {
+                next_seq: 0,
+                entries: BinaryHeap::new(),
+                bytes: 0,
+            }),
+            #[cfg(feature = "tokio")]
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+}
+
+impl<P, T, D, C> RetryQueue<P, T, D, C> {
+    pub fn clock<NC>(self, clock: NC) -> RetryQueue<P, T, D, NC> {
+        RetryQueue {
+            policy: self.policy,
+            clock,
+            deliver: self.deliver,
+            base: self.base,
+            max: self.max,
+            caps: self.caps,
+            pending: self.pending,
+            #[cfg(feature = "tokio")]
+            notify: self.notify,
+        }
+    }
+
+    /// Back off from `base` on the second rejection of an item, doubling up to `max`. 100ms and
+    /// one minute by default.
+    pub fn backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base = base.as_millis() as u64;
+        self.max = max.as_millis() as u64;
+        self
+    }
+
+    /// Hold at most `max` items.
+    pub fn max_items(mut self, max: usize) -> Self {
+        self.caps.max_items = Some(max);
+        self
+    }
+
+    /// Hold at most `max` bytes of items, with the size of an item given by `size`.
+    pub fn max_bytes(mut self, max: usize, size: fn(&T) -> usize) -> Self {
+        self.caps.max_bytes = Some(max);
+        self.caps.size = size;
+        self
+    }
+
+    /// Turn items away while the oldest queued item was submitted longer than `max` ago.
+    pub fn max_age(mut self, max: Duration) -> Self {
+        self.caps.max_age = Some(max);
+        self
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    /// Number of items waiting for another attempt.
+    pub fn len(&self) -> usize {
+        self.pending.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<P, T, D, C> RetryQueue<P, T, D, C>
+where
+    P: Policy,
+    D: Deliver<T>,
+    C: Clock,
+{
+    /// Deliver `item` now if the policy admits it, otherwise queue it for later. An item that
+    /// would take the queue over a cap is given back instead.
+    pub fn submit(&self, item: T) -> Result<(), Backlogged<T>> {
+        let now = now_millis(&self.clock);
+        let Err((item, denied)) = self.offer(item) else {
+            return Ok(());
+        };
+        let size = self.caps.size(&item);
+        {
+            let mut pending = self.pending.lock();
+            let oldest = match self.caps.max_age {
+                Some(_) => pending.entries.iter().map(|entry| entry.submitted).min(),
+                None => None,
+            };
+            let (len, bytes) = (pending.entries.len(), pending.bytes);
+            if let Err(overflow) = self.caps.check(len, bytes, size, oldest, now) {
+                return Err(Backlogged { item, overflow });
+            }
+            pending.bytes += size;
+        }
+        self.requeue(
+            Entry {
+                due: now,
+                seq: 0,
+                rejections: 0,
+                submitted: now,
+                size,
+                item,
+            },
+            denied,
+            now,
+        );
+        Ok(())
+    }
+
+    /// Offer every due item to the policy once. Returns how many were delivered.
+    pub fn process_due(&self) -> usize {
+        let now = now_millis(&self.clock);
+        let mut delivered = 0;
+        loop {
+            let entry = {
+                let mut pending = self.pending.lock();
+                match pending.entries.peek() {
+                    Some(entry) if entry.due <= now => pending.entries.pop(),
+                    _ => None,
+                }
+            };
+            let Some(mut entry) = entry else {
+                return delivered;
+            };
+            match self.offer(entry.item) {
+                Ok(()) => {
+                    self.pending.lock().bytes -= entry.size;
+                    delivered += 1;
+                }
+                Err((item, denied)) => {
+                    entry.item = item;
+                    self.requeue(entry, denied, now);
+                }
+            }
+        }
+    }
+
+    /// Time until the earliest queued item is due, `None` if the queue is empty.
+    pub fn next_due(&self) -> Option<Duration> {
+        let now = now_millis(&self.clock);
+        let pending = self.pending.lock();
+        let due = pending.entries.peek()?.due;
+        Some(Duration::from_millis(due.saturating_sub(now)))
+    }
+
+    /// Deliver `item` if the policy admits it, otherwise give it back with the denial.
+    fn offer(&self, item: T) -> Result<(), (T, Denied)> {
+        match self.policy.check() {
+            Ok(()) => {
+                self.deliver.lock().deliver(item);
+                Ok(())
+            }
+            Err(denied) => Err((item, denied)),
+        }
+    }
+
+    /// Queue `entry` again after it was `denied`, backing off by its rejections so far.
+    fn requeue(&self, mut entry: Entry<T>, denied: Denied, now: Timestamp) {
+        // rounded up, as the clock reads whole milliseconds
+        let asked = to_millis(duration_nanos(denied.retry_after()));
+        let delay = match entry.rejections {
+            0 => asked,
+            n => {
+                let backoff = self.base.saturating_mul(1 << cmp::min(n - 1, 32));
+                cmp::max(asked, cmp::min(backoff, self.max))
+            }
+        };
+        let mut pending = self.pending.lock();
+        // at least one ms, so an item is never offered twice in one `process_due`
+        entry.due = now + cmp::max(delay, 1);
+        entry.seq = pending.next_seq;
+        pending.next_seq += 1;
+        entry.rejections += 1;
+        pending.entries.push(entry);
+        drop(pending);
+        #[cfg(feature = "tokio")]
+        self.notify.notify_one();
+    }
+
+    /// Process due items forever, sleeping until the next one is due.
+    #[cfg(feature = "tokio")]
+    pub async fn run(&self) {
+        loop {
+            self.process_due();
+            let notified = self.notify.notified();
+            match self.next_due() {
+                Some(delay) => {
+                    let _ = tokio::time::timeout(delay, notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::VirtualScheduling;
+    use crate::overflow::Overflow;
+
+    use super::*;
+
+    #[test]
+    fn test_retry_queue_redelivers_when_due() {
+        let clock = MockClock::new(1_000_000);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let vs = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .build();
+        let queue = RetryQueue::new(&vs, tx).clock(&clock);
+        for i in 0..3 {
+            queue.submit(i).unwrap();
+        }
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0]);
+        assert_eq!(queue.next_due(), Some(Duration::from_millis(100)));
+
+        clock.forward(Duration::from_millis(100));
+        assert_eq!(queue.process_due(), 1);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [1]);
+        // 2 was rejected twice and backs off from the base of 100ms
+        assert_eq!(queue.next_due(), Some(Duration::from_millis(100)));
+        clock.forward(Duration::from_millis(100));
+        assert_eq!(queue.process_due(), 1);
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [2]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_retry_queue_escalates() {
+        let clock = MockClock::new(1_000_000);
+        let mut delivered = Vec::new();
+        let vs = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(10))
+            .build();
+        let queue = RetryQueue::new(&vs, |item| delivered.push(item))
+            .clock(&clock)
+            .backoff(Duration::from_millis(50), Duration::from_millis(150));
+        queue.submit("a").unwrap();
+        queue.submit("b").unwrap();
+        let mut delays = Vec::new();
+        for _ in 0..4 {
+            let delay = queue.next_due().unwrap();
+            delays.push(delay);
+            clock.forward(delay);
+            // take the slot "b" is waiting for, so every retry is rejected
+            assert!(vs.pass());
+            assert_eq!(queue.process_due(), 0);
+        }
+        assert_eq!(delays, [10, 50, 100, 150].map(Duration::from_millis));
+        drop(queue);
+        assert_eq!(delivered, ["a"]);
+    }
+
+    #[test]
+    fn test_retry_queue_caps() {
+        let clock = MockClock::new(1_000_000);
+        let vs = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .build();
+        let queue = RetryQueue::new(&vs, |_| {})
+            .clock(&clock)
+            .max_items(2)
+            .max_age(Duration::from_millis(150));
+        // admitted right away, so it takes no room
+        queue.submit(0).unwrap();
+        queue.submit(1).unwrap();
+        queue.submit(2).unwrap();
+        assert_eq!(
+            queue.submit(3),
+            Err(Backlogged {
+                item: 3,
+                overflow: Overflow::Items(2),
+            })
+        );
+
+        clock.forward(Duration::from_millis(200));
+        assert_eq!(queue.process_due(), 1);
+        // 2 was submitted 200ms ago, and is still waiting
+        let err = queue.submit(4).unwrap_err();
+        assert_eq!(err.overflow, Overflow::Age(Duration::from_millis(150)));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn test_retry_queue_run() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let vs = VirtualScheduling::builder()
+            .gap(Duration::from_millis(20))
+            .build();
+        let queue =
+            RetryQueue::new(vs, tx).backoff(Duration::from_millis(10), Duration::from_secs(1));
+        for i in 0..3 {
+            queue.submit(i).unwrap();
+        }
+        let _ = tokio::time::timeout(Duration::from_millis(100), queue.run()).await;
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [0, 1, 2]);
+    }
+}
@@ -0,0 +1,123 @@
+//! Charging a policy once per batch of calls, for paths where one atomic per call is too much.
+
+use std::cell::Cell;
+use std::ops::Deref;
+
+use crate::clock::Clock;
+use crate::describe::{Describe, Description};
+use crate::gcra::{Denied, Gcra, Policy};
+
+/// A per-thread handle that charges a shared [`Gcra`] once every `every` calls, with cost `every`.
+///
+/// The handle prepays a batch of `every` cells and hands them out from a plain counter, so only
+/// one call in `every` touches the shared state. It is `!Sync` on purpose: give each thread its
+/// own handle over a `&Gcra` or an `Arc<Gcra>`.
+///
+/// # Accuracy
+/// Batches are charged up front, so the limit is never exceeded. The cost is under-admission
+/// instead: up to `every - 1` cells per handle can be paid for but not used yet, and a batch is
+/// only admitted once the policy has room for all of it. With `t` handles the policy can thus
+/// look up to `t * (every - 1)` requests fuller than it really is, and it never admits anything
+/// through a handle whose `every` exceeds its burst capacity, `tolerance / gap + 1`. Keep `every`
+/// well below the burst and the number of handles small.
+///
+/// # Example
+/// ```
+/// use ratelimit::{Policy, Sampled, VirtualScheduling};
+///
+/// let vs = VirtualScheduling::builder().rate(1000).build();
+/// let sampled = Sampled::new(&vs, 16);
+/// assert!(sampled.check().is_ok());
+/// ```
+pub struct Sampled<G> {
+    gcra: G,
+    every: u64,
+    left: Cell<u64>,
+}
+
+impl<G> Sampled<G> {
+    /// # Panics
+    /// Panics if `every` is zero.
+    pub fn new(gcra: G, every: u64) -> Self {
+        assert!(every > 0, "every must be positive");
+        Sampled {
+            gcra,
+            every,
+            left: Cell::new(0),
+        }
+    }
+
+    /// Cells paid for but not handed out yet.
+    pub fn prepaid(&self) -> u64 {
+        self.left.get()
+    }
+
+    pub fn into_inner(self) -> G {
+        self.gcra
+    }
+}
+
+impl<G, C> Policy for Sampled<G>
+where
+    G: Deref<Target = Gcra<C>>,
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        let left = self.left.get();
+        if left > 0 {
+            self.left.set(left - 1);
+            return Ok(());
+        }
+        self.gcra.check_n(self.every)?;
+        self.left.set(self.every - 1);
+        Ok(())
+    }
+
+    fn refund(&self) {
+        self.left.set(self.left.get() + 1);
+    }
+}
+
+impl<G, C> Describe for Sampled<G>
+where
+    G: Deref<Target = Gcra<C>>,
+    C: Clock,
+{
+    fn describe(&self) -> Description {
+        Description::new("sampled")
+            .setting("every", self.every)
+            .child(self.gcra.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::gcra::VirtualScheduling;
+
+    use super::*;
+
+    #[test]
+    fn test_sampled_charges_per_batch() {
+        let vs = VirtualScheduling::builder()
+            .clock(MockClock::new(1_000_000))
+            .rate(10)
+            .build();
+        let sampled = Sampled::new(&vs, 4);
+        // burst of 10 cells: two batches of 4, the third does not fit
+        for _ in 0..8 {
+            assert!(sampled.pass());
+        }
+        assert!(sampled.check().is_err());
+        assert_eq!(vs.check_n(2), Ok(()));
+        assert!(!vs.pass());
+
+        vs.forward(Duration::from_millis(400));
+        assert!(sampled.pass());
+        assert_eq!(sampled.prepaid(), 3);
+        sampled.refund();
+        assert_eq!(sampled.prepaid(), 4);
+    }
+}
@@ -0,0 +1,178 @@
+//! Checking a configuration for mistakes before the service takes traffic.
+//!
+//! [`EndpointConfig::self_check`] goes through every entry and reports all problems at once,
+//! unlike [`build`](EndpointConfig::build), which stops at the first. Errors are entries that
+//! cannot be built at all; warnings are entries that build but are unlikely to do what was meant,
+//! judged against what the deployment tells about itself in [`CapacityHints`].
+
+use std::fmt;
+
+use crate::clock::SystemClock;
+use crate::config::{EndpointConfig, LimiterConfig, Selector};
+
+/// Facts about the deployment that quotas can be checked against.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CapacityHints {
+    /// Requests per second the protected backend can take.
+    pub backend_qps: Option<u64>,
+    /// The largest cost a single request may have. A request costing more than a limiter's
+    /// capacity is never admitted, and waiting for it never ends.
+    pub max_request_cost: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// One problem found by a self-check.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub severity: Severity,
+    /// The offending entry, in the form of [`ConfigError::path`](crate::ConfigError::path).
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.message)
+    }
+}
+
+impl EndpointConfig {
+    /// Report every problem of this configuration. Start serving only if there is no
+    /// [`Severity::Error`]: those are the entries [`build`](Self::build) rejects, and limiters
+    /// that can never admit a request of `max_request_cost`.
+    pub fn self_check(&self, hints: &CapacityHints) -> Vec<Finding> {
+        let mut findings = Vec::new();
+        check_limiter(&self.default, "default".to_owned(), hints, &mut findings);
+        let mut selectors = Vec::new();
+        for (key, config) in &self.overrides {
+            let path = format!("overrides[{key:?}]");
+            match Selector::parse(key) {
+                Ok(selector) => selectors.push((selector, key, config)),
+                Err(e) => findings.push(Finding {
+                    severity: Severity::Error,
+                    path: path.clone(),
+                    message: e.message().to_owned(),
+                }),
+            }
+            check_limiter(config, path, hints, &mut findings);
+        }
+        // an override for a subset of requests that is more generous than the one for the whole
+        // set is dead: the broader override's limit is not enforced on the subset
+        for (child, child_key, child_config) in &selectors {
+            for (parent, parent_key, parent_config) in &selectors {
+                if child_key != parent_key
+                    && child.within(parent)
+                    && child_config.rate() > parent_config.rate()
+                {
+                    findings.push(Finding {
+                        severity: Severity::Warning,
+                        path: format!("overrides[{child_key:?}]"),
+                        message: format!(
+                            "rate {} exceeds the rate {} of the broader override {parent_key:?}",
+                            child_config.rate(),
+                            parent_config.rate()
+                        ),
+                    });
+                }
+            }
+        }
+        findings
+    }
+}
+
+fn check_limiter(
+    config: &LimiterConfig,
+    path: String,
+    hints: &CapacityHints,
+    findings: &mut Vec<Finding>,
+) {
+    let mut report = |severity, message: String| {
+        findings.push(Finding {
+            severity,
+            path: path.clone(),
+            message,
+        })
+    };
+    if let Err(e) = config.build_with_clock(SystemClock) {
+        report(Severity::Error, e.message().to_owned());
+        return;
+    }
+    if let Some(backend) = hints.backend_qps {
+        if config.rate() > backend as f64 {
+            report(
+                Severity::Warning,
+                format!(
+                    "rate {} exceeds the backend capacity of {backend} qps",
+                    config.rate()
+                ),
+            );
+        } else if config.capacity() > backend {
+            report(
+                Severity::Warning,
+                format!(
+                    "a burst of {} requests exceeds the backend capacity of {backend} qps",
+                    config.capacity()
+                ),
+            );
+        }
+    }
+    if let Some(cost) = hints.max_request_cost {
+        if cost > config.capacity() {
+            report(
+                Severity::Error,
+                format!(
+                    "requests costing up to {cost} can never pass a capacity of {}",
+                    config.capacity()
+                ),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_self_check() {
+        let gcra = |rate, burst| LimiterConfig::Gcra { rate, burst };
+        let config = EndpointConfig {
+            default: gcra(100, 100),
+            overrides: BTreeMap::from([
+                ("/upload".to_owned(), gcra(5, 0)),
+                ("POST /upload".to_owned(), gcra(10, 0)),
+                ("post".to_owned(), gcra(0, 0)),
+            ]),
+        };
+        let hints = CapacityHints {
+            backend_qps: Some(150),
+            max_request_cost: Some(8),
+        };
+        let findings: Vec<_> = config
+            .self_check(&hints)
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            findings,
+            [
+                "warning: default: a burst of 200 requests exceeds the backend capacity of 150 qps",
+                r#"error: overrides["/upload"]: requests costing up to 8 can never pass a capacity of 5"#,
+                r#"error: overrides["post"]: `post` is neither a method, a route nor `tenant=<name>`"#,
+                r#"error: overrides["post"]: `rate` must be positive"#,
+                r#"warning: overrides["POST /upload"]: rate 10 exceeds the rate 5 of the broader override "/upload""#,
+            ]
+        );
+    }
+}
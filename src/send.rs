@@ -0,0 +1,328 @@
+//! Throttling notifications, e.g. emails, SMS or push messages.
+//!
+//! Notification limits come in layers: a recipient should not get more than a few messages in a
+//! burst nor more than a handful a day, the provider account has a rate of its own, and a
+//! retried job must not send the same message twice. [`SendGovernor`] combines these into one
+//! decision per message.
+
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock, Timestamp, NANOS_PER_MS};
+use crate::gcra::{Denied, Gcra, GcraBuilder};
+use crate::keyed::{KeyedLimiter, ToKey};
+use crate::quota::Quota;
+use crate::sync::Mutex;
+use crate::window::QuotaStatus;
+
+const DAY: u64 = 86_400_000;
+
+/// What to do with a message, see [`SendGovernor::check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendVerdict {
+    Send,
+    /// The same message went to the same recipient within the dedup window. Drop it.
+    Duplicate,
+    /// Over the per-recipient or the global rate. Retry later.
+    Throttled(Denied),
+    /// The recipient, or the governor as a whole, reached its daily cap. `retry_after` is the
+    /// time until the next day starts.
+    CapReached(Denied),
+}
+
+/// Per-recipient and global limits on sending messages.
+///
+/// A message is sent only if it passes all limits, and only then counted against them, so a
+/// duplicate or a message over the daily cap costs no rate. Days are calendar days of the
+/// clock, i.e. UTC days with [`SystemClock`].
+///
+/// Recipients and message hashes are kept for the day and the dedup window respectively, so
+/// call [`retain_recent`](Self::retain_recent) periodically.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use ratelimit::{KeyedLimiter, Quota, SendGovernor, SendVerdict};
+///
+/// let per_recipient: KeyedLimiter<String> =
+///     KeyedLimiter::builder(Quota::per_second(1).burst(2)).build();
+/// let governor = SendGovernor::new(per_recipient)
+///     .global(Quota::per_second(100))
+///     .daily_cap(5)
+///     .dedup_window(Duration::from_secs(600));
+/// let body = "Your code is 123456";
+/// assert_eq!(governor.check("+15550100", body), SendVerdict::Send);
+/// assert_eq!(governor.check("+15550100", body), SendVerdict::Duplicate);
+/// ```
+pub struct SendGovernor<K, C = SystemClock> {
+    recipients: KeyedLimiter<K, C>,
+    global: Option<Gcra<()>>,
+    daily_cap: Option<u32>,
+    global_daily_cap: Option<u32>,
+    // ms
+    dedup_window: u64,
+    state: Mutex<SendState<K>>,
+}
+
+struct SendState<K> {
+    day: u64,
+    sent_today: u32,
+    sent_today_to: HashMap<K, u32>,
+    // hash of recipient and message, to when it was sent
+    recent: HashMap<u64, Timestamp>,
+}
+
+impl<K, C> SendGovernor<K, C> {
+    /// Limit each recipient by `recipients`, with no global rate, no caps and no dedup.
+    pub fn new(recipients: KeyedLimiter<K, C>) -> Self {
+        SendGovernor {
+            recipients,
+            global: None,
+            daily_cap: None,
+            global_daily_cap: None,
+            dedup_window: 0,
+            state: Mutex::new(SendState {
+                day: 0,
+                sent_today: 0,
+                sent_today_to: HashMap::new(),
+                recent: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Limit all messages together, e.g. to the rate of the provider account.
+    pub fn global(mut self, quota: Quota) -> Self {
+        self.global = Some(GcraBuilder::new().quota(quota).build());
+        self
+    }
+
+    /// Messages per recipient per calendar day.
+    pub fn daily_cap(mut self, cap: u32) -> Self {
+        self.daily_cap = Some(cap);
+        self
+    }
+
+    /// Messages per calendar day over all recipients.
+    pub fn global_daily_cap(mut self, cap: u32) -> Self {
+        self.global_daily_cap = Some(cap);
+        self
+    }
+
+    /// Drop messages identical to one sent to the same recipient within `window`. Zero, the
+    /// default, disables dedup.
+    pub fn dedup_window(mut self, window: Duration) -> Self {
+        self.dedup_window = window.as_millis() as u64;
+        self
+    }
+
+    pub fn recipients(&self) -> &KeyedLimiter<K, C> {
+        &self.recipients
+    }
+}
+
+impl<K, C> SendGovernor<K, C>
+where
+    K: Hash + Eq,
+    C: Clock,
+{
+    /// Decide on sending `message` to `recipient`. `message` is only hashed, for dedup; pass
+    /// the rendered body, or an idempotency key of the notification.
+    pub fn check<Q, M>(&self, recipient: &Q, message: &M) -> SendVerdict
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+        M: Hash + ?Sized,
+    {
+        let nanos = self.recipients.now_nanos();
+        let now = nanos / NANOS_PER_MS;
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let day = now / DAY;
+        if state.day != day {
+            state.day = day;
+            state.sent_today = 0;
+            state.sent_today_to.clear();
+        }
+        let digest = (self.dedup_window > 0).then(|| {
+            let mut hasher = DefaultHasher::new();
+            recipient.hash(&mut hasher);
+            message.hash(&mut hasher);
+            hasher.finish()
+        });
+        if let Some(digest) = digest {
+            if state
+                .recent
+                .get(&digest)
+                .is_some_and(|&sent| now < sent + self.dedup_window)
+            {
+                return SendVerdict::Duplicate;
+            }
+        }
+        let verdict = self.decide(state, recipient, nanos);
+        if let (Some(digest), SendVerdict::Send) = (digest, verdict) {
+            state.recent.insert(digest, now);
+        }
+        verdict
+    }
+
+    fn decide<Q>(&self, state: &mut SendState<K>, recipient: &Q, nanos: u64) -> SendVerdict
+    where
+        K: Borrow<Q>,
+        Q: ToKey<K> + ?Sized,
+    {
+        let now = nanos / NANOS_PER_MS;
+        let sent_to = state.sent_today_to.get(recipient).copied().unwrap_or(0);
+        if self.daily_cap.is_some_and(|cap| sent_to >= cap)
+            || self
+                .global_daily_cap
+                .is_some_and(|cap| state.sent_today >= cap)
+        {
+            let next_day = (now / DAY + 1) * DAY;
+            return SendVerdict::CapReached(Denied::new(Duration::from_millis(next_day - now)));
+        }
+        if let Some(global) = &self.global {
+            if let Err(denied) = global.check_at(nanos) {
+                return SendVerdict::Throttled(denied);
+            }
+        }
+        if let Err(denied) = self.recipients.check(recipient) {
+            if let Some(global) = &self.global {
+                global.refund_n(1);
+            }
+            return SendVerdict::Throttled(denied);
+        }
+        state.sent_today += 1;
+        if self.daily_cap.is_some() {
+            match state.sent_today_to.get_mut(recipient) {
+                Some(sent) => *sent += 1,
+                None => {
+                    state.sent_today_to.insert(recipient.to_key(), 1);
+                }
+            }
+        }
+        SendVerdict::Send
+    }
+
+    /// What `recipient` has left of its daily cap, or `None` without one. The global cap and
+    /// rates are not taken into account.
+    pub fn quota_status<Q>(&self, recipient: &Q) -> Option<QuotaStatus>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let cap = self.daily_cap?;
+        let now = self.recipients.now();
+        let state = self.state.lock();
+        let sent = if state.day == now / DAY {
+            state.sent_today_to.get(recipient).copied().unwrap_or(0)
+        } else {
+            0
+        };
+        let next_day = (now / DAY + 1) * DAY;
+        Some(QuotaStatus {
+            limit: cap.into(),
+            remaining: cap.saturating_sub(sent).into(),
+            resets_in: Duration::from_millis(next_day - now),
+        })
+    }
+
+    /// Forget message hashes older than the dedup window, and drop idle entries of the
+    /// per-recipient limiter.
+    pub fn retain_recent(&self) {
+        let now = self.recipients.now();
+        self.state
+            .lock()
+            .recent
+            .retain(|_, &mut sent| now < sent + self.dedup_window);
+        self.recipients.retain_recent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_send_governor() {
+        let clock = MockClock::new(10 * DAY + 1000);
+        let recipients: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(&clock)
+            .build();
+        let governor = SendGovernor::new(recipients)
+            .global(Quota::per_second(2))
+            .daily_cap(2)
+            .dedup_window(Duration::from_secs(60));
+
+        assert_eq!(governor.check("alice", "hi"), SendVerdict::Send);
+        assert_eq!(governor.check("alice", "hi"), SendVerdict::Duplicate);
+        assert!(matches!(
+            governor.check("alice", "again"),
+            SendVerdict::Throttled(_)
+        ));
+        assert_eq!(governor.check("bob", "hi"), SendVerdict::Send);
+        // alice is within her rate, but the global one is spent
+        clock.forward(Duration::from_millis(1000));
+        assert_eq!(governor.check("carol", "hi"), SendVerdict::Send);
+        assert_eq!(governor.check("bob", "hey"), SendVerdict::Send);
+        assert!(matches!(
+            governor.check("alice", "again"),
+            SendVerdict::Throttled(_)
+        ));
+
+        clock.forward(Duration::from_millis(1000));
+        assert_eq!(governor.check("alice", "again"), SendVerdict::Send);
+        clock.forward(Duration::from_millis(1000));
+        assert_eq!(
+            governor.check("alice", "third"),
+            SendVerdict::CapReached(Denied::new(Duration::from_millis(DAY - 4000)))
+        );
+
+        // a new day resets the caps, and the dedup window has passed
+        clock.forward(Duration::from_millis(DAY));
+        governor.retain_recent();
+        assert_eq!(governor.check("alice", "hi"), SendVerdict::Send);
+    }
+
+    #[test]
+    fn test_quota_status() {
+        let clock = MockClock::new(10 * DAY + 1000);
+        let recipients = || {
+            KeyedLimiter::<String, _>::builder(Quota::per_second(1).burst(9))
+                .clock(&clock)
+                .build()
+        };
+        assert_eq!(SendGovernor::new(recipients()).quota_status("alice"), None);
+
+        let governor = SendGovernor::new(recipients()).daily_cap(2);
+        assert_eq!(governor.check("alice", "hi"), SendVerdict::Send);
+        assert_eq!(
+            governor.quota_status("alice"),
+            Some(QuotaStatus {
+                limit: 2,
+                remaining: 1,
+                resets_in: Duration::from_millis(DAY - 1000)
+            })
+        );
+        assert_eq!(governor.check("alice", "again"), SendVerdict::Send);
+        assert_eq!(governor.quota_status("alice").unwrap().remaining, 0);
+        // a recipient nothing was sent to has the whole cap, and asking sends nothing
+        assert_eq!(governor.quota_status("bob").unwrap().remaining, 2);
+        assert_eq!(governor.check("bob", "hi"), SendVerdict::Send);
+
+        // the cap is back the next day, before anything is sent
+        clock.forward(Duration::from_millis(DAY));
+        assert_eq!(
+            governor.quota_status("alice"),
+            Some(QuotaStatus {
+                limit: 2,
+                remaining: 2,
+                resets_in: Duration::from_millis(DAY - 1000)
+            })
+        );
+    }
+}
@@ -0,0 +1,554 @@
+//! Tower middleware, enabled by the `tower` feature.
+//!
+//! [`RateLimit`] is generic over the request type, so one [`RateLimitLayer`] built from one
+//! [`KeyedLimiter`] can wrap axum routes and tonic services alike. What to limit on and how much
+//! a request costs are pluggable: a [`KeyExtractor`] picks the key, e.g. the client address or an
+//! API key header, and a [`CostExtractor`] weighs the request, one cell by default.
+//!
+//! A rejected request never reaches the inner service and fails with
+//! [`RateLimitError::Limited`]. Map it to the protocol's answer where the stack handles errors,
+//! e.g. `RESOURCE_EXHAUSTED` for gRPC, or have the layer answer it with a [`RejectionBody`], e.g.
+//! a 429 for HTTP, set with [`RateLimitLayer::rejection_body`].
+//!
+//! The layer can also be built on an [`Escalation`], see [`KeyedPolicy`], or on the per-endpoint
+//! limiters of an [`EndpointConfig`], see [`RateLimitLayer::endpoints`]. Keys it challenges are
+//! handed to a [`ChallengeHandler`], e.g. one answering with a CAPTCHA page, set with
+//! [`RateLimitLayer::challenge`]; without one, challenged requests are rejected as well.
+//!
+//! # Example
+//! ```
+//! use std::sync::Arc;
+//! use ratelimit::{KeyedLimiter, Quota, RateLimitLayer};
+//!
+//! struct Request {
+//!     tenant: String,
+//!     items: u64,
+//! }
+//!
+//! let limiter: Arc<KeyedLimiter<String>> =
+//!     Arc::new(KeyedLimiter::builder(Quota::per_second(100)).build());
+//! let layer = RateLimitLayer::new(limiter, |req: &Request| req.tenant.clone())
+//!     .cost(|req: &Request| req.items);
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use pin_project_lite::pin_project;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::clock::Clock;
+use crate::config::{ConfigError, EndpointConfig, EndpointKey, EndpointLimits};
+use crate::escalation::{Escalation, Verdict};
+use crate::gcra::Denied;
+use crate::keyed::KeyedLimiter;
+use crate::pipeline::KeyExtractor;
+use crate::rejection::RejectionBody;
+
+/// Decides on requests by key for a [`RateLimit`]. Implemented by [`KeyedLimiter`],
+/// [`Escalation`] and [`EndpointLimits`].
+pub trait KeyedPolicy<K> {
+    fn decide(&self, key: &K, cost: u64) -> Verdict;
+
+    /// Decide as if the request arrived `offset` later, see [`KeyExtractor::offset`]. Policies
+    /// without a notion of offset ignore it.
+    fn decide_with_offset(&self, key: &K, cost: u64, offset: Duration) -> Verdict {
+        let _ = offset;
+        self.decide(key, cost)
+    }
+}
+
+impl<K, C> KeyedPolicy<K> for KeyedLimiter<K, C>
+where
+    K: Hash + Eq + Clone,
+    C: Clock,
+{
+    fn decide(&self, key: &K, cost: u64) -> Verdict {
+        self.decide_with_offset(key, cost, Duration::ZERO)
+    }
+
+    fn decide_with_offset(&self, key: &K, cost: u64, offset: Duration) -> Verdict {
+        match self.check_n_with_offset(key, cost, offset) {
+            Ok(()) => Verdict::Allow,
+            Err(denied) => Verdict::Deny(denied),
+        }
+    }
+}
+
+impl<C> KeyedPolicy<EndpointKey> for EndpointLimits<C>
+where
+    C: Clock,
+{
+    fn decide(&self, key: &EndpointKey, cost: u64) -> Verdict {
+        match self.check_n(&key.as_endpoint(), cost) {
+            Ok(()) => Verdict::Allow,
+            Err(denied) => Verdict::Deny(denied),
+        }
+    }
+}
+
+impl<K, C> KeyedPolicy<K> for Escalation<K, C>
+where
+    K: Hash + Eq + Clone + Display,
+    C: Clock,
+{
+    fn decide(&self, key: &K, cost: u64) -> Verdict {
+        Escalation::decide(self, key, cost)
+    }
+}
+
+/// Weighs a request in cells. Any `Fn(&Req) -> u64` closure is a `CostExtractor`.
+pub trait CostExtractor<Req> {
+    fn cost(&self, req: &Req) -> u64;
+}
+
+impl<F, Req> CostExtractor<Req> for F
+where
+    F: Fn(&Req) -> u64,
+{
+    fn cost(&self, req: &Req) -> u64 {
+        self(req)
+    }
+}
+
+/// Every request costs one cell.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnitCost;
+
+impl<Req> CostExtractor<Req> for UnitCost {
+    fn cost(&self, _req: &Req) -> u64 {
+        1
+    }
+}
+
+/// Answers a challenged request in place of the inner service, e.g. with a CAPTCHA page. Any
+/// `Fn(Req, u32) -> Resp` closure, given the request and the key's escalation level, is a
+/// `ChallengeHandler`.
+pub trait ChallengeHandler<Req, Resp> {
+    /// The response to send instead of calling the inner service, or `None` to reject the
+    /// request.
+    fn challenge(&self, req: Req, level: u32) -> Option<Resp>;
+}
+
+impl<F, Req, Resp> ChallengeHandler<Req, Resp> for F
+where
+    F: Fn(Req, u32) -> Resp,
+{
+    fn challenge(&self, req: Req, level: u32) -> Option<Resp> {
+        Some(self(req, level))
+    }
+}
+
+/// Rejects challenged requests like denied ones.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoChallenge;
+
+impl<Req, Resp> ChallengeHandler<Req, Resp> for NoChallenge {
+    fn challenge(&self, _req: Req, _level: u32) -> Option<Resp> {
+        None
+    }
+}
+
+/// Answers a rejected request in place of the inner service. Set from a [`RejectionBody`] of
+/// the request with [`RateLimitLayer::rejection_body`].
+pub trait RejectionHandler<Req, Resp> {
+    /// The response to send instead of calling the inner service, or `None` to fail with
+    /// [`RateLimitError::Limited`].
+    fn reject(&self, denied: &Denied, req: &Req) -> Option<Resp>;
+}
+
+/// Fails rejected requests with [`RateLimitError::Limited`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoRejectionBody;
+
+impl<Req, Resp> RejectionHandler<Req, Resp> for NoRejectionBody {
+    fn reject(&self, _denied: &Denied, _req: &Req) -> Option<Resp> {
+        None
+    }
+}
+
+/// Answers rejected requests with the body a [`RejectionBody`] builds from them.
+#[derive(Debug, Clone, Copy)]
+pub struct WithRejectionBody<B>(pub B);
+
+impl<B, Req> RejectionHandler<Req, B::Body> for WithRejectionBody<B>
+where
+    B: RejectionBody<Req>,
+{
+    fn reject(&self, denied: &Denied, req: &Req) -> Option<B::Body> {
+        Some(self.0.build(denied, req))
+    }
+}
+
+/// Error of a [`RateLimit`] service.
+#[derive(Debug)]
+pub enum RateLimitError<E> {
+    /// The request was rejected without calling the inner service.
+    Limited(Denied),
+    /// The inner service failed.
+    Inner(E),
+}
+
+impl<E: fmt::Display> fmt::Display for RateLimitError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RateLimitError::Limited(denied) => denied.fmt(f),
+            RateLimitError::Inner(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for RateLimitError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RateLimitError::Limited(denied) => Some(denied),
+            RateLimitError::Inner(e) => Some(e),
+        }
+    }
+}
+
+/// Applies [`RateLimit`] to services, sharing one limiter between all of them.
+pub struct RateLimitLayer<G, E, W = UnitCost, H = NoChallenge, R = NoRejectionBody> {
+    limiter: Arc<G>,
+    key: E,
+    cost: W,
+    challenge: H,
+    rejection: R,
+}
+
+impl<G, E> RateLimitLayer<G, E> {
+    pub fn new(limiter: Arc<G>, key: E) -> Self {
+        RateLimitLayer {
+            limiter,
+            key,
+            cost: UnitCost,
+            challenge: NoChallenge,
+            rejection: NoRejectionBody,
+        }
+    }
+}
+
+impl<E> RateLimitLayer<EndpointLimits, E> {
+    /// Limit every endpoint by the limiter `config` assigns it, shared by all callers of the
+    /// endpoint. `endpoint` tells which endpoint a request is for.
+    pub fn endpoints(config: &EndpointConfig, endpoint: E) -> Result<Self, ConfigError> {
+        Ok(RateLimitLayer::new(Arc::new(config.build()?), endpoint))
+    }
+}
+
+impl<G, E, W, H, R> RateLimitLayer<G, E, W, H, R> {
+    pub fn cost<NW>(self, cost: NW) -> RateLimitLayer<G, E, NW, H, R> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            key: self.key,
+            cost,
+            challenge: self.challenge,
+            rejection: self.rejection,
+        }
+    }
+
+    pub fn challenge<NH>(self, challenge: NH) -> RateLimitLayer<G, E, W, NH, R> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            key: self.key,
+            cost: self.cost,
+            challenge,
+            rejection: self.rejection,
+        }
+    }
+
+    /// Answer rejected requests, including challenged ones without a
+    /// [`challenge`](Self::challenge) handler, with the body `body` builds from the request,
+    /// rather than failing with [`RateLimitError::Limited`]. The body is the response of the
+    /// service, e.g. a closure building a 429 from the request's headers.
+    pub fn rejection_body<B>(self, body: B) -> RateLimitLayer<G, E, W, H, WithRejectionBody<B>> {
+        RateLimitLayer {
+            limiter: self.limiter,
+            key: self.key,
+            cost: self.cost,
+            challenge: self.challenge,
+            rejection: WithRejectionBody(body),
+        }
+    }
+}
+
+impl<G, E: Clone, W: Clone, H: Clone, R: Clone> Clone for RateLimitLayer<G, E, W, H, R> {
+    fn clone(&self) -> Self {
+        RateLimitLayer {
+            limiter: self.limiter.clone(),
+            key: self.key.clone(),
+            cost: self.cost.clone(),
+            challenge: self.challenge.clone(),
+            rejection: self.rejection.clone(),
+        }
+    }
+}
+
+impl<S, G, E: Clone, W: Clone, H: Clone, R: Clone> Layer<S> for RateLimitLayer<G, E, W, H, R> {
+    type Service = RateLimit<S, G, E, W, H, R>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// Rejects requests over the limit of their key before they reach the inner service.
+pub struct RateLimit<S, G, E, W = UnitCost, H = NoChallenge, R = NoRejectionBody> {
+    inner: S,
+    layer: RateLimitLayer<G, E, W, H, R>,
+}
+
+impl<S, G, E, W, H, R> RateLimit<S, G, E, W, H, R> {
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: Clone, G, E: Clone, W: Clone, H: Clone, R: Clone> Clone for RateLimit<S, G, E, W, H, R> {
+    fn clone(&self) -> Self {
+        RateLimit {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<S, Req, G, E, W, H, R> Service<Req> for RateLimit<S, G, E, W, H, R>
+where
+    S: Service<Req>,
+    E: KeyExtractor<Req>,
+    G: KeyedPolicy<E::Key>,
+    W: CostExtractor<Req>,
+    H: ChallengeHandler<Req, S::Response>,
+    R: RejectionHandler<Req, S::Response>,
+{
+    type Response = S::Response;
+    type Error = RateLimitError<S::Error>;
+    type Future = ResponseFuture<S::Future, S::Response>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(RateLimitError::Inner)
+    }
+
+    fn call(&mut self, req: Req) -> Self::Future {
+        let key = self.layer.key.key(&req);
+        let cost = self.layer.cost.cost(&req);
+        let offset = self.layer.key.offset(&req);
+        match self.layer.limiter.decide_with_offset(&key, cost, offset) {
+            Verdict::Allow => ResponseFuture::Inner {
+                future: self.inner.call(req),
+            },
+            Verdict::Challenge { level, denied } => {
+                // the rejection body is built before the handler takes the request
+                let rejected = self.layer.rejection.reject(&denied, &req);
+                match self.layer.challenge.challenge(req, level) {
+                    Some(response) => ResponseFuture::Challenged {
+                        response: Some(response),
+                    },
+                    None => ResponseFuture::rejected(denied, rejected),
+                }
+            }
+            Verdict::Deny(denied) => {
+                ResponseFuture::rejected(denied, self.layer.rejection.reject(&denied, &req))
+            }
+        }
+    }
+}
+
+pin_project! {
+    /// Response future of [`RateLimit`].
+    #[project = ResponseFutureProj]
+    pub enum ResponseFuture<F, T> {
+        Inner { #[pin] future: F },
+        Limited { denied: Denied },
+        Challenged { response: Option<T> },
+        Rejected { response: Option<T> },
+    }
+}
+
+impl<F, T> ResponseFuture<F, T> {
+    fn rejected(denied: Denied, response: Option<T>) -> Self {
+        match response {
+            Some(response) => ResponseFuture::Rejected {
+                response: Some(response),
+            },
+            None => ResponseFuture::Limited { denied },
+        }
+    }
+}
+
+impl<F, T, E> Future for ResponseFuture<F, T>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    type Output = Result<T, RateLimitError<E>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.project() {
+            ResponseFutureProj::Inner { future } => future.poll(cx).map_err(RateLimitError::Inner),
+            ResponseFutureProj::Limited { denied } => {
+                Poll::Ready(Err(RateLimitError::Limited(*denied)))
+            }
+            ResponseFutureProj::Challenged { response }
+            | ResponseFutureProj::Rejected { response } => Poll::Ready(Ok(response
+                .take()
+                .expect("ResponseFuture polled after completion"))),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::{poll_fn, ready, Ready};
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::config::LimiterConfig;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    struct Echo;
+
+    impl<Req> Service<Req> for Echo {
+        type Response = Req;
+        type Error = Infallible;
+        type Future = Ready<Result<Req, Infallible>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Req) -> Self::Future {
+            ready(Ok(req))
+        }
+    }
+
+    async fn send<S: Service<Req>, Req>(svc: &mut S, req: Req) -> Result<S::Response, S::Error> {
+        poll_fn(|cx| svc.poll_ready(cx)).await?;
+        svc.call(req).await
+    }
+
+    #[tokio::test]
+    async fn test_layer_shared_across_request_types() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limiter = Arc::new(
+            KeyedLimiter::builder(Quota::per_second(1).burst(2))
+                .clock(clock.clone())
+                .build(),
+        );
+        let layer = RateLimitLayer::new(limiter, |req: &(&'static str, u64)| req.0)
+            .cost(|req: &(&'static str, u64)| req.1);
+        let mut http = layer.layer(Echo);
+        let mut grpc = layer.layer(Echo);
+
+        assert!(send(&mut http, ("a", 2)).await.is_ok());
+        match send(&mut grpc, ("a", 2)).await {
+            Err(RateLimitError::Limited(denied)) => {
+                assert_eq!(denied.retry_after(), Duration::from_secs(1))
+            }
+            _ => panic!("expected a rejection"),
+        }
+        assert!(send(&mut grpc, ("a", 1)).await.is_ok());
+        assert!(send(&mut grpc, ("b", 3)).await.is_ok());
+        clock.forward(Duration::from_secs(1));
+        assert!(send(&mut http, ("a", 1)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_layer_challenge() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limiter = KeyedLimiter::builder(Quota::per_second(1))
+            .clock(clock.clone())
+            .build();
+        let escalation = Arc::new(Escalation::new(limiter).strikes(1).challenge_levels(1));
+        let layer = RateLimitLayer::new(escalation.clone(), |req: &String| req.clone())
+            .challenge(|req: String, level| format!("captcha for {req} at level {level}"));
+        let mut svc = layer.layer(Echo);
+
+        assert_eq!(send(&mut svc, "a".to_owned()).await.unwrap(), "a");
+        assert_eq!(
+            send(&mut svc, "a".to_owned()).await.unwrap(),
+            "captcha for a at level 1"
+        );
+        escalation.pass_challenge("a");
+        clock.forward(Duration::from_secs(1));
+        assert_eq!(send(&mut svc, "a".to_owned()).await.unwrap(), "a");
+        // level 2 is a hard ban
+        assert!(matches!(
+            send(&mut svc, "a".to_owned()).await,
+            Err(RateLimitError::Limited(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_layer_endpoints() {
+        let gcra = |rate| LimiterConfig::Gcra { rate, burst: 0 };
+        let config = EndpointConfig {
+            default: gcra(100),
+            overrides: [("POST /upload".to_owned(), gcra(1))].into(),
+        };
+        let layer = RateLimitLayer::endpoints(&config, |req: &(&str, &str)| EndpointKey {
+            method: req.0.to_owned(),
+            route: req.1.to_owned(),
+            tenant: None,
+        })
+        .unwrap();
+        let mut svc = layer.layer(Echo);
+
+        assert!(send(&mut svc, ("POST", "/upload")).await.is_ok());
+        assert!(matches!(
+            send(&mut svc, ("POST", "/upload")).await,
+            Err(RateLimitError::Limited(_))
+        ));
+        assert!(send(&mut svc, ("GET", "/upload")).await.is_ok());
+
+        let invalid = EndpointConfig {
+            default: gcra(0),
+            overrides: Default::default(),
+        };
+        let err = RateLimitLayer::endpoints(&invalid, |_: &()| EndpointKey::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.path(), "default");
+    }
+
+    #[tokio::test]
+    async fn test_layer_rejection_body() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let limiter = Arc::new(
+            KeyedLimiter::builder(Quota::per_second(1))
+                .clock(clock.clone())
+                .build(),
+        );
+        let layer = RateLimitLayer::new(limiter, |req: &String| req.clone());
+        let mut json = layer
+            .clone()
+            .rejection_body(crate::JsonRejection::new())
+            .layer(Echo);
+        let mut custom = layer
+            .rejection_body(|denied: &Denied, req: &String| format!("429 {req}: {denied}"))
+            .layer(Echo);
+
+        assert_eq!(send(&mut json, "a".to_owned()).await.unwrap(), "a");
+        assert_eq!(
+            send(&mut json, "a".to_owned()).await.unwrap(),
+            r#"{"error":"rate_limited","retry_after_ms":1000}"#
+        );
+        assert_eq!(
+            send(&mut custom, "a".to_owned()).await.unwrap(),
+            "429 a: rate limited, retry after 1s"
+        );
+        assert_eq!(send(&mut custom, "b".to_owned()).await.unwrap(), "b");
+    }
+}
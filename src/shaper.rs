@@ -0,0 +1,394 @@
+//! Shaping queued work to a rate, fairly between keys.
+//!
+//! A [`Shaper`] holds items in one queue per key, e.g. per tenant, and releases them at the
+//! rate of its quota. Which queue goes next is decided by deficit round robin: on its turn, a
+//! key with a backlog is credited its quantum of cells and sends items for as long as the
+//! credit covers their cost. So the output interleaves keys in proportion to their quanta,
+//! however long one tenant's backlog is or however heavy its items, instead of draining
+//! whichever backlog came first.
+
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp};
+use crate::gcra::{duration_nanos, Gcra, GcraBuilder};
+use crate::overflow::{Backlogged, Caps};
+use crate::quota::Quota;
+use crate::retry::Deliver;
+use crate::sync::Mutex;
+
+/// Delivers items submitted per key at the rate of a quota, interleaving keys by deficit round
+/// robin.
+///
+/// Every key has a quantum, 1 cell by default: with items costing one cell each, keys take
+/// turns one item at a time, and a key with a quantum of 3 sends three items per turn. Credit a
+/// key did not use on its turn carries over while it has a backlog, so an item costing more
+/// than its quantum goes out once enough turns added up, and is not starved by cheaper items of
+/// other keys. An item's cost is charged to the quota at most up to the quota's capacity, so no
+/// item waits forever.
+///
+/// As with [`RetryQueue`](crate::RetryQueue), items go out as they are submitted while the
+/// quota has room, and otherwise only when [`process_due`](Self::process_due) runs;
+/// [`next_due`](Self::next_due) tells when that is worth doing. With caps set, an item that
+/// would take the queue over one is given back as [`Backlogged`].
+///
+/// # Example
+/// ```
+/// use std::sync::mpsc;
+/// use ratelimit::{Quota, Shaper};
+///
+/// let (tx, rx) = mpsc::channel();
+/// let shaper = Shaper::new(Quota::per_second(1), tx).quantum("bulk", 2);
+/// shaper.submit("bulk", "b1").unwrap();
+/// shaper.submit("bulk", "b2").unwrap();
+/// shaper.submit("web", "w1").unwrap();
+/// assert_eq!(rx.try_recv(), Ok("b1"));
+/// // the rest follow once a second: "b2", then "w1"
+/// assert_eq!(shaper.len(), 2);
+/// ```
+pub struct Shaper<K, T, D, C = SystemClock> {
+    gcra: Gcra<()>,
+    capacity: u64,
+    clock: C,
+    deliver: Mutex<D>,
+    default_quantum: u64,
+    quanta: HashMap<K, u64>,
+    caps: Caps<T>,
+    queues: Mutex<Queues<K, T>>,
+    #[cfg(feature = "tokio")]
+    notify: tokio::sync::Notify,
+}
+
+struct Queues<K, T> {
+    backlogs: HashMap<K, Backlog<T>>,
+    // keys with a backlog, in the order of their turns; the front one is served
+    round: VecDeque<K>,
+    // whether the front key was credited its quantum for the current turn
+    turn_started: bool,
+    len: usize,
+    bytes: usize,
+    // when the quota admits the next item, as of the last denial, in ns
+    due: u64,
+}
+
+struct Backlog<T> {
+    items: VecDeque<Queued<T>>,
+    deficit: u64,
+}
+
+struct Queued<T> {
+    item: T,
+    cost: u64,
+    // in bytes, 0 without a byte cap
+    size: usize,
+    queued_at: Timestamp,
+}
+
+impl<K, T> Queues<K, T>
+where
+    K: Hash + Eq,
+{
+    /// The cost of the item to send next, crediting keys and moving turns on as needed.
+    fn next_cost(&mut self, quantum: impl Fn(&K) -> u64) -> Option<u64> {
+        loop {
+            let key = self.round.front()?;
+            let backlog = self
+                .backlogs
+                .get_mut(key)
+                .expect("keys in the round have a backlog");
+            if !self.turn_started {
+                backlog.deficit = backlog.deficit.saturating_add(quantum(key));
+                self.turn_started = true;
+            }
+            let cost = backlog.items.front().expect("backlogs are not empty").cost;
+            if cost <= backlog.deficit {
+                return Some(cost);
+            }
+            self.turn_started = false;
+            self.round.rotate_left(1);
+        }
+    }
+
+    /// Take the item [`next_cost`](Self::next_cost) was for.
+    fn pop(&mut self) -> T {
+        let key = self.round.front().expect("an item was found");
+        let backlog = self
+            .backlogs
+            .get_mut(key)
+            .expect("keys in the round have a backlog");
+        let queued = backlog.items.pop_front().expect("backlogs are not empty");
+        backlog.deficit -= queued.cost;
+        self.len -= 1;
+        self.bytes -= queued.size;
+        if backlog.items.is_empty() {
+            // an idle key keeps no credit
+            let key = self.round.pop_front().expect("an item was found");
+            self.backlogs.remove(&key);
+            self.turn_started = false;
+        }
+        queued.item
+    }
+}
+
+impl<K, T, D> Shaper<K, T, D, SystemClock> {
+    pub fn new(quota: Quota, deliver: D) -> Self {
+        Shaper {
+            gcra: GcraBuilder::new().quota(quota).build(),
+            capacity: quota.capacity(),
+            clock: SystemClock,
+            deliver: Mutex::new(deliver),
+            default_quantum: 1,
+            quanta: HashMap::new(),
+            caps: Caps::default(),
+            queues: Mutex::new(Queues {
+                backlogs: HashMap::new(),
+                round: VecDeque::new(),
+                turn_started: false,
+                len: 0,
+                bytes: 0,
+                due: 0,
+            }),
+            #[cfg(feature = "tokio")]
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+}
+
+impl<K, T, D, C> Shaper<K, T, D, C> {
+    pub fn clock<NC>(self, clock: NC) -> Shaper<K, T, D, NC> {
+        Shaper {
+            gcra: self.gcra,
+            capacity: self.capacity,
+            clock,
+            deliver: self.deliver,
+            default_quantum: self.default_quantum,
+            quanta: self.quanta,
+            caps: self.caps,
+            queues: self.queues,
+            #[cfg(feature = "tokio")]
+            notify: self.notify,
+        }
+    }
+
+    /// Credit keys without a quantum of their own `cells` per turn, 1 by default.
+    ///
+    /// # Panics
+    /// Panics if `cells` is 0.
+    pub fn default_quantum(mut self, cells: u64) -> Self {
+        assert!(cells > 0, "a quantum must be positive");
+        self.default_quantum = cells;
+        self
+    }
+
+    /// Hold at most `max` items, over all keys.
+    pub fn max_items(mut self, max: usize) -> Self {
+        self.caps.max_items = Some(max);
+        self
+    }
+
+    /// Hold at most `max` bytes of items, over all keys, with the size of an item given by
+    /// `size`.
+    pub fn max_bytes(mut self, max: usize, size: fn(&T) -> usize) -> Self {
+        self.caps.max_bytes = Some(max);
+        self.caps.size = size;
+        self
+    }
+
+    /// Turn items away while the oldest item of their key has waited longer than `max`.
+    pub fn max_age(mut self, max: Duration) -> Self {
+        self.caps.max_age = Some(max);
+        self
+    }
+
+    /// Number of items waiting, over all keys.
+    pub fn len(&self) -> usize {
+        self.queues.lock().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K, T, D, C> Shaper<K, T, D, C>
+where
+    K: Hash + Eq,
+{
+    /// Credit `key` `cells` per turn, e.g. more for a tenant paying for a larger share.
+    ///
+    /// # Panics
+    /// Panics if `cells` is 0.
+    pub fn quantum(mut self, key: K, cells: u64) -> Self {
+        assert!(cells > 0, "a quantum must be positive");
+        self.quanta.insert(key, cells);
+        self
+    }
+}
+
+impl<K, T, D, C> Shaper<K, T, D, C>
+where
+    K: Hash + Eq + Clone,
+    D: Deliver<T>,
+    C: Clock,
+{
+    /// Queue `item` for `key`, worth one cell, and deliver what the quota has room for.
+    pub fn submit(&self, key: K, item: T) -> Result<(), Backlogged<T>> {
+        self.submit_with_cost(key, item, 1)
+    }
+
+    /// Queue `item` for `key`, worth `cost` cells, and deliver what the quota has room for.
+    /// An item that would take the queue over a cap is given back instead.
+    pub fn submit_with_cost(&self, key: K, item: T, cost: u64) -> Result<(), Backlogged<T>> {
+        {
+            let now = now_millis(&self.clock);
+            let mut guard = self.queues.lock();
+            let queues = &mut *guard;
+            let size = self.caps.size(&item);
+            let oldest = queues
+                .backlogs
+                .get(&key)
+                .and_then(|backlog| backlog.items.front())
+                .map(|queued| queued.queued_at);
+            if let Err(overflow) = self.caps.check(queues.len, queues.bytes, size, oldest, now) {
+                return Err(Backlogged { item, overflow });
+            }
+            let backlog = queues
+                .backlogs
+                .entry(key.clone())
+                .or_insert_with(|| Backlog {
+                    items: VecDeque::new(),
+                    deficit: 0,
+                });
+            if backlog.items.is_empty() {
+                queues.round.push_back(key);
+            }
+            backlog.items.push_back(Queued {
+                item,
+                cost: cost.min(self.capacity),
+                size,
+                queued_at: now,
+            });
+            queues.len += 1;
+            queues.bytes += size;
+        }
+        self.process_due();
+        #[cfg(feature = "tokio")]
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// Deliver queued items in turn for as long as the quota admits them. Returns how many
+    /// were delivered.
+    pub fn process_due(&self) -> usize {
+        let mut delivered = 0;
+        loop {
+            let item = {
+                let mut queues = self.queues.lock();
+                let quantum = |key: &K| *self.quanta.get(key).unwrap_or(&self.default_quantum);
+                let Some(cost) = queues.next_cost(quantum) else {
+                    return delivered;
+                };
+                let now = self.clock.now_nanos();
+                if let Err(denied) = self.gcra.check_n_at(now, cost) {
+                    queues.due = now.saturating_add(duration_nanos(denied.retry_after()));
+                    return delivered;
+                }
+                queues.pop()
+            };
+            self.deliver.lock().deliver(item);
+            delivered += 1;
+        }
+    }
+
+    /// Time until the quota admits the next queued item, `None` if the queue is empty.
+    pub fn next_due(&self) -> Option<Duration> {
+        let now = self.clock.now_nanos();
+        let queues = self.queues.lock();
+        if queues.len == 0 {
+            return None;
+        }
+        Some(Duration::from_nanos(queues.due.saturating_sub(now)))
+    }
+
+    /// Process queued items forever, sleeping until the next one is due.
+    #[cfg(feature = "tokio")]
+    pub async fn run(&self) {
+        loop {
+            self.process_due();
+            let notified = self.notify.notified();
+            match self.next_due() {
+                Some(delay) => {
+                    let _ = tokio::time::timeout(delay, notified).await;
+                }
+                None => notified.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::overflow::Overflow;
+
+    use super::*;
+
+    #[test]
+    fn test_shaper_interleaves_keys() {
+        let clock = MockClock::new(1_000_000);
+        let (tx, rx) = std::sync::mpsc::channel();
+        let shaper = Shaper::new(Quota::per_second(1).burst(1), tx)
+            .clock(&clock)
+            .quantum("bulk", 2);
+        for i in 0..6 {
+            shaper.submit("bulk", format!("b{i}")).unwrap();
+        }
+        shaper.submit("web", "w0".to_string()).unwrap();
+        // heavier than the web quantum, sent once two turns added up
+        shaper.submit_with_cost("web", "w1".to_string(), 2).unwrap();
+        shaper.submit("web", "w2".to_string()).unwrap();
+        assert_eq!(shaper.next_due(), Some(Duration::from_secs(1)));
+
+        let mut order = rx.try_iter().collect::<Vec<_>>();
+        while !shaper.is_empty() {
+            clock.forward(shaper.next_due().unwrap());
+            assert!(shaper.process_due() > 0);
+            order.extend(rx.try_iter());
+        }
+        // "b0" and "b1" went out on submission with the burst, then one a second
+        assert_eq!(
+            order,
+            ["b0", "b1", "b2", "b3", "w0", "b4", "b5", "w1", "w2"]
+        );
+    }
+
+    #[test]
+    fn test_shaper_caps() {
+        let clock = MockClock::new(1_000_000);
+        let shaper = Shaper::new(Quota::per_second(1), |_: Vec<u8>| {})
+            .clock(&clock)
+            .max_items(3)
+            .max_bytes(10, Vec::len)
+            .max_age(Duration::from_secs(2));
+        // the first goes out right away, the others wait
+        for _ in 0..3 {
+            shaper.submit("a", vec![0; 4]).unwrap();
+        }
+        let err = shaper.submit("b", vec![0; 4]).unwrap_err();
+        assert_eq!(err.overflow, Overflow::Bytes(10));
+        assert_eq!(err.item.len(), 4);
+        shaper.submit("b", vec![0; 2]).unwrap();
+        assert_eq!(
+            shaper.submit("b", vec![]).unwrap_err().overflow,
+            Overflow::Items(3)
+        );
+
+        // "a" sent one more, and its next item has waited 3s
+        clock.forward(Duration::from_secs(3));
+        shaper.process_due();
+        let err = shaper.submit("a", vec![]).unwrap_err();
+        assert_eq!(err.overflow, Overflow::Age(Duration::from_secs(2)));
+        assert_eq!(err.to_string(), "backlogged: queue is more than 2s behind");
+    }
+}
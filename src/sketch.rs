@@ -0,0 +1,102 @@
+//! Count-min sketch for approximate per-key counting in fixed memory.
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::hash::{BuildHasher, Hash, Hasher};
+
+use crate::clock::Timestamp;
+
+const DEPTH: usize = 4;
+
+/// Counts how often keys were seen, never undercounting. Hash collisions can only make a key look
+/// more frequent than it is. Counts saturate at 255 and are halved every `decay` ms, so keys that
+/// stop showing up are forgotten.
+///
+/// Which keys collide depends on a seed, random unless given, so runs with the same seed and the
+/// same keys count the same.
+pub struct CountMin {
+    seed: u64,
+    width: usize,
+    counters: Vec<u8>,
+    decay: u64,
+    decayed_at: Timestamp,
+}
+
+impl CountMin {
+    pub fn new(width: usize, decay: u64) -> Self {
+        Self::with_seed(width, decay, random_seed())
+    }
+
+    pub fn with_seed(width: usize, decay: u64, seed: u64) -> Self {
+        let width = width.max(1);
+        CountMin {
+            seed,
+            width,
+            counters: vec![0; width * DEPTH],
+            decay,
+            decayed_at: 0,
+        }
+    }
+
+    /// Count one sighting of `key` and return the estimated number of sightings, this one
+    /// included.
+    pub fn increment<Q>(&mut self, key: &Q, now: Timestamp) -> u8
+    where
+        Q: Hash + ?Sized,
+    {
+        if now.saturating_sub(self.decayed_at) >= self.decay {
+            for counter in &mut self.counters {
+                *counter /= 2;
+            }
+            self.decayed_at = now;
+        }
+        let mut estimate = u8::MAX;
+        for row in 0..DEPTH {
+            let mut hasher = DefaultHasher::new();
+            hasher.write_u64(self.seed);
+            (row, key).hash(&mut hasher);
+            let column = hasher.finish() as usize % self.width;
+            let counter = &mut self.counters[row * self.width + column];
+            *counter = counter.saturating_add(1);
+            estimate = estimate.min(*counter);
+        }
+        estimate
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.counters.len()
+    }
+}
+
+/// A seed from the per-thread random keys of the standard library's `RandomState`.
+fn random_seed() -> u64 {
+    RandomState::new().hash_one(0u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_min() {
+        let mut sketch = CountMin::new(1024, 1000);
+        for i in 1..=5 {
+            assert_eq!(sketch.increment("a", 0), i);
+        }
+        assert_eq!(sketch.increment("b", 0), 1);
+        // counts are halved once `decay` has passed
+        assert_eq!(sketch.increment("a", 1000), 3);
+    }
+
+    #[test]
+    fn test_count_min_seed() {
+        // narrow enough that keys collide, so the counts depend on the hashing
+        let counts = |seed| {
+            let mut sketch = CountMin::with_seed(4, 1000, seed);
+            (0..64)
+                .map(|key| sketch.increment(&key, 0))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(counts(7), counts(7));
+        assert_ne!(counts(7), counts(8));
+    }
+}
@@ -0,0 +1,80 @@
+//! Blocking sleeps for the waits in [`Limiter::acquire`](crate::Limiter::acquire).
+
+use std::time::Duration;
+
+/// Block the current thread for `duration`.
+///
+/// With `precise`, on Linux with the `precise-sleep` feature, the deadline is fixed on
+/// `CLOCK_MONOTONIC` before sleeping and the thread sleeps until it with
+/// `clock_nanosleep(TIMER_ABSTIME)`, so neither interruptions nor time spent around the call
+/// push the wakeup back. Elsewhere it is [`std::thread::sleep`].
+#[cfg_attr(
+    not(all(feature = "precise-sleep", target_os = "linux")),
+    allow(unused_variables)
+)]
+pub(crate) fn sleep(duration: Duration, precise: bool) {
+    #[cfg(all(feature = "precise-sleep", target_os = "linux"))]
+    if precise {
+        return sleep_until(deadline(duration));
+    }
+    std::thread::sleep(duration);
+}
+
+#[cfg(all(feature = "precise-sleep", target_os = "linux"))]
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// `duration` from now on `CLOCK_MONOTONIC`.
+#[cfg(all(feature = "precise-sleep", target_os = "linux"))]
+fn deadline(duration: Duration) -> libc::timespec {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // SAFETY: `now` is a valid timespec to write to, and CLOCK_MONOTONIC is always supported
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let nanos = now.tv_nsec as u64 + u64::from(duration.subsec_nanos());
+    let secs = duration.as_secs() + nanos / NANOS_PER_SEC;
+    libc::timespec {
+        tv_sec: now
+            .tv_sec
+            .saturating_add(libc::time_t::try_from(secs).unwrap_or(libc::time_t::MAX)),
+        tv_nsec: (nanos % NANOS_PER_SEC) as libc::c_long,
+    }
+}
+
+#[cfg(all(feature = "precise-sleep", target_os = "linux"))]
+fn sleep_until(deadline: libc::timespec) {
+    loop {
+        // SAFETY: `deadline` is a valid timespec, and the remaining time is not asked for
+        let result = unsafe {
+            libc::clock_nanosleep(
+                libc::CLOCK_MONOTONIC,
+                libc::TIMER_ABSTIME,
+                &deadline,
+                std::ptr::null_mut(),
+            )
+        };
+        // interrupted by a signal, the deadline stays the same
+        if result != libc::EINTR {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn test_sleep() {
+        for precise in [false, true] {
+            let start = Instant::now();
+            for _ in 0..10 {
+                sleep(Duration::from_millis(1), precise);
+            }
+            assert!(start.elapsed() >= Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! Shedding low-priority traffic while a service misses its objectives.
+//!
+//! A rate limit protects a service from more traffic than it was sized for, but not from
+//! overload at a rate it should handle, e.g. while a dependency is slow. [`SloGuard`] watches
+//! service level indicators fed by the application, latency and errors, and while they breach
+//! their targets it denies a growing fraction of low-priority requests. Once the indicators are
+//! comfortably back within target, shedding ramps back down.
+
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp};
+use crate::gcra::{Denied, Policy};
+use crate::observed::Ewma;
+use crate::sync::Mutex;
+
+/// A [`Policy`] shedding low-priority requests while latency or error rate miss their targets.
+///
+/// Every `every`, the guard compares the indicators over the last `window` or so with their
+/// targets: while any breaches its target, the fraction of shed requests goes up by `step`, up
+/// to `max_shed`; once all are below `recover_below` times their targets, it goes down by `step`.
+/// In between it holds, so shedding does not flap around the target. Requests are shed evenly,
+/// not at random: at a fraction of 0.25, every fourth request is denied.
+///
+/// Requests at or above the [`protect_from`](Self::protect_from) priority are never shed;
+/// [`Policy::check`] asks at priority 0.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use ratelimit::{Policy, SloGuard};
+///
+/// let guard = SloGuard::new()
+///     .latency_target(0.99, Duration::from_millis(300))
+///     .error_rate_target(0.01);
+/// if guard.check().is_ok() {
+///     // handle the request, then
+///     guard.record_latency(Duration::from_millis(20));
+///     guard.record_success();
+/// }
+/// ```
+pub struct SloGuard<C = SystemClock> {
+    clock: C,
+    // quantile and the latency it should stay under
+    latency_target: Option<(f64, Duration)>,
+    error_target: Option<f64>,
+    latencies: Ewma,
+    slow: Ewma,
+    outcomes: Ewma,
+    errors: Ewma,
+    step: f64,
+    every: u64, // in ms
+    max_shed: f64,
+    recover_below: f64,
+    protect_from: u32,
+    state: Mutex<ShedState>,
+}
+
+struct ShedState {
+    fraction: f64,
+    // shed requests owed, one is shed whenever it reaches 1
+    owed: f64,
+    adjusted_at: Timestamp,
+}
+
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+
+impl SloGuard<SystemClock> {
+    /// A guard without targets, which never sheds until given one.
+    pub fn new() -> Self {
+        SloGuard {
+            clock: SystemClock,
+            latency_target: None,
+            error_target: None,
+            latencies: Ewma::new(DEFAULT_WINDOW),
+            slow: Ewma::new(DEFAULT_WINDOW),
+            outcomes: Ewma::new(DEFAULT_WINDOW),
+            errors: Ewma::new(DEFAULT_WINDOW),
+            step: 0.1,
+            every: 1000,
+            max_shed: 0.9,
+            recover_below: 0.8,
+            protect_from: 1,
+            state: Mutex::new(ShedState {
+                fraction: 0.0,
+                owed: 0.0,
+                adjusted_at: 0,
+            }),
+        }
+    }
+}
+
+impl Default for SloGuard<SystemClock> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> SloGuard<C> {
+    pub fn clock<NC>(self, clock: NC) -> SloGuard<NC> {
+        SloGuard {
+            clock,
+            latency_target: self.latency_target,
+            error_target: self.error_target,
+            latencies: self.latencies,
+            slow: self.slow,
+            outcomes: self.outcomes,
+            errors: self.errors,
+            step: self.step,
+            every: self.every,
+            max_shed: self.max_shed,
+            recover_below: self.recover_below,
+            protect_from: self.protect_from,
+            state: self.state,
+        }
+    }
+
+    /// Keep the `quantile` of latencies, e.g. 0.99 for p99, at or below `target`.
+    ///
+    /// # Panics
+    /// Panics if `quantile` is not within `0.0..1.0`.
+    pub fn latency_target(mut self, quantile: f64, target: Duration) -> Self {
+        assert!((0.0..1.0).contains(&quantile), "quantile must be in [0, 1)");
+        self.latency_target = Some((quantile, target));
+        self
+    }
+
+    /// Keep the fraction of failed requests at or below `rate`.
+    pub fn error_rate_target(mut self, rate: f64) -> Self {
+        self.error_target = Some(rate);
+        self
+    }
+
+    /// Roughly how far back the indicators look, 10 seconds by default.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.latencies = Ewma::new(window);
+        self.slow = Ewma::new(window);
+        self.outcomes = Ewma::new(window);
+        self.errors = Ewma::new(window);
+        self
+    }
+
+    /// Move the shed fraction by `step` at most once every `every`. 0.1 every second by default.
+    pub fn ramp(mut self, step: f64, every: Duration) -> Self {
+        self.step = step;
+        self.every = every.as_millis().max(1) as u64;
+        self
+    }
+
+    /// Never shed more than `fraction` of the low-priority requests, 0.9 by default, so some
+    /// still get through to show whether the service recovered.
+    pub fn max_shed(mut self, fraction: f64) -> Self {
+        self.max_shed = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Ramp shedding down only once all indicators are below `ratio` times their targets, 0.8
+    /// by default.
+    pub fn recover_below(mut self, ratio: f64) -> Self {
+        self.recover_below = ratio;
+        self
+    }
+
+    /// Never shed requests of `priority` or above, 1 by default.
+    pub fn protect_from(mut self, priority: u32) -> Self {
+        self.protect_from = priority;
+        self
+    }
+}
+
+impl<C> SloGuard<C>
+where
+    C: Clock,
+{
+    pub fn record_latency(&self, latency: Duration) {
+        let now = now_millis(&self.clock);
+        self.latencies.record(now, 1);
+        if self
+            .latency_target
+            .is_some_and(|(_, target)| latency > target)
+        {
+            self.slow.record(now, 1);
+        }
+    }
+
+    pub fn record_success(&self) {
+        self.outcomes.record(now_millis(&self.clock), 1);
+    }
+
+    pub fn record_error(&self) {
+        let now = now_millis(&self.clock);
+        self.outcomes.record(now, 1);
+        self.errors.record(now, 1);
+    }
+
+    /// The fraction of low-priority requests currently shed.
+    pub fn shed_fraction(&self) -> f64 {
+        let now = now_millis(&self.clock);
+        let mut state = self.state.lock();
+        self.adjust(&mut state, now);
+        state.fraction
+    }
+
+    /// Decide on a request of `priority`. A shed request is denied until the next adjustment.
+    pub fn check_with_priority(&self, priority: u32) -> Result<(), Denied> {
+        let now = now_millis(&self.clock);
+        let mut state = self.state.lock();
+        self.adjust(&mut state, now);
+        if priority >= self.protect_from || state.fraction == 0.0 {
+            return Ok(());
+        }
+        state.owed += state.fraction;
+        if state.owed < 1.0 {
+            return Ok(());
+        }
+        state.owed -= 1.0;
+        let next = state.adjusted_at + self.every;
+        Err(Denied::new(Duration::from_millis(next.saturating_sub(now))))
+    }
+
+    /// How far each indicator is from its target, as a ratio, e.g. 1.5 for 50% over. The
+    /// worst of them, 0 if there are no targets or no samples.
+    fn pressure(&self, now: Timestamp) -> f64 {
+        let ratio = |bad: &Ewma, all: &Ewma, target: f64| {
+            let all = all.count(now);
+            if all == 0.0 {
+                return 0.0;
+            }
+            let rate = bad.count(now) / all;
+            if target <= 0.0 {
+                return if rate > 0.0 { f64::INFINITY } else { 0.0 };
+            }
+            rate / target
+        };
+        let latency = self.latency_target.map_or(0.0, |(quantile, _)| {
+            ratio(&self.slow, &self.latencies, 1.0 - quantile)
+        });
+        let errors = self
+            .error_target
+            .map_or(0.0, |target| ratio(&self.errors, &self.outcomes, target));
+        latency.max(errors)
+    }
+
+    fn adjust(&self, state: &mut ShedState, now: Timestamp) {
+        if now < state.adjusted_at.saturating_add(self.every) {
+            return;
+        }
+        state.adjusted_at = now;
+        let pressure = self.pressure(now);
+        if pressure > 1.0 {
+            state.fraction = (state.fraction + self.step).min(self.max_shed);
+        } else if pressure < self.recover_below {
+            state.fraction = (state.fraction - self.step).max(0.0);
+        }
+        if state.fraction == 0.0 {
+            state.owed = 0.0;
+        }
+    }
+}
+
+impl<C> Policy for SloGuard<C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_with_priority(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    #[test]
+    fn test_slo_guard() {
+        let clock = MockClock::new(1_000_000);
+        let guard = SloGuard::new()
+            .clock(&clock)
+            .latency_target(0.9, Duration::from_millis(100))
+            .ramp(0.25, Duration::from_secs(1));
+        let mut shed = 0;
+        for second in 0..4 {
+            clock.forward(Duration::from_secs(1));
+            for _ in 0..100 {
+                shed += guard.check().is_err() as u32;
+                // high priority traffic gets through regardless
+                assert!(guard.check_with_priority(1).is_ok());
+                // half the requests are slow, far over the 10% allowed
+                let latency = if second < 3 { 500 } else { 50 };
+                guard.record_latency(Duration::from_millis(latency));
+                guard.record_latency(Duration::from_millis(50));
+            }
+        }
+        // ramped up by a quarter every second, from the second one on
+        assert_eq!(shed, 25 + 50 + 75);
+        assert_eq!(guard.shed_fraction(), 0.75);
+
+        // ramped back down once latencies are well within the target
+        for _ in 0..60 {
+            clock.forward(Duration::from_secs(1));
+            guard.shed_fraction();
+            for _ in 0..100 {
+                guard.record_latency(Duration::from_millis(50));
+            }
+        }
+        assert_eq!(guard.shed_fraction(), 0.0);
+        assert!(guard.check().is_ok());
+    }
+}
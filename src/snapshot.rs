@@ -0,0 +1,311 @@
+//! JSON dumps of limiter state, for debug endpoints.
+//!
+//! The schema is stable and meant to be polled by scrapers and dashboards:
+//!
+//! ```json
+//! {
+//!   "name": "api",
+//!   "config": {"algorithm": "gcra", "gap_ms": 100, "tolerance_ms": 900,
+//!              "gap_ns": 100000000, "tolerance_ns": 900000000},
+//!   "level": {"limit": 10, "remaining": 4, "reset_after_ms": 600},
+//!   "observed_rate": {"offered": 12.500, "admitted": 10.000},
+//!   "stats": {"allowed": 1200, "denied": 310}
+//! }
+//! ```
+//!
+//! `config` and `level` are written by the policy, see [`Snapshot`]. New fields may be added, but
+//! existing ones keep their meaning. `gap_ms` and `tolerance_ms` are rounded down to whole
+//! milliseconds; `gap_ns` and `tolerance_ns` are exact.
+//!
+//! A [`TokenBucket`] writes its rate per second, its capacity, and how it refills, with the
+//! interval of the `interval` and `aligned_tick` strategies:
+//!
+//! ```json
+//! {"algorithm": "token_bucket", "rate": 100, "capacity": 120, "refill": "interval",
+//!  "interval_ms": 1000}
+//! ```
+//!
+//! A [`Registry`](crate::Registry) lists the dumps of its limiters, in order of name:
+//!
+//! ```json
+//! {"limiters": [{"name": "search", "config": ..., "level": ..., ...}]}
+//! ```
+//!
+//! Keyed limiters report the keys furthest ahead of their schedule, with how long until each is
+//! idle again:
+//!
+//! ```json
+//! {
+//!   "config": {"algorithm": "gcra", "gap_ms": 100, "tolerance_ms": 900,
+//!              "gap_ns": 100000000, "tolerance_ns": 900000000},
+//!   "keys": 1250,
+//!   "approx_bytes": 98304,
+//!   "top_offenders": [{"key": "10.0.0.7", "busy_ms": 5400}]
+//! }
+//! ```
+
+use crate::any::AnyLimiter;
+use crate::clock::{Clock, NANOS_PER_MS};
+use crate::gcra::Gcra;
+use crate::json;
+use crate::keyed::KeyedLimiter;
+use crate::limiter::Limiter;
+use crate::registry::Registry;
+use crate::token_bucket::{Refill, TokenBucket};
+use crate::window::QuotaWindow;
+
+/// Policies that can describe their configuration and current level as JSON objects.
+pub trait Snapshot {
+    fn write_config(&self, out: &mut String);
+
+    fn write_level(&self, out: &mut String);
+}
+
+impl<C> Snapshot for Gcra<C>
+where
+    C: Clock,
+{
+    fn write_config(&self, out: &mut String) {
+        let (gap, tolerance) = self.params();
+        out.push_str(&gcra_config(gap, tolerance));
+    }
+
+    fn write_level(&self, out: &mut String) {
+        out.push_str(&level(&self.quota_window()));
+    }
+}
+
+impl<C> Snapshot for TokenBucket<C>
+where
+    C: Clock,
+{
+    fn write_config(&self, out: &mut String) {
+        let (rate, capacity) = self.params();
+        out.push_str(&format!(
+            r#"{{"algorithm":"token_bucket","rate":{rate},"capacity":{capacity},"refill":"#
+        ));
+        match self.refill_strategy() {
+            Refill::Greedy => out.push_str(r#""greedy"}"#),
+            Refill::Interval(interval) => out.push_str(&format!(
+                r#""interval","interval_ms":{}}}"#,
+                interval.as_millis()
+            )),
+            Refill::AlignedTick(interval) => out.push_str(&format!(
+                r#""aligned_tick","interval_ms":{}}}"#,
+                interval.as_millis()
+            )),
+        }
+    }
+
+    fn write_level(&self, out: &mut String) {
+        out.push_str(&level(&self.quota_window()));
+    }
+}
+
+impl<C> Snapshot for AnyLimiter<C>
+where
+    C: Clock,
+{
+    fn write_config(&self, out: &mut String) {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.write_config(out),
+            AnyLimiter::TokenBucket(bucket) => bucket.write_config(out),
+        }
+    }
+
+    fn write_level(&self, out: &mut String) {
+        match self {
+            AnyLimiter::Gcra(gcra) => gcra.write_level(out),
+            AnyLimiter::TokenBucket(bucket) => bucket.write_level(out),
+        }
+    }
+}
+
+impl<P, C> Limiter<P, C>
+where
+    P: Snapshot,
+    C: Clock,
+{
+    /// Dump the limiter's state, see the [module docs](crate::snapshot) for the schema.
+    pub fn to_json_snapshot(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out);
+        out
+    }
+
+    pub(crate) fn write_json(&self, out: &mut String) {
+        out.push_str(r#"{"name":"#);
+        json::push_str(out, self.name());
+        out.push_str(r#","config":"#);
+        self.policy().write_config(out);
+        out.push_str(r#","level":"#);
+        self.policy().write_level(out);
+        let rate = self.observed_rate();
+        out.push_str(r#","observed_rate":{"offered":"#);
+        json::push_f64(out, rate.offered);
+        out.push_str(r#","admitted":"#);
+        json::push_f64(out, rate.admitted);
+        let stats = self.stats();
+        out.push_str(&format!(
+            r#"}},"stats":{{"allowed":{},"denied":{}}}}}"#,
+            stats.allowed, stats.denied
+        ));
+    }
+}
+
+impl<K, C> KeyedLimiter<K, C>
+where
+    K: std::hash::Hash + Eq + std::fmt::Display,
+    C: Clock,
+{
+    /// Dump the limiter's state with up to `top` offending keys, see the
+    /// [module docs](crate::snapshot) for the schema.
+    pub fn to_json_snapshot(&self, top: usize) -> String {
+        let (gap, tolerance) = self.params();
+        let mut out = format!(
+            r#"{{"config":{},"keys":{},"approx_bytes":{},"top_offenders":["#,
+            gcra_config(gap, tolerance),
+            self.len(),
+            self.approx_bytes()
+        );
+        for (i, (key, busy)) in self.busiest(top).into_iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            out.push_str(r#"{"key":"#);
+            json::push_str(&mut out, &key);
+            out.push_str(&format!(r#","busy_ms":{}}}"#, busy.as_millis()));
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+impl<C> Registry<C>
+where
+    C: Clock,
+{
+    /// Dump the state of every limiter, see the [module docs](crate::snapshot) for the schema.
+    pub fn to_json_snapshot(&self) -> String {
+        let mut out = String::from(r#"{"limiters":["#);
+        for (i, (_, limiter)) in self.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            limiter.write_json(&mut out);
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+/// The `level` object of a policy reporting `window`.
+fn level(window: &QuotaWindow) -> String {
+    format!(
+        r#"{{"limit":{},"remaining":{},"reset_after_ms":{}}}"#,
+        window.limit,
+        window.remaining,
+        window.reset_after.as_millis()
+    )
+}
+
+/// The `config` object of a GCRA, from `gap` and `tolerance` in ns.
+fn gcra_config(gap: u64, tolerance: u64) -> String {
+    format!(
+        r#"{{"algorithm":"gcra","gap_ms":{},"tolerance_ms":{},"gap_ns":{gap},"tolerance_ns":{tolerance}}}"#,
+        gap / NANOS_PER_MS,
+        tolerance / NANOS_PER_MS
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::clock::MockClock;
+    use crate::config::LimiterConfig;
+    use crate::gcra::{LeakyBucket, Policy};
+    use crate::quota::Quota;
+
+    use super::*;
+
+    #[test]
+    fn test_limiter_json_snapshot() {
+        let clock = MockClock::new(1_000_000);
+        let limiter = Limiter::new(LeakyBucket::builder().clock(&clock).rate(10).build())
+            .clock(&clock)
+            .named("a\"pi");
+        for _ in 0..12 {
+            limiter.pass();
+        }
+        clock.forward(Duration::from_millis(400));
+        assert_eq!(
+            limiter.to_json_snapshot(),
+            concat!(
+                r#"{"name":"a\"pi","config":{"algorithm":"gcra","gap_ms":100,"tolerance_ms":900,"gap_ns":100000000,"tolerance_ns":900000000},"#,
+                r#""level":{"limit":10,"remaining":4,"reset_after_ms":600},"#,
+                r#""observed_rate":{"offered":1.153,"admitted":0.961},"#,
+                r#""stats":{"allowed":10,"denied":2}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_registry_json_snapshot() {
+        let clock = MockClock::new(1_000_000);
+        let mut registry = Registry::with_clock(&clock);
+        registry
+            .insert(
+                "upload",
+                &LimiterConfig::TokenBucket {
+                    rate: 2,
+                    burst: 1,
+                    refill_interval_ms: Some(1000),
+                },
+            )
+            .unwrap();
+        registry
+            .insert("search", &LimiterConfig::Gcra { rate: 10, burst: 0 })
+            .unwrap();
+        registry.get("upload").unwrap().pass();
+        assert_eq!(
+            registry.to_json_snapshot(),
+            concat!(
+                r#"{"limiters":[{"name":"search","config":{"algorithm":"gcra","gap_ms":100,"tolerance_ms":900,"gap_ns":100000000,"tolerance_ns":900000000},"#,
+                r#""level":{"limit":10,"remaining":10,"reset_after_ms":0},"#,
+                r#""observed_rate":{"offered":0.000,"admitted":0.000},"#,
+                r#""stats":{"allowed":0,"denied":0}},"#,
+                r#"{"name":"upload","config":{"algorithm":"token_bucket","rate":2,"capacity":3,"refill":"interval","interval_ms":1000},"#,
+                r#""level":{"limit":3,"remaining":2,"reset_after_ms":1000},"#,
+                r#""observed_rate":{"offered":0.100,"admitted":0.100},"#,
+                r#""stats":{"allowed":1,"denied":0}}]}"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_keyed_json_snapshot() {
+        let clock = MockClock::new(1_000_000);
+        let limiter: KeyedLimiter<String, _> = KeyedLimiter::builder(Quota::per_second(10))
+            .clock(&clock)
+            .build();
+        for (key, n) in [("a", 3), ("b", 7), ("c", 1)] {
+            for _ in 0..n {
+                limiter.pass(key);
+            }
+        }
+        let json = limiter.to_json_snapshot(2);
+        assert!(
+            json.starts_with(
+                r#"{"config":{"algorithm":"gcra","gap_ms":100,"tolerance_ms":900,"gap_ns":100000000,"tolerance_ns":900000000},"keys":3,"#
+            ),
+            "{json}"
+        );
+        assert!(
+            json.ends_with(
+                r#""top_offenders":[{"key":"b","busy_ms":700},{"key":"a","busy_ms":300}]}"#
+            ),
+            "{json}"
+        );
+    }
+}
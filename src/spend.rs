@@ -0,0 +1,169 @@
+//! Capping cumulative spend, e.g. compute units of a metered API.
+//!
+//! A rate limit bounds how fast a client may send requests, but with requests of very different
+//! cost, a client can stay within its rate and still run up a bill far beyond what anyone
+//! expected. [`SpendCap`] adds a ceiling on the total cost over a rolling window on top of a
+//! policy for the instantaneous rate.
+
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock};
+use crate::describe::{Describe, Description};
+use crate::gcra::{Denied, Headroom, Policy};
+use crate::observed::Ewma;
+
+/// A policy for the request rate plus a ceiling on the cost spent over a rolling window.
+///
+/// Spend is tracked with a counter that decays exponentially with the window as time constant,
+/// so a client spending steadily settles at its spend per window, and the counter needs no
+/// buckets. The cap is approximate at the edges: a full ceiling spent at once frees up gradually,
+/// about two thirds of it within one window.
+///
+/// As a [`Policy`] every request costs one unit; use [`check_cost`](Self::check_cost) for
+/// requests of known cost.
+///
+/// # Example
+/// ```
+/// use std::time::Duration;
+/// use ratelimit::{LeakyBucket, SpendCap};
+///
+/// // 100 requests per second, but no more than 1M compute units per hour
+/// let cap = SpendCap::new(LeakyBucket::builder().rate(100).build())
+///     .ceiling(1_000_000, Duration::from_secs(3600));
+/// assert!(cap.check_cost(250_000).is_ok());
+/// assert!(cap.check_cost(800_000).is_err());
+/// ```
+pub struct SpendCap<P, C = SystemClock> {
+    policy: P,
+    clock: C,
+    ceiling: u64,
+    spent: Ewma,
+}
+
+impl<P> SpendCap<P, SystemClock> {
+    /// Cap spend of `policy` at 1M per hour until configured with [`ceiling`](Self::ceiling).
+    pub fn new(policy: P) -> Self {
+        SpendCap {
+            policy,
+            clock: SystemClock,
+            ceiling: 1_000_000,
+            spent: Ewma::new(Duration::from_secs(3600)),
+        }
+    }
+}
+
+impl<P, C> SpendCap<P, C> {
+    pub fn clock<NC>(self, clock: NC) -> SpendCap<P, NC> {
+        SpendCap {
+            policy: self.policy,
+            clock,
+            ceiling: self.ceiling,
+            spent: self.spent,
+        }
+    }
+
+    /// Spend no more than `ceiling` per `window`. Resets the spend counted so far.
+    pub fn ceiling(mut self, ceiling: u64, window: Duration) -> Self {
+        self.ceiling = ceiling;
+        self.spent = Ewma::new(window);
+        self
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+}
+
+impl<P, C> SpendCap<P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    /// Decide on one request costing `cost`. Admitted only if both the cost fits under the
+    /// ceiling and the policy admits the request; a request over the ceiling does not charge the
+    /// policy. A request costing more than the whole ceiling is never admitted.
+    pub fn check_cost(&self, cost: u64) -> Result<(), Denied> {
+        let now = now_millis(&self.clock);
+        let ceiling = self.ceiling as f64;
+        if let Err(spent) = self.spent.record_within(now, cost, ceiling) {
+            let wait = self.spent.decays_to(spent, ceiling - cost as f64);
+            return Err(Denied::new(wait));
+        }
+        self.policy.check().inspect_err(|_| {
+            self.spent.unrecord(now, cost);
+        })
+    }
+
+    /// Cost spent over the window, as the decayed counter has it.
+    pub fn spent(&self) -> u64 {
+        self.spent.count(now_millis(&self.clock)).round() as u64
+    }
+}
+
+impl<P, C> Policy for SpendCap<P, C>
+where
+    P: Policy,
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_cost(1)
+    }
+
+    fn refund(&self) {
+        self.policy.refund();
+        self.spent.unrecord(now_millis(&self.clock), 1);
+    }
+
+    /// Headroom of the policy, with `remaining` capped by what is left under the ceiling.
+    fn headroom(&self) -> Option<Headroom> {
+        let headroom = self.policy.headroom()?;
+        let left = self.ceiling.saturating_sub(self.spent());
+        Some(Headroom {
+            remaining: headroom.remaining.min(left),
+            ..headroom
+        })
+    }
+}
+
+impl<P, C> Describe for SpendCap<P, C>
+where
+    P: Describe,
+{
+    fn describe(&self) -> Description {
+        Description::new("spend_cap")
+            .setting("ceiling", self.ceiling)
+            .setting("window", format_args!("{:?}", self.spent.window()))
+            .child(self.policy.describe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::LeakyBucket;
+
+    use super::*;
+
+    #[test]
+    fn test_spend_cap() {
+        let clock = MockClock::new(1_000_000);
+        let cap = SpendCap::new(LeakyBucket::builder().clock(&clock).rate(2).build())
+            .ceiling(1000, Duration::from_secs(60))
+            .clock(&clock);
+
+        assert_eq!(cap.check_cost(600), Ok(()));
+        // over the ceiling: denied without using up the rate
+        let denied = cap.check_cost(500).unwrap_err();
+        // 600 decays to 500 after 60s * ln(1.2)
+        assert_eq!(denied.retry_after(), Duration::from_millis(10940));
+        assert_eq!(cap.check_cost(400), Ok(()));
+        assert_eq!(cap.spent(), 1000);
+        // within the ceiling, but over the rate
+        assert!(cap.check_cost(0).is_err());
+        assert_eq!(cap.spent(), 1000);
+
+        clock.forward(denied.retry_after());
+        assert_eq!(cap.check_cost(100), Ok(()));
+        assert!(cap.check_cost(2000).unwrap_err().retry_after() == Duration::MAX);
+    }
+}
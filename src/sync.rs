@@ -0,0 +1,87 @@
+//! Synchronization primitives used by the limiters.
+//!
+//! Every piece of state shared between threads goes through this module, so the whole crate
+//! can be switched over to model-checked primitives in a single place: built with
+//! `RUSTFLAGS="--cfg loom"`, the atomics and locks are those of [loom](https://docs.rs/loom), and
+//! `cargo test --lib loom` runs the model tests.
+
+#[cfg(not(loom))]
+pub(crate) use parking_lot::Mutex;
+#[cfg(all(not(loom), not(feature = "lock-free-hot-keys")))]
+use parking_lot::RwLock;
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::thread::yield_now;
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now;
+
+#[cfg(feature = "lock-free-hot-keys")]
+pub(crate) use arc_swap::ArcSwap;
+
+/// An `Arc` swapped under a read-write lock, in place of the lock-free `ArcSwap` of the
+/// `lock-free-hot-keys` feature.
+#[cfg(not(feature = "lock-free-hot-keys"))]
+pub(crate) struct ArcSwap<T>(RwLock<std::sync::Arc<T>>);
+
+#[cfg(not(feature = "lock-free-hot-keys"))]
+impl<T> ArcSwap<T> {
+    pub(crate) fn from_pointee(value: T) -> Self {
+        ArcSwap(RwLock::new(std::sync::Arc::new(value)))
+    }
+
+    pub(crate) fn load(&self) -> std::sync::Arc<T> {
+        self.0.read().clone()
+    }
+
+    pub(crate) fn swap(&self, new: std::sync::Arc<T>) -> std::sync::Arc<T> {
+        std::mem::replace(&mut *self.0.write(), new)
+    }
+
+    pub(crate) fn store(&self, new: std::sync::Arc<T>) {
+        *self.0.write() = new;
+    }
+}
+
+/// loom's mutex with the API of parking_lot's, which never poisons.
+#[cfg(loom)]
+#[derive(Debug, Default)]
+pub(crate) struct Mutex<T>(loom::sync::Mutex<T>);
+
+#[cfg(loom)]
+impl<T> Mutex<T> {
+    pub(crate) fn new(value: T) -> Self {
+        Mutex(loom::sync::Mutex::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> loom::sync::MutexGuard<'_, T> {
+        self.0.lock().unwrap()
+    }
+
+    pub(crate) fn try_lock(&self) -> Option<loom::sync::MutexGuard<'_, T>> {
+        self.0.try_lock().ok()
+    }
+}
+
+/// loom's read-write lock with the API of parking_lot's.
+#[cfg(all(loom, not(feature = "lock-free-hot-keys")))]
+#[derive(Debug, Default)]
+struct RwLock<T>(loom::sync::RwLock<T>);
+
+#[cfg(all(loom, not(feature = "lock-free-hot-keys")))]
+impl<T> RwLock<T> {
+    fn new(value: T) -> Self {
+        RwLock(loom::sync::RwLock::new(value))
+    }
+
+    fn read(&self) -> loom::sync::RwLockReadGuard<'_, T> {
+        self.0.read().unwrap()
+    }
+
+    fn write(&self) -> loom::sync::RwLockWriteGuard<'_, T> {
+        self.0.write().unwrap()
+    }
+}
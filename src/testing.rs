@@ -0,0 +1,972 @@
+//! Helpers for testing rate limit policies.
+//!
+//! [`Reference`] is a deliberately slow and simple GCRA, written straight from the continuous-state
+//! leaky bucket definition. [`differential`] runs two policies sharing one [`MockClock`] through the
+//! same sequence of [`Op`]s and reports the first step where their decisions differ, which makes it
+//! easy to check an optimized or custom policy against the reference. [`SingleKey`] puts one key
+//! of a [`KeyedLimiter`] behind the [`Policy`] interface, so keyed state can be checked the same way.
+//!
+//! # Example
+//! ```
+//! use std::time::Duration;
+//!
+//! use ratelimit::testing::{differential, Op, Reference};
+//! use ratelimit::{MockClock, VirtualScheduling};
+//!
+//! let clock = MockClock::new(0);
+//! let reference = Reference::new(&clock, Duration::from_millis(10), Duration::from_millis(30));
+//! let policy = VirtualScheduling::builder()
+//!     .clock(&clock)
+//!     .gap(Duration::from_millis(10))
+//!     .tolerance(Duration::from_millis(30))
+//!     .build();
+//!
+//! let ops = Op::decode(b"\x00\x00\x00\x00\x00\x8a\x00\x00");
+//! assert_eq!(differential(&reference, &policy, &clock, &ops), Ok(()));
+//! ```
+//!
+//! [`Trace`]s replay recorded decisions against a configured policy, and with
+//! [`run_remote`](Trace::run_remote) against the wrappers of remote policies around it, each
+//! asking the policy through an [`InProcess`] backend.
+//!
+//! For conformance tests of a configured policy, [`assert_admits!`](crate::assert_admits) checks
+//! decisions against a compact [`Schedule`] pattern and [`assert_rate!`](crate::assert_rate) checks
+//! the number of requests admitted over a stretch of time.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, MockClock, Timestamp};
+use crate::config::LimiterConfig;
+use crate::gcra::{Denied, Policy};
+use crate::keyed::KeyedLimiter;
+use crate::optimistic::Optimistic;
+use crate::prefetch::Prefetch;
+use crate::quota::Quota;
+use crate::remote::{Backend, Cached, Degrade, FailureMode, Fallback};
+use crate::sync::{AtomicBool, Mutex, Ordering};
+
+/// Reference GCRA, parameterized by emission interval (`gap`) and `tolerance` like
+/// [`VirtualScheduling`](crate::VirtualScheduling).
+///
+/// State is kept as bucket content and last conformance time in `i128`, so no step can overflow
+/// or saturate. It is not meant to be fast.
+pub struct Reference<C> {
+    clock: C,
+    gap: i128,
+    tolerance: i128,
+    state: Mutex<ReferenceState>,
+}
+
+struct ReferenceState {
+    // bucket content, in ns
+    x: i128,
+    // last conformance time
+    lct: i128,
+}
+
+impl<C> Reference<C> {
+    pub fn new(clock: C, gap: Duration, tolerance: Duration) -> Self {
+        Reference {
+            clock,
+            gap: gap.as_nanos() as i128,
+            tolerance: tolerance.as_nanos() as i128,
+            state: Mutex::new(ReferenceState { x: 0, lct: 0 }),
+        }
+    }
+}
+
+impl<C> Policy for Reference<C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        if n == 0 {
+            return Ok(());
+        }
+        let now = self.clock.now_nanos() as i128;
+        let mut state = self.state.lock();
+        // drain the bucket by the time elapsed since the last conforming cell
+        let x = std::cmp::max(0, state.x - (now - state.lct));
+        // the request conforms if its last cell would
+        let last = x + (n as i128 - 1) * self.gap;
+        if last > self.tolerance {
+            let wait = u64::try_from(last - self.tolerance).unwrap_or(u64::MAX);
+            return Err(Denied::new(Duration::from_nanos(wait)));
+        }
+        state.x = x + n as i128 * self.gap;
+        state.lct = now;
+        Ok(())
+    }
+}
+
+/// One key of a [`KeyedLimiter`] as a [`Policy`], to run keyed state through [`differential`].
+pub struct SingleKey<'a, K, C> {
+    limiter: &'a KeyedLimiter<K, C>,
+    key: K,
+}
+
+impl<'a, K, C> SingleKey<'a, K, C> {
+    pub fn new(limiter: &'a KeyedLimiter<K, C>, key: K) -> Self {
+        SingleKey { limiter, key }
+    }
+}
+
+impl<K, C> Policy for SingleKey<'_, K, C>
+where
+    K: Clone + std::hash::Hash + Eq,
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.limiter.check(&self.key)
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        self.limiter.check_n(&self.key, n)
+    }
+}
+
+/// A [`Backend`] deciding with a policy in the same process, so the wrappers of remote policies
+/// can be tested without a store. It can be [taken down](Self::set_down) to exercise their
+/// failure paths.
+pub struct InProcess<P> {
+    policy: P,
+    down: AtomicBool,
+}
+
+/// The error of an [`InProcess`] backend that is down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Down;
+
+impl<P> InProcess<P> {
+    pub fn new(policy: P) -> Self {
+        InProcess {
+            policy,
+            down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn policy(&self) -> &P {
+        &self.policy
+    }
+
+    /// Fail every request with [`Down`] until set back up.
+    pub fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+    }
+}
+
+impl<P> Backend for InProcess<P>
+where
+    P: Policy,
+{
+    type Error = Down;
+
+    fn try_check(&self) -> Result<Result<(), Denied>, Down> {
+        self.try_check_n(1)
+    }
+
+    fn try_check_n(&self, n: u64) -> Result<Result<(), Denied>, Down> {
+        if self.down.load(Ordering::Relaxed) {
+            return Err(Down);
+        }
+        Ok(self.policy.check_n(n))
+    }
+}
+
+/// One step of a [`differential`] run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Ask both policies for a decision.
+    Pass,
+    /// Ask both policies for a decision on a request worth this many cells.
+    PassN(u64),
+    /// Move the shared clock forward.
+    Advance(Duration),
+}
+
+impl Op {
+    /// Decode arbitrary bytes into a sequence of ops, for use as a fuzz target input.
+    ///
+    /// A byte below `0x40` is a [`Op::Pass`], one below `0x80` is a [`Op::PassN`] of its lower six
+    /// bits, and anything else advances the clock by the lower seven bits in milliseconds.
+    pub fn decode(data: &[u8]) -> Vec<Op> {
+        data.iter()
+            .map(|&b| match b {
+                0x00..=0x3f => Op::Pass,
+                0x40..=0x7f => Op::PassN((b & 0x3f) as u64),
+                _ => Op::Advance(Duration::from_millis((b & 0x7f) as u64)),
+            })
+            .collect()
+    }
+}
+
+/// The first step at which two policies disagreed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub step: usize,
+    pub op: Op,
+    pub now: Timestamp,
+    pub left: bool,
+    pub right: bool,
+}
+
+/// Run `left` and `right` through `ops`, moving `clock` on [`Op::Advance`]. [`Op::PassN`] goes
+/// through [`Policy::check_n`], so policies that cannot weigh requests deny it.
+///
+/// Both policies must read time from `clock`.
+pub fn differential<L, R>(
+    left: &L,
+    right: &R,
+    clock: &MockClock,
+    ops: &[Op],
+) -> Result<(), Divergence>
+where
+    L: Policy + ?Sized,
+    R: Policy + ?Sized,
+{
+    for (step, &op) in ops.iter().enumerate() {
+        let (l, r) = match op {
+            Op::Pass => (left.check().is_ok(), right.check().is_ok()),
+            Op::PassN(n) => (left.check_n(n).is_ok(), right.check_n(n).is_ok()),
+            Op::Advance(dur) => {
+                clock.forward(dur);
+                continue;
+            }
+        };
+        if l != r {
+            return Err(Divergence {
+                step,
+                op,
+                now: now_millis(clock),
+                left: l,
+                right: r,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// A parsed arrival schedule, as used by [`assert_admits!`](crate::assert_admits).
+///
+/// A pattern is a whitespace separated list of tokens:
+///
+/// - `+` expects the policy to admit a request, `-` expects it to deny one. Either may be followed
+///   by a count up to [`MAX_COUNT`](Self::MAX_COUNT), so `+10` is ten admitted requests in a row.
+/// - `>` followed by a duration moves the clock forward, e.g. `>100ms` or `>2s`.
+///
+/// Tokens may also be written without spaces in between, like `++->1s+`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    steps: Vec<Step>,
+}
+
+/// One step of a [`Schedule`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Ask the policy for a decision and expect it to be this one.
+    Expect(bool),
+    /// Move the clock forward.
+    Advance(Duration),
+}
+
+/// A pattern that could not be parsed, with the byte offset of the offending token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// The first step of a [`Schedule`] where the policy did not decide as expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// Index of the request within the schedule, counting only `+` and `-`.
+    pub request: usize,
+    pub now: Timestamp,
+    pub expected: bool,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verdict = |admit| if admit { "admitted" } else { "denied" };
+        write!(
+            f,
+            "request #{} at {}ms: expected {}, was {}",
+            self.request,
+            self.now,
+            verdict(self.expected),
+            verdict(!self.expected)
+        )
+    }
+}
+
+impl Schedule {
+    /// The largest count after `+` or `-`, so a typo cannot build a huge schedule.
+    pub const MAX_COUNT: u64 = 1_000_000;
+
+    pub fn parse(pattern: &str) -> Result<Self, ParseError> {
+        let bytes = pattern.as_bytes();
+        let mut steps = Vec::new();
+        let mut i = 0;
+        // `None` without digits
+        let number = |i: &mut usize, offset| {
+            let start = *i;
+            while *i < bytes.len() && bytes[*i].is_ascii_digit() {
+                *i += 1;
+            }
+            if start == *i {
+                return Ok(None);
+            }
+            pattern[start..*i]
+                .parse::<u64>()
+                .map(Some)
+                .map_err(|_| ParseError {
+                    offset,
+                    message: "number out of range",
+                })
+        };
+        while i < bytes.len() {
+            let offset = i;
+            match bytes[i] {
+                b if b.is_ascii_whitespace() => i += 1,
+                b @ (b'+' | b'-') => {
+                    i += 1;
+                    let count = number(&mut i, offset)?.unwrap_or(1);
+                    if count > Self::MAX_COUNT {
+                        return Err(ParseError {
+                            offset,
+                            message: "count over Schedule::MAX_COUNT",
+                        });
+                    }
+                    steps.extend((0..count).map(|_| Step::Expect(b == b'+')));
+                }
+                b'>' => {
+                    i += 1;
+                    let n = number(&mut i, offset)?.ok_or(ParseError {
+                        offset,
+                        message: "expected a duration after `>`",
+                    })?;
+                    let dur = if pattern[i..].starts_with("ms") {
+                        i += 2;
+                        Duration::from_millis(n)
+                    } else if pattern[i..].starts_with('s') {
+                        i += 1;
+                        Duration::from_secs(n)
+                    } else {
+                        return Err(ParseError {
+                            offset,
+                            message: "expected `ms` or `s` after the duration",
+                        });
+                    };
+                    steps.push(Step::Advance(dur));
+                }
+                _ => {
+                    return Err(ParseError {
+                        offset,
+                        message: "unexpected character",
+                    })
+                }
+            }
+        }
+        Ok(Schedule { steps })
+    }
+
+    pub fn steps(&self) -> &[Step] {
+        &self.steps
+    }
+
+    /// Drive `policy` through the schedule, moving `clock` on every [`Step::Advance`].
+    pub fn run<P>(&self, policy: &P, clock: &MockClock) -> Result<(), Mismatch>
+    where
+        P: Policy + ?Sized,
+    {
+        let mut request = 0;
+        for step in &self.steps {
+            match *step {
+                Step::Expect(expected) => {
+                    if policy.check().is_ok() != expected {
+                        return Err(Mismatch {
+                            request,
+                            now: now_millis(clock),
+                            expected,
+                        });
+                    }
+                    request += 1;
+                }
+                Step::Advance(dur) => clock.forward(dur),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A conformance trace: arrivals with their cost and the decision every implementation of the
+/// configured algorithm must reach.
+///
+/// The text format has the configuration on its first line, as `key=value` pairs naming the
+/// fields of a [`LimiterConfig`], then one arrival per line: the clock time in ms, the cost and
+/// `allow` or `deny`. Lines starting with `#` are comments.
+///
+/// ```text
+/// algorithm=gcra rate=2 burst=1
+/// 0 1 allow
+/// 0 3 deny
+/// ```
+///
+/// The traces every policy of this crate is checked against are [`reference_traces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trace {
+    config: LimiterConfig,
+    arrivals: Vec<Arrival>,
+}
+
+/// One line of a [`Trace`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Arrival {
+    pub at: Timestamp,
+    pub cost: u64,
+    pub admit: bool,
+}
+
+impl Trace {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut config = None;
+        let mut arrivals = Vec::new();
+        let mut next = 0;
+        for line in text.lines() {
+            let offset = next;
+            next += line.len() + 1;
+            let error = |message| ParseError { offset, message };
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            if config.is_none() {
+                config = Some(parse_config(trimmed).map_err(error)?);
+                continue;
+            }
+            let mut fields = trimmed.split_whitespace();
+            let mut number = || fields.next().and_then(|f| f.parse::<u64>().ok());
+            let at = number().ok_or_else(|| error("expected a time in ms"))?;
+            let cost = number().ok_or_else(|| error("expected a cost"))?;
+            let admit = match fields.next() {
+                Some("allow") => true,
+                Some("deny") => false,
+                _ => return Err(error("expected `allow` or `deny`")),
+            };
+            if arrivals.last().is_some_and(|last: &Arrival| last.at > at) {
+                return Err(error("arrivals must be in time order"));
+            }
+            arrivals.push(Arrival { at, cost, admit });
+        }
+        let config = config.ok_or(ParseError {
+            offset: 0,
+            message: "missing configuration line",
+        })?;
+        Ok(Trace { config, arrivals })
+    }
+
+    pub fn config(&self) -> &LimiterConfig {
+        &self.config
+    }
+
+    pub fn arrivals(&self) -> &[Arrival] {
+        &self.arrivals
+    }
+
+    /// Replay the trace, moving `clock` to the time of each arrival and asking `check` for a
+    /// decision on its cost. The clock must not be past the first arrival.
+    pub fn run(
+        &self,
+        clock: &MockClock,
+        mut check: impl FnMut(u64) -> bool,
+    ) -> Result<(), Mismatch> {
+        for (request, arrival) in self.arrivals.iter().enumerate() {
+            clock.forward(Duration::from_millis(
+                arrival.at.saturating_sub(now_millis(clock)),
+            ));
+            if check(arrival.cost) != arrival.admit {
+                return Err(Mismatch {
+                    request,
+                    now: now_millis(clock),
+                    expected: arrival.admit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay the trace against the policy built from its configuration.
+    pub fn run_config(&self) -> Result<(), Mismatch> {
+        let clock = MockClock::new(0);
+        let policy = self
+            .config
+            .build_with_clock(&clock)
+            .expect("trace configurations are valid");
+        self.run(&clock, |cost| policy.check_n(cost).is_ok())
+    }
+
+    /// Replay the trace against each wrapper of a remote policy around an [`InProcess`] backend
+    /// deciding with the policy built from its configuration: [`Degrade`], [`Cached`] and
+    /// [`Prefetch`] with the backend up, [`Fallback`] with it up and with it down in front of the
+    /// configured policy, [`Optimistic`] with the configured policy both local and behind the
+    /// backend, and for a `gcra` trace, `Degrade` with the backend down and
+    /// [`FailureMode::Local`] of the same quota. The first disagreement is returned with the
+    /// name of the wrapper.
+    ///
+    /// The background work of `Prefetch` and `Optimistic` is waited for after every arrival.
+    /// `Cached` only caches denials here and `Prefetch` fetches a single token at a time: tokens
+    /// prepaid or prefetched are charged before they are used, which may change the decisions
+    /// near the limit. Even a single token prefetched across a window boundary can, so a trace
+    /// of another configuration may fail for `Prefetch` alone without either being wrong.
+    pub fn run_remote(&self) -> Result<(), (&'static str, Mismatch)> {
+        let policy = |clock: &Arc<MockClock>| {
+            self.config
+                .build_with_clock(clock.clone())
+                .expect("trace configurations are valid")
+        };
+        let backend = |clock: &Arc<MockClock>| InProcess::new(policy(clock));
+        let down = |clock: &Arc<MockClock>| {
+            let backend = backend(clock);
+            backend.set_down(true);
+            backend
+        };
+
+        let clock = Arc::new(MockClock::new(0));
+        let degrade = Degrade::new(backend(&clock), FailureMode::Closed).clock(clock.clone());
+        self.replay("Degrade", &clock, &degrade, |_| {})?;
+        if let LimiterConfig::Gcra { rate, burst } = self.config {
+            let clock = Arc::new(MockClock::new(0));
+            let quota = Quota::per_second(rate).burst(burst);
+            let degrade =
+                Degrade::new(down(&clock), FailureMode::Local(quota)).clock(clock.clone());
+            self.replay("Degrade down", &clock, &degrade, |_| {})?;
+        }
+
+        let clock = Arc::new(MockClock::new(0));
+        let fallback = Fallback::new(backend(&clock), policy(&clock));
+        self.replay("Fallback", &clock, &fallback, |_| {})?;
+        let clock = Arc::new(MockClock::new(0));
+        let fallback = Fallback::new(down(&clock), policy(&clock));
+        self.replay("Fallback down", &clock, &fallback, |_| {})?;
+
+        let clock = Arc::new(MockClock::new(0));
+        let cached = Cached::new(backend(&clock)).clock(clock.clone());
+        let cached = Degrade::new(cached, FailureMode::Closed).clock(clock.clone());
+        self.replay("Cached", &clock, &cached, |_| {})?;
+
+        let clock = Arc::new(MockClock::new(0));
+        let prefetch = Degrade::new(Prefetch::new(backend(&clock), 1), FailureMode::Closed)
+            .clock(clock.clone());
+        self.replay("Prefetch", &clock, &prefetch, |prefetch| {
+            while prefetch.backend().is_fetching() {
+                std::thread::yield_now();
+            }
+        })?;
+
+        let clock = Arc::new(MockClock::new(0));
+        let optimistic = Optimistic::new(policy(&clock), backend(&clock));
+        self.replay("Optimistic", &clock, &optimistic, |optimistic| {
+            while optimistic.pending() > 0 {
+                std::thread::yield_now();
+            }
+        })
+    }
+
+    fn replay<P: Policy>(
+        &self,
+        name: &'static str,
+        clock: &MockClock,
+        policy: &P,
+        settle: impl Fn(&P),
+    ) -> Result<(), (&'static str, Mismatch)> {
+        self.run(clock, |cost| {
+            let admitted = policy.check_n(cost).is_ok();
+            settle(policy);
+            admitted
+        })
+        .map_err(|mismatch| (name, mismatch))
+    }
+}
+
+fn parse_config(line: &str) -> Result<LimiterConfig, &'static str> {
+    let mut fields = HashMap::new();
+    for pair in line.split_whitespace() {
+        let (key, value) = pair.split_once('=').ok_or("expected `key=value`")?;
+        fields.insert(key, value);
+    }
+    let number = |key| -> Result<Option<u64>, &'static str> {
+        fields
+            .get(key)
+            .map(|value| value.parse().map_err(|_| "expected a number"))
+            .transpose()
+    };
+    let required = |key| number(key)?.ok_or("missing a field of the algorithm");
+    match fields.get("algorithm").copied() {
+        Some("gcra") => Ok(LimiterConfig::Gcra {
+            rate: required("rate")?,
+            burst: number("burst")?.unwrap_or(0),
+        }),
+        Some("token_bucket") => Ok(LimiterConfig::TokenBucket {
+            rate: required("rate")?,
+            burst: number("burst")?.unwrap_or(0),
+            refill_interval_ms: number("refill_interval_ms")?,
+        }),
+        Some("fixed_window") => Ok(LimiterConfig::FixedWindow {
+            limit: required("limit")?,
+            window_ms: required("window_ms")?,
+        }),
+        _ => Err("expected `algorithm=` gcra, token_bucket or fixed_window"),
+    }
+}
+
+/// The conformance traces shipped with the crate, by name: `gcra`, `token_bucket` and
+/// `fixed_window`.
+pub fn reference_traces() -> Vec<(&'static str, Trace)> {
+    [
+        ("gcra", include_str!("../conformance/gcra.trace")),
+        (
+            "token_bucket",
+            include_str!("../conformance/token_bucket.trace"),
+        ),
+        (
+            "fixed_window",
+            include_str!("../conformance/fixed_window.trace"),
+        ),
+    ]
+    .into_iter()
+    .map(|(name, text)| (name, Trace::parse(text).expect("reference traces parse")))
+    .collect()
+}
+
+/// Offer `policy` one request every `every` for `over`, and count how many were admitted.
+///
+/// The first request is offered at the current time, the clock ends up `over` later.
+pub fn admitted<P>(policy: &P, clock: &MockClock, over: Duration, every: Duration) -> u64
+where
+    P: Policy + ?Sized,
+{
+    assert!(!every.is_zero(), "`every` must not be zero");
+    let mut count = 0;
+    let mut elapsed = Duration::ZERO;
+    while elapsed < over {
+        count += policy.check().is_ok() as u64;
+        clock.forward(every);
+        elapsed += every;
+    }
+    count
+}
+
+/// Assert that a policy decides as described by a [`Schedule`] pattern.
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ratelimit::{assert_admits, LeakyBucket, MockClock};
+///
+/// let clock = MockClock::new(0);
+/// let policy = LeakyBucket::builder().clock(&clock).rate(2).build();
+/// assert_admits!(policy, clock, "++- >500ms +- >1s ++-");
+/// ```
+#[macro_export]
+macro_rules! assert_admits {
+    ($policy:expr, $clock:expr, $pattern:expr $(,)?) => {
+        match $crate::testing::Schedule::parse($pattern) {
+            Ok(schedule) => {
+                if let Err(mismatch) = schedule.run(&$policy, &$clock) {
+                    panic!("assertion failed for pattern {:?}: {}", $pattern, mismatch);
+                }
+            }
+            Err(err) => panic!("invalid pattern {:?}: {}", $pattern, err),
+        }
+    };
+}
+
+/// Assert that a policy admits exactly `expected` requests over `over` when offered one every
+/// millisecond. See [`admitted`](crate::testing::admitted).
+///
+/// ```
+/// use std::time::Duration;
+///
+/// use ratelimit::{assert_rate, LeakyBucket, MockClock};
+///
+/// let clock = MockClock::new(0);
+/// let policy = LeakyBucket::builder().clock(&clock).rate(10).build();
+/// // the first 10 go through at once, then one every 100ms
+/// assert_rate!(policy, clock, 29, Duration::from_secs(2));
+/// ```
+#[macro_export]
+macro_rules! assert_rate {
+    ($policy:expr, $clock:expr, $expected:expr, $over:expr $(,)?) => {
+        let admitted = $crate::testing::admitted(
+            &$policy,
+            &$clock,
+            $over,
+            ::std::time::Duration::from_millis(1),
+        );
+        assert_eq!(
+            admitted, $expected,
+            "admitted {} requests over {:?}, expected {}",
+            admitted, $over, $expected
+        );
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::gcra::VirtualScheduling;
+    use crate::quota::Quota;
+
+    use super::*;
+
+    // xorshift64, good enough to generate op sequences
+    fn bytes(mut seed: u64, len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reference_matches_virtual_scheduling() {
+        for seed in 1..500u64 {
+            let data = bytes(seed, 512);
+            let gap = Duration::from_millis(data[0] as u64 % 20);
+            let tolerance = Duration::from_millis(data[1] as u64 % 100);
+            let clock = MockClock::new(seed * 1000);
+            let reference = Reference::new(&clock, gap, tolerance);
+            let policy = VirtualScheduling::builder()
+                .clock(&clock)
+                .gap(gap)
+                .tolerance(tolerance)
+                .build();
+            assert_eq!(
+                differential(&reference, &policy, &clock, &Op::decode(&data[2..])),
+                Ok(()),
+                "seed {seed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reference_matches_keyed() {
+        for seed in 1..200u64 {
+            let data = bytes(seed, 512);
+            // keyed limiters take a window, which is a tolerance of whole gaps
+            let gap = data[0] as u64 % 20 + 1;
+            let limit = data[1] as u64 % 10 + 1;
+            let clock = MockClock::new(seed * 1000);
+            let reference = Reference::new(
+                &clock,
+                Duration::from_millis(gap),
+                Duration::from_millis(gap * (limit - 1)),
+            );
+            let keyed: KeyedLimiter<u8, _> = KeyedLimiter::builder(Quota::per_second(1))
+                .window(limit, Duration::from_millis(gap * limit))
+                .clock(&clock)
+                .build();
+            assert_eq!(
+                differential(
+                    &reference,
+                    &SingleKey::new(&keyed, 0),
+                    &clock,
+                    &Op::decode(&data[2..])
+                ),
+                Ok(()),
+                "seed {seed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_differential_check_n() {
+        let clock = MockClock::new(0);
+        let reference =
+            Reference::new(&clock, Duration::from_millis(10), Duration::from_millis(30));
+        let policy = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(10))
+            .tolerance(Duration::from_millis(30))
+            .build();
+        let ops = [
+            Op::PassN(3),
+            Op::PassN(2),
+            Op::PassN(0),
+            Op::Advance(Duration::from_millis(10)),
+            Op::PassN(2),
+        ];
+        assert_eq!(differential(&reference, &policy, &clock, &ops), Ok(()));
+        assert!(reference.check_n(2).is_err());
+        // a policy that cannot weigh requests diverges on more than one cell
+        struct Single<'a>(&'a Reference<&'a MockClock>);
+        impl Policy for Single<'_> {
+            fn check(&self) -> Result<(), Denied> {
+                self.0.check()
+            }
+        }
+        let fresh = Reference::new(&clock, Duration::from_millis(10), Duration::from_millis(30));
+        let other = Reference::new(&clock, Duration::from_millis(10), Duration::from_millis(30));
+        assert_eq!(
+            differential(&fresh, &Single(&other), &clock, &[Op::Pass, Op::PassN(2)]),
+            Err(Divergence {
+                step: 1,
+                op: Op::PassN(2),
+                now: 10,
+                left: true,
+                right: false,
+            })
+        );
+    }
+
+    #[test]
+    fn test_differential_reports_divergence() {
+        let clock = MockClock::new(0);
+        let reference = Reference::new(&clock, Duration::from_millis(10), Duration::ZERO);
+        let policy = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(5))
+            .build();
+        let ops = [Op::Pass, Op::Advance(Duration::from_millis(5)), Op::Pass];
+        assert_eq!(
+            differential(&reference, &policy, &clock, &ops),
+            Err(Divergence {
+                step: 2,
+                op: Op::Pass,
+                now: 5,
+                left: false,
+                right: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            Op::decode(&[0x00, 0x85, 0x3f, 0x43, 0x7f]),
+            vec![
+                Op::Pass,
+                Op::Advance(Duration::from_millis(5)),
+                Op::Pass,
+                Op::PassN(3),
+                Op::PassN(63),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_schedule_parse() {
+        assert_eq!(
+            Schedule::parse("+2 - >100ms+>1s").unwrap().steps(),
+            &[
+                Step::Expect(true),
+                Step::Expect(true),
+                Step::Expect(false),
+                Step::Advance(Duration::from_millis(100)),
+                Step::Expect(true),
+                Step::Advance(Duration::from_secs(1)),
+            ]
+        );
+        assert_eq!(Schedule::parse("+ >5m").unwrap_err().offset, 2);
+        assert_eq!(Schedule::parse("+ x").unwrap_err().offset, 2);
+        // too large for a u64, which is not a count of one
+        assert_eq!(
+            Schedule::parse("+ -99999999999999999999"),
+            Err(ParseError {
+                offset: 2,
+                message: "number out of range",
+            })
+        );
+        assert_eq!(
+            Schedule::parse(">18446744073709551616s")
+                .unwrap_err()
+                .offset,
+            0
+        );
+        assert_eq!(
+            Schedule::parse("+1000001").unwrap_err().message,
+            "count over Schedule::MAX_COUNT"
+        );
+        assert_eq!(
+            Schedule::parse("-1000000").unwrap().steps().len(),
+            1_000_000
+        );
+    }
+
+    #[test]
+    fn test_schedule_reports_mismatch() {
+        let clock = MockClock::new(0);
+        let policy = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .build();
+        let schedule = Schedule::parse("+- >100ms ++").unwrap();
+        assert_eq!(
+            schedule.run(&policy, &clock),
+            Err(Mismatch {
+                request: 3,
+                now: 100,
+                expected: true,
+            })
+        );
+    }
+
+    #[test]
+    fn test_assert_macros() {
+        let clock = MockClock::new(0);
+        let policy = VirtualScheduling::builder()
+            .clock(&clock)
+            .gap(Duration::from_millis(100))
+            .tolerance(Duration::from_millis(200))
+            .build();
+        crate::assert_admits!(policy, clock, "+3 - >100ms +-");
+        crate::assert_rate!(policy, clock, 9, Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_reference_traces() {
+        let traces = reference_traces();
+        for (name, trace) in &traces {
+            assert_eq!(trace.run_config(), Ok(()), "{name}");
+            assert_eq!(trace.run_remote(), Ok(()), "{name}");
+        }
+        // keyed state goes through the same GCRA step
+        let LimiterConfig::Gcra { rate, burst } = *traces[0].1.config() else {
+            panic!("the first trace is gcra");
+        };
+        let clock = MockClock::new(0);
+        let keyed: KeyedLimiter<String, _> =
+            KeyedLimiter::builder(Quota::per_second(rate).burst(burst))
+                .clock(&clock)
+                .build();
+        assert_eq!(
+            traces[0]
+                .1
+                .run(&clock, |cost| keyed.check_n("k", cost).is_ok()),
+            Ok(())
+        );
+        assert_eq!(
+            Trace::parse("algorithm=gcra rate=1\n0 1 allow\n0 1 maybe"),
+            Err(ParseError {
+                offset: 32,
+                message: "expected `allow` or `deny`",
+            })
+        );
+    }
+}
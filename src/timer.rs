@@ -0,0 +1,119 @@
+//! Timers for the async waits, e.g. [`Limiter::until_ready`](crate::Limiter::until_ready), on
+//! any runtime.
+//!
+//! Each runtime feature brings a timer: `tokio` [`TokioTimer`], `async-std` and `smol`
+//! [`AsyncIoTimer`], as both run on `async-io`, and `futures-timer` [`FuturesTimer`], which
+//! keeps a thread of its own and works on any executor. The waits use the first of these
+//! enabled, in this order. [`Limiter::timer`](crate::Limiter::timer) picks another, including a
+//! [`Sleep`] of your own.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+#[cfg(not(any(
+    feature = "tokio",
+    feature = "async-std",
+    feature = "smol",
+    feature = "futures-timer"
+)))]
+compile_error!("async-wait needs a timer: enable tokio, async-std, smol or futures-timer");
+
+/// A timer of an async runtime.
+pub trait Sleep: Send + Sync {
+    /// A future ready once `duration` has passed. [`Duration::MAX`] never passes.
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// `tokio::time::sleep`, which needs a tokio runtime with the time driver enabled.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Sleep for TokioTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// `async_io::Timer`, the timer of async-std and smol.
+#[cfg(any(feature = "async-std", feature = "smol"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AsyncIoTimer;
+
+#[cfg(any(feature = "async-std", feature = "smol"))]
+impl Sleep for AsyncIoTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let timer = async_io::Timer::after(duration);
+        Box::pin(async move {
+            timer.await;
+        })
+    }
+}
+
+/// `futures_timer::Delay`, run by a timer thread of its own.
+#[cfg(feature = "futures-timer")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuturesTimer;
+
+#[cfg(feature = "futures-timer")]
+impl Sleep for FuturesTimer {
+    fn sleep(&self, duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        // the delay's deadline would overflow
+        if std::time::Instant::now().checked_add(duration).is_none() {
+            return Box::pin(std::future::pending());
+        }
+        Box::pin(futures_timer::Delay::new(duration))
+    }
+}
+
+/// The timer of the first runtime feature enabled.
+#[cfg(feature = "tokio")]
+pub(crate) fn default_timer() -> Box<dyn Sleep> {
+    Box::new(TokioTimer)
+}
+
+#[cfg(all(not(feature = "tokio"), any(feature = "async-std", feature = "smol")))]
+pub(crate) fn default_timer() -> Box<dyn Sleep> {
+    Box::new(AsyncIoTimer)
+}
+
+#[cfg(all(
+    not(any(feature = "tokio", feature = "async-std", feature = "smol")),
+    feature = "futures-timer"
+))]
+pub(crate) fn default_timer() -> Box<dyn Sleep> {
+    Box::new(FuturesTimer)
+}
+
+/// Sleep on the [default timer](default_timer).
+pub(crate) fn sleep(duration: Duration) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    default_timer().sleep(duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timers() {
+        let timers: Vec<Box<dyn Sleep>> = vec![
+            default_timer(),
+            #[cfg(any(feature = "async-std", feature = "smol"))]
+            Box::new(AsyncIoTimer),
+            #[cfg(feature = "futures-timer")]
+            Box::new(FuturesTimer),
+        ];
+        for timer in timers {
+            let start = Instant::now();
+            timer.sleep(Duration::from_millis(10)).await;
+            assert!(start.elapsed() >= Duration::from_millis(10));
+            let never = timer.sleep(Duration::MAX);
+            let raced = tokio::time::timeout(Duration::from_millis(1), never).await;
+            assert!(raced.is_err());
+        }
+    }
+}
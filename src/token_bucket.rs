@@ -0,0 +1,491 @@
+//! A token bucket with a choice of refill strategies.
+//!
+//! A [`Gcra`](crate::Gcra) behaves like a token bucket refilled continuously. Limiters elsewhere
+//! often refill in steps instead, e.g. once per second or at the start of every minute. A client
+//! pacing itself continuously against such a limiter sees rejections whenever its requests drift
+//! across the other side's ticks. [`TokenBucket`] can refill the same way, see [`Refill`], so
+//! both sides agree on when capacity comes back.
+
+use std::cmp;
+use std::time::Duration;
+
+use crate::clock::{unix_millis, Clock, SystemClock};
+use crate::describe::{Describe, Description};
+use crate::gcra::{duration_nanos, Allowed, Denied, Headroom, Policy};
+use crate::quota::Quota;
+use crate::sync::Mutex;
+use crate::window::QuotaWindow;
+
+// the level is kept in billionths of a token, so a continuous refill adds `rate` per ns
+const UNIT: u64 = 1_000_000_000;
+
+/// How a [`TokenBucket`] gets its tokens back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Refill {
+    /// Continuously, as a GCRA does.
+    #[default]
+    Greedy,
+    /// The tokens of a whole interval at once, at the end of every interval since the bucket
+    /// was built.
+    Interval(Duration),
+    /// Back to full capacity at every multiple of the interval since the Unix epoch, like a
+    /// fixed window resetting at the start of each second or minute.
+    AlignedTick(Duration),
+}
+
+/// A bucket of `rate + burst` tokens, refilled at `rate` per second following a [`Refill`]
+/// strategy. Every request takes a token. The bucket starts full.
+pub struct TokenBucket<C = SystemClock> {
+    clock: C,
+    rate: u64,
+    capacity: u64,
+    refill: Refill,
+    state: Mutex<State>,
+}
+
+struct State {
+    // billionths of a token
+    level: u64,
+    // last continuous refill, or the start of the current interval, in ns as all times here
+    refilled_at: u64,
+    frozen_at: Option<u64>,
+}
+
+pub struct TokenBucketBuilder<C> {
+    clock: C,
+    quota: Quota,
+    refill: Refill,
+}
+
+impl TokenBucket<SystemClock> {
+    pub fn builder(quota: Quota) -> TokenBucketBuilder<SystemClock> {
+        TokenBucketBuilder {
+            clock: SystemClock,
+            quota,
+            refill: Refill::default(),
+        }
+    }
+}
+
+impl<C> TokenBucketBuilder<C> {
+    pub fn clock<NC>(self, clock: NC) -> TokenBucketBuilder<NC> {
+        TokenBucketBuilder {
+            clock,
+            quota: self.quota,
+            refill: self.refill,
+        }
+    }
+
+    /// # Panics
+    /// Panics if the interval of `refill` is zero.
+    pub fn refill(mut self, refill: Refill) -> Self {
+        if let Refill::Interval(interval) | Refill::AlignedTick(interval) = refill {
+            assert!(!interval.is_zero(), "refill interval must be positive");
+        }
+        self.refill = refill;
+        self
+    }
+}
+
+impl<C: Clock> TokenBucketBuilder<C> {
+    pub fn build(self) -> TokenBucket<C> {
+        let capacity = self.quota.capacity().saturating_mul(UNIT);
+        let refilled_at = match self.refill {
+            Refill::AlignedTick(interval) => align(self.clock.now_nanos(), nanos(interval)),
+            Refill::Greedy | Refill::Interval(_) => self.clock.now_nanos(),
+        };
+        TokenBucket {
+            clock: self.clock,
+            rate: self.quota.rate(),
+            capacity,
+            refill: self.refill,
+            state: Mutex::new(State {
+                level: capacity,
+                refilled_at,
+                frozen_at: None,
+            }),
+        }
+    }
+}
+
+fn nanos(interval: Duration) -> u64 {
+    duration_nanos(interval)
+}
+
+fn align(now: u64, interval: u64) -> u64 {
+    now - now % interval
+}
+
+impl<C> TokenBucket<C> {
+    /// The rate in tokens per second, and the capacity in tokens.
+    pub(crate) fn params(&self) -> (u64, u64) {
+        (self.rate, self.capacity / UNIT)
+    }
+
+    pub fn refill_strategy(&self) -> Refill {
+        self.refill
+    }
+
+    /// Whole tokens left in the bucket, as of the last decision.
+    pub fn tokens(&self) -> u64 {
+        self.state.lock().level / UNIT
+    }
+
+    fn refill_at(&self, state: &mut State, now: u64) {
+        if state.frozen_at.is_some() || now <= state.refilled_at {
+            return;
+        }
+        #[cfg(feature = "debug-internals")]
+        let before = state.level;
+        match self.refill {
+            Refill::Greedy => {
+                let added = (now - state.refilled_at).saturating_mul(self.rate);
+                state.level = cmp::min(state.level.saturating_add(added), self.capacity);
+                state.refilled_at = now;
+            }
+            Refill::Interval(interval) => {
+                let interval = nanos(interval);
+                let ticks = (now - state.refilled_at) / interval;
+                let added = (ticks * interval).saturating_mul(self.rate);
+                state.level = cmp::min(state.level.saturating_add(added), self.capacity);
+                state.refilled_at += ticks * interval;
+            }
+            Refill::AlignedTick(interval) => {
+                let tick = align(now, nanos(interval));
+                if tick > state.refilled_at {
+                    state.level = self.capacity;
+                    state.refilled_at = tick;
+                }
+            }
+        }
+        trace_internals!(
+            added = state.level.saturating_sub(before),
+            level = state.level,
+            refilled_at = state.refilled_at,
+            "token bucket refilled, in billionths of a token"
+        );
+    }
+
+    /// Time in ns from `now` until the bucket holds `needed` more billionths of a token.
+    fn wait_for(&self, state: &State, now: u64, needed: u64) -> u64 {
+        match self.refill {
+            Refill::Greedy => needed.div_ceil(self.rate),
+            Refill::Interval(interval) => {
+                let interval = nanos(interval);
+                let ticks = needed.div_ceil(interval.saturating_mul(self.rate));
+                state
+                    .refilled_at
+                    .saturating_add(ticks.saturating_mul(interval))
+                    .saturating_sub(now)
+            }
+            Refill::AlignedTick(interval) => {
+                (state.refilled_at + nanos(interval)).saturating_sub(now)
+            }
+        }
+    }
+}
+
+impl<C> TokenBucket<C>
+where
+    C: Clock,
+{
+    /// Put `n` tokens into the bucket on top of its refill, e.g. when credit was bought. The
+    /// bucket never holds more than its capacity, so tokens that would overflow it are lost.
+    pub fn add_tokens(&self, n: u64) {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        state.level = cmp::min(
+            state.level.saturating_add(n.saturating_mul(UNIT)),
+            self.capacity,
+        );
+    }
+
+    /// Report the current state as a [`QuotaWindow`]: the limit is the capacity, and the window
+    /// resets when the bucket is full again. For [`Refill::AlignedTick`] that is the start of the
+    /// next window, unless the bucket is still full.
+    pub fn quota_window(&self) -> QuotaWindow {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        let reset_after = match self.capacity - state.level {
+            0 => 0,
+            missing => self.wait_for(&state, now, missing),
+        };
+        QuotaWindow {
+            limit: self.capacity / UNIT,
+            remaining: state.level / UNIT,
+            resets_at: unix_millis(self.clock.to_system_time(now.saturating_add(reset_after))),
+            reset_after: Duration::from_nanos(reset_after),
+        }
+    }
+
+    /// Take all tokens left, so nothing passes until the next refill.
+    pub fn drain(&self) {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        state.level = 0;
+    }
+
+    /// Stop refilling until [`thaw`](Self::thaw). Requests are still admitted from the tokens
+    /// left.
+    pub fn freeze(&self) {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        state.frozen_at.get_or_insert(now);
+    }
+
+    /// Refill again. The time spent frozen does not count, except that aligned ticks stay
+    /// aligned: the next refill is at the next tick.
+    pub fn thaw(&self) {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        let Some(frozen_at) = state.frozen_at.take() else {
+            return;
+        };
+        state.refilled_at = match self.refill {
+            Refill::AlignedTick(interval) => align(now, nanos(interval)),
+            Refill::Greedy | Refill::Interval(_) => {
+                state.refilled_at + now.saturating_sub(frozen_at)
+            }
+        };
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.state.lock().frozen_at.is_some()
+    }
+
+    /// Take `n` tokens at once, all or nothing. A request for more tokens than the capacity is
+    /// never admitted, and is denied with a `retry_after` of [`Duration::MAX`].
+    pub fn check_n(&self, n: u64) -> Result<(), Denied> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        self.take(&mut state, now, n)
+    }
+
+    /// Decide on requests taking `costs` tokens each, in order, as [`check_n`](Self::check_n)
+    /// would, under one lock.
+    pub fn check_many(&self, costs: &[u64]) -> Vec<Result<(), Denied>> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        costs
+            .iter()
+            .map(|&n| self.take(&mut state, now, n))
+            .collect()
+    }
+
+    fn take(&self, state: &mut State, now: u64, n: u64) -> Result<(), Denied> {
+        let cost = n.saturating_mul(UNIT);
+        if state.level >= cost {
+            state.level -= cost;
+            return Ok(());
+        }
+        if cost > self.capacity {
+            trace_internals!(
+                cost,
+                capacity = self.capacity,
+                "token bucket denied over capacity"
+            );
+            return Err(Denied::new(Duration::MAX));
+        }
+        let wait = self.wait_for(state, now, cost - state.level);
+        trace_internals!(
+            level = state.level,
+            cost,
+            wait_ns = wait,
+            "token bucket denied"
+        );
+        Err(Denied::new(Duration::from_nanos(wait)))
+    }
+}
+
+impl<C> Policy for TokenBucket<C>
+where
+    C: Clock,
+{
+    fn check(&self) -> Result<(), Denied> {
+        self.check_n(1)
+    }
+
+    fn check_remaining(&self) -> Result<Allowed, Denied> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        self.take(&mut state, now, 1)?;
+        Ok(Allowed {
+            remaining: Some(state.level / UNIT),
+        })
+    }
+
+    fn check_n(&self, n: u64) -> Result<(), Denied> {
+        TokenBucket::check_n(self, n)
+    }
+
+    fn refund(&self) {
+        let mut state = self.state.lock();
+        state.level = cmp::min(state.level.saturating_add(UNIT), self.capacity);
+    }
+
+    fn headroom(&self) -> Option<Headroom> {
+        let now = self.clock.now_nanos();
+        let mut state = self.state.lock();
+        self.refill_at(&mut state, now);
+        let refill_rate = match self.refill {
+            Refill::Greedy | Refill::Interval(_) => self.rate as f64,
+            Refill::AlignedTick(interval) => (self.capacity / UNIT) as f64 / interval.as_secs_f64(),
+        };
+        Some(Headroom {
+            remaining: state.level / UNIT,
+            refill_rate,
+        })
+    }
+}
+
+impl<C> Describe for TokenBucket<C>
+where
+    C: Clock,
+{
+    fn describe(&self) -> Description {
+        let description = Description::new("token_bucket")
+            .setting("rate", format_args!("{}/s", self.rate))
+            .setting("capacity", self.capacity / UNIT);
+        let description = match self.refill {
+            Refill::Greedy => description.setting("refill", "greedy"),
+            Refill::Interval(interval) => {
+                description.setting("refill", format_args!("every {interval:?}"))
+            }
+            Refill::AlignedTick(interval) => {
+                description.setting("refill", format_args!("aligned {interval:?}"))
+            }
+        };
+        if self.is_frozen() {
+            return description.setting("mode", "frozen");
+        }
+        description
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+
+    use super::*;
+
+    fn bucket(clock: &MockClock, refill: Refill) -> TokenBucket<&MockClock> {
+        TokenBucket::builder(Quota::per_second(4))
+            .clock(clock)
+            .refill(refill)
+            .build()
+    }
+
+    fn drain(bucket: &TokenBucket<&MockClock>) -> Denied {
+        loop {
+            if let Err(denied) = bucket.check() {
+                return denied;
+            }
+        }
+    }
+
+    #[test]
+    fn test_token_bucket_greedy() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::Greedy);
+        assert_eq!(drain(&tb).retry_after(), Duration::from_millis(250));
+        clock.forward(Duration::from_millis(500));
+        assert_eq!(tb.check_n(2), Ok(()));
+        assert!(!tb.pass());
+    }
+
+    #[test]
+    fn test_token_bucket_sub_millisecond() {
+        // 4000/s is a token every 250us, which a refill counted in ms would round to 1ms
+        let clock = MockClock::new(1_000_100);
+        let tb = TokenBucket::builder(Quota::per_second(4000))
+            .clock(&clock)
+            .build();
+        assert_eq!(drain(&tb).retry_after(), Duration::from_micros(250));
+        for _ in 0..4 {
+            clock.forward(Duration::from_micros(250));
+            assert!(tb.pass());
+            assert!(!tb.pass());
+        }
+    }
+
+    #[test]
+    fn test_token_bucket_over_capacity() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::Greedy);
+        assert_eq!(tb.check_n(5), Err(Denied::new(Duration::MAX)));
+        assert_eq!(tb.check_n(u64::MAX), Err(Denied::new(Duration::MAX)));
+        // the bucket is full, so it is not the level that denies them
+        assert_eq!(tb.check_n(4), Ok(()));
+        assert_eq!(
+            tb.check_many(&[5, 1]),
+            vec![
+                Err(Denied::new(Duration::MAX)),
+                Err(Denied::new(Duration::from_millis(250)))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_token_bucket_interval() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::Interval(Duration::from_secs(1)));
+        assert_eq!(drain(&tb).retry_after(), Duration::from_secs(1));
+        // nothing comes back before the interval is over, then all of it
+        clock.forward(Duration::from_millis(999));
+        assert!(!tb.pass());
+        clock.forward(Duration::from_millis(1));
+        assert_eq!(tb.check_n(4), Ok(()));
+    }
+
+    #[test]
+    fn test_token_bucket_add_tokens() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::Interval(Duration::from_secs(1)));
+        drain(&tb);
+        // no refill before the interval is over, but added tokens are there at once
+        tb.add_tokens(2);
+        assert_eq!(tb.check_n(2), Ok(()));
+        assert!(!tb.pass());
+        // the bucket holds no more than its capacity
+        clock.forward(Duration::from_secs(1));
+        tb.add_tokens(3);
+        assert!(tb.check_n(5).is_err());
+        assert_eq!(tb.check_n(4), Ok(()));
+    }
+
+    #[test]
+    fn test_token_bucket_aligned_tick() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::AlignedTick(Duration::from_secs(1)));
+        // the next tick is at the next full second, not a second after the bucket was built
+        assert_eq!(drain(&tb).retry_after(), Duration::from_millis(900));
+        clock.forward(Duration::from_millis(900));
+        assert_eq!(tb.check_n(4), Ok(()));
+        assert_eq!(tb.tokens(), 0);
+        let window = tb.quota_window();
+        assert_eq!((window.limit, window.remaining), (4, 0));
+        assert_eq!(window.resets_at, 1_002_000);
+    }
+
+    #[test]
+    fn test_token_bucket_drain_freeze() {
+        let clock = MockClock::new(1_000_100);
+        let tb = bucket(&clock, Refill::Greedy);
+        tb.drain();
+        assert_eq!(tb.tokens(), 0);
+        tb.freeze();
+        clock.forward(Duration::from_secs(10));
+        assert!(!tb.pass());
+        tb.thaw();
+        assert!(!tb.pass());
+        clock.forward(Duration::from_millis(250));
+        assert!(tb.pass());
+    }
+}
@@ -0,0 +1,280 @@
+//! Usage reports for billing and capacity planning.
+//!
+//! A [`UsageAggregator`] counts admitted and denied requests per limiter, and optionally per key,
+//! over fixed intervals and hands a [`UsageReport`] for every interval to a [`Deliver`] sink: a
+//! closure, or the sending half of a channel. With the `serde` feature reports serialize, e.g.
+//! to JSON for an export pipeline.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::time::Duration;
+
+use crate::clock::{now_millis, Clock, SystemClock, Timestamp};
+use crate::listener::{Event, Listener};
+use crate::retry::Deliver;
+use crate::sync::Mutex;
+
+/// Requests seen by one limiter, or one key of it, during a report interval.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsageRecord {
+    pub limiter: String,
+    pub key: Option<String>,
+    pub allowed: u64,
+    pub denied: u64,
+}
+
+/// Everything counted from `start` until `end`, in ms since the Unix epoch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UsageReport {
+    pub start: Timestamp,
+    pub end: Timestamp,
+    /// Ordered by limiter, then key.
+    pub records: Vec<UsageRecord>,
+}
+
+/// Counts requests over fixed intervals and delivers a report for each.
+///
+/// Attached to a [`Limiter`](crate::Limiter) as a [`Listener`], it counts that limiter's
+/// decisions under its name. Per-key counts, e.g. from a [`KeyedLimiter`](crate::KeyedLimiter),
+/// are recorded explicitly with [`record`](Self::record).
+///
+/// A report is delivered once its interval is over and the aggregator notices, either when the
+/// next request is recorded or on [`tick`](Self::tick). Intervals without any requests produce no
+/// report. On shutdown, [`flush`](Self::flush) delivers what was counted in the current interval
+/// so far.
+///
+/// # Example
+/// ```
+/// use std::sync::mpsc;
+/// use std::time::Duration;
+/// use ratelimit::UsageAggregator;
+///
+/// let (tx, rx) = mpsc::channel();
+/// let usage = UsageAggregator::new(Duration::from_secs(60), tx);
+/// usage.record("api", Some("tenant-a"), true);
+/// usage.record("api", Some("tenant-a"), false);
+/// usage.flush();
+/// let report = rx.try_recv().unwrap();
+/// assert_eq!(report.records[0].allowed, 1);
+/// assert_eq!(report.records[0].denied, 1);
+/// ```
+pub struct UsageAggregator<D, C = SystemClock> {
+    clock: C,
+    // ms
+    interval: u64,
+    deliver: Mutex<D>,
+    current: Mutex<Interval>,
+}
+
+struct Interval {
+    start: Timestamp,
+    counts: BTreeMap<(String, Option<String>), (u64, u64)>,
+}
+
+impl<D> UsageAggregator<D, SystemClock> {
+    /// # Panics
+    /// Panics if `interval` is shorter than a millisecond.
+    pub fn new(interval: Duration, deliver: D) -> Self {
+        let interval = interval.as_millis() as u64;
+        assert!(interval > 0, "report interval must be at least 1ms");
+        UsageAggregator {
+            clock: SystemClock,
+            interval,
+            deliver: Mutex::new(deliver),
+            current: Mutex::new(Interval {
+                start: now_millis(&SystemClock),
+                counts: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl<D, C> UsageAggregator<D, C> {
+    /// Use `clock`, starting the first interval at its current time.
+    pub fn clock<NC: Clock>(self, clock: NC) -> UsageAggregator<D, NC> {
+        let start = now_millis(&clock);
+        UsageAggregator {
+            clock,
+            interval: self.interval,
+            deliver: self.deliver,
+            current: Mutex::new(Interval {
+                start,
+                counts: BTreeMap::new(),
+            }),
+        }
+    }
+}
+
+impl<D, C> UsageAggregator<D, C>
+where
+    D: Deliver<UsageReport>,
+    C: Clock,
+{
+    pub fn record(&self, limiter: &str, key: Option<&str>, allowed: bool) {
+        let now = now_millis(&self.clock);
+        let ended = {
+            let mut current = self.current.lock();
+            let ended = self.roll_over(&mut current, now);
+            let counts = current
+                .counts
+                .entry((limiter.to_owned(), key.map(str::to_owned)))
+                .or_default();
+            if allowed {
+                counts.0 += 1;
+            } else {
+                counts.1 += 1;
+            }
+            ended
+        };
+        self.deliver(ended);
+    }
+
+    /// Deliver the report of the interval that just ended, if any. Worth calling from a timer
+    /// when requests may stop coming for a while.
+    pub fn tick(&self) {
+        let now = now_millis(&self.clock);
+        let ended = self.roll_over(&mut self.current.lock(), now);
+        self.deliver(ended);
+    }
+
+    /// Deliver what was counted in the current interval so far and start a new one.
+    pub fn flush(&self) {
+        let now = now_millis(&self.clock);
+        let ended = {
+            let mut current = self.current.lock();
+            let counts = mem::take(&mut current.counts);
+            let start = mem::replace(&mut current.start, now);
+            report(start, now, counts)
+        };
+        self.deliver(ended);
+    }
+
+    /// Deliver reports on time until `shutdown` completes, then flush.
+    #[cfg(feature = "tokio")]
+    pub async fn run(&self, shutdown: impl std::future::Future<Output = ()>) {
+        let interval = Duration::from_millis(self.interval);
+        let mut shutdown = std::pin::pin!(shutdown);
+        while tokio::time::timeout(interval, &mut shutdown).await.is_err() {
+            self.tick();
+        }
+        self.flush();
+    }
+
+    fn roll_over(&self, current: &mut Interval, now: Timestamp) -> Option<UsageReport> {
+        if now < current.start + self.interval {
+            return None;
+        }
+        let start = current.start;
+        // skip the intervals nothing happened in, staying on the same grid
+        current.start = now - (now - start) % self.interval;
+        report(start, start + self.interval, mem::take(&mut current.counts))
+    }
+
+    fn deliver(&self, report: Option<UsageReport>) {
+        if let Some(report) = report {
+            self.deliver.lock().deliver(report);
+        }
+    }
+}
+
+fn report(
+    start: Timestamp,
+    end: Timestamp,
+    counts: BTreeMap<(String, Option<String>), (u64, u64)>,
+) -> Option<UsageReport> {
+    if counts.is_empty() {
+        return None;
+    }
+    let records = counts
+        .into_iter()
+        .map(|((limiter, key), (allowed, denied))| UsageRecord {
+            limiter,
+            key,
+            allowed,
+            denied,
+        })
+        .collect();
+    Some(UsageReport {
+        start,
+        end,
+        records,
+    })
+}
+
+impl<D, C> Listener for UsageAggregator<D, C>
+where
+    D: Deliver<UsageReport> + Send,
+    C: Clock + Send + Sync,
+{
+    fn on_event(&self, event: &Event<'_>) {
+        match *event {
+            Event::Allowed { policy, .. } => self.record(policy, None, true),
+            Event::Denied { policy, .. } => self.record(policy, None, false),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc};
+
+    use crate::clock::MockClock;
+    use crate::gcra::{Policy, VirtualScheduling};
+    use crate::limiter::Limiter;
+
+    use super::*;
+
+    #[test]
+    fn test_usage_intervals() {
+        let clock = Arc::new(MockClock::new(1_000_000));
+        let (tx, rx) = mpsc::channel();
+        let usage =
+            Arc::new(UsageAggregator::new(Duration::from_secs(60), tx).clock(clock.clone()));
+        let limiter = Limiter::new(
+            VirtualScheduling::builder()
+                .clock(clock.clone())
+                .gap(Duration::from_secs(1))
+                .build(),
+        )
+        .named("api")
+        .listener(usage.clone());
+
+        assert!(limiter.pass());
+        assert!(!limiter.pass());
+        usage.record("api", Some("tenant-a"), true);
+        clock.forward(Duration::from_secs(59));
+        usage.tick();
+        assert!(rx.try_recv().is_err());
+
+        // the first request after the interval delivers its report
+        clock.forward(Duration::from_secs(150));
+        assert!(limiter.pass());
+        let report = rx.try_recv().unwrap();
+        assert_eq!((report.start, report.end), (1_000_000, 1_060_000));
+        assert_eq!(
+            report.records,
+            [
+                UsageRecord {
+                    limiter: "api".into(),
+                    key: None,
+                    allowed: 1,
+                    denied: 1,
+                },
+                UsageRecord {
+                    limiter: "api".into(),
+                    key: Some("tenant-a".into()),
+                    allowed: 1,
+                    denied: 0,
+                },
+            ]
+        );
+
+        usage.flush();
+        let report = rx.try_recv().unwrap();
+        assert_eq!((report.start, report.end), (1_180_000, 1_209_000));
+        assert_eq!(report.records.len(), 1);
+    }
+}
@@ -0,0 +1,259 @@
+//! The queue of async waiters of a [`Limiter`](crate::Limiter).
+//!
+//! Only the waiter at the head of the queue asks the policy for admission; everybody else is
+//! parked until they get to the head. The head is the waiter with the highest priority, ties
+//! broken by arrival. With aging, a waiter gains one priority level for every `aging` it spent
+//! in the queue, so low priority waiters cannot starve.
+
+use std::task::Waker;
+
+use crate::clock::Timestamp;
+use crate::sync::Mutex;
+
+pub struct WaitQueue {
+    inner: Mutex<Inner>,
+    // ms per priority level gained while waiting
+    aging: Option<u64>,
+}
+
+struct Inner {
+    next_id: u64,
+    waiters: Vec<Waiter>,
+}
+
+struct Waiter {
+    id: u64,
+    priority: u32,
+    since: Timestamp,
+    waker: Option<Waker>,
+}
+
+impl WaitQueue {
+    pub fn new(aging: Option<u64>) -> Self {
+        WaitQueue {
+            inner: Mutex::new(Inner {
+                next_id: 0,
+                waiters: Vec::new(),
+            }),
+            aging,
+        }
+    }
+
+    pub(crate) fn set_aging(&mut self, aging: Option<u64>) {
+        self.aging = aging;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.inner.lock().waiters.len()
+    }
+
+    /// Wake every parked waiter, e.g. to let it notice that the limiter closed.
+    pub(crate) fn wake_all(&self) {
+        let wakers: Vec<Waker> = self
+            .inner
+            .lock()
+            .waiters
+            .iter_mut()
+            .filter_map(|w| w.waker.take())
+            .collect();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Join the queue. The returned ticket leaves it again when dropped.
+    pub fn join(&self, priority: u32, now: Timestamp) -> Ticket<'_> {
+        let mut inner = self.inner.lock();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.waiters.push(Waiter {
+            id,
+            priority,
+            since: now,
+            waker: None,
+        });
+        Ticket { queue: self, id }
+    }
+
+    fn head(&self, inner: &Inner) -> Option<u64> {
+        // With aging, a waiter's effective priority is `priority + (now - since) / aging`. All
+        // waiters age at the same pace, so comparing `priority * aging - since` gives the same
+        // order at any `now`.
+        let rank = |w: &Waiter| match self.aging {
+            Some(aging) => (
+                w.priority as i128 * aging as i128 - w.since as i128,
+                -(w.id as i128),
+            ),
+            None => (w.priority as i128, -(w.id as i128)),
+        };
+        inner.waiters.iter().max_by_key(|w| rank(w)).map(|w| w.id)
+    }
+}
+
+pub struct Ticket<'a> {
+    queue: &'a WaitQueue,
+    id: u64,
+}
+
+impl Ticket<'_> {
+    /// Whether this waiter is at the head of the queue. If not, `waker` is woken once it is.
+    pub fn is_head(&self, waker: &Waker) -> bool {
+        let mut inner = self.queue.inner.lock();
+        if self.queue.head(&inner) == Some(self.id) {
+            return true;
+        }
+        if let Some(w) = inner.waiters.iter_mut().find(|w| w.id == self.id) {
+            w.waker = Some(waker.clone());
+        }
+        false
+    }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        let mut inner = self.queue.inner.lock();
+        inner.waiters.retain(|w| w.id != self.id);
+        let head = self.queue.head(&inner);
+        let waker = inner
+            .waiters
+            .iter_mut()
+            .find(|w| Some(w.id) == head)
+            .and_then(|w| w.waker.take());
+        drop(inner);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Waker;
+
+    use super::*;
+
+    #[test]
+    fn test_wait_queue_order() {
+        let queue = WaitQueue::new(None);
+        let waker = Waker::noop();
+        let low = queue.join(0, 0);
+        let high = queue.join(5, 10);
+        let high2 = queue.join(5, 20);
+        assert!(high.is_head(waker));
+        assert!(!high2.is_head(waker));
+        assert!(!low.is_head(waker));
+        drop(high);
+        assert!(high2.is_head(waker));
+        drop(high2);
+        assert!(low.is_head(waker));
+        drop(low);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_wait_queue_aging() {
+        // one level per 100ms in the queue
+        let queue = WaitQueue::new(Some(100));
+        let waker = Waker::noop();
+        let old = queue.join(0, 0);
+        let young = queue.join(3, 250);
+        assert!(young.is_head(waker));
+        drop(young);
+        let young = queue.join(3, 350);
+        assert!(old.is_head(waker));
+        drop(old);
+        assert!(young.is_head(waker));
+    }
+
+    #[test]
+    fn test_wait_queue_join_leave() {
+        // what benches/waiters.rs measures: a waiter passing a crowd of parked ones and leaving
+        let queue = WaitQueue::new(None);
+        let waker = Waker::noop();
+        let parked: Vec<_> = (0..1000).map(|i| queue.join(i % 4, 0)).collect();
+        for _ in 0..3 {
+            let ticket = queue.join(8, 0);
+            assert!(ticket.is_head(waker));
+            drop(ticket);
+            assert_eq!(queue.len(), 1000);
+        }
+        // the first of the highest parked priority is back at the head
+        assert!(parked[3].is_head(waker));
+        assert!(!parked[7].is_head(waker));
+    }
+
+    /// A waker counting its wakes.
+    #[cfg(loom)]
+    struct Wakes(crate::sync::AtomicUsize);
+
+    #[cfg(loom)]
+    impl std::task::Wake for Wakes {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.fetch_add(1, crate::sync::Ordering::SeqCst);
+        }
+    }
+
+    #[cfg(loom)]
+    fn counting_waker() -> (std::sync::Arc<Wakes>, Waker) {
+        let wakes = std::sync::Arc::new(Wakes(crate::sync::AtomicUsize::new(0)));
+        (wakes.clone(), Waker::from(wakes))
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_join_drop() {
+        use crate::sync::Ordering;
+
+        loom::model(|| {
+            let queue = std::sync::Arc::new(WaitQueue::new(None));
+            let (wakes, waker) = counting_waker();
+            let parked = queue.join(0, 0);
+            let head = queue.join(5, 0);
+            assert!(!parked.is_head(&waker));
+            // a waiter passing the parked one joins, polls and gives up while the head leaves
+            let passing = {
+                let queue = queue.clone();
+                loom::thread::spawn(move || {
+                    let (_, waker) = counting_waker();
+                    let ticket = queue.join(3, 0);
+                    ticket.is_head(&waker);
+                })
+            };
+            drop(head);
+            passing.join().unwrap();
+            // whichever left last handed the head to the parked waiter, and woke it
+            assert!(parked.is_head(Waker::noop()));
+            assert!(wakes.0.load(Ordering::SeqCst) >= 1);
+        });
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn test_loom_poll_drop() {
+        use crate::sync::Ordering;
+
+        loom::model(|| {
+            let queue = std::sync::Arc::new(WaitQueue::new(None));
+            let left = std::sync::Arc::new(crate::sync::AtomicBool::new(false));
+            let head = queue.join(5, 0);
+            // the waiter polls for the first time while the head leaves: it sees itself at the
+            // head, or its waker is registered in time for the head to wake it
+            let polling = {
+                let (queue, left) = (queue.clone(), left.clone());
+                loom::thread::spawn(move || {
+                    let (wakes, waker) = counting_waker();
+                    let ticket = queue.join(0, 0);
+                    let is_head = ticket.is_head(&waker);
+                    while !left.load(Ordering::SeqCst) {
+                        crate::sync::yield_now();
+                    }
+                    assert!(is_head || wakes.0.load(Ordering::SeqCst) == 1);
+                    assert!(ticket.is_head(Waker::noop()));
+                })
+            };
+            drop(head);
+            left.store(true, Ordering::SeqCst);
+            polling.join().unwrap();
+        });
+    }
+}
@@ -0,0 +1,176 @@
+//! Reporting GCRA state as a fixed window quota.
+//!
+//! Popular APIs present their limits as "`limit` requests per window, `remaining` left, window
+//! resets at `resets_at`". GCRA has no windows, but its state maps onto these numbers naturally:
+//! the limit is the number of requests that go through at once, remaining is how many would go
+//! through right now, and the "window" resets when the TAT is reached, as from then on the full
+//! limit is available again. [`TokenBucket`](crate::TokenBucket) reports the same numbers, with
+//! the exact start of the next window for aligned ticks.
+//!
+//! With the `chrono` feature, the reset can also be rendered as an RFC 3339 date in any time
+//! zone, e.g. a fixed UTC offset or an IANA zone from `chrono-tz`.
+
+use std::time::{Duration, SystemTime};
+
+use crate::clock::{unix_millis, Clock, Timestamp};
+use crate::gcra::{available, Gcra};
+
+/// Quota numbers of a nominal window, as reported by [`Gcra::quota_window`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaWindow {
+    pub limit: u64,
+    pub remaining: u64,
+    /// When `remaining` is back to `limit`, in ms since the unix epoch, converted from clock
+    /// time with [`Clock::to_system_time`].
+    pub resets_at: Timestamp,
+    /// How long until `resets_at`.
+    pub reset_after: Duration,
+}
+
+impl QuotaWindow {
+    /// The window as `X-RateLimit-*` headers, the way GitHub reports it. The reset is given in
+    /// unix seconds, rounded up.
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            ("X-RateLimit-Limit", self.limit.to_string()),
+            ("X-RateLimit-Remaining", self.remaining.to_string()),
+            (
+                "X-RateLimit-Reset",
+                self.resets_at.div_ceil(1000).to_string(),
+            ),
+        ]
+    }
+
+    /// `resets_at` as an RFC 3339 date with millisecond precision in the zone `tz`, e.g.
+    /// `2024-03-01T09:00:00.000+01:00`.
+    #[cfg(feature = "chrono")]
+    pub fn resets_at_rfc3339<Tz>(&self, tz: &Tz) -> String
+    where
+        Tz: chrono::TimeZone,
+        Tz::Offset: std::fmt::Display,
+    {
+        rfc3339(self.resets_at, tz)
+    }
+
+    pub fn resets_at_system_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_millis(self.resets_at)
+    }
+}
+
+/// What a key has left of its quota, for showing users e.g. "37 calls left this hour" from the
+/// state that enforces it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaStatus {
+    pub limit: u64,
+    pub remaining: u64,
+    /// How long until `remaining` is back to `limit`.
+    pub resets_in: Duration,
+}
+
+impl From<QuotaWindow> for QuotaStatus {
+    fn from(window: QuotaWindow) -> Self {
+        QuotaStatus {
+            limit: window.limit,
+            remaining: window.remaining,
+            resets_in: window.reset_after,
+        }
+    }
+}
+
+/// A time in ms since the unix epoch as an RFC 3339 date with millisecond precision in the zone
+/// `tz`.
+#[cfg(feature = "chrono")]
+pub fn rfc3339<Tz>(timestamp: Timestamp, tz: &Tz) -> String
+where
+    Tz: chrono::TimeZone,
+    Tz::Offset: std::fmt::Display,
+{
+    let ms = i64::try_from(timestamp).unwrap_or(i64::MAX);
+    let utc = chrono::DateTime::from_timestamp_millis(ms)
+        .unwrap_or(chrono::DateTime::<chrono::Utc>::MAX_UTC);
+    utc.with_timezone(tz)
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+impl<C> Gcra<C>
+where
+    C: Clock,
+{
+    /// Report the current state as a [`QuotaWindow`]. A policy with a zero `gap` never runs out,
+    /// and reports a limit of `u64::MAX`.
+    pub fn quota_window(&self) -> QuotaWindow {
+        let now = self.now();
+        let (tat, gap, tolerance) = self.state();
+        let tat = std::cmp::max(tat, now);
+        if gap == 0 {
+            return QuotaWindow {
+                limit: u64::MAX,
+                remaining: u64::MAX,
+                resets_at: unix_millis(self.clock.to_system_time(now)),
+                reset_after: Duration::ZERO,
+            };
+        }
+        let limit = tolerance / gap + 1;
+        let remaining = available(tat, now, gap, tolerance);
+        QuotaWindow {
+            limit,
+            remaining,
+            resets_at: unix_millis(self.clock.to_system_time(tat)),
+            reset_after: Duration::from_nanos(tat - now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::clock::MockClock;
+    use crate::gcra::{LeakyBucket, Policy};
+
+    use super::*;
+
+    #[test]
+    fn test_quota_window() {
+        let clock = MockClock::new(10_000);
+        let rl = LeakyBucket::builder().clock(&clock).rate(10).build();
+        let window = rl.quota_window();
+        assert_eq!((window.limit, window.remaining), (10, 10));
+        assert_eq!(window.reset_after, Duration::ZERO);
+
+        for _ in 0..4 {
+            assert!(rl.pass());
+        }
+        let window = rl.quota_window();
+        assert_eq!((window.limit, window.remaining), (10, 6));
+        assert_eq!(window.resets_at, 10_400);
+
+        for _ in 0..6 {
+            assert!(rl.pass());
+        }
+        assert_eq!(rl.quota_window().remaining, 0);
+        clock.forward(Duration::from_millis(250));
+        let window = rl.quota_window();
+        assert_eq!(window.remaining, 2);
+        assert_eq!(window.reset_after, Duration::from_millis(750));
+        assert_eq!(window.headers()[2], ("X-RateLimit-Reset", "11".to_string()));
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn test_resets_at_rfc3339() {
+        let window = QuotaWindow {
+            limit: 10,
+            remaining: 0,
+            resets_at: 1_709_280_000_500,
+            reset_after: Duration::from_millis(500),
+        };
+        assert_eq!(
+            window.resets_at_rfc3339(&chrono::Utc),
+            "2024-03-01T08:00:00.500Z"
+        );
+        let cet = chrono::FixedOffset::east_opt(3600).unwrap();
+        assert_eq!(
+            window.resets_at_rfc3339(&cet),
+            "2024-03-01T09:00:00.500+01:00"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+//! Stress tests on the real clock, opt in with `cargo test --features stress`.
+//!
+//! The unit tests drive time by hand and cannot see what only shows up under real
+//! concurrency: lost updates, waiters that oversleep or stampede, drift between the policy and
+//! the clock. These run many tasks and threads against shared limiters for a few seconds each
+//! and check that the admitted throughput stays within a band around the configured rate.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ratelimit::{KeyedLimiter, LeakyBucket, Limiter, Policy, Quota};
+
+/// How far admitted throughput may be off the configured rate.
+const TOLERANCE: f64 = 0.05;
+
+fn assert_within_band(admitted: u64, expected: u64) {
+    let low = expected as f64 * (1.0 - TOLERANCE);
+    let high = expected as f64 * (1.0 + TOLERANCE);
+    assert!(
+        (low..=high).contains(&(admitted as f64)),
+        "admitted {admitted}, expected {expected} ± {}%",
+        TOLERANCE * 100.0
+    );
+}
+
+/// Call `f` from `threads` threads as fast as they can for `run`, counting its successes.
+fn hammer(threads: usize, run: Duration, f: impl Fn() -> bool + Sync) -> u64 {
+    let admitted = AtomicU64::new(0);
+    let barrier = Barrier::new(threads);
+    // the same end for all, as threads may start late on a busy machine
+    let end = Instant::now() + run;
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                barrier.wait();
+                while Instant::now() < end {
+                    if f() {
+                        admitted.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+        }
+    });
+    admitted.into_inner()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn waiters_drain_at_the_configured_rate() {
+    const TASKS: usize = 400;
+    const RATE: u64 = 500;
+    let run = Duration::from_secs(2);
+    let limiter = Arc::new(Limiter::new(LeakyBucket::builder().rate(RATE).build()));
+    let admitted = Arc::new(AtomicU64::new(0));
+    let end = tokio::time::Instant::now() + run;
+    let tasks: Vec<_> = (0..TASKS)
+        .map(|_| {
+            let limiter = limiter.clone();
+            let admitted = admitted.clone();
+            tokio::spawn(async move {
+                while let Ok(ready) = tokio::time::timeout_at(end, limiter.until_ready()).await {
+                    ready.unwrap();
+                    admitted.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    // the initial burst of one second's worth, then the rate
+    assert_within_band(admitted.load(Ordering::Relaxed), RATE * 3);
+    assert_eq!(limiter.waiting(), 0);
+}
+
+#[test]
+fn gcra_holds_its_rate_under_contention() {
+    const RATE: u64 = 1000;
+    let gcra = LeakyBucket::builder().rate(RATE).build();
+    let admitted = hammer(64, Duration::from_secs(2), || gcra.check().is_ok());
+    assert_within_band(admitted, RATE * 3);
+}
+
+#[test]
+fn keyed_limiter_holds_every_key_to_its_rate() {
+    const KEYS: usize = 16;
+    const RATE: u64 = 100;
+    let limiter: KeyedLimiter<usize> = KeyedLimiter::builder(Quota::per_second(RATE)).build();
+    let counts: Vec<_> = (0..KEYS).map(|_| AtomicU64::new(0)).collect();
+    let next = AtomicU64::new(0);
+    hammer(32, Duration::from_secs(2), || {
+        let key = next.fetch_add(1, Ordering::Relaxed) as usize % KEYS;
+        let admitted = limiter.check(&key).is_ok();
+        if admitted {
+            counts[key].fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    });
+    for count in counts {
+        assert_within_band(count.into_inner(), RATE * 3);
+    }
+}